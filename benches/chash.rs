@@ -0,0 +1,43 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+use hd_api::hashing;
+
+const SIZE: usize = 16 * 1024 * 1024;
+
+fn data() -> Vec<u8> {
+    // Pseudo-random, non-zero content: zero blocks are not hashed.
+    let mut x: u32 = 12345;
+    (0..SIZE)
+        .map(|_| {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            (x >> 16) as u8 | 1
+        })
+        .collect()
+}
+
+fn bench_chash(c: &mut Criterion) {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let data = data();
+
+    let mut g = c.benchmark_group("chash");
+    g.throughput(Throughput::Bytes(SIZE as u64));
+    g.sample_size(10);
+    g.bench_function("serial", |b| {
+        b.iter(|| rt.block_on(hashing::chash(&data[..])).unwrap())
+    });
+    for tasks in [2, 4, 8] {
+        g.bench_function(format!("parallel/{}", tasks), |b| {
+            b.iter(|| {
+                rt.block_on(hashing::chash_parallel(&data[..], tasks))
+                    .unwrap()
+            })
+        });
+    }
+    g.finish();
+}
+
+criterion_group!(benches, bench_chash);
+criterion_main!(benches);
@@ -3,13 +3,11 @@ use hd_api::{self, hidrive, Params};
 
 use serde_json::to_string_pretty;
 
-async fn list_me(mut u: hidrive::HiDriveUser<'_>) -> anyhow::Result<()> {
-    let mut p = Params::new();
-    p.add_str("fields", "account,alias,descr,email,email_pending,email_verified,encrypted,folder.id,folder.path,folder.size,home,home_id,is_admin,is_owner,language,protocols,has_password");
-    let me = u.me(Some(&p)).await?;
-    println!("{}", to_string_pretty(&me)?);
-    Ok(())
-}
+/// The home directory is requested separately, by `HiDriveUser::home()`.
+const ME_FIELDS: &str = concat!(
+    "account,alias,descr,email,email_pending,email_verified,encrypted,folder.id,folder.path,",
+    "folder.size,is_admin,is_owner,language,protocols,has_password"
+);
 
 const CLIENT_SECRET_PATH: &str = "clientsecret.json";
 const CREDENTIALS_PATH: &str = "credentials.json";
@@ -47,5 +45,10 @@ async fn main() {
     let authz = oauth2::Authorizer::new_with_client(credentials, client_secret, client.clone());
 
     let mut hd = hidrive::HiDrive::new(client, authz);
-    list_me(hd.user()).await.unwrap();
+    let mut p = Params::new();
+    p.add_str("fields", ME_FIELDS);
+    let me = hd.user().me(Some(&p)).await.unwrap();
+    println!("{}", to_string_pretty(&me).unwrap());
+    let home = hd.user().home().await.unwrap();
+    println!("Home directory: {} ({})", home.path, home.id);
 }
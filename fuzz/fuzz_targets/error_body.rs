@@ -0,0 +1,14 @@
+#![no_main]
+
+use hd_api::http::error_from_body;
+use hd_api::types::{error_status, Item};
+use libfuzzer_sys::fuzz_target;
+use reqwest::StatusCode;
+
+fuzz_target!(|data: &[u8]| {
+    let body = String::from_utf8_lossy(data);
+    let e = error_from_body(StatusCode::BAD_REQUEST, None, &body);
+    let status = error_status(&e).expect("error responses have a status");
+    assert!((100..=599).contains(&status));
+    let _ = serde_json::from_slice::<Item>(data);
+});
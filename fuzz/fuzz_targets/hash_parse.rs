@@ -0,0 +1,12 @@
+#![no_main]
+
+use hd_api::hashing::Hash;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|s: &str| {
+    if let Ok(h) = Hash::parse(s) {
+        assert_eq!(s.to_ascii_lowercase(), h.to_string());
+    }
+    // The same parser is used when deserializing API responses.
+    let _ = serde_json::from_value::<Hash>(serde_json::Value::String(s.to_string()));
+});
@@ -0,0 +1,12 @@
+#![no_main]
+
+use hd_api::types::Identifier;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|s: &str| {
+    if let Ok(id) = s.parse::<Identifier>() {
+        let formatted = id.to_string();
+        let again: Identifier = formatted.parse().expect("formatted identifier parses");
+        assert_eq!(formatted, again.to_string());
+    }
+});
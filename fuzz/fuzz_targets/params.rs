@@ -0,0 +1,12 @@
+#![no_main]
+
+use hd_api::types::Params;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|s: &str| {
+    if let Ok(p) = s.parse::<Params>() {
+        for (k, _) in p.iter() {
+            assert!(!k.is_empty());
+        }
+    }
+});
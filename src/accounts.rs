@@ -0,0 +1,166 @@
+//! Several HiDrive accounts in one process, see `Accounts`.
+
+use crate::hidrive::{Endpoints, HiDrive};
+use crate::http::Transport;
+use crate::oauth2::{Authorizer, ClientSecret, Credentials};
+use crate::throttle::Throttle;
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+
+/// Limits applying to the requests of one account, across all its forks.
+#[derive(Debug, Clone, Default)]
+pub struct AccountLimits {
+    /// Maximum number of requests in flight, see `HiDrive::set_concurrency_limit()`.
+    pub concurrency: Option<usize>,
+    /// Maximum transfer rate in bytes per second, see `HiDrive::set_throttle()`.
+    pub bandwidth: Option<u64>,
+}
+
+/// Holds a `HiDrive` per account (e.g. personal and business), identified by a name. All
+/// accounts share one HTTP client and with it the connection pool; each has its own tokens and
+/// limits.
+///
+/// ```ignore
+/// let mut accounts = Accounts::new()?;
+/// let limits = AccountLimits::default();
+/// accounts.load("work", "work_secret.json", "work_credentials.json", &limits).await?;
+/// let mut hd = accounts.get("work").unwrap();
+/// ```
+pub struct Accounts {
+    transport: Arc<dyn Transport>,
+    endpoints: Option<Endpoints>,
+    accounts: BTreeMap<String, HiDrive>,
+}
+
+impl Accounts {
+    /// Accounts using a default HTTP client.
+    pub fn new() -> Result<Accounts> {
+        let cl = reqwest::Client::builder()
+            .gzip(true)
+            .brotli(true)
+            .build()
+            .context("Accounts: building HTTP client")?;
+        Ok(Accounts::with_transport(Arc::new(cl)))
+    }
+
+    /// Accounts sending all requests, including token requests, through `transport`, e.g. a
+    /// configured `reqwest::Client`.
+    pub fn with_transport(transport: Arc<dyn Transport>) -> Accounts {
+        Accounts {
+            transport,
+            endpoints: None,
+            accounts: BTreeMap::new(),
+        }
+    }
+
+    /// Talk to `endpoints` instead of the HiDrive production servers, for accounts added
+    /// afterwards.
+    pub fn set_endpoints(&mut self, endpoints: Option<Endpoints>) {
+        self.endpoints = endpoints;
+    }
+
+    /// Add the account `name`, replacing an account of the same name.
+    pub fn add(
+        &mut self,
+        name: impl Into<String>,
+        cred: Credentials,
+        cs: ClientSecret,
+        limits: &AccountLimits,
+    ) -> Result<&mut HiDrive> {
+        let authz = Authorizer::new_with_transport(cred, cs, self.transport.clone());
+        let mut builder = HiDrive::builder(authz).transport(self.transport.clone());
+        if let Some(ref e) = self.endpoints {
+            builder = builder.endpoints(e.clone());
+        }
+        let mut hd = builder.build()?;
+        hd.set_concurrency_limit(limits.concurrency);
+        hd.set_throttle(
+            limits
+                .bandwidth
+                .map(|rate| Arc::new(Throttle::new(Some(rate)))),
+        );
+        let name = name.into();
+        self.accounts.remove(&name);
+        Ok(self.accounts.entry(name).or_insert(hd))
+    }
+
+    /// Add the account `name` with the client secret and credentials stored in files, see
+    /// `ClientSecret::load()` and `Credentials::load()`.
+    pub async fn load(
+        &mut self,
+        name: impl Into<String>,
+        client_secret: impl AsRef<Path>,
+        credentials: impl AsRef<Path>,
+        limits: &AccountLimits,
+    ) -> Result<&mut HiDrive> {
+        let cs = ClientSecret::load(client_secret).await?;
+        let cred = Credentials::load(credentials).await?;
+        self.add(name, cred, cs, limits)
+    }
+
+    /// A fork (see `HiDrive::fork()`) of the account `name`, sharing its limits.
+    pub fn get(&self, name: &str) -> Option<HiDrive> {
+        self.accounts.get(name).map(HiDrive::fork)
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut HiDrive> {
+        self.accounts.get_mut(name)
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<HiDrive> {
+        self.accounts.remove(name)
+    }
+
+    /// The account names, in order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.accounts.keys().map(String::as_str)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::mock::{MockTransport, TOKEN_RESPONSE};
+    use crate::types::Identifier;
+
+    #[tokio::test]
+    async fn test_accounts() {
+        let t = MockTransport::new();
+        let mut accounts = Accounts::with_transport(t.clone());
+        accounts.set_endpoints(Some(Endpoints::for_host("http://localhost:1234")));
+        let cred = || serde_json::from_str::<Credentials>(TOKEN_RESPONSE).unwrap();
+        let limits = AccountLimits {
+            concurrency: Some(2),
+            bandwidth: Some(1 << 20),
+        };
+        accounts
+            .add("work", cred(), ClientSecret::default(), &limits)
+            .unwrap();
+        accounts
+            .add(
+                "home",
+                cred(),
+                ClientSecret::default(),
+                &AccountLimits::default(),
+            )
+            .unwrap();
+        assert_eq!(vec!["home", "work"], accounts.names().collect::<Vec<_>>());
+
+        let mut work = accounts.get("work").unwrap();
+        assert!(work.throttle().is_some());
+        assert!(accounts.get("home").unwrap().throttle().is_none());
+        t.push(200, r#"{"path": "/a"}"#);
+        work.files()
+            .get_dir(Identifier::Path("/a".into()), None)
+            .await
+            .unwrap();
+        assert_eq!(Some("localhost"), t.last().url.host_str());
+
+        assert!(accounts.remove("home").is_some());
+        assert!(accounts.get("home").is_none());
+    }
+}
@@ -0,0 +1,562 @@
+//! Archiving remote directories as tar or zip streams, see `write_archive()`.
+//!
+//! Files are read with `RemoteFile` and written to the archive as they arrive, so that cloud
+//! folders can be archived to a local file or a pipe without intermediate copies. Zip archives
+//! store files uncompressed. Both formats handle files larger than 4 GiB (GNU tar size
+//! encoding, ZIP64).
+
+use crate::hidrive::HiDrive;
+use crate::ignore::IgnoreRules;
+use crate::remote::RemoteFile;
+use crate::sync::{remote_tree, tree_entries};
+use crate::types::{Identifier, Item};
+
+use anyhow::{Context, Result};
+use log::info;
+use time::OffsetDateTime;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const TAR_BLOCK: usize = 512;
+const ZIP_MAX32: u64 = 0xFFFF_FFFF;
+/// Version 4.5: ZIP64.
+const ZIP_VERSION: u16 = 45;
+/// Language encoding flag: names are UTF-8.
+const ZIP_FLAG_UTF8: u16 = 1 << 11;
+/// CRC and sizes follow the data in a data descriptor.
+const ZIP_FLAG_DESCRIPTOR: u16 = 1 << 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Tar,
+    /// Uncompressed zip.
+    Zip,
+}
+
+/// What `write_archive()` wrote.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ArchiveReport {
+    pub files: usize,
+    pub dirs: usize,
+    /// The size of the archived files.
+    pub bytes: u64,
+}
+
+/// A file or directory to archive.
+struct Entry {
+    /// Relative to the archived directory.
+    rel: String,
+    path: String,
+    dir: bool,
+    size: u64,
+    mtime: i64,
+}
+
+impl Entry {
+    fn new(rel: String, it: &Item) -> Entry {
+        Entry {
+            rel,
+            path: it.path.clone(),
+            dir: it.typ.as_deref() == Some("dir"),
+            size: it.size.unwrap_or(0) as u64,
+            mtime: it.mtime.map_or(0, |t| t.unix_timestamp()),
+        }
+    }
+}
+
+/// Write the contents of the remote directory `root` as archive to `dst`. Entry names are
+/// relative to `root`. `dst` is flushed, but not shut down.
+///
+/// ```ignore
+/// let f = tokio::fs::File::create("photos.zip").await?;
+/// let root = Identifier::Path("/users/me/photos".into());
+/// archive::write_archive(&mut hd, root, ArchiveFormat::Zip, f).await?;
+/// ```
+pub async fn write_archive<W: AsyncWrite + Unpin>(
+    hd: &mut HiDrive,
+    root: Identifier,
+    format: ArchiveFormat,
+    dst: W,
+) -> Result<ArchiveReport> {
+    let tree = remote_tree(hd, root, &IgnoreRules::new()).await?;
+    let entries: Vec<Entry> = tree_entries(&tree)
+        .into_iter()
+        .map(|(rel, it)| Entry::new(rel, it))
+        .collect();
+
+    let mut out = Output { w: dst, pos: 0 };
+    let mut central = vec![];
+    let mut report = ArchiveReport::default();
+    let mut buf = vec![0; 64 << 10];
+    for e in entries.iter() {
+        let offset = out.pos;
+        match format {
+            ArchiveFormat::Tar => out.put(&tar_headers(e)).await?,
+            ArchiveFormat::Zip => out.put(&zip_local_header(e)).await?,
+        }
+        if e.dir {
+            report.dirs += 1;
+            if format == ArchiveFormat::Zip {
+                central.extend(zip_central_header(e, 0, offset));
+            }
+            continue;
+        }
+        let mut f = RemoteFile::new(hd.fork(), Identifier::Path(e.path.clone()), e.size);
+        let (mut crc, mut n) = (0, 0);
+        loop {
+            let k = f
+                .read(&mut buf)
+                .await
+                .with_context(|| format!("write_archive: reading {}", e.path))?;
+            if k == 0 {
+                break;
+            }
+            crc = crc32(crc, &buf[..k]);
+            out.put(&buf[..k]).await?;
+            n += k as u64;
+        }
+        if n != e.size {
+            anyhow::bail!(
+                "write_archive: {} changed while archiving ({} bytes instead of {})",
+                e.path,
+                n,
+                e.size
+            );
+        }
+        match format {
+            ArchiveFormat::Tar => out.put(&tar_padding(n)).await?,
+            ArchiveFormat::Zip => {
+                out.put(&zip_data_descriptor(crc, n)).await?;
+                central.extend(zip_central_header(e, crc, offset));
+            }
+        }
+        report.files += 1;
+        report.bytes += n;
+    }
+    match format {
+        ArchiveFormat::Tar => out.put(&[0; 2 * TAR_BLOCK]).await?,
+        ArchiveFormat::Zip => {
+            let start = out.pos;
+            out.put(&central).await?;
+            out.put(&zip_end(
+                entries.len() as u64,
+                start,
+                central.len() as u64,
+                out.pos,
+            ))
+            .await?;
+        }
+    }
+    out.w.flush().await?;
+    info!(
+        target: "hd_api::archive",
+        "write_archive: {} files, {} dirs, {} bytes",
+        report.files,
+        report.dirs,
+        report.bytes
+    );
+    Ok(report)
+}
+
+/// Counts the bytes written, for the offsets of the zip central directory.
+struct Output<W> {
+    w: W,
+    pos: u64,
+}
+
+impl<W: AsyncWrite + Unpin> Output<W> {
+    async fn put(&mut self, b: &[u8]) -> Result<()> {
+        self.w
+            .write_all(b)
+            .await
+            .context("write_archive: writing archive")?;
+        self.pos += b.len() as u64;
+        Ok(())
+    }
+}
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut t = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 {
+                0xEDB8_8320 ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+            k += 1;
+        }
+        t[i] = c;
+        i += 1;
+    }
+    t
+};
+
+/// Continue the CRC-32 (as used by zip) `crc` of preceding data with `data`.
+fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut c = !crc;
+    for b in data {
+        c = CRC32_TABLE[((c ^ *b as u32) & 0xFF) as usize] ^ (c >> 8);
+    }
+    !c
+}
+
+/// Write `v` as NUL-terminated octal number filling `field`.
+fn octal(field: &mut [u8], v: u64) {
+    let width = field.len() - 1;
+    let max = (1u64 << (3 * width)) - 1;
+    let s = format!("{:0width$o}", v.min(max), width = width);
+    field[..width].copy_from_slice(s.as_bytes());
+    field[width] = 0;
+}
+
+fn tar_header(name: &[u8], prefix: &[u8], size: u64, mtime: i64, typ: u8) -> [u8; TAR_BLOCK] {
+    let mut h = [0; TAR_BLOCK];
+    h[..name.len()].copy_from_slice(name);
+    octal(&mut h[100..108], if typ == b'5' { 0o755 } else { 0o644 });
+    octal(&mut h[108..116], 0);
+    octal(&mut h[116..124], 0);
+    if size < 1 << 33 {
+        octal(&mut h[124..136], size);
+    } else {
+        // GNU base-256 encoding for files of 8 GiB and more.
+        h[124] = 0x80;
+        h[128..136].copy_from_slice(&size.to_be_bytes());
+    }
+    octal(&mut h[136..148], mtime.max(0) as u64);
+    h[156] = typ;
+    h[257..263].copy_from_slice(b"ustar\0");
+    h[263..265].copy_from_slice(b"00");
+    h[345..345 + prefix.len()].copy_from_slice(prefix);
+    h[148..156].fill(b' ');
+    let sum: u64 = h.iter().map(|b| *b as u64).sum();
+    octal(&mut h[148..155], sum);
+    h
+}
+
+/// The headers of `e`: a ustar header, preceded by a GNU long name entry if the name doesn't
+/// fit.
+fn tar_headers(e: &Entry) -> Vec<u8> {
+    let name = if e.dir {
+        format!("{}/", e.rel)
+    } else {
+        e.rel.clone()
+    };
+    let name = name.as_bytes();
+    let (size, typ) = if e.dir { (0, b'5') } else { (e.size, b'0') };
+    if name.len() <= 100 {
+        return tar_header(name, b"", size, e.mtime, typ).to_vec();
+    }
+    // Split into prefix and name at a slash, if possible.
+    let split = (0..name.len())
+        .rev()
+        .filter(|i| name[*i] == b'/')
+        .find(|i| *i <= 155 && name.len() - i - 1 <= 100 && name.len() - i - 1 > 0);
+    if let Some(i) = split {
+        return tar_header(&name[i + 1..], &name[..i], size, e.mtime, typ).to_vec();
+    }
+    let mut v = tar_header(b"././@LongLink", b"", name.len() as u64 + 1, 0, b'L').to_vec();
+    v.extend_from_slice(name);
+    v.push(0);
+    v.extend(tar_padding(name.len() as u64 + 1));
+    v.extend(tar_header(&name[..100], b"", size, e.mtime, typ));
+    v
+}
+
+/// Zeros filling up the last block of `size` bytes of data.
+fn tar_padding(size: u64) -> Vec<u8> {
+    vec![0; (TAR_BLOCK - (size % TAR_BLOCK as u64) as usize) % TAR_BLOCK]
+}
+
+/// `mtime` as MS-DOS time and date (UTC), limited to the range they can represent.
+fn dos_time(mtime: i64) -> (u16, u16) {
+    let t = OffsetDateTime::from_unix_timestamp(mtime).unwrap_or(OffsetDateTime::UNIX_EPOCH);
+    if t.year() < 1980 {
+        return (0, (1 << 5) | 1);
+    }
+    if t.year() > 2107 {
+        return ((23 << 11) | (59 << 5) | 29, (127 << 9) | (12 << 5) | 31);
+    }
+    let time = ((t.hour() as u16) << 11) | ((t.minute() as u16) << 5) | (t.second() as u16 / 2);
+    let date = (((t.year() - 1980) as u16) << 9) | ((t.month() as u16) << 5) | t.day() as u16;
+    (time, date)
+}
+
+/// The extended timestamp extra field, holding the mtime in seconds.
+fn zip_time_extra(mtime: i64) -> Vec<u8> {
+    let mut x = vec![];
+    x.extend(0x5455u16.to_le_bytes());
+    x.extend(5u16.to_le_bytes());
+    x.push(1);
+    x.extend((mtime.clamp(0, u32::MAX as i64) as u32).to_le_bytes());
+    x
+}
+
+fn zip_name(e: &Entry) -> Vec<u8> {
+    if e.dir {
+        format!("{}/", e.rel).into_bytes()
+    } else {
+        e.rel.clone().into_bytes()
+    }
+}
+
+fn zip_flags(e: &Entry) -> u16 {
+    if e.dir {
+        ZIP_FLAG_UTF8
+    } else {
+        ZIP_FLAG_UTF8 | ZIP_FLAG_DESCRIPTOR
+    }
+}
+
+/// The local file header of `e`. CRC and sizes of files follow in the data descriptor.
+fn zip_local_header(e: &Entry) -> Vec<u8> {
+    let name = zip_name(e);
+    let zip64 = e.size >= ZIP_MAX32;
+    let mut extra = zip_time_extra(e.mtime);
+    if zip64 {
+        extra.extend(1u16.to_le_bytes());
+        extra.extend(16u16.to_le_bytes());
+        extra.extend([0; 16]);
+    }
+    let sizes = if zip64 { ZIP_MAX32 as u32 } else { 0 };
+    let (time, date) = dos_time(e.mtime);
+    let mut h = vec![];
+    h.extend(0x0403_4b50u32.to_le_bytes());
+    h.extend(ZIP_VERSION.to_le_bytes());
+    h.extend(zip_flags(e).to_le_bytes());
+    h.extend(0u16.to_le_bytes());
+    h.extend(time.to_le_bytes());
+    h.extend(date.to_le_bytes());
+    h.extend(0u32.to_le_bytes());
+    h.extend(sizes.to_le_bytes());
+    h.extend(sizes.to_le_bytes());
+    h.extend((name.len() as u16).to_le_bytes());
+    h.extend((extra.len() as u16).to_le_bytes());
+    h.extend(name);
+    h.extend(extra);
+    h
+}
+
+fn zip_data_descriptor(crc: u32, size: u64) -> Vec<u8> {
+    let mut d = vec![];
+    d.extend(0x0807_4b50u32.to_le_bytes());
+    d.extend(crc.to_le_bytes());
+    if size >= ZIP_MAX32 {
+        d.extend(size.to_le_bytes());
+        d.extend(size.to_le_bytes());
+    } else {
+        d.extend((size as u32).to_le_bytes());
+        d.extend((size as u32).to_le_bytes());
+    }
+    d
+}
+
+/// The central directory record of `e`, whose local header is at `offset`.
+fn zip_central_header(e: &Entry, crc: u32, offset: u64) -> Vec<u8> {
+    let name = zip_name(e);
+    let size = if e.dir { 0 } else { e.size };
+    let mut extra = zip_time_extra(e.mtime);
+    let mut zip64 = vec![];
+    if size >= ZIP_MAX32 {
+        zip64.extend(size.to_le_bytes());
+        zip64.extend(size.to_le_bytes());
+    }
+    if offset >= ZIP_MAX32 {
+        zip64.extend(offset.to_le_bytes());
+    }
+    if !zip64.is_empty() {
+        extra.extend(1u16.to_le_bytes());
+        extra.extend((zip64.len() as u16).to_le_bytes());
+        extra.extend(zip64);
+    }
+    let mode: u32 = if e.dir { 0o40755 } else { 0o100644 };
+    // Unix mode, plus the MS-DOS directory attribute.
+    let attrs = (mode << 16) | if e.dir { 0x10 } else { 0 };
+    let (time, date) = dos_time(e.mtime);
+    let mut h = vec![];
+    h.extend(0x0201_4b50u32.to_le_bytes());
+    // Made by: Unix.
+    h.extend(((3 << 8) | ZIP_VERSION).to_le_bytes());
+    h.extend(ZIP_VERSION.to_le_bytes());
+    h.extend(zip_flags(e).to_le_bytes());
+    h.extend(0u16.to_le_bytes());
+    h.extend(time.to_le_bytes());
+    h.extend(date.to_le_bytes());
+    h.extend(crc.to_le_bytes());
+    h.extend((size.min(ZIP_MAX32) as u32).to_le_bytes());
+    h.extend((size.min(ZIP_MAX32) as u32).to_le_bytes());
+    h.extend((name.len() as u16).to_le_bytes());
+    h.extend((extra.len() as u16).to_le_bytes());
+    // Comment length, disk number, internal attributes.
+    h.extend([0; 6]);
+    h.extend(attrs.to_le_bytes());
+    h.extend((offset.min(ZIP_MAX32) as u32).to_le_bytes());
+    h.extend(name);
+    h.extend(extra);
+    h
+}
+
+/// The end of central directory record, preceded by the ZIP64 record and locator if needed;
+/// written at `pos`.
+fn zip_end(entries: u64, start: u64, size: u64, pos: u64) -> Vec<u8> {
+    let mut r = vec![];
+    if entries >= 0xFFFF || start >= ZIP_MAX32 || size >= ZIP_MAX32 {
+        r.extend(0x0606_4b50u32.to_le_bytes());
+        r.extend(44u64.to_le_bytes());
+        r.extend(((3 << 8) | ZIP_VERSION).to_le_bytes());
+        r.extend(ZIP_VERSION.to_le_bytes());
+        r.extend([0; 8]);
+        r.extend(entries.to_le_bytes());
+        r.extend(entries.to_le_bytes());
+        r.extend(size.to_le_bytes());
+        r.extend(start.to_le_bytes());
+        r.extend(0x0706_4b50u32.to_le_bytes());
+        r.extend(0u32.to_le_bytes());
+        r.extend(pos.to_le_bytes());
+        r.extend(1u32.to_le_bytes());
+    }
+    r.extend(0x0605_4b50u32.to_le_bytes());
+    r.extend([0; 4]);
+    r.extend((entries.min(0xFFFF) as u16).to_le_bytes());
+    r.extend((entries.min(0xFFFF) as u16).to_le_bytes());
+    r.extend((size.min(ZIP_MAX32) as u32).to_le_bytes());
+    r.extend((start.min(ZIP_MAX32) as u32).to_le_bytes());
+    r.extend(0u16.to_le_bytes());
+    r
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::MockHiDrive;
+
+    fn u16_at(b: &[u8], i: usize) -> u16 {
+        u16::from_le_bytes(b[i..i + 2].try_into().unwrap())
+    }
+
+    fn u32_at(b: &[u8], i: usize) -> u32 {
+        u32::from_le_bytes(b[i..i + 4].try_into().unwrap())
+    }
+
+    fn tar_field(h: &[u8]) -> String {
+        let end = h.iter().position(|b| *b == 0).unwrap_or(h.len());
+        String::from_utf8_lossy(&h[..end]).into_owned()
+    }
+
+    #[test]
+    fn test_formats() {
+        assert_eq!(0xCBF4_3926, crc32(0, b"123456789"));
+        assert_eq!(crc32(0, b"123456789"), crc32(crc32(0, b"1234"), b"56789"));
+
+        let e = |rel: &str| Entry {
+            rel: rel.into(),
+            path: String::new(),
+            dir: false,
+            size: 1 << 34,
+            mtime: 1234567890,
+        };
+        let h = tar_headers(&e("a.txt"));
+        assert_eq!(TAR_BLOCK, h.len());
+        assert_eq!("a.txt", tar_field(&h[..100]));
+        assert_eq!(0x80, h[124]);
+        assert_eq!((1u64 << 34).to_be_bytes(), h[128..136]);
+        assert_eq!("11145401322", tar_field(&h[136..148]));
+        let sum: u64 = h[..148]
+            .iter()
+            .chain(&[b' '; 8])
+            .chain(&h[156..])
+            .map(|b| *b as u64)
+            .sum();
+        assert_eq!(format!("{:06o}", sum), tar_field(&h[148..156]));
+
+        let long = format!("{}/{}", "d".repeat(120), "f".repeat(90));
+        let h = tar_headers(&e(&long));
+        assert_eq!(TAR_BLOCK, h.len());
+        assert_eq!("f".repeat(90), tar_field(&h[..100]));
+        assert_eq!("d".repeat(120), tar_field(&h[345..500]));
+        let h = tar_headers(&e(&"x".repeat(200)));
+        assert_eq!(3 * TAR_BLOCK, h.len());
+        assert_eq!(b'L', h[156]);
+        assert_eq!("x".repeat(200), tar_field(&h[TAR_BLOCK..2 * TAR_BLOCK]));
+
+        // 2009-02-13 23:31:30 UTC.
+        assert_eq!(
+            ((23 << 11) | (31 << 5) | 15, (29 << 9) | (2 << 5) | 13),
+            dos_time(1234567890)
+        );
+        assert_eq!((0, (1 << 5) | 1), dos_time(0));
+        let l = zip_local_header(&e("big"));
+        assert_eq!(u32::MAX, u32_at(&l, 18));
+        assert_eq!(1, u16_at(&l, 30 + 3 + 9));
+        assert_eq!(24, zip_data_descriptor(1, 1 << 34).len());
+    }
+
+    #[tokio::test]
+    async fn test_write_archive() {
+        let (fake, mut hd) = MockHiDrive::start().await;
+        fake.put("a.txt", "first", 1234567890).await;
+        fake.put("sub/b.txt", &"b".repeat(1000), 1234567890).await;
+        let root = || Identifier::Path("/m".into());
+
+        let mut tar = vec![];
+        let r = write_archive(&mut hd, root(), ArchiveFormat::Tar, &mut tar)
+            .await
+            .unwrap();
+        assert_eq!(
+            ArchiveReport {
+                files: 2,
+                dirs: 1,
+                bytes: 1005
+            },
+            r
+        );
+        // a.txt, sub/, sub/b.txt and the end marker.
+        assert_eq!(TAR_BLOCK * (2 + 1 + 3 + 2), tar.len());
+        assert_eq!("a.txt", tar_field(&tar[..100]));
+        assert_eq!("first", tar_field(&tar[TAR_BLOCK..2 * TAR_BLOCK]));
+        assert_eq!("sub/", tar_field(&tar[2 * TAR_BLOCK..2 * TAR_BLOCK + 100]));
+        assert_eq!(b'5', tar[2 * TAR_BLOCK + 156]);
+        assert_eq!(
+            "sub/b.txt",
+            tar_field(&tar[3 * TAR_BLOCK..3 * TAR_BLOCK + 100])
+        );
+        assert_eq!(
+            "00000001750",
+            tar_field(&tar[3 * TAR_BLOCK + 124..3 * TAR_BLOCK + 136])
+        );
+        assert!(tar[tar.len() - 2 * TAR_BLOCK..].iter().all(|b| *b == 0));
+
+        let mut zip = vec![];
+        write_archive(&mut hd, root(), ArchiveFormat::Zip, &mut zip)
+            .await
+            .unwrap();
+        let end = zip.len() - 22;
+        assert_eq!(0x0605_4b50, u32_at(&zip, end));
+        assert_eq!(3, u16_at(&zip, end + 10));
+        let mut cd = u32_at(&zip, end + 16) as usize;
+        let mut names = vec![];
+        for _ in 0..3 {
+            assert_eq!(0x0201_4b50, u32_at(&zip, cd));
+            let crc = u32_at(&zip, cd + 16);
+            let size = u32_at(&zip, cd + 24) as usize;
+            let name_len = u16_at(&zip, cd + 28) as usize;
+            let name = String::from_utf8(zip[cd + 46..cd + 46 + name_len].to_vec()).unwrap();
+            // The data follows the local header.
+            let local = u32_at(&zip, cd + 42) as usize;
+            assert_eq!(0x0403_4b50, u32_at(&zip, local));
+            let data =
+                local + 30 + u16_at(&zip, local + 26) as usize + u16_at(&zip, local + 28) as usize;
+            assert_eq!(crc32(0, &zip[data..data + size]), crc);
+            if name == "a.txt" {
+                assert_eq!(b"first", &zip[data..data + size]);
+                assert_eq!(0x0807_4b50, u32_at(&zip, data + size));
+                assert_eq!(crc, u32_at(&zip, data + size + 4));
+            }
+            names.push(name);
+            cd += 46 + name_len + u16_at(&zip, cd + 30) as usize;
+        }
+        assert_eq!(vec!["a.txt", "sub/", "sub/b.txt"], names);
+    }
+}
@@ -0,0 +1,1034 @@
+//! Incremental, deduplicated backups of local directories into a HiDrive directory, the
+//! repository.
+//!
+//! Files are split into chunks (see `chunking`), each stored once below `chunks/` (see
+//! `chunkstore::HiDriveChunkStore`). Each backup run adds a generation: a JSON manifest in
+//! `generations/` listing the files and their chunks. Files whose size and mtime are the same as
+//! in the previous generation of the same directory are not read again. `Repository::forget()`
+//! removes generations according to a `Retention` policy and deletes the chunks no longer
+//! referenced. Don't run it concurrently with a backup into the same repository.
+//! `Repository::restore()` restores a file or directory as of a generation, whose contents can
+//! be browsed with `Manifest::list()`.
+
+use crate::chunking::ChunkingConfig;
+use crate::chunkstore::{put_chunked, ChunkStore, HiDriveChunkStore};
+use crate::dedup::DedupIndex;
+use crate::hashing::Hash;
+use crate::hidrive::{HiDrive, NO_PARAMS};
+use crate::ignore::IgnoreRules;
+use crate::metrics::Metrics;
+use crate::planner::Snapshot;
+use crate::platform::{check_local_path, local_path};
+use crate::types::{error_status, Identifier, Params};
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{self, Context, Result};
+use log::info;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use time::{Date, Month, OffsetDateTime, Time};
+use tokio::io::AsyncWriteExt;
+
+const REPO_VERSION: u32 = 1;
+const CONFIG_FILE: &str = "config.json";
+const CHUNKS_DIR: &str = "chunks";
+const GENERATIONS_DIR: &str = "generations";
+
+/// Stored in `config.json` at the root of a repository.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RepoConfig {
+    version: u32,
+    chunking: ChunkingConfig,
+}
+
+/// A file in a generation.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileEntry {
+    pub size: u64,
+    pub mtime: i64,
+    /// The file's chunks in order.
+    pub chunks: Vec<Hash>,
+}
+
+/// The contents of a generation. Paths are relative to `source`, with `/` as separator.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    #[serde(with = "time::serde::timestamp")]
+    pub time: OffsetDateTime,
+    /// The local directory backed up.
+    pub source: String,
+    pub files: BTreeMap<String, FileEntry>,
+    /// All directories, including empty ones.
+    pub dirs: BTreeSet<String>,
+}
+
+impl Manifest {
+    /// The files and directories directly inside the directory `dir` (`""` for the root).
+    pub fn list(&self, dir: &str) -> Vec<ListEntry> {
+        let dir = dir.trim_matches('/');
+        let child = |p: &str| match p.rsplit_once('/') {
+            Some((parent, name)) if parent == dir => Some(name.to_string()),
+            None if dir.is_empty() => Some(p.to_string()),
+            _ => None,
+        };
+        let mut entries: Vec<ListEntry> = self
+            .dirs
+            .iter()
+            .filter_map(|d| {
+                Some(ListEntry {
+                    name: child(d)?,
+                    file: None,
+                })
+            })
+            .collect();
+        entries.extend(self.files.iter().filter_map(|(p, f)| {
+            Some(ListEntry {
+                name: child(p)?,
+                file: Some(f.clone()),
+            })
+        }));
+        entries
+    }
+}
+
+/// An entry of `Manifest::list()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListEntry {
+    pub name: String,
+    /// `None` for directories.
+    pub file: Option<FileEntry>,
+}
+
+/// A backup generation, named after its time (e.g. `20240422T013000Z`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Generation {
+    pub name: String,
+    pub time: OffsetDateTime,
+}
+
+#[derive(Clone, Default)]
+pub struct BackupOptions {
+    /// Files and directories to leave out. The rules in `.hdignore` are not applied.
+    pub ignore: IgnoreRules,
+    /// Receives metrics of backup runs. Counters: `hd_backup_runs_total`,
+    /// `hd_backup_errors_total`, `hd_backup_files_total`, `hd_backup_chunks_uploaded_total`,
+    /// `hd_backup_bytes_uploaded_total`. Gauges: `hd_backup_last_duration_seconds`,
+    /// `hd_backup_last_success_timestamp_seconds`.
+    pub metrics: Option<Arc<dyn Metrics>>,
+}
+
+/// What `Repository::backup()` did.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BackupReport {
+    /// The name of the new generation.
+    pub generation: String,
+    pub files: usize,
+    /// Files taken over from the previous generation without reading them.
+    pub unchanged: usize,
+    /// Chunks uploaded, and their total size.
+    pub new_chunks: usize,
+    pub bytes_uploaded: u64,
+}
+
+/// Which generations `Repository::forget()` keeps: the `keep_last` most recent ones, and the
+/// most recent one of each of the last `keep_daily` days, `keep_weekly` weeks and
+/// `keep_monthly` months that have generations (in UTC). A generation kept by any rule is kept.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Retention {
+    pub keep_last: usize,
+    pub keep_daily: usize,
+    pub keep_weekly: usize,
+    pub keep_monthly: usize,
+}
+
+impl Retention {
+    fn is_empty(&self) -> bool {
+        *self == Retention::default()
+    }
+
+    /// For each of `times`, whether it is kept.
+    fn keep(&self, times: &[OffsetDateTime]) -> Vec<bool> {
+        let mut order: Vec<usize> = (0..times.len()).collect();
+        order.sort_by(|a, b| times[*b].cmp(&times[*a]));
+        let mut keep = vec![false; times.len()];
+        let rules: [(usize, &dyn Fn(usize) -> i64); 4] = [
+            (self.keep_last, &|i| i as i64),
+            (self.keep_daily, &|i| {
+                let t = times[i];
+                t.year() as i64 * 1000 + t.ordinal() as i64
+            }),
+            (self.keep_weekly, &|i| {
+                let (year, week, _) = times[i].to_iso_week_date();
+                year as i64 * 100 + week as i64
+            }),
+            (self.keep_monthly, &|i| {
+                let t = times[i];
+                t.year() as i64 * 100 + t.month() as i64
+            }),
+        ];
+        for (count, bucket) in rules {
+            let mut last = None;
+            let mut n = 0;
+            for &i in order.iter() {
+                if n == count {
+                    break;
+                }
+                let b = bucket(i);
+                if last != Some(b) {
+                    keep[i] = true;
+                    last = Some(b);
+                    n += 1;
+                }
+            }
+        }
+        keep
+    }
+}
+
+/// What `Repository::forget()` did.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ForgetReport {
+    /// Names of the generations removed.
+    pub removed: Vec<String>,
+    pub chunks_deleted: usize,
+}
+
+/// Options for `Repository::restore()`.
+#[derive(Debug, Clone, Default)]
+pub struct RestoreOptions {
+    /// Replace existing local files. Otherwise, restoring a file that exists fails.
+    pub overwrite: bool,
+    /// Local files to read chunks from instead of downloading them. Only chunks of an index
+    /// using the repository's chunking configuration are found.
+    pub local: Option<Arc<DedupIndex>>,
+}
+
+/// What `Repository::restore()` did.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RestoreReport {
+    pub files: usize,
+    pub dirs: usize,
+    pub bytes: u64,
+    /// Bytes read from local files (see `RestoreOptions::local`) instead of downloaded.
+    pub local_bytes: u64,
+}
+
+/// The name of a generation made at `t`.
+fn generation_name(t: OffsetDateTime) -> String {
+    let t = t.to_offset(time::UtcOffset::UTC);
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        t.year(),
+        t.month() as u8,
+        t.day(),
+        t.hour(),
+        t.minute(),
+        t.second()
+    )
+}
+
+/// The inverse of `generation_name()`.
+fn generation_time(name: &str) -> Option<OffsetDateTime> {
+    if name.len() != 16 || !name.is_ascii() || &name[8..9] != "T" || !name.ends_with('Z') {
+        return None;
+    }
+    let num = |r: std::ops::Range<usize>| name[r].parse::<u32>().ok();
+    let date = Date::from_calendar_date(
+        num(0..4)? as i32,
+        Month::try_from(num(4..6)? as u8).ok()?,
+        num(6..8)? as u8,
+    )
+    .ok()?;
+    let time = Time::from_hms(num(9..11)? as u8, num(11..13)? as u8, num(13..15)? as u8).ok()?;
+    Some(date.with_time(time).assume_utc())
+}
+
+/// A `ChunkStore` answering `contains()` from the chunks referenced by existing generations,
+/// instead of asking the server for each chunk.
+struct KnownChunks<'a> {
+    store: &'a HiDriveChunkStore,
+    known: Mutex<HashSet<Hash>>,
+    new: AtomicUsize,
+    bytes: AtomicU64,
+}
+
+#[async_trait::async_trait]
+impl ChunkStore for KnownChunks<'_> {
+    async fn contains(&self, hash: &Hash) -> Result<bool> {
+        Ok(self.known.lock().unwrap().contains(hash))
+    }
+
+    async fn put(&self, hash: &Hash, data: &[u8]) -> Result<()> {
+        self.store.put(hash, data).await?;
+        self.known.lock().unwrap().insert(hash.clone());
+        self.new.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(data.len() as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
+    async fn get(&self, hash: &Hash) -> Result<Option<Vec<u8>>> {
+        self.store.get(hash).await
+    }
+}
+
+/// A backup repository in a HiDrive directory.
+///
+/// ```ignore
+/// let mut repo = Repository::open(&hd, "/users/me/backup").await?;
+/// repo.backup("/home/me/documents", &BackupOptions::default()).await?;
+/// let retention = Retention { keep_daily: 7, keep_weekly: 4, keep_monthly: 12, keep_last: 0 };
+/// repo.forget(&retention).await?;
+/// ```
+pub struct Repository {
+    hd: HiDrive,
+    root: String,
+    config: RepoConfig,
+    chunks: HiDriveChunkStore,
+}
+
+impl Repository {
+    /// Create a repository in the directory `root`, which is created if needed. `chunking`
+    /// can't be changed later.
+    pub async fn init(
+        hd: &HiDrive,
+        root: impl Into<String>,
+        chunking: ChunkingConfig,
+    ) -> Result<Repository> {
+        chunking.validate()?;
+        let root = root.into().trim_end_matches('/').to_string();
+        let mut hd = hd.fork();
+        for dir in [
+            root.clone(),
+            format!("{}/{}", root, CHUNKS_DIR),
+            format!("{}/{}", root, GENERATIONS_DIR),
+        ] {
+            if let Err(e) = hd
+                .files()
+                .mkdir(Identifier::Path(dir.clone()), NO_PARAMS)
+                .await
+            {
+                if error_status(&e) != Some(409) {
+                    return Err(e.context(format!("backup: creating {}", dir)));
+                }
+            }
+        }
+        let config = RepoConfig {
+            version: REPO_VERSION,
+            chunking,
+        };
+        hd.files()
+            .upload_no_overwrite(
+                Identifier::Path(root.clone()),
+                CONFIG_FILE,
+                serde_json::to_vec_pretty(&config)?,
+                NO_PARAMS,
+            )
+            .await
+            .with_context(|| format!("backup: {} is a repository already", root))?;
+        info!(target: "hd_api::backup", "init: created repository {}", root);
+        Ok(Repository::new(hd, root, config))
+    }
+
+    /// Open the repository in the directory `root`.
+    pub async fn open(hd: &HiDrive, root: impl Into<String>) -> Result<Repository> {
+        let root = root.into().trim_end_matches('/').to_string();
+        let mut hd = hd.fork();
+        let config: RepoConfig = read_json(&mut hd, &format!("{}/{}", root, CONFIG_FILE))
+            .await
+            .with_context(|| format!("backup: opening repository {}", root))?;
+        if config.version != REPO_VERSION {
+            return Err(anyhow::Error::msg(format!(
+                "backup: unsupported repository version {}",
+                config.version
+            )));
+        }
+        Ok(Repository::new(hd, root, config))
+    }
+
+    fn new(hd: HiDrive, root: String, config: RepoConfig) -> Repository {
+        let chunks = HiDriveChunkStore::new(&hd, format!("{}/{}", root, CHUNKS_DIR));
+        Repository {
+            hd,
+            root,
+            config,
+            chunks,
+        }
+    }
+
+    fn generation_path(&self, name: &str) -> String {
+        format!("{}/{}/{}.json", self.root, GENERATIONS_DIR, name)
+    }
+
+    /// All generations, oldest first.
+    pub async fn generations(&mut self) -> Result<Vec<Generation>> {
+        let mut p = Params::new();
+        p.add_str("members", "all")
+            .add_str("fields", "members.name");
+        let dir = self
+            .hd
+            .files()
+            .get_dir(
+                Identifier::Path(format!("{}/{}", self.root, GENERATIONS_DIR)),
+                Some(&p),
+            )
+            .await?;
+        let mut gens: Vec<Generation> = dir
+            .members
+            .iter()
+            .filter_map(|it| {
+                let name = it.name.as_deref()?.strip_suffix(".json")?;
+                Some(Generation {
+                    name: name.to_string(),
+                    time: generation_time(name)?,
+                })
+            })
+            .collect();
+        gens.sort_by(|a, b| a.time.cmp(&b.time));
+        Ok(gens)
+    }
+
+    /// The contents of the generation `name`.
+    pub async fn manifest(&mut self, name: &str) -> Result<Manifest> {
+        let path = self.generation_path(name);
+        read_json(&mut self.hd, &path)
+            .await
+            .with_context(|| format!("backup: reading generation {}", name))
+    }
+
+    /// Back up the local directory `local` into a new generation. Symbolic links are left out.
+    pub async fn backup(
+        &mut self,
+        local: impl AsRef<Path>,
+        opts: &BackupOptions,
+    ) -> Result<BackupReport> {
+        self.backup_at(local.as_ref(), opts, OffsetDateTime::now_utc())
+            .await
+    }
+
+    async fn backup_at(
+        &mut self,
+        local: &Path,
+        opts: &BackupOptions,
+        time: OffsetDateTime,
+    ) -> Result<BackupReport> {
+        let start = std::time::Instant::now();
+        let r = self.backup_at_(local, opts, time).await;
+        if let Some(ref m) = opts.metrics {
+            report_backup(m.as_ref(), &r, start.elapsed());
+        }
+        r
+    }
+
+    async fn backup_at_(
+        &mut self,
+        local: &Path,
+        opts: &BackupOptions,
+        time: OffsetDateTime,
+    ) -> Result<BackupReport> {
+        let source = local.to_string_lossy().into_owned();
+        let name = generation_name(time);
+        let snapshot = Snapshot::scan_with(local, &opts.ignore).await?;
+
+        let mut known = HashSet::new();
+        let mut previous = None;
+        for g in self.generations().await? {
+            if g.name == name {
+                return Err(anyhow::Error::msg(format!(
+                    "backup: generation {} exists already",
+                    name
+                )));
+            }
+            let m = self.manifest(&g.name).await?;
+            known.extend(m.files.values().flat_map(|f| f.chunks.iter().cloned()));
+            if m.source == source {
+                previous = Some(m);
+            }
+        }
+
+        let store = KnownChunks {
+            store: &self.chunks,
+            known: Mutex::new(known),
+            new: AtomicUsize::new(0),
+            bytes: AtomicU64::new(0),
+        };
+        let mut chunker = self.config.chunking.chunker()?;
+        let mut manifest = Manifest {
+            time,
+            source,
+            files: BTreeMap::new(),
+            dirs: snapshot.dirs,
+        };
+        let mut report = BackupReport {
+            generation: name.clone(),
+            files: snapshot.files.len(),
+            ..Default::default()
+        };
+        for (rel, f) in snapshot.files {
+            let unchanged = previous
+                .as_ref()
+                .and_then(|m| m.files.get(&rel))
+                .filter(|e| e.size == f.size && e.mtime == f.mtime);
+            let entry = match unchanged {
+                Some(e) => {
+                    report.unchanged += 1;
+                    e.clone()
+                }
+                None => {
+                    let path = local_path(local, &rel);
+                    let file = tokio::fs::File::open(&path)
+                        .await
+                        .with_context(|| format!("backup: opening {:?}", path))?;
+                    let refs = put_chunked(&mut chunker, file, &store).await?;
+                    FileEntry {
+                        size: refs.iter().map(|c| c.len as u64).sum(),
+                        mtime: f.mtime,
+                        chunks: refs.into_iter().map(|c| c.hash).collect(),
+                    }
+                }
+            };
+            manifest.files.insert(rel, entry);
+        }
+        report.new_chunks = store.new.load(Ordering::Relaxed);
+        report.bytes_uploaded = store.bytes.load(Ordering::Relaxed);
+
+        // The manifest is written last, so that an interrupted run leaves only unreferenced
+        // chunks, which the next run uploads again or `forget()` deletes.
+        self.hd
+            .files()
+            .upload_no_overwrite(
+                Identifier::Path(format!("{}/{}", self.root, GENERATIONS_DIR)),
+                format!("{}.json", name),
+                serde_json::to_vec(&manifest)?,
+                NO_PARAMS,
+            )
+            .await
+            .context("backup: storing manifest")?;
+        info!(
+            target: "hd_api::backup",
+            "backup: generation {}: {} files ({} unchanged), {} new chunks ({} bytes)",
+            name,
+            report.files,
+            report.unchanged,
+            report.new_chunks,
+            report.bytes_uploaded
+        );
+        Ok(report)
+    }
+
+    /// Remove the generations not kept by `retention`, then delete the chunks not referenced by
+    /// the remaining ones.
+    pub async fn forget(&mut self, retention: &Retention) -> Result<ForgetReport> {
+        if retention.is_empty() {
+            return Err(anyhow::Error::msg(
+                "backup: retention policy would remove all generations",
+            ));
+        }
+        let gens = self.generations().await?;
+        let times: Vec<OffsetDateTime> = gens.iter().map(|g| g.time).collect();
+        let keep = retention.keep(&times);
+        let mut report = ForgetReport::default();
+        let mut referenced = HashSet::new();
+        for (g, keep) in gens.iter().zip(keep.iter()) {
+            if *keep {
+                let m = self.manifest(&g.name).await?;
+                referenced.extend(m.files.into_values().flat_map(|f| f.chunks));
+            }
+        }
+        for (g, keep) in gens.iter().zip(keep.iter()) {
+            if !*keep {
+                let path = self.generation_path(&g.name);
+                self.hd
+                    .files()
+                    .delete(Identifier::Path(path), NO_PARAMS)
+                    .await
+                    .with_context(|| format!("backup: removing generation {}", g.name))?;
+                report.removed.push(g.name.clone());
+            }
+        }
+        report.chunks_deleted = self.prune(&referenced).await?;
+        info!(
+            target: "hd_api::backup",
+            "forget: removed {} generations, deleted {} chunks",
+            report.removed.len(),
+            report.chunks_deleted
+        );
+        Ok(report)
+    }
+
+    /// Delete all chunks not in `referenced`, returning their number.
+    async fn prune(&mut self, referenced: &HashSet<Hash>) -> Result<usize> {
+        let chunks_dir = format!("{}/{}", self.root, CHUNKS_DIR);
+        let mut p = Params::new();
+        p.add_str("members", "all")
+            .add_str("fields", "members.name,members.type");
+        let top = self
+            .hd
+            .files()
+            .get_dir(Identifier::Path(chunks_dir.clone()), Some(&p))
+            .await?;
+        let mut n = 0;
+        for sub in top
+            .members
+            .iter()
+            .filter(|it| it.typ.as_deref() == Some("dir"))
+        {
+            let prefix = sub.name.clone().unwrap_or_default();
+            let sub_dir = format!("{}/{}", chunks_dir, prefix);
+            let dir = self
+                .hd
+                .files()
+                .get_dir(Identifier::Path(sub_dir.clone()), Some(&p))
+                .await?;
+            for it in dir.members {
+                let name = it.name.unwrap_or_default();
+                match Hash::parse(format!("{}{}", prefix, name)) {
+                    Ok(h) if referenced.contains(&h) => continue,
+                    Ok(_) => (),
+                    // Not a chunk, e.g. left over from an interrupted upload.
+                    Err(_) => continue,
+                }
+                self.hd
+                    .files()
+                    .delete(Identifier::Path(format!("{}/{}", sub_dir, name)), NO_PARAMS)
+                    .await?;
+                n += 1;
+            }
+        }
+        Ok(n)
+    }
+
+    /// Restore `path` as of the generation `generation` to the local path `target`. A file is
+    /// written to `target`; the contents of a directory (`""` for everything) are written below
+    /// `target`. Chunk hashes are verified; a file with a missing or corrupted chunk isn't
+    /// written, failing the restore.
+    pub async fn restore(
+        &mut self,
+        generation: &str,
+        path: &str,
+        target: impl AsRef<Path>,
+        opts: &RestoreOptions,
+    ) -> Result<RestoreReport> {
+        let target = target.as_ref();
+        let m = self.manifest(generation).await?;
+        let path = path.trim_matches('/');
+        let mut report = RestoreReport::default();
+        if let Some(f) = m.files.get(path) {
+            report.local_bytes = self.restore_file(f, target, opts).await?;
+            report.files = 1;
+            report.bytes = f.size;
+            return Ok(report);
+        }
+        if !path.is_empty() && !m.dirs.contains(path) {
+            anyhow::bail!("backup: {} not found in generation {}", path, generation);
+        }
+        let below = |p: &'_ str| -> Option<String> {
+            let rel = if path.is_empty() {
+                p
+            } else {
+                p.strip_prefix(path)?.strip_prefix('/')?
+            };
+            Some(rel.to_string())
+        };
+        tokio::fs::create_dir_all(target)
+            .await
+            .with_context(|| format!("backup: creating {:?}", target))?;
+        for rel in m.dirs.iter().filter_map(|d| below(d)) {
+            tokio::fs::create_dir_all(local_path(target, checked(&rel)?)).await?;
+            report.dirs += 1;
+        }
+        for (p, f) in m.files.iter() {
+            if let Some(rel) = below(p) {
+                report.local_bytes += self
+                    .restore_file(f, &local_path(target, checked(&rel)?), opts)
+                    .await?;
+                report.files += 1;
+                report.bytes += f.size;
+            }
+        }
+        info!(
+            target: "hd_api::backup",
+            "restore: {} files and {} directories of {} restored to {:?}",
+            report.files,
+            report.dirs,
+            generation,
+            target
+        );
+        Ok(report)
+    }
+
+    /// Reassemble `f` at `dst`, through a temporary file next to it. Returns the number of bytes
+    /// read from local files.
+    async fn restore_file(&self, f: &FileEntry, dst: &Path, opts: &RestoreOptions) -> Result<u64> {
+        if !opts.overwrite && tokio::fs::try_exists(dst).await? {
+            anyhow::bail!("backup: {:?} exists already", dst);
+        }
+        if let Some(parent) = dst.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let mut tmp_name = dst.file_name().unwrap_or_default().to_os_string();
+        tmp_name.push(".hdrestore");
+        let tmp = dst.with_file_name(tmp_name);
+        let write = async {
+            let mut out = tokio::fs::File::create(&tmp)
+                .await
+                .with_context(|| format!("backup: creating {:?}", tmp))?;
+            let (mut n, mut local) = (0, 0);
+            for h in f.chunks.iter() {
+                let found = match opts.local {
+                    Some(ref idx) => idx.read_chunk(h).await?,
+                    None => None,
+                };
+                let data = match found {
+                    Some(data) => {
+                        local += data.len() as u64;
+                        data
+                    }
+                    None => self.chunks.get(h).await?.ok_or_else(|| {
+                        anyhow::Error::msg(format!("backup: chunk {} is missing", h))
+                    })?,
+                };
+                n += data.len() as u64;
+                out.write_all(&data).await?;
+            }
+            if n != f.size {
+                anyhow::bail!("backup: restored {} bytes instead of {}", n, f.size);
+            }
+            out.sync_all().await?;
+            Ok(local)
+        };
+        let local = match write.await {
+            Ok(local) => local,
+            Err(e) => {
+                let _ = tokio::fs::remove_file(&tmp).await;
+                return Err(e.context(format!("backup: restoring {:?}", dst)));
+            }
+        };
+        tokio::fs::rename(&tmp, dst).await?;
+        filetime::set_file_mtime(dst, filetime::FileTime::from_unix_time(f.mtime, 0))?;
+        Ok(local)
+    }
+}
+
+/// `rel` if it is a relative path staying below the restore target. Backslashes and drive
+/// prefixes are rejected on all platforms, as they would escape the target on Windows.
+fn checked(rel: &str) -> Result<&str> {
+    let drive = rel.as_bytes().get(1) == Some(&b':') && rel.as_bytes()[0].is_ascii_alphabetic();
+    if drive
+        || rel.contains('\\')
+        || rel
+            .split('/')
+            .any(|c| c.is_empty() || c == "." || c == "..")
+    {
+        anyhow::bail!("backup: invalid path {:?} in manifest", rel);
+    }
+    check_local_path(rel).context("backup: path in manifest")?;
+    Ok(rel)
+}
+
+fn report_backup(m: &dyn Metrics, r: &Result<BackupReport>, duration: std::time::Duration) {
+    m.counter("hd_backup_runs_total", "Backup runs.", 1);
+    m.gauge(
+        "hd_backup_last_duration_seconds",
+        "Duration of the last backup run.",
+        duration.as_secs_f64(),
+    );
+    let report = match r {
+        Ok(report) => report,
+        Err(_) => {
+            m.counter("hd_backup_errors_total", "Failed backup runs.", 1);
+            return;
+        }
+    };
+    m.counter(
+        "hd_backup_files_total",
+        "Files backed up, including unchanged ones.",
+        report.files as u64,
+    );
+    m.counter(
+        "hd_backup_chunks_uploaded_total",
+        "Chunks uploaded by backup runs.",
+        report.new_chunks as u64,
+    );
+    m.counter(
+        "hd_backup_bytes_uploaded_total",
+        "Bytes of the chunks uploaded by backup runs.",
+        report.bytes_uploaded,
+    );
+    m.gauge(
+        "hd_backup_last_success_timestamp_seconds",
+        "Time of the last successful backup run.",
+        OffsetDateTime::now_utc().unix_timestamp() as f64,
+    );
+}
+
+async fn read_json<T: DeserializeOwned>(hd: &mut HiDrive, path: &str) -> Result<T> {
+    let mut buf = vec![];
+    hd.files()
+        .get(Identifier::Path(path.to_string()), &mut buf, NO_PARAMS)
+        .await?;
+    Ok(serde_json::from_slice(&buf)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::MockHiDrive;
+
+    fn at(month: Month, day: u8, hour: u8) -> OffsetDateTime {
+        Date::from_calendar_date(2024, month, day)
+            .unwrap()
+            .with_hms(hour, 0, 0)
+            .unwrap()
+            .assume_utc()
+    }
+
+    #[test]
+    fn test_generation_name() {
+        let t = at(Month::April, 22, 1) + time::Duration::seconds(1799);
+        assert_eq!("20240422T012959Z", generation_name(t));
+        assert_eq!(Some(t), generation_time("20240422T012959Z"));
+        assert_eq!(None, generation_time("20241322T012959Z"));
+        assert_eq!(None, generation_time("latest"));
+    }
+
+    #[test]
+    fn test_checked() {
+        assert_eq!("a/b.txt", checked("a/b.txt").unwrap());
+        for bad in [
+            "",
+            "/a",
+            "a//b",
+            "./a",
+            "a/../../x",
+            "..\\..\\x",
+            "C:\\evil",
+            "c:/evil",
+            "a\\b",
+        ] {
+            assert!(checked(bad).is_err(), "{}", bad);
+        }
+        assert_eq!(cfg!(windows), checked("dir/CON").is_err());
+    }
+
+    #[test]
+    fn test_retention() {
+        // Two per day on April 1-3 (April 1 is a Monday), one each on April 8, 15 and May 1.
+        let times = vec![
+            at(Month::April, 1, 1),
+            at(Month::April, 1, 13),
+            at(Month::April, 2, 1),
+            at(Month::April, 2, 13),
+            at(Month::April, 3, 1),
+            at(Month::April, 3, 13),
+            at(Month::April, 8, 1),
+            at(Month::April, 15, 1),
+            at(Month::May, 1, 1),
+        ];
+        let kept = |r: Retention| -> Vec<usize> {
+            r.keep(&times)
+                .iter()
+                .enumerate()
+                .filter(|(_, k)| **k)
+                .map(|(i, _)| i)
+                .collect()
+        };
+        let r = Retention {
+            keep_last: 2,
+            ..Default::default()
+        };
+        assert_eq!(vec![7, 8], kept(r));
+        let r = Retention {
+            keep_daily: 4,
+            ..Default::default()
+        };
+        assert_eq!(vec![5, 6, 7, 8], kept(r));
+        let r = Retention {
+            keep_weekly: 3,
+            ..Default::default()
+        };
+        assert_eq!(vec![6, 7, 8], kept(r));
+        let r = Retention {
+            keep_weekly: 4,
+            keep_monthly: 2,
+            ..Default::default()
+        };
+        assert_eq!(vec![5, 6, 7, 8], kept(r));
+    }
+
+    #[tokio::test]
+    async fn test_backup_and_forget() {
+        let local = std::env::temp_dir().join("hd_api_test_backup");
+        let _ = std::fs::remove_dir_all(&local);
+        std::fs::create_dir_all(local.join("sub/empty")).unwrap();
+        let mut x: u32 = 4711;
+        let big: Vec<u8> = (0..300_000)
+            .map(|_| {
+                x = x.wrapping_mul(1103515245).wrapping_add(12345);
+                (x >> 16) as u8
+            })
+            .collect();
+        std::fs::write(local.join("sub/big.bin"), &big).unwrap();
+        std::fs::write(local.join("a.txt"), b"first").unwrap();
+
+        let (fake, hd) = MockHiDrive::start().await;
+        let chunking = ChunkingConfig::small_files();
+        let mut repo = Repository::init(&hd, "/m/repo", chunking.clone())
+            .await
+            .unwrap();
+        assert!(Repository::init(&hd, "/m/repo", chunking).await.is_err());
+
+        let metrics = Arc::new(crate::metrics::PrometheusMetrics::new());
+        let opts = BackupOptions {
+            metrics: Some(metrics.clone()),
+            ..Default::default()
+        };
+        let r1 = repo
+            .backup_at(&local, &opts, at(Month::April, 1, 1))
+            .await
+            .unwrap();
+        assert_eq!(2, r1.files);
+        assert_eq!(0, r1.unchanged);
+        assert!(r1.new_chunks > 10);
+        assert_eq!(big.len() as u64 + 5, r1.bytes_uploaded);
+
+        // Only the changed file is read again.
+        std::fs::write(local.join("a.txt"), b"second").unwrap();
+        let mut repo = Repository::open(&hd, "/m/repo").await.unwrap();
+        let r2 = repo
+            .backup_at(&local, &opts, at(Month::April, 2, 1))
+            .await
+            .unwrap();
+        assert_eq!(1, r2.unchanged);
+        assert_eq!(1, r2.new_chunks);
+        assert_eq!(6, r2.bytes_uploaded);
+        assert_eq!(Some(2.0), metrics.get("hd_backup_runs_total"));
+        assert_eq!(
+            Some((r1.bytes_uploaded + 6) as f64),
+            metrics.get("hd_backup_bytes_uploaded_total")
+        );
+
+        let gens = repo.generations().await.unwrap();
+        assert_eq!(
+            vec!["20240401T010000Z", "20240402T010000Z"],
+            gens.iter().map(|g| g.name.as_str()).collect::<Vec<_>>()
+        );
+        let m = repo.manifest("20240402T010000Z").await.unwrap();
+        assert!(m.dirs.contains("sub/empty"));
+        assert_eq!(300_000, m.files["sub/big.bin"].size);
+
+        let chunk_files = |files: Vec<String>| {
+            files
+                .iter()
+                .filter(|f| f.starts_with("repo/chunks/"))
+                .count()
+        };
+        let before = chunk_files(fake.files().await);
+        let f = repo
+            .forget(&Retention {
+                keep_last: 1,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(vec!["20240401T010000Z".to_string()], f.removed);
+        // The chunk of the first version of a.txt.
+        assert_eq!(1, f.chunks_deleted);
+        assert_eq!(before - 1, chunk_files(fake.files().await));
+        assert!(repo.forget(&Retention::default()).await.is_err());
+        std::fs::remove_dir_all(&local).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_restore() {
+        let local = std::env::temp_dir().join("hd_api_test_restore");
+        let _ = std::fs::remove_dir_all(&local);
+        let src = local.join("src");
+        std::fs::create_dir_all(src.join("sub/empty")).unwrap();
+        std::fs::write(src.join("a.txt"), b"version 1").unwrap();
+        std::fs::write(src.join("sub/b.txt"), b"bbb").unwrap();
+
+        let (fake, hd) = MockHiDrive::start().await;
+        let mut repo = Repository::init(&hd, "/m/repo", ChunkingConfig::small_files())
+            .await
+            .unwrap();
+        let opts = BackupOptions::default();
+        let gen1 = repo
+            .backup_at(&src, &opts, at(Month::April, 1, 1))
+            .await
+            .unwrap()
+            .generation;
+        std::fs::write(src.join("a.txt"), b"version 2").unwrap();
+        repo.backup_at(&src, &opts, at(Month::April, 2, 1))
+            .await
+            .unwrap();
+
+        let m = repo.manifest(&gen1).await.unwrap();
+        let names: Vec<String> = m.list("").into_iter().map(|e| e.name).collect();
+        assert_eq!(vec!["sub", "a.txt"], names);
+        let sub = m.list("sub");
+        assert_eq!("empty", sub[0].name);
+        assert_eq!(None, sub[0].file);
+        assert_eq!(Some(3), sub[1].file.as_ref().map(|f| f.size));
+
+        // A single file, as of the first generation.
+        let dst = local.join("a.txt");
+        let r = repo
+            .restore(&gen1, "a.txt", &dst, &RestoreOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(1, r.files);
+        assert_eq!(b"version 1".to_vec(), std::fs::read(&dst).unwrap());
+        assert!(repo
+            .restore(&gen1, "a.txt", &dst, &RestoreOptions::default())
+            .await
+            .is_err());
+
+        // Everything.
+        let dst = local.join("all");
+        let r = repo
+            .restore(&gen1, "", &dst, &RestoreOptions::default())
+            .await
+            .unwrap();
+        assert_eq!((2, 2, 12, 0), (r.files, r.dirs, r.bytes, r.local_bytes));
+        assert_eq!(
+            b"bbb".to_vec(),
+            std::fs::read(dst.join("sub/b.txt")).unwrap()
+        );
+        assert!(dst.join("sub/empty").is_dir());
+
+        // Chunks still present in the source tree are read from there.
+        let mut idx = DedupIndex::new(ChunkingConfig::small_files());
+        idx.update_tree(&src).await.unwrap();
+        let opts = RestoreOptions {
+            local: Some(Arc::new(idx)),
+            ..Default::default()
+        };
+        let dst = local.join("local");
+        let r = repo.restore(&gen1, "", &dst, &opts).await.unwrap();
+        assert_eq!(3, r.local_bytes);
+        assert_eq!(
+            b"version 1".to_vec(),
+            std::fs::read(dst.join("a.txt")).unwrap()
+        );
+
+        // A corrupted chunk fails the restore and leaves no file behind.
+        let h = m.files["sub/b.txt"].chunks[0].to_string();
+        fake.put(&format!("repo/chunks/{}/{}", &h[..2], &h[2..]), "bbc", 0)
+            .await;
+        let dst = local.join("b.txt");
+        assert!(repo
+            .restore(&gen1, "sub/b.txt", &dst, &RestoreOptions::default())
+            .await
+            .is_err());
+        assert!(!dst.exists());
+        assert!(repo
+            .restore(&gen1, "nothing", &dst, &RestoreOptions::default())
+            .await
+            .is_err());
+        std::fs::remove_dir_all(&local).unwrap();
+    }
+}
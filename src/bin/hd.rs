@@ -0,0 +1,850 @@
+//! `hd`, a command line client for HiDrive. Build it with `cargo build --features cli`.
+//!
+//! Accounts are configured in `$XDG_CONFIG_HOME/hd/config.json` (or `~/.config/hd/config.json`):
+//!
+//! ```json
+//! {
+//!   "default": "me",
+//!   "accounts": {
+//!     "me": { "client_secret": "clientsecret.json", "credentials": "me.credentials.json" }
+//!   }
+//! }
+//! ```
+//!
+//! Relative file names are resolved against the directory of the configuration file. Run
+//! `hd login` once per account to obtain credentials. Remote paths are relative to the home
+//! directory unless they start with `/`.
+
+use hd_api::backup::{BackupOptions, Repository, RestoreOptions, Retention};
+use hd_api::chunking::ChunkingConfig;
+use hd_api::hidrive::HiDrive;
+use hd_api::ignore::IgnoreRules;
+use hd_api::oauth2::{self, ClientSecret, Credentials};
+use hd_api::sharelink::ShareLink;
+use hd_api::sync::{self, MirrorOptions, MirrorReport, SyncSummary};
+use hd_api::types::{Item, OnExist};
+use hd_api::{Identifier, Params};
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use clap::{CommandFactory, Parser, Subcommand};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, to_string_pretty};
+
+const ITEM_FIELDS: &str = "path,name,id,type,size,mtime,ctime,chash,nmembers,readable,writable";
+const DIR_FIELDS: &str = concat!(
+    "path,name,id,type,nmembers,",
+    "members.path,members.name,members.id,members.type,members.size,members.mtime,members.nmembers"
+);
+const ME_FIELDS: &str =
+    "account,alias,descr,email,email_verified,encrypted,home,home_id,is_admin,is_owner,language";
+
+#[derive(Parser)]
+#[command(name = "hd", about = "Access a HiDrive account.", version)]
+struct Args {
+    /// Configuration file [default: $XDG_CONFIG_HOME/hd/config.json]
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    /// Account to use [default: the configuration's `default`]
+    #[arg(short, long, global = true)]
+    account: Option<String>,
+    /// Print results as JSON.
+    #[arg(long, global = true)]
+    json: bool,
+    /// Log API requests (-v) and their details (-vv).
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Authorize access to the account and store its credentials.
+    Login,
+    /// Show the account's user information.
+    Me,
+    /// Show used and free space.
+    Quota,
+    /// List a directory.
+    Ls {
+        #[arg(default_value = "")]
+        path: String,
+    },
+    /// Show metadata of a file or directory.
+    Stat { path: String },
+    /// Download a file.
+    Get {
+        remote: String,
+        /// Local file [default: the remote file's name]
+        local: Option<PathBuf>,
+    },
+    /// Upload a file into a directory, replacing an existing file.
+    Put { local: PathBuf, dir: String },
+    /// Delete a file.
+    Rm { path: String },
+    /// Create a directory.
+    Mkdir { path: String },
+    /// Delete a directory.
+    Rmdir {
+        path: String,
+        /// Delete its contents, too.
+        #[arg(short, long)]
+        recursive: bool,
+    },
+    /// Move a file or directory.
+    Mv {
+        from: String,
+        to: String,
+        /// Replace an existing destination.
+        #[arg(short, long)]
+        force: bool,
+    },
+    /// Copy a file or directory.
+    Cp {
+        from: String,
+        to: String,
+        /// Replace an existing destination.
+        #[arg(short, long)]
+        force: bool,
+    },
+    /// Print a temporary download URL for a file.
+    Url { path: String },
+    /// Download the thumbnail of an image.
+    Thumbnail { path: String, local: PathBuf },
+    /// Search file names below the home directory.
+    Search { pattern: String },
+    /// Print change notifications as they arrive.
+    Listen,
+    /// Move files into a remote trash directory, and restore or delete them from there.
+    Trash {
+        #[command(subcommand)]
+        command: TrashCommand,
+        /// The trash directory.
+        #[arg(long, global = true, default_value = ".trash")]
+        dir: String,
+    },
+    /// Make and restore deduplicated backups of local directories.
+    Backup {
+        #[command(subcommand)]
+        command: BackupCommand,
+        /// The remote directory holding the backup repository.
+        #[arg(long, global = true)]
+        repo: String,
+    },
+    /// List and download the contents of a public sharelink. No account is needed.
+    Share {
+        #[command(subcommand)]
+        command: ShareCommand,
+        /// The sharelink's password.
+        #[arg(long, global = true)]
+        password: Option<String>,
+    },
+    /// Synchronize a local and a remote directory.
+    Sync {
+        #[command(subcommand)]
+        direction: SyncCommand,
+        #[command(flatten)]
+        opts: SyncArgs,
+    },
+    /// Print a shell completion script.
+    Completions { shell: clap_complete::Shell },
+}
+
+#[derive(Subcommand)]
+enum TrashCommand {
+    /// List the trash.
+    Ls,
+    /// Move a file or directory into the trash.
+    Put { path: String },
+    /// Move a file or directory out of the trash.
+    Restore {
+        /// The name in the trash.
+        name: String,
+        to: String,
+    },
+    /// Delete everything in the trash.
+    Empty,
+}
+
+#[derive(Subcommand)]
+enum BackupCommand {
+    /// Create a new repository.
+    Init,
+    /// Back up a local directory into a new generation.
+    Run {
+        local: PathBuf,
+        /// Leave out files matching this pattern (`.hdignore` syntax); repeatable.
+        #[arg(long)]
+        exclude: Vec<String>,
+    },
+    /// List the generations, or the contents of a directory in a generation.
+    Ls {
+        generation: Option<String>,
+        #[arg(default_value = "")]
+        path: String,
+    },
+    /// Restore a file or directory of a generation.
+    Restore {
+        generation: String,
+        local: PathBuf,
+        /// The file or directory within the generation [default: everything]
+        #[arg(long, default_value = "")]
+        path: String,
+        /// Replace existing local files.
+        #[arg(long)]
+        overwrite: bool,
+    },
+    /// Remove generations not kept by the retention policy, and chunks no longer used.
+    Forget {
+        #[arg(long, default_value_t = 0)]
+        keep_last: usize,
+        #[arg(long, default_value_t = 0)]
+        keep_daily: usize,
+        #[arg(long, default_value_t = 0)]
+        keep_weekly: usize,
+        #[arg(long, default_value_t = 0)]
+        keep_monthly: usize,
+    },
+}
+
+#[derive(Subcommand)]
+enum ShareCommand {
+    /// List a shared directory.
+    Ls {
+        /// The sharelink URL or ID.
+        link: String,
+        /// The directory within the shared directory.
+        #[arg(default_value = "")]
+        path: String,
+    },
+    /// Download a shared file, or a file within a shared directory.
+    Get {
+        /// The sharelink URL or ID.
+        link: String,
+        /// The file within the shared directory [default: the shared file]
+        #[arg(default_value = "")]
+        path: String,
+        /// Local file [default: the remote file's name]
+        local: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+enum SyncCommand {
+    /// Make the remote directory a copy of the local one.
+    Up { local: PathBuf, remote: String },
+    /// Make the local directory a copy of the remote one.
+    Down { remote: String, local: PathBuf },
+    /// Propagate changes in both directions.
+    Bi {
+        local: PathBuf,
+        remote: String,
+        /// State of the previous run.
+        #[arg(long)]
+        state: PathBuf,
+    },
+}
+
+#[derive(clap::Args)]
+struct SyncArgs {
+    /// Only show what would be done.
+    #[arg(short = 'n', long, global = true)]
+    dry_run: bool,
+    /// Don't delete files missing in the source.
+    #[arg(long, global = true)]
+    no_delete: bool,
+    /// Move deleted remote files into this remote directory instead.
+    #[arg(long, global = true)]
+    trash: Option<String>,
+    /// Ignore files matching this pattern (`.hdignore` syntax); repeatable.
+    #[arg(long, global = true)]
+    exclude: Vec<String>,
+    /// Number of concurrent transfers.
+    #[arg(long, global = true, default_value_t = 1)]
+    transfers: usize,
+}
+
+impl SyncArgs {
+    fn options(&self) -> MirrorOptions {
+        let mut ignore = IgnoreRules::new();
+        for pattern in self.exclude.iter() {
+            ignore.add(pattern);
+        }
+        MirrorOptions {
+            delete: !self.no_delete,
+            trash: self.trash.clone(),
+            dry_run: self.dry_run,
+            ignore,
+            transfers: self.transfers,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct Config {
+    default: Option<String>,
+    accounts: BTreeMap<String, Account>,
+}
+
+#[derive(Deserialize)]
+struct Account {
+    client_secret: PathBuf,
+    credentials: PathBuf,
+}
+
+fn default_config_path() -> Result<PathBuf> {
+    let base = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME").context("HOME is not set")?).join(".config"),
+    };
+    Ok(base.join("hd").join("config.json"))
+}
+
+/// The account selected by `name` (or the default one), with its file names resolved.
+async fn load_account(path: &Path, name: Option<&str>) -> Result<Account> {
+    let text = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("reading configuration {:?}", path))?;
+    let mut config: Config =
+        serde_json::from_str(&text).with_context(|| format!("parsing configuration {:?}", path))?;
+    let name = match (name, &config.default) {
+        (Some(name), _) => name.to_string(),
+        (None, Some(name)) => name.clone(),
+        (None, None) if config.accounts.len() == 1 => {
+            config.accounts.keys().next().unwrap().clone()
+        }
+        (None, None) => return Err(anyhow!("no account given and no default configured")),
+    };
+    let account = config
+        .accounts
+        .remove(&name)
+        .ok_or_else(|| anyhow!("account {:?} isn't configured", name))?;
+    let dir = path.parent().unwrap_or(Path::new(""));
+    Ok(Account {
+        client_secret: dir.join(account.client_secret),
+        credentials: dir.join(account.credentials),
+    })
+}
+
+/// State shared by the commands.
+struct Ctx {
+    hd: HiDrive,
+    json: bool,
+}
+
+impl Ctx {
+    /// The remote `path`; relative paths are resolved against the home directory.
+    async fn id(&mut self, path: &str) -> Result<Identifier> {
+        if path.starts_with('/') {
+            return Ok(Identifier::Path(path.to_string()));
+        }
+        Ok(self.hd.user().home().await?.join(path))
+    }
+
+    /// Like `id()`, but as a path.
+    async fn path(&mut self, path: &str) -> Result<String> {
+        if path.starts_with('/') {
+            return Ok(path.to_string());
+        }
+        let home = self.hd.user().home().await?;
+        Ok(format!(
+            "{}/{}",
+            home.path.trim_end_matches('/'),
+            path.trim_matches('/')
+        ))
+    }
+
+    fn print<T: Serialize>(&self, value: &T, text: impl FnOnce(&T) -> String) -> Result<()> {
+        print(self.json, value, text)
+    }
+
+    fn print_item(&self, it: &Item) -> Result<()> {
+        self.print(it, |it| it.path.clone())
+    }
+
+    /// Print the members of the directory `id`.
+    async fn ls(&mut self, id: Identifier) -> Result<()> {
+        let mut p = Params::new();
+        p.add_str("members", "all").add_str("fields", DIR_FIELDS);
+        let dir = self.hd.files().get_dir(id, Some(&p)).await?;
+        self.print(&dir.members, |members| format_members(members))
+    }
+
+    /// Move the file or directory `from` to `to`.
+    async fn mv(&mut self, from: Identifier, to: Identifier, p: &Params) -> Result<()> {
+        let mut files = self.hd.files();
+        let it = if is_dir(&files.metadata(from.clone(), "type", None).await?) {
+            files.mvdir(from, to, Some(p)).await?
+        } else {
+            files.mv(from, to, Some(p)).await?
+        };
+        self.print_item(&it)
+    }
+
+    /// Print the outcome of a sync run; `value` holds the paths acted on.
+    fn print_sync(
+        &self,
+        mut value: serde_json::Value,
+        failed: &[(String, String)],
+        s: &SyncSummary,
+    ) -> Result<()> {
+        value["failed"] = json!(failed);
+        value["files_scanned"] = json!(s.files_scanned);
+        value["skipped"] = json!(s.skipped);
+        value["bytes"] = json!(s.bytes);
+        value["elapsed_seconds"] = json!(s.elapsed.as_secs_f64());
+        self.print(&value, |_| {
+            let mut lines: Vec<String> = failed
+                .iter()
+                .map(|(path, err)| format!("failed: {}: {}", path, err))
+                .collect();
+            lines.push(s.to_string());
+            lines.join("\n")
+        })
+    }
+}
+
+/// Print `value` as JSON if `json` is set, or `text` otherwise.
+fn print<T: Serialize>(json: bool, value: &T, text: impl FnOnce(&T) -> String) -> Result<()> {
+    if json {
+        println!("{}", to_string_pretty(value)?);
+    } else {
+        let text = text(value);
+        if !text.is_empty() {
+            println!("{}", text);
+        }
+    }
+    Ok(())
+}
+
+/// One line per directory member, with its size or number of members and its name.
+fn format_members(members: &[Item]) -> String {
+    members
+        .iter()
+        .map(|it| {
+            let name = it.name.as_deref().unwrap_or(basename(&it.path));
+            if is_dir(it) {
+                format!(
+                    "{:>12}  {}/",
+                    format!("{} sub", it.nmembers.unwrap_or(0)),
+                    name
+                )
+            } else {
+                format!("{:>12}  {}", it.size.unwrap_or(0), name)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn mirror_paths(r: &MirrorReport) -> serde_json::Value {
+    json!({
+        "transferred": r.transferred,
+        "deleted": r.deleted,
+        "created_dirs": r.created_dirs,
+    })
+}
+
+fn on_exist(force: bool) -> Params {
+    let mut p = Params::new();
+    if force {
+        p.add_str("on_exist", OnExist::Overwrite.to_string());
+    }
+    p
+}
+
+fn is_dir(it: &Item) -> bool {
+    it.typ.as_deref() == Some("dir")
+}
+
+fn basename(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+async fn run(cx: &mut Ctx, command: Command) -> Result<()> {
+    match command {
+        Command::Login | Command::Completions { .. } | Command::Share { .. } => {
+            unreachable!("handled in main()")
+        }
+        Command::Me => {
+            let mut p = Params::new();
+            p.add_str("fields", ME_FIELDS);
+            let me = cx.hd.user().me(Some(&p)).await?;
+            cx.print(&me, |me| to_string_pretty(me).unwrap_or_default())
+        }
+        Command::Quota => {
+            let q = cx.hd.user().quota().await?;
+            let value = json!({ "limit": q.limit, "used": q.used, "free": q.free() });
+            cx.print(&value, |_| {
+                format!("{} of {} bytes used, {} free", q.used, q.limit, q.free())
+            })
+        }
+        Command::Ls { path } => {
+            let id = cx.id(&path).await?;
+            cx.ls(id).await
+        }
+        Command::Stat { path } => {
+            let id = cx.id(&path).await?;
+            let it = cx.hd.files().metadata(id, ITEM_FIELDS, None).await?;
+            cx.print(&it, |it| to_string_pretty(it).unwrap_or_default())
+        }
+        Command::Get { remote, local } => {
+            let local = local.unwrap_or_else(|| PathBuf::from(basename(&remote)));
+            let id = cx.id(&remote).await?;
+            let n = cx.hd.files().download_to_path(id, &local, None).await?;
+            cx.print(&json!({ "bytes": n }), |_| {
+                format!("downloaded {} bytes to {:?}", n, local)
+            })
+        }
+        Command::Put { local, dir } => {
+            let name = local
+                .file_name()
+                .and_then(|n| n.to_str())
+                .ok_or_else(|| anyhow!("invalid file name {:?}", local))?
+                .to_string();
+            let f = tokio::fs::File::open(&local)
+                .await
+                .with_context(|| format!("opening {:?}", local))?;
+            let id = cx.id(&dir).await?;
+            let it = cx.hd.files().upload(id, name, f, None).await?;
+            cx.print_item(&it)
+        }
+        Command::Rm { path } => {
+            let id = cx.id(&path).await?;
+            cx.hd.files().delete(id, None).await
+        }
+        Command::Mkdir { path } => {
+            let id = cx.id(&path).await?;
+            let it = cx.hd.files().mkdir(id, None).await?;
+            cx.print_item(&it)
+        }
+        Command::Rmdir { path, recursive } => {
+            let id = cx.id(&path).await?;
+            let mut p = Params::new();
+            if recursive {
+                p.add_str("recursive", "true");
+            }
+            cx.hd.files().delete_dir(id, Some(&p)).await?;
+            Ok(())
+        }
+        Command::Mv { from, to, force } => {
+            let (from, to) = (cx.id(&from).await?, cx.id(&to).await?);
+            cx.mv(from, to, &on_exist(force)).await
+        }
+        Command::Cp { from, to, force } => {
+            let (from, to, p) = (cx.id(&from).await?, cx.id(&to).await?, on_exist(force));
+            let mut files = cx.hd.files();
+            let it = if is_dir(&files.metadata(from.clone(), "type", None).await?) {
+                files.copy_dir(from, to, Some(&p)).await?
+            } else {
+                files.copy(from, to, Some(&p)).await?
+            };
+            cx.print_item(&it)
+        }
+        Command::Url { path } => {
+            let id = cx.id(&path).await?;
+            let url = cx.hd.files().url(id, None).await?;
+            cx.print(&url, |url| url.url.clone())
+        }
+        Command::Thumbnail { path, local } => {
+            let id = cx.id(&path).await?;
+            let f = tokio::fs::File::create(&local)
+                .await
+                .with_context(|| format!("creating {:?}", local))?;
+            cx.hd.files().thumbnail(id, f, None).await?;
+            Ok(())
+        }
+        Command::Search { pattern } => {
+            let id = cx.id("").await?;
+            let mut p = Params::new();
+            p.add_str("pattern", pattern);
+            let found = cx.hd.files().search(id, "path,type,size", Some(&p)).await?;
+            cx.print(&found, |found| {
+                found
+                    .iter()
+                    .map(|it| it.path.clone())
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            })
+        }
+        Command::Listen => {
+            let mut n = cx.hd.notifications().await?;
+            while let Some(event) = n.next().await? {
+                println!("{}", serde_json::to_string(&event)?);
+            }
+            Ok(())
+        }
+        Command::Trash { command, dir } => {
+            let trash = cx.id(&dir).await?;
+            match command {
+                TrashCommand::Ls => cx.ls(trash).await,
+                TrashCommand::Put { path } => {
+                    let id = cx.id(&path).await?;
+                    // The trash is created on first use. If that fails, moving fails, too.
+                    let _ = cx.hd.files().mkdir(trash.clone(), None).await;
+                    let to = trash.join(basename(path.trim_end_matches('/')));
+                    let mut p = Params::new();
+                    p.add_on_exist(OnExist::Autoname);
+                    cx.mv(id, to, &p).await
+                }
+                TrashCommand::Restore { name, to } => {
+                    let to = cx.id(&to).await?;
+                    cx.mv(trash.join(&name), to, &on_exist(false)).await
+                }
+                TrashCommand::Empty => {
+                    let mut p = Params::new();
+                    p.add_bool("recursive", true);
+                    cx.hd.files().delete_dir(trash, Some(&p)).await?;
+                    Ok(())
+                }
+            }
+        }
+        Command::Backup { command, repo } => {
+            let root = cx.path(&repo).await?;
+            if let BackupCommand::Init = command {
+                Repository::init(&cx.hd, &root, ChunkingConfig::default()).await?;
+                return Ok(());
+            }
+            let mut repo = Repository::open(&cx.hd, &root).await?;
+            match command {
+                BackupCommand::Init => unreachable!(),
+                BackupCommand::Run { local, exclude } => {
+                    let mut opts = BackupOptions::default();
+                    for pattern in exclude.iter() {
+                        opts.ignore.add(pattern);
+                    }
+                    let r = repo.backup(&local, &opts).await?;
+                    let value = json!({
+                        "generation": r.generation,
+                        "files": r.files,
+                        "unchanged": r.unchanged,
+                        "new_chunks": r.new_chunks,
+                        "bytes_uploaded": r.bytes_uploaded,
+                    });
+                    cx.print(&value, |_| {
+                        format!(
+                            "{}: {} files ({} unchanged), {} new chunks, {} bytes uploaded",
+                            r.generation, r.files, r.unchanged, r.new_chunks, r.bytes_uploaded
+                        )
+                    })
+                }
+                BackupCommand::Ls {
+                    generation: None, ..
+                } => {
+                    let names: Vec<String> = repo
+                        .generations()
+                        .await?
+                        .into_iter()
+                        .map(|g| g.name)
+                        .collect();
+                    cx.print(&names, |names| names.join("\n"))
+                }
+                BackupCommand::Ls {
+                    generation: Some(generation),
+                    path,
+                } => {
+                    let entries = repo.manifest(&generation).await?.list(&path);
+                    let value: Vec<serde_json::Value> = entries
+                        .iter()
+                        .map(|e| match e.file {
+                            Some(ref f) => {
+                                json!({ "name": e.name, "size": f.size, "mtime": f.mtime })
+                            }
+                            None => json!({ "name": e.name }),
+                        })
+                        .collect();
+                    cx.print(&value, |_| {
+                        entries
+                            .iter()
+                            .map(|e| match e.file {
+                                Some(ref f) => format!("{:>12}  {}", f.size, e.name),
+                                None => format!("{:>12}  {}/", "", e.name),
+                            })
+                            .collect::<Vec<_>>()
+                            .join("\n")
+                    })
+                }
+                BackupCommand::Restore {
+                    generation,
+                    local,
+                    path,
+                    overwrite,
+                } => {
+                    let opts = RestoreOptions {
+                        overwrite,
+                        ..Default::default()
+                    };
+                    let r = repo.restore(&generation, &path, &local, &opts).await?;
+                    let value = json!({ "files": r.files, "dirs": r.dirs, "bytes": r.bytes });
+                    cx.print(&value, |_| {
+                        format!(
+                            "restored {} files, {} directories, {} bytes",
+                            r.files, r.dirs, r.bytes
+                        )
+                    })
+                }
+                BackupCommand::Forget {
+                    keep_last,
+                    keep_daily,
+                    keep_weekly,
+                    keep_monthly,
+                } => {
+                    let retention = Retention {
+                        keep_last,
+                        keep_daily,
+                        keep_weekly,
+                        keep_monthly,
+                    };
+                    let r = repo.forget(&retention).await?;
+                    let value = json!({ "removed": r.removed, "chunks_deleted": r.chunks_deleted });
+                    cx.print(&value, |_| {
+                        let mut lines: Vec<String> =
+                            r.removed.iter().map(|g| format!("removed {}", g)).collect();
+                        lines.push(format!("{} chunks deleted", r.chunks_deleted));
+                        lines.join("\n")
+                    })
+                }
+            }
+        }
+        Command::Sync { direction, opts } => {
+            let opts = opts.options();
+            let failed = match direction {
+                SyncCommand::Up { local, remote } => {
+                    let id = cx.id(&remote).await?;
+                    let r = sync::mirror_up(&mut cx.hd, &local, id, &opts).await?;
+                    cx.print_sync(mirror_paths(&r), &r.failed, &r.summary)?;
+                    r.failed.len()
+                }
+                SyncCommand::Down { remote, local } => {
+                    let id = cx.id(&remote).await?;
+                    let r = sync::mirror_down(&mut cx.hd, id, &local, &opts).await?;
+                    cx.print_sync(mirror_paths(&r), &r.failed, &r.summary)?;
+                    r.failed.len()
+                }
+                SyncCommand::Bi {
+                    local,
+                    remote,
+                    state,
+                } => {
+                    let id = cx.id(&remote).await?;
+                    let r = sync::bisync(&mut cx.hd, &local, id, &state, &opts).await?;
+                    let propagated: Vec<&str> = r.propagated.iter().map(|op| op.path()).collect();
+                    let value = json!({
+                        "uploaded": r.uploaded,
+                        "downloaded": r.downloaded,
+                        "propagated": propagated,
+                        "conflicts": r.conflicts,
+                    });
+                    cx.print_sync(value, &r.failed, &r.summary)?;
+                    r.failed.len()
+                }
+            };
+            if failed == 0 {
+                Ok(())
+            } else {
+                Err(anyhow!("{} files couldn't be synchronized", failed))
+            }
+        }
+    }
+}
+
+async fn share(command: ShareCommand, password: Option<String>, json: bool) -> Result<()> {
+    let new_link = |link: &str| -> Result<ShareLink> {
+        let link = ShareLink::new(link)?;
+        Ok(match password {
+            Some(ref password) => link.with_password(password),
+            None => link,
+        })
+    };
+    match command {
+        ShareCommand::Ls { link, path } => {
+            let mut p = Params::new();
+            p.add_str("members", "all").add_str("fields", DIR_FIELDS);
+            let dir = new_link(&link)?.list(&path, Some(&p)).await?;
+            print(json, &dir.members, |members| format_members(members))
+        }
+        ShareCommand::Get { link, path, local } => {
+            let mut link = new_link(&link)?;
+            let local = match local {
+                Some(local) => local,
+                None if path.is_empty() => PathBuf::from(
+                    link.info()
+                        .await?
+                        .name
+                        .ok_or_else(|| anyhow!("the sharelink has no file name"))?,
+                ),
+                None => PathBuf::from(basename(&path)),
+            };
+            let f = tokio::fs::File::create(&local)
+                .await
+                .with_context(|| format!("creating {:?}", local))?;
+            let n = link.download(&path, f).await?;
+            print(json, &json!({ "bytes": n }), |_| {
+                format!("downloaded {} bytes to {:?}", n, local)
+            })
+        }
+    }
+}
+
+async fn login(account: &Account) -> Result<()> {
+    let client_secret = ClientSecret::load(&account.client_secret).await?;
+    let scope = oauth2::Scope {
+        role: oauth2::Role::User,
+        access: oauth2::Access::Rw,
+    };
+    let credentials = oauth2::authorize_user(
+        &mut oauth2::DefaultAuthorizationHandler,
+        client_secret,
+        scope,
+    )
+    .await?;
+    credentials.save(&account.credentials).await
+}
+
+async fn main_(args: Args) -> Result<()> {
+    if let Command::Completions { shell } = args.command {
+        clap_complete::generate(shell, &mut Args::command(), "hd", &mut std::io::stdout());
+        return Ok(());
+    }
+    if let Command::Share { command, password } = args.command {
+        return share(command, password, args.json).await;
+    }
+    let config = match args.config {
+        Some(path) => path,
+        None => default_config_path()?,
+    };
+    let account = load_account(&config, args.account.as_deref()).await?;
+    if let Command::Login = args.command {
+        return login(&account).await;
+    }
+    let client_secret = ClientSecret::load(&account.client_secret).await?;
+    let credentials = Credentials::load(&account.credentials)
+        .await
+        .context("loading credentials; run `hd login` first")?;
+    let authz = oauth2::Authorizer::new(credentials, client_secret);
+    let mut cx = Ctx {
+        hd: HiDrive::builder(authz).build()?,
+        json: args.json,
+    };
+    run(&mut cx, args.command).await
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let args = Args::parse();
+    let level = match args.verbose {
+        0 => log::Level::Warn,
+        1 => log::Level::Info,
+        _ => log::Level::Debug,
+    };
+    simple_logger::init_with_level(level).unwrap();
+    if let Err(e) = main_(args).await {
+        eprintln!("hd: {:#}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,323 @@
+//! A synchronous `HiDrive`, for applications not using tokio themselves (GUI toolkits, plugins).
+//!
+//! Each call runs the corresponding async call to completion on an internal single-threaded
+//! runtime. Don't call these methods from within a tokio runtime; that panics.
+//!
+//! `RemoteFile` and `RemoteWriter` adapt the handles of the `remote` module to `std::io`, for
+//! synchronous libraries such as zip readers or image decoders.
+
+use crate::hidrive::{self, NO_PARAMS};
+use crate::remote;
+use crate::sync::{self, BisyncReport, MirrorOptions, MirrorReport};
+use crate::types::{Home, Identifier, Item, Params, Quota, Url, User, UserPatch};
+
+use std::future::Future;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::runtime::Runtime;
+
+/// Wraps a `hidrive::HiDrive` and its own runtime. Methods correspond to those of
+/// `HiDriveUser` and `HiDriveFiles` and the `sync` functions; `block_on()` runs anything else.
+///
+/// ```ignore
+/// let mut hd = hd_api::blocking::HiDrive::new(hd_api::HiDrive::builder(authz).build()?)?;
+/// let dir = hd.get_dir(Identifier::Path("/users/me".into()), None)?;
+/// ```
+pub struct HiDrive {
+    rt: Arc<Runtime>,
+    hd: hidrive::HiDrive,
+}
+
+impl HiDrive {
+    pub fn new(hd: hidrive::HiDrive) -> Result<HiDrive> {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .context("creating runtime")?;
+        Ok(HiDrive {
+            rt: Arc::new(rt),
+            hd,
+        })
+    }
+
+    /// The wrapped async client, e.g. to configure it.
+    pub fn inner(&mut self) -> &mut hidrive::HiDrive {
+        &mut self.hd
+    }
+
+    /// Run `f` to completion on the internal runtime. Futures using the wrapped client can be
+    /// run by forking it: `hd.block_on(async move { fork.files().get_dir(...).await })`, with
+    /// `fork = hd.inner().fork()`.
+    pub fn block_on<F: Future>(&self, f: F) -> F::Output {
+        self.rt.block_on(f)
+    }
+
+    pub fn me(&mut self, p: Option<&Params>) -> Result<User> {
+        self.rt.block_on(self.hd.user().me(p))
+    }
+
+    pub fn quota(&mut self) -> Result<Quota> {
+        self.rt.block_on(self.hd.user().quota())
+    }
+
+    pub fn home(&mut self) -> Result<Home> {
+        self.rt.block_on(self.hd.user().home())
+    }
+
+    pub fn update_user(&mut self, patch: &UserPatch) -> Result<User> {
+        self.rt.block_on(self.hd.user().update(patch))
+    }
+
+    pub fn metadata(&mut self, id: Identifier, fields: &str) -> Result<Item> {
+        self.rt
+            .block_on(self.hd.files().metadata(id, fields, NO_PARAMS))
+    }
+
+    pub fn get_dir(&mut self, id: Identifier, p: Option<&Params>) -> Result<Item> {
+        self.rt.block_on(self.hd.files().get_dir(id, p))
+    }
+
+    /// Download a file into memory.
+    pub fn read(&mut self, id: Identifier) -> Result<Vec<u8>> {
+        let mut buf = vec![];
+        self.rt
+            .block_on(self.hd.files().get(id, &mut buf, NO_PARAMS))?;
+        Ok(buf)
+    }
+
+    /// Download a file to the local file `path`.
+    pub fn download_to_path(&mut self, id: Identifier, path: impl AsRef<Path>) -> Result<usize> {
+        self.rt
+            .block_on(self.hd.files().download_to_path(id, path, NO_PARAMS))
+    }
+
+    /// Upload `data` as `name` into the directory `dir`, overwriting an existing file.
+    pub fn upload(
+        &mut self,
+        dir: Identifier,
+        name: &str,
+        data: impl Into<reqwest::Body>,
+    ) -> Result<Item> {
+        self.rt
+            .block_on(self.hd.files().upload(dir, name, data, NO_PARAMS))
+    }
+
+    /// Upload the local file `path` into the directory `dir`, keeping its name.
+    pub fn upload_file(&mut self, dir: Identifier, path: impl AsRef<Path>) -> Result<Item> {
+        let path = path.as_ref();
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .with_context(|| format!("invalid file name {:?}", path))?;
+        let hd = &mut self.hd;
+        self.rt.block_on(async {
+            let f = tokio::fs::File::open(path)
+                .await
+                .with_context(|| format!("opening {:?}", path))?;
+            hd.files().upload(dir, name, f, NO_PARAMS).await
+        })
+    }
+
+    /// Open the file `id` for reading, see `remote::RemoteFile`.
+    pub fn open(&mut self, id: Identifier) -> Result<RemoteFile> {
+        let f = self.rt.block_on(self.hd.files().open(id))?;
+        Ok(RemoteFile {
+            rt: self.rt.clone(),
+            f,
+        })
+    }
+
+    /// Create the file `name` in `dir` for writing, see `remote::RemoteWriter`.
+    pub fn create(&mut self, dir: Identifier, name: &str) -> RemoteWriter {
+        RemoteWriter {
+            rt: self.rt.clone(),
+            w: self.hd.files().create(dir, name),
+            finished: false,
+        }
+    }
+
+    pub fn url(&mut self, id: Identifier) -> Result<Url> {
+        self.rt.block_on(self.hd.files().url(id, NO_PARAMS))
+    }
+
+    pub fn copy(&mut self, from: Identifier, to: Identifier, p: Option<&Params>) -> Result<Item> {
+        self.rt.block_on(self.hd.files().copy(from, to, p))
+    }
+
+    pub fn mv(&mut self, from: Identifier, to: Identifier, p: Option<&Params>) -> Result<Item> {
+        self.rt.block_on(self.hd.files().mv(from, to, p))
+    }
+
+    pub fn delete(&mut self, id: Identifier) -> Result<()> {
+        self.rt.block_on(self.hd.files().delete(id, NO_PARAMS))
+    }
+
+    pub fn mkdir(&mut self, id: Identifier) -> Result<Item> {
+        self.rt.block_on(self.hd.files().mkdir(id, NO_PARAMS))
+    }
+
+    /// Parameters: `recursive, parent_mtime`.
+    pub fn delete_dir(&mut self, id: Identifier, p: Option<&Params>) -> Result<Item> {
+        self.rt.block_on(self.hd.files().delete_dir(id, p))
+    }
+
+    pub fn search(
+        &mut self,
+        root: Identifier,
+        fields: &str,
+        p: Option<&Params>,
+    ) -> Result<Vec<Item>> {
+        self.rt.block_on(self.hd.files().search(root, fields, p))
+    }
+
+    /// See `sync::mirror_up()`.
+    pub fn mirror_up(
+        &mut self,
+        local_dir: impl AsRef<Path>,
+        remote_id: Identifier,
+        opts: &MirrorOptions,
+    ) -> Result<MirrorReport> {
+        self.rt
+            .block_on(sync::mirror_up(&mut self.hd, local_dir, remote_id, opts))
+    }
+
+    /// See `sync::mirror_down()`.
+    pub fn mirror_down(
+        &mut self,
+        remote_id: Identifier,
+        local_dir: impl AsRef<Path>,
+        opts: &MirrorOptions,
+    ) -> Result<MirrorReport> {
+        self.rt
+            .block_on(sync::mirror_down(&mut self.hd, remote_id, local_dir, opts))
+    }
+
+    /// See `sync::bisync()`.
+    pub fn bisync(
+        &mut self,
+        local_dir: impl AsRef<Path>,
+        remote_id: Identifier,
+        state: impl AsRef<Path>,
+        opts: &MirrorOptions,
+    ) -> Result<BisyncReport> {
+        self.rt.block_on(sync::bisync(
+            &mut self.hd,
+            local_dir,
+            remote_id,
+            state,
+            opts,
+        ))
+    }
+}
+
+/// A `remote::RemoteFile` implementing `Read` and `Seek`. Like `HiDrive`, it must not be used
+/// from within a tokio runtime.
+pub struct RemoteFile {
+    rt: Arc<Runtime>,
+    f: remote::RemoteFile,
+}
+
+impl RemoteFile {
+    pub fn size(&self) -> u64 {
+        self.f.size()
+    }
+
+    /// See `remote::RemoteFile::set_read_ahead()`.
+    pub fn set_read_ahead(&mut self, bytes: usize) {
+        self.f.set_read_ahead(bytes);
+    }
+}
+
+impl Read for RemoteFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.rt.block_on(self.f.read(buf))
+    }
+}
+
+impl Seek for RemoteFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.rt.block_on(self.f.seek(pos))
+    }
+}
+
+/// A `remote::RemoteWriter` implementing `Write`. Call `finish()` when done; dropping the writer
+/// finishes it too, but ignores errors. Like `HiDrive`, it must not be used from within a tokio
+/// runtime.
+pub struct RemoteWriter {
+    rt: Arc<Runtime>,
+    w: remote::RemoteWriter,
+    finished: bool,
+}
+
+impl RemoteWriter {
+    /// See `remote::RemoteWriter::set_chunk_size()`.
+    pub fn set_chunk_size(&mut self, bytes: usize) {
+        self.w.set_chunk_size(bytes);
+    }
+
+    /// Upload the remaining data; see `remote::RemoteWriter`.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.finished = true;
+        self.rt.block_on(self.w.shutdown())
+    }
+}
+
+impl Write for RemoteWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.rt.block_on(self.w.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.rt.block_on(self.w.flush())
+    }
+}
+
+impl Drop for RemoteWriter {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.rt.block_on(self.w.shutdown());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::mock::{self, MockTransport};
+
+    #[test]
+    fn test_blocking() {
+        let t = MockTransport::new();
+        let mut hd = HiDrive::new(mock::hidrive(t.clone())).unwrap();
+
+        t.push(200, r#"{"path": "/a", "type": "dir", "members": []}"#);
+        let dir = hd.get_dir(Identifier::Path("/a".into()), None).unwrap();
+        assert_eq!("/a", dir.path);
+
+        t.push(200, "content");
+        assert_eq!(
+            b"content".to_vec(),
+            hd.read(Identifier::Path("/a/b".into())).unwrap()
+        );
+        assert_eq!(Some("/a/b"), t.last().param("path").as_deref());
+
+        t.push(200, r#"{"id": "b1.2", "size": 7}"#);
+        let mut f = hd.open(Identifier::Path("/a/b".into())).unwrap();
+        f.seek(SeekFrom::Start(2)).unwrap();
+        t.push(206, "ntent");
+        let mut s = String::new();
+        f.read_to_string(&mut s).unwrap();
+        assert_eq!("ntent", s);
+        assert_eq!("bytes=2-6", t.last().headers["range"]);
+
+        let mut w = hd.create(Identifier::Path("/a".into()), "c");
+        t.push(200, "{}");
+        write!(w, "{}-{}", 1, 2).unwrap();
+        w.finish().unwrap();
+        assert_eq!(Some(b"1-2".to_vec()), t.last().body);
+    }
+}
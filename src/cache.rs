@@ -0,0 +1,330 @@
+//! Caching of API responses using ETags.
+//!
+//! If a `ResponseCache` is configured (`HiDrive::set_cache()`), GET requests answered with an
+//! `ETag` header are stored, and subsequent identical requests are sent with `If-None-Match`. A
+//! `304 Not Modified` response is then served from the cache. Any non-GET request invalidates
+//! cached responses referring to the same objects (by ID, or by path including parent and child
+//! directories).
+//!
+//! A `MetadataCache` (`HiDrive::set_metadata_cache()`) goes further: `get_dir()` and `metadata()`
+//! responses are served without any request for a fixed time, e.g. for interactive browsers
+//! listing the same directories again and again. Writes invalidate entries the same way.
+//! Changes made by other clients show up only after the entries expired.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// Query parameters identifying the object(s) a request refers to.
+const ID_PARAMS: &[&str] = &[
+    "pid", "path", "dir", "dir_id", "src", "src_id", "dst", "dst_id",
+];
+
+/// A cached response body, with the ETag it was served with.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CachedResponse {
+    pub etag: String,
+    pub body: String,
+    /// IDs and paths the request referred to.
+    pub ids: Vec<String>,
+}
+
+/// Storage for cached responses. Keys are full request URLs. Implement this to persist cached
+/// responses, e.g. on disk.
+#[async_trait::async_trait]
+pub trait ResponseCache: Send + Sync {
+    async fn get(&self, key: &str) -> Option<CachedResponse>;
+    async fn put(&self, key: &str, rp: CachedResponse);
+    /// Remove all entries referring to one of `ids` (see `related()`).
+    async fn invalidate(&self, ids: &[String]);
+}
+
+/// Extract the values of identifying parameters from a request URL.
+pub fn identifiers(u: &reqwest::Url) -> Vec<String> {
+    u.query_pairs()
+        .filter(|(k, _)| ID_PARAMS.contains(&k.as_ref()))
+        .map(|(_, v)| v.into_owned())
+        .collect()
+}
+
+fn is_parent(p: &str, c: &str) -> bool {
+    let p = p.trim_end_matches('/');
+    c.len() > p.len() && c.starts_with(p) && c.as_bytes()[p.len()] == b'/'
+}
+
+/// Returns true if a write to `a` may change a response about `b`, or vice versa: if they are
+/// equal, or one is a parent directory of the other.
+pub fn related(a: &str, b: &str) -> bool {
+    a == b || is_parent(a, b) || is_parent(b, a)
+}
+
+/// An in-memory cache holding up to a fixed number of entries. If full, the oldest entry is
+/// evicted.
+pub struct MemoryCache {
+    max_entries: usize,
+    m: Mutex<(u64, HashMap<String, (u64, CachedResponse)>)>,
+}
+
+impl MemoryCache {
+    pub fn new(max_entries: usize) -> MemoryCache {
+        MemoryCache {
+            max_entries,
+            m: Mutex::new((0, HashMap::new())),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl ResponseCache for MemoryCache {
+    async fn get(&self, key: &str) -> Option<CachedResponse> {
+        self.m.lock().unwrap().1.get(key).map(|(_, c)| c.clone())
+    }
+
+    async fn put(&self, key: &str, rp: CachedResponse) {
+        let mut m = self.m.lock().unwrap();
+        let (seq, entries) = &mut *m;
+        if entries.len() >= self.max_entries && !entries.contains_key(key) {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, (s, _))| *s)
+                .map(|(k, _)| k.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        *seq += 1;
+        entries.insert(key.to_string(), (*seq, rp));
+    }
+
+    async fn invalidate(&self, ids: &[String]) {
+        self.m
+            .lock()
+            .unwrap()
+            .1
+            .retain(|_, (_, c)| !c.ids.iter().any(|a| ids.iter().any(|b| related(a, b))));
+    }
+}
+
+/// An entry of a `MetadataCache`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct MetadataEntry {
+    body: String,
+    ids: Vec<String>,
+    /// Milliseconds since the epoch.
+    stored: u64,
+    /// Last use, for evicting the least recently used entry.
+    #[serde(skip)]
+    used: u64,
+}
+
+#[derive(Default)]
+struct MetadataEntries {
+    seq: u64,
+    /// Incremented by each invalidation, see `MetadataCache::insert()`.
+    epoch: u64,
+    entries: HashMap<String, MetadataEntry>,
+}
+
+/// Caches metadata responses (`get_dir()`, `metadata()`) for `ttl`, holding up to `max_entries`
+/// and evicting the least recently used one if full. Can be saved to and loaded from a file, to
+/// keep entries across restarts. See the module documentation.
+pub struct MetadataCache {
+    ttl: Duration,
+    max_entries: usize,
+    m: Mutex<MetadataEntries>,
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+impl MetadataCache {
+    pub fn new(ttl: Duration, max_entries: usize) -> MetadataCache {
+        MetadataCache {
+            ttl,
+            max_entries,
+            m: Mutex::new(MetadataEntries::default()),
+        }
+    }
+
+    /// Like `new()`, with the entries saved in `path` by `save()`. A missing file results in an
+    /// empty cache.
+    pub async fn load(
+        path: impl AsRef<Path>,
+        ttl: Duration,
+        max_entries: usize,
+    ) -> Result<MetadataCache> {
+        let path = path.as_ref();
+        let c = MetadataCache::new(ttl, max_entries);
+        let entries: HashMap<String, MetadataEntry> = match tokio::fs::read(path).await {
+            Ok(b) => serde_json::from_slice(&b)
+                .with_context(|| format!("reading metadata cache {:?}", path))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(c),
+            Err(e) => return Err(e).with_context(|| format!("reading metadata cache {:?}", path)),
+        };
+        let mut m = c.m.lock().unwrap();
+        for (key, e) in entries {
+            if c.fresh(&e) && m.entries.len() < max_entries {
+                m.entries.insert(key, e);
+            }
+        }
+        drop(m);
+        Ok(c)
+    }
+
+    /// Write the unexpired entries to `path`.
+    pub async fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let data = {
+            let m = self.m.lock().unwrap();
+            let fresh: HashMap<&String, &MetadataEntry> =
+                m.entries.iter().filter(|(_, e)| self.fresh(e)).collect();
+            serde_json::to_vec(&fresh)?
+        };
+        let tmp = path.with_extension("tmp");
+        tokio::fs::write(&tmp, data)
+            .await
+            .with_context(|| format!("writing metadata cache {:?}", tmp))?;
+        tokio::fs::rename(&tmp, path).await?;
+        Ok(())
+    }
+
+    fn fresh(&self, e: &MetadataEntry) -> bool {
+        now_millis().saturating_sub(e.stored) < self.ttl.as_millis() as u64
+    }
+
+    /// The cached response body for the request URL `key`, unless expired.
+    pub fn get(&self, key: &str) -> Option<String> {
+        let mut m = self.m.lock().unwrap();
+        m.seq += 1;
+        let seq = m.seq;
+        match m.entries.get_mut(key) {
+            Some(e) if self.fresh(e) => {
+                e.used = seq;
+                Some(e.body.clone())
+            }
+            Some(_) => {
+                m.entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Changes with every invalidation. Pass it to `insert()`.
+    pub(crate) fn epoch(&self) -> u64 {
+        self.m.lock().unwrap().epoch
+    }
+
+    /// Store a response body for the request URL `key`, referring to `ids`, unless an
+    /// invalidation happened since `epoch()` returned `epoch`, i.e. while the request was in
+    /// flight: the response may be outdated then.
+    pub(crate) fn insert(&self, key: &str, body: String, ids: Vec<String>, epoch: u64) {
+        let mut m = self.m.lock().unwrap();
+        if m.epoch != epoch {
+            return;
+        }
+        if m.entries.len() >= self.max_entries && !m.entries.contains_key(key) {
+            let lru = m
+                .entries
+                .iter()
+                .min_by_key(|(_, e)| e.used)
+                .map(|(k, _)| k.clone());
+            if let Some(lru) = lru {
+                m.entries.remove(&lru);
+            }
+        }
+        m.seq += 1;
+        let e = MetadataEntry {
+            body,
+            ids,
+            stored: now_millis(),
+            used: m.seq,
+        };
+        m.entries.insert(key.to_string(), e);
+    }
+
+    /// Remove all entries referring to one of `ids` (see `related()`).
+    pub fn invalidate(&self, ids: &[String]) {
+        let mut m = self.m.lock().unwrap();
+        m.epoch += 1;
+        m.entries
+            .retain(|_, e| !e.ids.iter().any(|a| ids.iter().any(|b| related(a, b))));
+    }
+
+    pub fn clear(&self) {
+        let mut m = self.m.lock().unwrap();
+        m.epoch += 1;
+        m.entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_related() {
+        assert!(related("/a/b", "/a/b"));
+        assert!(related("/a", "/a/b"));
+        assert!(related("/a/b/c", "/a/"));
+        assert!(!related("/a/b", "/a/bc"));
+        assert!(!related("b1234.4", "b1234.5"));
+    }
+
+    #[tokio::test]
+    async fn test_memory_cache() {
+        let c = MemoryCache::new(2);
+        let entry = |ids: &[&str]| CachedResponse {
+            etag: "e".into(),
+            body: "{}".into(),
+            ids: ids.iter().map(|s| s.to_string()).collect(),
+        };
+        c.put("k1", entry(&["/a/b"])).await;
+        c.put("k2", entry(&["/c"])).await;
+        c.put("k3", entry(&["/d"])).await;
+        assert!(c.get("k1").await.is_none());
+        assert!(c.get("k2").await.is_some());
+
+        c.invalidate(&["/c/file.txt".into()]).await;
+        assert!(c.get("k2").await.is_none());
+        assert!(c.get("k3").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_metadata_cache() {
+        let c = MetadataCache::new(Duration::from_secs(60), 2);
+        let ids = |id: &str| vec![id.to_string()];
+        c.insert("k1", "1".into(), ids("/a"), c.epoch());
+        c.insert("k2", "2".into(), ids("/b"), c.epoch());
+        assert_eq!(Some("1".into()), c.get("k1"));
+        // k2 is the least recently used entry.
+        c.insert("k3", "3".into(), ids("/c"), c.epoch());
+        assert!(c.get("k2").is_none());
+        assert!(c.get("k1").is_some());
+
+        // Invalidated while in flight.
+        let epoch = c.epoch();
+        c.invalidate(&["/c/d".into()]);
+        assert!(c.get("k3").is_none());
+        c.insert("k4", "4".into(), ids("/x"), epoch);
+        assert!(c.get("k4").is_none());
+
+        let path = std::env::temp_dir().join("hd_api_test_metadata_cache.json");
+        c.save(&path).await.unwrap();
+        let loaded = MetadataCache::load(&path, Duration::from_secs(60), 2)
+            .await
+            .unwrap();
+        assert_eq!(Some("1".into()), loaded.get("k1"));
+        let expired = MetadataCache::load(&path, Duration::ZERO, 2).await.unwrap();
+        assert!(expired.get("k1").is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
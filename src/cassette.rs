@@ -0,0 +1,226 @@
+//! Record and replay HTTP exchanges.
+//!
+//! A `Recorder` wraps another `Transport` and writes every request/response pair to a cassette
+//! file, with tokens and client secrets stripped. A `Player` serves responses from such a file,
+//! which allows deterministic tests of response parsing against real API output.
+
+use crate::http::{redact_url, Transport, REDACTED, SECRET_PARAMS};
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{self, Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+/// A response body. Stored as string if it is valid UTF-8, to keep cassettes readable.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Body {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl Body {
+    fn new(b: Vec<u8>) -> Body {
+        match String::from_utf8(b) {
+            Ok(s) => Body::Text(s),
+            Err(e) => Body::Binary(e.into_bytes()),
+        }
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        match self {
+            Body::Text(s) => s.into_bytes(),
+            Body::Binary(b) => b,
+        }
+    }
+}
+
+/// One recorded request/response pair.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Exchange {
+    pub method: String,
+    pub url: String,
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Body,
+}
+
+fn redact_body(b: Vec<u8>) -> Vec<u8> {
+    if let Ok(serde_json::Value::Object(mut m)) = serde_json::from_slice(&b) {
+        let mut changed = false;
+        for k in SECRET_PARAMS {
+            if let Some(v) = m.get_mut(*k) {
+                *v = serde_json::Value::String(REDACTED.into());
+                changed = true;
+            }
+        }
+        if changed {
+            return serde_json::to_vec(&m).unwrap_or(b);
+        }
+    }
+    b
+}
+
+fn build_response(
+    status: u16,
+    headers: &[(String, String)],
+    body: Vec<u8>,
+) -> Result<reqwest::Response> {
+    let mut rb = hyper::Response::builder().status(status);
+    for (k, v) in headers {
+        rb = rb.header(k.as_str(), v.as_str());
+    }
+    Ok(rb.body(body)?.into())
+}
+
+/// Recorder forwards requests to an inner transport and appends each exchange to a cassette file.
+/// The file is rewritten after every exchange, so it is complete even if the process is aborted.
+pub struct Recorder {
+    inner: Arc<dyn Transport>,
+    path: PathBuf,
+    exchanges: tokio::sync::Mutex<Vec<Exchange>>,
+}
+
+impl Recorder {
+    pub fn new(inner: Arc<dyn Transport>, path: impl Into<PathBuf>) -> Recorder {
+        Recorder {
+            inner,
+            path: path.into(),
+            exchanges: tokio::sync::Mutex::new(vec![]),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Transport for Recorder {
+    async fn execute(&self, rq: reqwest::Request) -> Result<reqwest::Response> {
+        let method = rq.method().to_string();
+        let url = redact_url(rq.url());
+        let resp = self.inner.execute(rq).await?;
+        let status = resp.status().as_u16();
+        let headers: Vec<(String, String)> = resp
+            .headers()
+            .iter()
+            .filter(|(k, _)| *k != reqwest::header::SET_COOKIE)
+            .filter_map(|(k, v)| v.to_str().ok().map(|v| (k.to_string(), v.to_string())))
+            .collect();
+        let body = resp.bytes().await?.to_vec();
+
+        let mut exchanges = self.exchanges.lock().await;
+        exchanges.push(Exchange {
+            method,
+            url,
+            status,
+            headers: headers.clone(),
+            body: Body::new(redact_body(body.clone())),
+        });
+        fs::write(&self.path, serde_json::to_vec_pretty(&*exchanges)?)
+            .await
+            .context("Recorder: writing cassette")?;
+        build_response(status, &headers, body)
+    }
+}
+
+/// Player answers requests from a cassette. Each request is matched to the first unused exchange
+/// with the same method and (redacted) URL; unmatched requests fail.
+pub struct Player {
+    exchanges: Mutex<Vec<Exchange>>,
+}
+
+impl Player {
+    pub fn new(exchanges: Vec<Exchange>) -> Player {
+        Player {
+            exchanges: Mutex::new(exchanges),
+        }
+    }
+
+    /// Load a cassette written by `Recorder`.
+    pub async fn load(path: impl AsRef<Path>) -> Result<Player> {
+        let b = fs::read(path.as_ref())
+            .await
+            .context("Player: reading cassette")?;
+        Ok(Player::new(serde_json::from_slice(&b)?))
+    }
+}
+
+#[async_trait::async_trait]
+impl Transport for Player {
+    async fn execute(&self, rq: reqwest::Request) -> Result<reqwest::Response> {
+        let method = rq.method().to_string();
+        let url = redact_url(rq.url());
+        let ex = {
+            let mut exchanges = self.exchanges.lock().unwrap();
+            match exchanges
+                .iter()
+                .position(|e| e.method == method && e.url == url)
+            {
+                Some(i) => exchanges.remove(i),
+                None => {
+                    return Err(anyhow::Error::msg(format!(
+                        "Player: no recorded exchange for {} {}",
+                        method, url
+                    )))
+                }
+            }
+        };
+        build_response(ex.status, &ex.headers, ex.body.into_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::mock::MockTransport;
+
+    #[test]
+    fn test_redact() {
+        let u = reqwest::Url::parse("https://my.hidrive.com/oauth2/token?client_id=abc&client_secret=def&refresh_token=rt-123").unwrap();
+        assert_eq!(
+            "https://my.hidrive.com/oauth2/token?client_id=abc&client_secret=REDACTED&refresh_token=REDACTED",
+            redact_url(&u)
+        );
+        let b = redact_body(br#"{"access_token": "secret", "alias": "x"}"#.to_vec());
+        let v: serde_json::Value = serde_json::from_slice(&b).unwrap();
+        assert_eq!("REDACTED", v["access_token"]);
+        assert_eq!("x", v["alias"]);
+    }
+
+    #[tokio::test]
+    async fn test_record_replay() {
+        let path = std::env::temp_dir().join("hd_api_test_cassette.json");
+        let t = MockTransport::new();
+        t.push(200, r#"{"path": "/a"}"#);
+        t.push(404, r#"{"code": 404, "msg": "Not Found"}"#);
+
+        let rec = Recorder::new(t, &path);
+        let cl = reqwest::Client::new();
+        for u in [
+            "https://api.hidrive.strato.com/2.1/dir?path=/a",
+            "https://api.hidrive.strato.com/2.1/dir?path=/b",
+        ] {
+            let rq = cl.get(u).build().unwrap();
+            rec.execute(rq).await.unwrap();
+        }
+
+        let player = Player::load(&path).await.unwrap();
+        let rq = cl
+            .get("https://api.hidrive.strato.com/2.1/dir?path=/b")
+            .build()
+            .unwrap();
+        let resp = player.execute(rq).await.unwrap();
+        assert_eq!(404, resp.status().as_u16());
+        let rq = cl
+            .get("https://api.hidrive.strato.com/2.1/dir?path=/a")
+            .build()
+            .unwrap();
+        let resp = player.execute(rq).await.unwrap();
+        assert_eq!(r#"{"path": "/a"}"#, resp.text().await.unwrap());
+        let rq = cl
+            .get("https://api.hidrive.strato.com/2.1/dir?path=/a")
+            .build()
+            .unwrap();
+        assert!(player.execute(rq).await.is_err());
+    }
+}
@@ -0,0 +1,220 @@
+//! Checksum manifests of remote directories, in the format of `sha1sum`, see `checksums()`.
+//!
+//! Manifests of `ChecksumKind::Sha1` can be verified with `sha1sum -c` in a local copy of the
+//! directory; those of `ChecksumKind::Chash` with `hashing::chash_file()`, but are cheap to
+//! produce, as the server provides the hashes.
+
+use crate::hashing::{self, Hash};
+use crate::hidrive::HiDrive;
+use crate::ignore::IgnoreRules;
+use crate::remote::RemoteFile;
+use crate::sync::{remote_tree, tree_entries};
+use crate::types::{Identifier, Item};
+
+use anyhow::{Context, Result};
+use log::info;
+use sha1::{Digest, Sha1};
+use tokio::io::AsyncReadExt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumKind {
+    /// Content hashes (`chash`) as provided by the server. Files without one are downloaded and
+    /// hashed.
+    Chash,
+    /// SHA-1 of the content, as computed by `sha1sum`. Every file is downloaded.
+    Sha1,
+}
+
+/// The checksum of a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChecksumEntry {
+    /// Relative to the directory of the manifest, with `/` as separator.
+    pub path: String,
+    pub hash: Hash,
+    /// The file was downloaded to compute the checksum.
+    pub downloaded: bool,
+}
+
+/// The checksums of all files below the remote directory `root`, sorted by path.
+pub async fn checksums(
+    hd: &mut HiDrive,
+    root: Identifier,
+    kind: ChecksumKind,
+) -> Result<Vec<ChecksumEntry>> {
+    let tree = remote_tree(hd, root, &IgnoreRules::new()).await?;
+    let mut entries = vec![];
+    for (rel, it) in tree_entries(&tree) {
+        if it.typ.as_deref() == Some("dir") {
+            continue;
+        }
+        let entry = match (kind, &it.chash) {
+            (ChecksumKind::Chash, Some(h)) => ChecksumEntry {
+                path: rel,
+                hash: h.clone(),
+                downloaded: false,
+            },
+            _ => ChecksumEntry {
+                hash: download_hash(hd, it, kind)
+                    .await
+                    .with_context(|| format!("checksums: hashing {}", it.path))?,
+                path: rel,
+                downloaded: true,
+            },
+        };
+        entries.push(entry);
+    }
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    info!(
+        target: "hd_api::checksums",
+        "checksums: {} files, {} downloaded",
+        entries.len(),
+        entries.iter().filter(|e| e.downloaded).count()
+    );
+    Ok(entries)
+}
+
+async fn download_hash(hd: &HiDrive, it: &Item, kind: ChecksumKind) -> Result<Hash> {
+    let size = it.size.unwrap_or(0) as u64;
+    let mut f = RemoteFile::new(hd.fork(), Identifier::Path(it.path.clone()), size);
+    match kind {
+        ChecksumKind::Chash => Ok(hashing::chash(f).await?.top_hash().clone()),
+        ChecksumKind::Sha1 => {
+            let mut h = Sha1::new();
+            let mut buf = vec![0; 64 << 10];
+            loop {
+                let n = f.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                h.update(&buf[..n]);
+            }
+            Ok(Hash::new_from_sha1(h.finalize()))
+        }
+    }
+}
+
+/// Format `entries` as manifest: one line `<hash>  <path>` per file. As with `sha1sum`, lines
+/// of paths containing a backslash or newline start with a backslash, and these characters are
+/// escaped.
+pub fn format_manifest(entries: &[ChecksumEntry]) -> String {
+    let mut s = String::new();
+    for e in entries {
+        if e.path.contains(['\\', '\n']) {
+            let path = e.path.replace('\\', "\\\\").replace('\n', "\\n");
+            s.push_str(&format!("\\{}  {}\n", e.hash, path));
+        } else {
+            s.push_str(&format!("{}  {}\n", e.hash, e.path));
+        }
+    }
+    s
+}
+
+/// Parse a manifest written by `format_manifest()` or `sha1sum`, returning paths and hashes.
+pub fn parse_manifest(manifest: &str) -> Result<Vec<(String, Hash)>> {
+    let mut out = vec![];
+    for (i, line) in manifest.lines().enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        let (escaped, line) = match line.strip_prefix('\\') {
+            Some(l) => (true, l),
+            None => (false, line),
+        };
+        let (hash, path) = line
+            .split_once(' ')
+            .with_context(|| format!("parse_manifest: invalid line {}", i + 1))?;
+        // The second separator character is ' ' (text) or '*' (binary mode).
+        let path = path
+            .strip_prefix([' ', '*'])
+            .with_context(|| format!("parse_manifest: invalid line {}", i + 1))?;
+        let path = if escaped {
+            unescape(path).with_context(|| format!("parse_manifest: invalid line {}", i + 1))?
+        } else {
+            path.to_string()
+        };
+        let hash = Hash::parse(hash).with_context(|| format!("parse_manifest: line {}", i + 1))?;
+        out.push((path, hash));
+    }
+    Ok(out)
+}
+
+fn unescape(s: &str) -> Option<String> {
+    let mut out = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next()? {
+                '\\' => out.push('\\'),
+                'n' => out.push('\n'),
+                _ => return None,
+            },
+            c => out.push(c),
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::MockHiDrive;
+
+    #[test]
+    fn test_manifest() {
+        let h = Hash::for_string("x");
+        let entries = vec![
+            ChecksumEntry {
+                path: "a b.txt".into(),
+                hash: h.clone(),
+                downloaded: false,
+            },
+            ChecksumEntry {
+                path: "odd\\name\nx".into(),
+                hash: h.clone(),
+                downloaded: false,
+            },
+        ];
+        let m = format_manifest(&entries);
+        assert_eq!(format!("{}  a b.txt\n\\{}  odd\\\\name\\nx\n", h, h), m);
+        let parsed = parse_manifest(&m).unwrap();
+        assert_eq!(
+            vec![
+                ("a b.txt".to_string(), h.clone()),
+                ("odd\\name\nx".to_string(), h.clone())
+            ],
+            parsed
+        );
+        assert_eq!(
+            vec![("bin".to_string(), h.clone())],
+            parse_manifest(&format!("{} *bin\n", h)).unwrap()
+        );
+        assert!(parse_manifest("abc  x").is_err());
+        assert!(parse_manifest(&h.to_string()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_checksums() {
+        let (fake, mut hd) = MockHiDrive::start().await;
+        fake.put("b.txt", "bbb", 0).await;
+        fake.put("sub/a.txt", "aaa", 0).await;
+        let root = || Identifier::Path("/m".into());
+
+        let c = checksums(&mut hd, root(), ChecksumKind::Chash)
+            .await
+            .unwrap();
+        assert_eq!(
+            vec!["b.txt", "sub/a.txt"],
+            c.iter().map(|e| e.path.as_str()).collect::<Vec<_>>()
+        );
+        let chash = hashing::chash(&b"bbb"[..]).await.unwrap();
+        assert_eq!(chash.top_hash(), &c[0].hash);
+        assert!(!c[0].downloaded);
+
+        let c = checksums(&mut hd, root(), ChecksumKind::Sha1)
+            .await
+            .unwrap();
+        assert_eq!(Hash::for_string("bbb"), c[0].hash);
+        assert_eq!(Hash::for_string("aaa"), c[1].hash);
+        assert!(c[1].downloaded);
+    }
+}
@@ -1,9 +1,398 @@
+//! Content-defined chunking: split data at borders determined by its content, so that insertions
+//! and deletions only affect the chunks around them.
+
+use crate::hashing::Hash;
+
+use std::collections::VecDeque;
+
 use anyhow::{self, Result};
+use bytes::Bytes;
+use futures_util::Stream;
 use rolling_dual_crc::RollingDualCrc;
+use serde::{Deserialize, Serialize};
+
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt};
+
+/// Size of the buffer used to read data for chunking.
+const READ_SIZE: usize = 64 * 1024;
+
+/// The hash identifying a chunk: the SHA-1 of its contents.
+pub fn chunk_hash(data: &[u8]) -> Hash {
+    Hash::for_string(data)
+}
+
+/// A chunk of a stream, as yielded by `Chunker::stream()`.
+#[derive(Debug, Clone)]
+pub struct Chunk {
+    /// Offset of the chunk from the beginning of the stream.
+    pub offset: u64,
+    pub data: Bytes,
+    /// See `chunk_hash()`.
+    pub hash: Hash,
+}
+
+impl Chunk {
+    fn new(offset: u64, data: Vec<u8>) -> Chunk {
+        Chunk {
+            offset,
+            hash: chunk_hash(&data),
+            data: data.into(),
+        }
+    }
+}
+
+/// A content-defined chunker. Chunkers are fed a stream of data piece by piece and report the
+/// positions where chunks end.
+pub trait Chunker {
+    /// Scan `data`, which continues the data passed in earlier calls. Returns the offsets (from
+    /// the beginning of the stream) of chunk borders found, i.e. the end of each chunk. The end
+    /// of the stream is not reported as a border.
+    fn scan(&mut self, data: &[u8]) -> Vec<usize>;
+
+    /// Forget all state, to start chunking a new stream.
+    fn reset(&mut self);
+
+    /// Read `r` to the end and yield its chunks as they are found, so that they can be hashed
+    /// and uploaded while reading continues. Only one chunk and a read buffer are held in
+    /// memory. The stream ends after the first error.
+    fn stream<R: AsyncRead + Unpin>(mut self, r: R) -> impl Stream<Item = Result<Chunk>>
+    where
+        Self: Sized,
+    {
+        self.reset();
+        let state = StreamState {
+            chunker: self,
+            r,
+            buf: vec![0; READ_SIZE],
+            pending: vec![],
+            start: 0,
+            ready: VecDeque::new(),
+            eof: false,
+        };
+        futures_util::stream::unfold(state, |mut s| async move {
+            loop {
+                if let Some(c) = s.ready.pop_front() {
+                    return Some((Ok(c), s));
+                }
+                if s.eof {
+                    if s.pending.is_empty() {
+                        return None;
+                    }
+                    let data = std::mem::take(&mut s.pending);
+                    return Some((Ok(Chunk::new(s.start as u64, data)), s));
+                }
+                match s.r.read(&mut s.buf).await {
+                    Ok(0) => s.eof = true,
+                    Ok(n) => {
+                        let borders = s.chunker.scan(&s.buf[..n]);
+                        s.pending.extend_from_slice(&s.buf[..n]);
+                        for b in borders {
+                            let data = s.pending.drain(..b - s.start).collect();
+                            s.ready.push_back(Chunk::new(s.start as u64, data));
+                            s.start = b;
+                        }
+                    }
+                    Err(e) => {
+                        s.eof = true;
+                        s.pending.clear();
+                        return Some((Err(e.into()), s));
+                    }
+                }
+            }
+        })
+    }
+}
+
+impl<C: Chunker + ?Sized> Chunker for &mut C {
+    fn scan(&mut self, data: &[u8]) -> Vec<usize> {
+        (**self).scan(data)
+    }
+
+    fn reset(&mut self) {
+        (**self).reset()
+    }
+}
+
+impl<C: Chunker + ?Sized> Chunker for Box<C> {
+    fn scan(&mut self, data: &[u8]) -> Vec<usize> {
+        (**self).scan(data)
+    }
+
+    fn reset(&mut self) {
+        (**self).reset()
+    }
+}
+
+/// The chunking algorithm used by a `ChunkingConfig`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChunkingAlgorithm {
+    /// `CrcChunker`. Only `window_size` and `avg` are used.
+    Crc,
+    /// `FastCdc`. `window_size` is not used.
+    FastCdc,
+}
+
+/// Parameters for content-defined chunking. Chunks produced with different configurations don't
+/// deduplicate against each other, so store the configuration along with chunk lists.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkingConfig {
+    pub algorithm: ChunkingAlgorithm,
+    /// Size of the rolling hash window in bytes.
+    pub window_size: usize,
+    /// Minimum, target (average) and maximum chunk size in bytes. The target size must be a
+    /// power of two.
+    pub min: usize,
+    pub avg: usize,
+    pub max: usize,
+}
+
+impl ChunkingConfig {
+    /// Small chunks (2 KiB - 8 KiB - 32 KiB) for many small, frequently edited files such as
+    /// documents and source code: good deduplication, but many chunks.
+    pub fn small_files() -> ChunkingConfig {
+        ChunkingConfig::fastcdc(2 << 10, 8 << 10, 32 << 10)
+    }
+
+    /// Large chunks (256 KiB - 1 MiB - 4 MiB) for big, mostly immutable files such as photos and
+    /// videos, where little deduplication is expected and per-chunk overhead dominates.
+    pub fn large_media() -> ChunkingConfig {
+        ChunkingConfig::fastcdc(256 << 10, 1 << 20, 4 << 20)
+    }
+
+    /// A compromise (16 KiB - 64 KiB - 256 KiB) for backups of mixed data. This is the default.
+    pub fn backup() -> ChunkingConfig {
+        ChunkingConfig::fastcdc(16 << 10, 64 << 10, 256 << 10)
+    }
+
+    fn fastcdc(min: usize, avg: usize, max: usize) -> ChunkingConfig {
+        ChunkingConfig {
+            algorithm: ChunkingAlgorithm::FastCdc,
+            window_size: 64,
+            min,
+            avg,
+            max,
+        }
+    }
+
+    /// Number of mask bits corresponding to the target size.
+    pub fn mask_bits(&self) -> usize {
+        self.avg.trailing_zeros() as usize
+    }
+
+    /// Check that the parameters are usable.
+    pub fn validate(&self) -> Result<()> {
+        if !self.avg.is_power_of_two() || !(4..=1 << 30).contains(&self.avg) {
+            return Err(anyhow::Error::msg(format!(
+                "chunking: target size {} is not a power of two between 4 and 1 GiB",
+                self.avg
+            )));
+        }
+        match self.algorithm {
+            ChunkingAlgorithm::Crc if self.window_size == 0 => {
+                Err(anyhow::Error::msg("chunking: window size must not be 0"))
+            }
+            // `FastCdc` masks use two bits fewer than the target size.
+            ChunkingAlgorithm::FastCdc if self.avg < 8 => Err(anyhow::Error::msg(format!(
+                "chunking: target size {} is below the FastCDC minimum of 8",
+                self.avg
+            ))),
+            ChunkingAlgorithm::FastCdc
+                if !(0 < self.min && self.min <= self.avg && self.avg <= self.max) =>
+            {
+                Err(anyhow::Error::msg(format!(
+                    "chunking: need 0 < min <= avg <= max, got {} / {} / {}",
+                    self.min, self.avg, self.max
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Build a chunker with this configuration.
+    pub fn chunker(&self) -> Result<Box<dyn Chunker + Send>> {
+        self.validate()?;
+        Ok(match self.algorithm {
+            ChunkingAlgorithm::Crc => Box::new(CrcChunker::new(self.window_size, self.mask_bits())),
+            ChunkingAlgorithm::FastCdc => Box::new(FastCdc::new(self.min, self.avg, self.max)),
+        })
+    }
+}
+
+impl Default for ChunkingConfig {
+    fn default() -> ChunkingConfig {
+        ChunkingConfig::backup()
+    }
+}
+
+struct StreamState<C, R> {
+    chunker: C,
+    r: R,
+    buf: Vec<u8>,
+    /// Data read since the last border.
+    pending: Vec<u8>,
+    /// Offset of `pending`.
+    start: usize,
+    ready: VecDeque<Chunk>,
+    eof: bool,
+}
+
+/// Chunker using a rolling CRC over a window of `window_size` bytes. A border is placed wherever
+/// the lowest `zerobits` bits of the CRC are zero, resulting in an average chunk size of
+/// `2^zerobits` bytes, but without lower or upper bound.
+pub struct CrcChunker {
+    window_size: usize,
+    mask: u32,
+    window: Vec<u8>,
+    rdc: Option<RollingDualCrc>,
+    offset: usize,
+}
+
+impl CrcChunker {
+    pub fn new(window_size: usize, zerobits: usize) -> CrcChunker {
+        assert!(zerobits <= 32);
+        CrcChunker {
+            window_size,
+            mask: 0xffffffff >> (32 - zerobits),
+            window: Vec::with_capacity(window_size),
+            rdc: None,
+            offset: 0,
+        }
+    }
+}
+
+impl Chunker for CrcChunker {
+    fn scan(&mut self, mut data: &[u8]) -> Vec<usize> {
+        let mut borders = vec![];
+        if self.rdc.is_none() {
+            let n = usize::min(self.window_size - self.window.len(), data.len());
+            self.window.extend_from_slice(&data[..n]);
+            self.offset += n;
+            data = &data[n..];
+            if self.window.len() < self.window_size {
+                return borders;
+            }
+            self.rdc = Some(RollingDualCrc::new(&self.window));
+        }
+        let rdc = self.rdc.as_mut().unwrap();
+        for b in data {
+            if rdc.get32() & self.mask == 0 {
+                borders.push(self.offset);
+            }
+            rdc.roll(*b);
+            self.offset += 1;
+        }
+        borders
+    }
+
+    fn reset(&mut self) {
+        self.window.clear();
+        self.rdc = None;
+        self.offset = 0;
+    }
+}
+
+/// Random values for the gear hash, generated with SplitMix64.
+const GEAR: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut x: u64 = 0;
+    let mut i = 0;
+    while i < 256 {
+        x = x.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = x;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+/// FastCDC chunker (Xia et al., "FastCDC: a Fast and Efficient Content-Defined Chunking
+/// Approach for Data Deduplication", USENIX ATC 2016), using a gear hash with normalized
+/// chunking: below the average size, a stricter mask makes borders less likely, above it a
+/// looser one makes them more likely. Chunks are never smaller than `min` (except at the end of
+/// the stream) or larger than `max`.
+///
+/// Compared to `CrcChunker`, chunk sizes are distributed more tightly around the average, and
+/// the first `min` bytes of each chunk aren't hashed at all.
+pub struct FastCdc {
+    min: usize,
+    avg: usize,
+    max: usize,
+    mask_s: u64,
+    mask_l: u64,
+    fp: u64,
+    len: usize,
+    offset: usize,
+}
+
+impl FastCdc {
+    /// Minimum, average and maximum chunk size in bytes. `avg` is rounded down to a power of
+    /// two, which must be at least 8.
+    pub fn new(min: usize, avg: usize, max: usize) -> FastCdc {
+        assert!(0 < min && min <= avg && avg <= max);
+        let bits = usize::BITS - 1 - avg.leading_zeros();
+        assert!((3..=62).contains(&bits));
+        // Masks use the top bits of the fingerprint, which depend on the last 64 bytes.
+        let mask = |bits: u32| !0u64 << (64 - bits);
+        FastCdc {
+            min,
+            avg,
+            max,
+            mask_s: mask(bits + 2),
+            mask_l: mask(bits - 2),
+            fp: 0,
+            len: 0,
+            offset: 0,
+        }
+    }
+}
+
+impl Default for FastCdc {
+    /// 16 KiB minimum, 64 KiB average and 256 KiB maximum chunk size.
+    fn default() -> FastCdc {
+        FastCdc::new(16 << 10, 64 << 10, 256 << 10)
+    }
+}
 
-use tokio::io::{AsyncBufRead, AsyncReadExt};
+impl Chunker for FastCdc {
+    fn scan(&mut self, data: &[u8]) -> Vec<usize> {
+        let mut borders = vec![];
+        let mut i = 0;
+        while i < data.len() {
+            // Skip the minimum chunk size without hashing.
+            if self.len < self.min {
+                let n = usize::min(self.min - self.len, data.len() - i);
+                self.len += n;
+                i += n;
+                continue;
+            }
+            self.fp = (self.fp << 1).wrapping_add(GEAR[data[i] as usize]);
+            self.len += 1;
+            i += 1;
+            let mask = if self.len <= self.avg {
+                self.mask_s
+            } else {
+                self.mask_l
+            };
+            if self.fp & mask == 0 || self.len >= self.max {
+                self.offset += self.len;
+                borders.push(self.offset);
+                self.len = 0;
+                self.fp = 0;
+            }
+        }
+        borders
+    }
 
-#[allow(unused)]
+    fn reset(&mut self) {
+        self.fp = 0;
+        self.len = 0;
+        self.offset = 0;
+    }
+}
+
+/// Find chunk borders in `r` using a rolling CRC, see `CrcChunker`.
 pub async fn find_borders<R: AsyncBufRead + Unpin>(
     r: &mut R,
     window_size: usize,
@@ -46,9 +435,146 @@ mod tests {
 
     #[tokio::test]
     async fn test_find_borders() {
-        println!(
-            "Borders: {:?}",
-            find_borders_of_file("OAuth2-ServerFlow_NativeLocalhostFlow_v1_2a.pdf").await
+        let file = "OAuth2-ServerFlow_NativeLocalhostFlow_v1_2a.pdf";
+        let len = tokio::fs::metadata(file).await.unwrap().len() as usize;
+        let borders = find_borders_of_file(file).await.unwrap();
+        // About one border per KiB (10 zero bits), in increasing order, after the first window.
+        assert!(borders.len() > len / 4096 && borders.len() < len / 256);
+        assert!(borders.windows(2).all(|w| w[0] < w[1]));
+        assert!(borders[0] >= 32 && *borders.last().unwrap() < len);
+    }
+
+    fn scan_in_pieces<C: Chunker>(c: &mut C, data: &[u8], piece: usize) -> Vec<usize> {
+        c.reset();
+        data.chunks(piece).flat_map(|p| c.scan(p)).collect()
+    }
+
+    #[tokio::test]
+    async fn test_crc_chunker() {
+        let file = "OAuth2-ServerFlow_NativeLocalhostFlow_v1_2a.pdf";
+        let data = tokio::fs::read(file).await.unwrap();
+        let expected = find_borders_of_file(file).await.unwrap();
+        let mut c = CrcChunker::new(32, 10);
+        assert!(!expected.is_empty());
+        for piece in [1, 7, 4096, data.len()] {
+            assert_eq!(expected, scan_in_pieces(&mut c, &data, piece));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fastcdc() {
+        let data = tokio::fs::read("HiDrive_Synchronization-v3.3-rev28.pdf")
+            .await
+            .unwrap();
+        let (min, avg, max) = (1024, 4096, 16384);
+        let mut c = FastCdc::new(min, avg, max);
+        let borders = scan_in_pieces(&mut c, &data, data.len());
+        assert!(borders.len() > 1);
+        let mut last = 0;
+        for b in borders.iter() {
+            assert!(b - last >= min && b - last <= max);
+            last = *b;
+        }
+        assert!(data.len() - last <= max);
+
+        // Independent of how the data is split up.
+        for piece in [1, 100, 5000] {
+            assert_eq!(borders, scan_in_pieces(&mut c, &data, piece));
+        }
+
+        // An insertion only shifts borders after it.
+        let mut modified = data.clone();
+        modified.splice(50000..50000, b"inserted".iter().cloned());
+        let shifted = scan_in_pieces(&mut c, &modified, modified.len());
+        let after: Vec<usize> = borders
+            .iter()
+            .filter(|b| **b > 50000 + max)
+            .cloned()
+            .collect();
+        let common = after.iter().filter(|b| shifted.contains(&(*b + 8))).count();
+        assert!(common * 10 >= after.len() * 9);
+        assert_eq!(
+            borders.iter().filter(|b| **b < 50000).collect::<Vec<_>>(),
+            shifted.iter().filter(|b| **b < 50000).collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stream() {
+        use futures_util::TryStreamExt;
+
+        let data = tokio::fs::read("HiDrive_Synchronization-v3.3-rev28.pdf")
+            .await
+            .unwrap();
+        let mut c = FastCdc::new(1024, 4096, 16384);
+        let borders = scan_in_pieces(&mut c, &data, data.len());
+        let chunks: Vec<Chunk> = c.stream(&data[..]).try_collect().await.unwrap();
+        assert_eq!(borders.len() + 1, chunks.len());
+        let mut joined = vec![];
+        for (i, ch) in chunks.iter().enumerate() {
+            assert_eq!(joined.len() as u64, ch.offset);
+            assert_eq!(chunk_hash(&ch.data), ch.hash);
+            joined.extend_from_slice(&ch.data);
+            if i < borders.len() {
+                assert_eq!(borders[i], joined.len());
+            }
+        }
+        assert_eq!(data, joined);
+
+        let mut c = FastCdc::default();
+        let empty: Vec<Chunk> = (&mut c).stream(&b""[..]).try_collect().await.unwrap();
+        assert!(empty.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_chunking_config() {
+        for cfg in [
+            ChunkingConfig::small_files(),
+            ChunkingConfig::large_media(),
+            ChunkingConfig::backup(),
+        ] {
+            cfg.validate().unwrap();
+        }
+        assert_eq!(16, ChunkingConfig::backup().mask_bits());
+
+        let data = tokio::fs::read("OAuth2-ServerFlow_NativeLocalhostFlow_v1_2a.pdf")
+            .await
+            .unwrap();
+        let cfg = ChunkingConfig {
+            algorithm: ChunkingAlgorithm::Crc,
+            window_size: 32,
+            min: 0,
+            avg: 1024,
+            max: 0,
+        };
+        let mut c = cfg.chunker().unwrap();
+        assert_eq!(
+            scan_in_pieces(&mut CrcChunker::new(32, 10), &data, 4096),
+            scan_in_pieces(&mut c, &data, 4096)
+        );
+
+        let cfg = ChunkingConfig {
+            avg: 1000,
+            ..ChunkingConfig::backup()
+        };
+        assert!(cfg.chunker().is_err());
+        let cfg = ChunkingConfig {
+            max: 1024,
+            ..ChunkingConfig::backup()
+        };
+        assert!(cfg.validate().is_err());
+        let cfg = ChunkingConfig::fastcdc(1, 4, 16);
+        assert!(cfg.chunker().is_err());
+        ChunkingConfig::fastcdc(1, 8, 16).chunker().unwrap();
+    }
+
+    #[test]
+    fn test_fastcdc_max() {
+        // Zeros never produce a border by content, only by maximum size.
+        let mut c = FastCdc::new(64, 256, 1024);
+        assert_eq!(
+            vec![1024, 2048, 3072],
+            scan_in_pieces(&mut c, &[0u8; 3500], 3500)
         );
     }
 }
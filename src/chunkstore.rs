@@ -0,0 +1,269 @@
+//! Content-addressed storage of chunks (see `chunking`), for deduplication across files and
+//! backup sets: a chunk that is already stored doesn't need to be stored (or uploaded) again.
+
+pub use crate::chunking::chunk_hash;
+use crate::chunking::{Chunk, Chunker};
+use crate::hashing::Hash;
+use crate::hidrive::{HiDrive, NO_PARAMS};
+use crate::types::{error_status, Identifier};
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{self, Context, Result};
+use futures_util::StreamExt;
+use log::info;
+use tokio::fs;
+use tokio::io::AsyncRead;
+
+/// Storage for chunks, addressed by their `chunk_hash()`.
+#[async_trait::async_trait]
+pub trait ChunkStore: Send + Sync {
+    async fn contains(&self, hash: &Hash) -> Result<bool>;
+    /// Store `data`, whose `chunk_hash()` is `hash`. Storing a chunk that already exists is not
+    /// an error.
+    async fn put(&self, hash: &Hash, data: &[u8]) -> Result<()>;
+    async fn get(&self, hash: &Hash) -> Result<Option<Vec<u8>>>;
+}
+
+/// A chunk store in a local directory, with one file per chunk in subdirectories named after
+/// the first two hex digits of the hash.
+pub struct DirChunkStore {
+    dir: PathBuf,
+}
+
+impl DirChunkStore {
+    /// Use (and create if needed) the directory `dir`.
+    pub async fn new(dir: impl AsRef<Path>) -> Result<DirChunkStore> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)
+            .await
+            .with_context(|| format!("creating chunk store {:?}", dir))?;
+        Ok(DirChunkStore { dir })
+    }
+
+    fn path(&self, hash: &Hash) -> PathBuf {
+        let h = hash.to_string();
+        self.dir.join(&h[..2]).join(&h[2..])
+    }
+}
+
+#[async_trait::async_trait]
+impl ChunkStore for DirChunkStore {
+    async fn contains(&self, hash: &Hash) -> Result<bool> {
+        Ok(fs::try_exists(self.path(hash)).await?)
+    }
+
+    async fn put(&self, hash: &Hash, data: &[u8]) -> Result<()> {
+        let path = self.path(hash);
+        if fs::try_exists(&path).await? {
+            return Ok(());
+        }
+        fs::create_dir_all(path.parent().unwrap()).await?;
+        // Write to a temporary file first, so that a chunk is never visible half-written.
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, data)
+            .await
+            .with_context(|| format!("writing chunk {:?}", tmp))?;
+        fs::rename(&tmp, &path).await?;
+        Ok(())
+    }
+
+    async fn get(&self, hash: &Hash) -> Result<Option<Vec<u8>>> {
+        let data = match fs::read(self.path(hash)).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        if chunk_hash(&data) != *hash {
+            return Err(anyhow::Error::msg(format!(
+                "chunk store: chunk {} is corrupted",
+                hash
+            )));
+        }
+        Ok(Some(data))
+    }
+}
+
+/// A chunk store in a HiDrive directory, laid out like a `DirChunkStore`.
+pub struct HiDriveChunkStore {
+    hd: HiDrive,
+    dir: String,
+}
+
+impl HiDriveChunkStore {
+    /// Use the existing remote directory `dir`. Calls are made on forks of `hd`.
+    pub fn new(hd: &HiDrive, dir: impl Into<String>) -> HiDriveChunkStore {
+        HiDriveChunkStore {
+            hd: hd.fork(),
+            dir: dir.into(),
+        }
+    }
+
+    /// The directory and file name of a chunk.
+    fn path(&self, hash: &Hash) -> (String, String) {
+        let h = hash.to_string();
+        (format!("{}/{}", self.dir, &h[..2]), h[2..].to_string())
+    }
+}
+
+#[async_trait::async_trait]
+impl ChunkStore for HiDriveChunkStore {
+    async fn contains(&self, hash: &Hash) -> Result<bool> {
+        let (dir, name) = self.path(hash);
+        let id = Identifier::Path(format!("{}/{}", dir, name));
+        match self.hd.fork().files().metadata(id, "path", NO_PARAMS).await {
+            Ok(_) => Ok(true),
+            Err(e) if error_status(&e) == Some(404) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn put(&self, hash: &Hash, data: &[u8]) -> Result<()> {
+        let (dir, name) = self.path(hash);
+        let mut hd = self.hd.fork();
+        let mut files = hd.files();
+        let id = Identifier::Path(dir.clone());
+        match files
+            .upload(id.clone(), &name, data.to_vec(), NO_PARAMS)
+            .await
+        {
+            Err(e) if error_status(&e) == Some(404) => {
+                // The first chunk in this subdirectory.
+                if let Err(e) = files.mkdir(id.clone(), NO_PARAMS).await {
+                    if error_status(&e) != Some(409) {
+                        return Err(e.context(format!("creating chunk directory {}", dir)));
+                    }
+                }
+                files.upload(id, &name, data.to_vec(), NO_PARAMS).await?;
+            }
+            r => {
+                r?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn get(&self, hash: &Hash) -> Result<Option<Vec<u8>>> {
+        let (dir, name) = self.path(hash);
+        let id = Identifier::Path(format!("{}/{}", dir, name));
+        let mut data = vec![];
+        match self.hd.fork().files().get(id, &mut data, NO_PARAMS).await {
+            Ok(_) => (),
+            Err(e) if error_status(&e) == Some(404) => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        if chunk_hash(&data) != *hash {
+            return Err(anyhow::Error::msg(format!(
+                "chunk store: chunk {} is corrupted",
+                hash
+            )));
+        }
+        Ok(Some(data))
+    }
+}
+
+/// A chunk of a file, as returned by `put_chunked()`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChunkRef {
+    pub hash: Hash,
+    pub offset: u64,
+    pub len: usize,
+    /// True if the chunk wasn't in the store before.
+    pub new: bool,
+}
+
+async fn put_chunk(store: &dyn ChunkStore, c: Chunk) -> Result<ChunkRef> {
+    let new = !store.contains(&c.hash).await?;
+    if new {
+        store.put(&c.hash, &c.data).await?;
+    }
+    Ok(ChunkRef {
+        hash: c.hash,
+        offset: c.offset,
+        len: c.data.len(),
+        new,
+    })
+}
+
+/// Split the data read from `r` into chunks using `chunker`, and put those not yet present into
+/// `store`. Returns all chunks in order, e.g. to record them in a backup manifest.
+pub async fn put_chunked<C: Chunker, R: AsyncRead + Unpin>(
+    chunker: &mut C,
+    r: R,
+    store: &dyn ChunkStore,
+) -> Result<Vec<ChunkRef>> {
+    let mut stream = std::pin::pin!(chunker.stream(r));
+    let mut chunks = vec![];
+    while let Some(c) = stream.next().await {
+        chunks.push(put_chunk(store, c?).await?);
+    }
+    info!(
+        target: "hd_api::chunkstore",
+        "put_chunked: {} chunks, {} new",
+        chunks.len(),
+        chunks.iter().filter(|c| c.new).count()
+    );
+    Ok(chunks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunking::FastCdc;
+
+    fn data(len: usize) -> Vec<u8> {
+        let mut x: u32 = 4711;
+        (0..len)
+            .map(|_| {
+                x = x.wrapping_mul(1103515245).wrapping_add(12345);
+                (x >> 16) as u8
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_dir_chunk_store() {
+        let dir = std::env::temp_dir().join("hd_api_test_dir_chunk_store");
+        let _ = std::fs::remove_dir_all(&dir);
+        let store = DirChunkStore::new(&dir).await.unwrap();
+        let h = chunk_hash(b"chunk");
+        assert!(!store.contains(&h).await.unwrap());
+        assert_eq!(None, store.get(&h).await.unwrap());
+        store.put(&h, b"chunk").await.unwrap();
+        store.put(&h, b"chunk").await.unwrap();
+        assert!(store.contains(&h).await.unwrap());
+        assert_eq!(Some(b"chunk".to_vec()), store.get(&h).await.unwrap());
+
+        std::fs::write(store.path(&h), b"garbage").unwrap();
+        assert!(store.get(&h).await.is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_put_chunked() {
+        let dir = std::env::temp_dir().join("hd_api_test_put_chunked");
+        let _ = std::fs::remove_dir_all(&dir);
+        let store = DirChunkStore::new(&dir).await.unwrap();
+        let mut c = FastCdc::new(1024, 4096, 16384);
+        let mut d = data(200_000);
+
+        let first = put_chunked(&mut c, &d[..], &store).await.unwrap();
+        assert!(first.len() > 10);
+        assert!(first.iter().all(|c| c.new));
+        assert_eq!(d.len(), first.iter().map(|c| c.len).sum::<usize>());
+        let mut joined = vec![];
+        for c in first.iter() {
+            assert_eq!(joined.len() as u64, c.offset);
+            joined.extend(store.get(&c.hash).await.unwrap().unwrap());
+        }
+        assert_eq!(d, joined);
+
+        // Unchanged data is deduplicated completely, changed data mostly.
+        let again = put_chunked(&mut c, &d[..], &store).await.unwrap();
+        assert!(again.iter().all(|c| !c.new));
+        d[100_000] ^= 0xff;
+        let changed = put_chunked(&mut c, &d[..], &store).await.unwrap();
+        assert!(changed.iter().filter(|c| c.new).count() <= 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,209 @@
+//! Cleaning up conflict copies, see `cleanup_conflicts()`.
+//!
+//! Conflict copies are created by the server when a file is copied or moved with
+//! `OnExist::Autoname`, as the sync functions do with `MirrorOptions::conflict_copies`. They are
+//! named like the original with a number appended, e.g. `file (2).txt`.
+
+use crate::hidrive::HiDrive;
+use crate::ignore::IgnoreRules;
+use crate::sync::{remote_tree, tree_entries, RemoteRemover};
+use crate::types::Identifier;
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+use anyhow::Result;
+use log::info;
+use time::OffsetDateTime;
+
+#[derive(Debug, Clone)]
+pub struct ConflictOptions {
+    /// Younger copies are left alone. Default: 30 days.
+    pub min_age: Duration,
+    /// Move copies into this remote directory (an absolute path outside of the cleaned up
+    /// directory), keeping their relative paths. If `None` (the default), copies are only
+    /// reported.
+    pub trash: Option<String>,
+}
+
+impl Default for ConflictOptions {
+    fn default() -> ConflictOptions {
+        ConflictOptions {
+            min_age: Duration::from_secs(30 * 86400),
+            trash: None,
+        }
+    }
+}
+
+/// A conflict copy found by `cleanup_conflicts()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConflictCopy {
+    /// Path relative to the cleaned up directory.
+    pub path: String,
+    /// The file it is a copy of, relative to the cleaned up directory.
+    pub original: String,
+    pub size: u64,
+    /// Creation time, or the mtime if the server didn't provide it.
+    pub time: Option<OffsetDateTime>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ConflictReport {
+    /// Copies older than `min_age`, sorted by name within each directory.
+    pub copies: Vec<ConflictCopy>,
+    /// Copies moved to the trash.
+    pub trashed: Vec<String>,
+    /// Copies that couldn't be moved, with the error.
+    pub failed: Vec<(String, String)>,
+}
+
+/// The name of the file `name` is a conflict copy of, if it looks like one: `a (1).txt` is a
+/// copy of `a.txt`, `a (2)` of `a`.
+pub fn conflict_original(name: &str) -> Option<String> {
+    let numbered = |stem: &str| {
+        let (base, n) = stem.strip_suffix(')')?.rsplit_once(" (")?;
+        let valid = !base.is_empty()
+            && !n.is_empty()
+            && !n.starts_with('0')
+            && n.bytes().all(|b| b.is_ascii_digit());
+        valid.then_some(base.len())
+    };
+    match name.rsplit_once('.') {
+        Some((s, _)) if !s.is_empty() => {
+            if let Some(l) = numbered(s) {
+                return Some(format!("{}{}", &name[..l], &name[s.len()..]));
+            }
+        }
+        _ => {}
+    }
+    numbered(name).map(|l| name[..l].to_string())
+}
+
+/// Find conflict copies below the remote directory `root` that are older than `opts.min_age`,
+/// and move them to `opts.trash` if set. Only files next to their original are considered.
+pub async fn cleanup_conflicts(
+    hd: &mut HiDrive,
+    root: Identifier,
+    opts: &ConflictOptions,
+) -> Result<ConflictReport> {
+    let tree = remote_tree(hd, root, &IgnoreRules::new()).await?;
+    let entries = tree_entries(&tree);
+    let files: HashSet<&str> = entries
+        .iter()
+        .filter(|(_, it)| it.typ.as_deref() != Some("dir"))
+        .map(|(rel, _)| rel.as_str())
+        .collect();
+    let cutoff = OffsetDateTime::now_utc() - opts.min_age;
+    let mut report = ConflictReport::default();
+    for (rel, it) in entries.iter() {
+        if !files.contains(rel.as_str()) {
+            continue;
+        }
+        let (parent, name) = rel.rsplit_once('/').unwrap_or(("", rel));
+        let original = match conflict_original(name) {
+            Some(o) if parent.is_empty() => o,
+            Some(o) => format!("{}/{}", parent, o),
+            None => continue,
+        };
+        let time = it.ctime.or(it.mtime);
+        if !files.contains(original.as_str()) || time.is_some_and(|t| t > cutoff) {
+            continue;
+        }
+        report.copies.push(ConflictCopy {
+            path: rel.clone(),
+            original,
+            size: it.size.unwrap_or(0) as u64,
+            time,
+        });
+    }
+    if let Some(trash) = opts.trash.as_deref() {
+        let mut remover = RemoteRemover::new(&tree.path, Some(trash));
+        for c in report.copies.iter() {
+            match remover.remove(hd, &c.path, false).await {
+                Ok(()) => report.trashed.push(c.path.clone()),
+                Err(e) => report.failed.push((c.path.clone(), format!("{:#}", e))),
+            }
+        }
+    }
+    info!(
+        target: "hd_api::conflicts",
+        "cleanup_conflicts: {}: {} copies, {} trashed, {} failed",
+        tree.path,
+        report.copies.len(),
+        report.trashed.len(),
+        report.failed.len()
+    );
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::MockHiDrive;
+
+    #[test]
+    fn test_conflict_original() {
+        assert_eq!(Some("a.txt".into()), conflict_original("a (1).txt"));
+        assert_eq!(
+            Some("a b.tar.gz".into()),
+            conflict_original("a b.tar (12).gz")
+        );
+        assert_eq!(Some("notes".into()), conflict_original("notes (2)"));
+        assert_eq!(Some(".bashrc".into()), conflict_original(".bashrc (3)"));
+        assert_eq!(Some("v1.2".into()), conflict_original("v1.2 (3)"));
+        for name in [
+            "a.txt",
+            "a (0).txt",
+            "a (x).txt",
+            "a ().txt",
+            " (2).txt",
+            "a(2).txt",
+        ] {
+            assert_eq!(None, conflict_original(name), "{}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_conflicts() {
+        let (fake, mut hd) = MockHiDrive::start().await;
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let old = now - 40 * 86400;
+        fake.put("data/a.txt", "new", now).await;
+        fake.put("data/a (1).txt", "old", old).await;
+        fake.put("data/a (2).txt", "recent", now).await;
+        fake.put("data/sub/b (1)", "old", old).await;
+        fake.put("data/sub/b", "new", now).await;
+        fake.put("data/orphan (1).txt", "old", old).await;
+        let root = || Identifier::Path("/m/data".into());
+
+        let r = cleanup_conflicts(&mut hd, root(), &ConflictOptions::default())
+            .await
+            .unwrap();
+        let copies: Vec<(&str, &str)> = r
+            .copies
+            .iter()
+            .map(|c| (c.path.as_str(), c.original.as_str()))
+            .collect();
+        assert_eq!(vec![("a (1).txt", "a.txt"), ("sub/b (1)", "sub/b")], copies);
+        assert!(r.trashed.is_empty());
+        assert_eq!(6, fake.files().await.len());
+
+        hd.files()
+            .mkdir(Identifier::Path("/m/trash".into()), None)
+            .await
+            .unwrap();
+        let opts = ConflictOptions {
+            trash: Some("/m/trash".into()),
+            ..Default::default()
+        };
+        let r = cleanup_conflicts(&mut hd, root(), &opts).await.unwrap();
+        assert_eq!(vec!["a (1).txt", "sub/b (1)"], r.trashed);
+        assert!(r.failed.is_empty());
+        let files = fake.files().await;
+        assert!(files.contains(&"trash/a (1).txt".to_string()));
+        assert!(files.contains(&"trash/sub/b (1)".to_string()));
+        assert!(!files.contains(&"data/a (1).txt".to_string()));
+        assert!(files.contains(&"data/a (2).txt".to_string()));
+        assert!(files.contains(&"data/orphan (1).txt".to_string()));
+    }
+}
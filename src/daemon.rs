@@ -0,0 +1,253 @@
+//! Running long-lived work, such as sync jobs or a transfer queue, as a service, e.g. under
+//! systemd. See `run_daemon()`.
+
+use std::future::Future;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{self, Context, Result};
+use log::{info, warn};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+/// Options of `run_daemon()`.
+#[derive(Debug, Clone)]
+pub struct DaemonOptions {
+    /// How long to wait for the work to finish after `Shutdown::stop` before triggering
+    /// `Shutdown::abort`. Default: 30 seconds.
+    pub shutdown_timeout: Duration,
+    /// The socket to send state changes to (see `sd_notify()`). Default: `$NOTIFY_SOCKET`.
+    pub notify_socket: Option<PathBuf>,
+}
+
+impl Default for DaemonOptions {
+    fn default() -> DaemonOptions {
+        DaemonOptions {
+            shutdown_timeout: Duration::from_secs(30),
+            notify_socket: std::env::var_os("NOTIFY_SOCKET").map(PathBuf::from),
+        }
+    }
+}
+
+/// Tells the work run by `run_daemon()` to end.
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown {
+    /// Finish what is in progress, but start nothing new.
+    pub stop: CancellationToken,
+    /// Interrupt what is in progress; triggered after `stop` if the work doesn't end in time.
+    pub abort: CancellationToken,
+}
+
+/// Send `state` (e.g. `READY=1`) to the service manager, see `sd_notify(3)`. Returns false if
+/// not running under a service manager supporting it (`$NOTIFY_SOCKET` isn't set).
+pub fn sd_notify(state: &str) -> Result<bool> {
+    match std::env::var_os("NOTIFY_SOCKET") {
+        Some(path) => send_state(Path::new(&path), state).map(|_| true),
+        None => Ok(false),
+    }
+}
+
+fn send_state(path: &Path, state: &str) -> Result<()> {
+    let sock = UnixDatagram::unbound()?;
+    let name = path.to_string_lossy();
+    if let Some(abstract_name) = name.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(abstract_name)?;
+            sock.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        anyhow::bail!("sd_notify: abstract socket {} not supported", abstract_name);
+    } else {
+        sock.send_to(state.as_bytes(), path)
+            .with_context(|| format!("sd_notify: sending to {:?}", path))?;
+    }
+    Ok(())
+}
+
+fn notify(opts: &DaemonOptions, state: &str) {
+    if let Some(ref path) = opts.notify_socket {
+        if let Err(e) = send_state(path, state) {
+            warn!(target: "hd_api::daemon", "{:#}", e);
+        }
+    }
+}
+
+/// What the service manager asked for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Event {
+    Reload,
+    Stop,
+}
+
+/// Run `work` with the configuration returned by `load`, as a service:
+///
+/// * Once `work` has been started, readiness is signaled to the service manager (see
+///   `sd_notify()` and `DaemonOptions::notify_socket`).
+/// * On `SIGHUP`, `work` is shut down (see `Shutdown`), the configuration loaded again, and
+///   `work` started again. If loading fails, the previous configuration is kept.
+/// * On `SIGTERM` or `SIGINT`, `work` is shut down and `run_daemon()` returns.
+///
+/// Returns early if `work` does, with its result.
+///
+/// ```ignore
+/// run_daemon(
+///     || Config::load("/etc/hd/config.json"),
+///     |cfg, shutdown| async move {
+///         let q = TransferQueue::open(&cfg.queue).await?;
+///         q.run_with_shutdown(&cfg.hidrive()?, 4, shutdown.stop, shutdown.abort).await
+///     },
+///     &DaemonOptions::default(),
+/// ).await?;
+/// ```
+pub async fn run_daemon<C, L, LF, W, WF>(load: L, work: W, opts: &DaemonOptions) -> Result<()>
+where
+    C: Clone,
+    L: FnMut() -> LF,
+    LF: Future<Output = Result<C>>,
+    W: FnMut(C, Shutdown) -> WF,
+    WF: Future<Output = Result<()>>,
+{
+    let mut hangup = signal(SignalKind::hangup()).context("daemon: installing signal handler")?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let (tx, rx) = mpsc::channel(1);
+    let signals = tokio::spawn(async move {
+        loop {
+            let event = tokio::select! {
+                _ = hangup.recv() => Event::Reload,
+                _ = terminate.recv() => Event::Stop,
+                _ = interrupt.recv() => Event::Stop,
+            };
+            if tx.send(event).await.is_err() {
+                return;
+            }
+        }
+    });
+    let r = run_events(load, work, opts, rx).await;
+    signals.abort();
+    r
+}
+
+/// `run_daemon()`, reacting to `events` instead of signals. A closed channel means `Stop`.
+async fn run_events<C, L, LF, W, WF>(
+    mut load: L,
+    mut work: W,
+    opts: &DaemonOptions,
+    mut events: mpsc::Receiver<Event>,
+) -> Result<()>
+where
+    C: Clone,
+    L: FnMut() -> LF,
+    LF: Future<Output = Result<C>>,
+    W: FnMut(C, Shutdown) -> WF,
+    WF: Future<Output = Result<()>>,
+{
+    let mut config = load().await.context("daemon: loading configuration")?;
+    loop {
+        let shutdown = Shutdown::default();
+        let task = work(config.clone(), shutdown.clone());
+        tokio::pin!(task);
+        notify(opts, "READY=1");
+        let reload = tokio::select! {
+            r = &mut task => return r,
+            event = events.recv() => event == Some(Event::Reload),
+        };
+        info!(target: "hd_api::daemon", "{}", if reload { "reloading" } else { "shutting down" });
+        notify(opts, if reload { "RELOADING=1" } else { "STOPPING=1" });
+        shutdown.stop.cancel();
+        let r = match tokio::time::timeout(opts.shutdown_timeout, &mut task).await {
+            Ok(r) => r,
+            Err(_) => {
+                warn!(target: "hd_api::daemon", "shutdown timed out, aborting");
+                shutdown.abort.cancel();
+                task.await
+            }
+        };
+        if !reload || r.is_err() {
+            return r;
+        }
+        match load().await {
+            Ok(c) => config = c,
+            Err(e) => warn!(
+                target: "hd_api::daemon",
+                "loading configuration failed, keeping the previous one: {:#}",
+                e
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    async fn wait_for(cond: impl Fn() -> bool) {
+        for _ in 0..200 {
+            if cond() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("timed out");
+    }
+
+    #[tokio::test]
+    async fn test_daemon() {
+        let sock_path = std::env::temp_dir().join("hd_api_test_daemon.sock");
+        let _ = std::fs::remove_file(&sock_path);
+        let sock = UnixDatagram::bind(&sock_path).unwrap();
+        sock.set_nonblocking(true).unwrap();
+        let opts = DaemonOptions {
+            notify_socket: Some(sock_path.clone()),
+            ..Default::default()
+        };
+
+        let (tx, rx) = mpsc::channel(1);
+        let loads = Arc::new(AtomicUsize::new(0));
+        let runs = Arc::new(Mutex::new(vec![]));
+        let (l, r) = (loads.clone(), runs.clone());
+        let daemon = tokio::spawn(async move {
+            run_events(
+                move || {
+                    let n = l.fetch_add(1, Ordering::SeqCst) + 1;
+                    async move { Ok(n) }
+                },
+                move |config, shutdown: Shutdown| {
+                    r.lock().unwrap().push(config);
+                    async move {
+                        shutdown.stop.cancelled().await;
+                        Ok(())
+                    }
+                },
+                &opts,
+                rx,
+            )
+            .await
+        });
+
+        wait_for(|| runs.lock().unwrap().len() == 1).await;
+        tx.send(Event::Reload).await.unwrap();
+        wait_for(|| runs.lock().unwrap().len() == 2).await;
+        assert_eq!(vec![1, 2], *runs.lock().unwrap());
+        tx.send(Event::Stop).await.unwrap();
+        daemon.await.unwrap().unwrap();
+
+        let mut states = vec![];
+        let mut buf = [0; 64];
+        while let Ok(n) = sock.recv(&mut buf) {
+            states.push(String::from_utf8_lossy(&buf[..n]).into_owned());
+        }
+        assert_eq!(
+            vec!["READY=1", "RELOADING=1", "READY=1", "STOPPING=1"],
+            states
+        );
+        std::fs::remove_file(&sock_path).unwrap();
+    }
+}
@@ -0,0 +1,400 @@
+//! An OpenDAL service backed by HiDrive, see `HiDriveBackend`.
+//!
+//! Built against OpenDAL 0.47 (the `Access` trait); the raw service API changes between minor
+//! releases, so the dependency is pinned. Paths are relative to a root directory, with
+//! directories ending in `/`. File contents are buffered in memory, both when reading and
+//! writing. OAuth and hashing stay inside `HiDrive`: configure it as usual and hand it over.
+
+use crate::hidrive::HiDrive;
+use crate::types::{error_status, Identifier, Item, Params};
+
+use std::collections::VecDeque;
+use std::fmt;
+
+use chrono::{DateTime, Utc};
+use opendal::raw::{
+    oio, Access, AccessorInfo, OpCreateDir, OpDelete, OpList, OpRead, OpStat, OpWrite, RpCreateDir,
+    RpDelete, RpList, RpRead, RpStat, RpWrite,
+};
+use opendal::{Buffer, Capability, EntryMode, Error, ErrorKind, Metadata, Operator, Result};
+use reqwest::header::{HeaderValue, RANGE};
+
+const META_FIELDS: &str = "path,type,size,mtime,chash";
+const LIST_FIELDS: &str =
+    "path,type,members.path,members.name,members.type,members.size,members.mtime,members.chash";
+
+/// Serves the directory `root` as OpenDAL service, e.g. the path `a/b.txt` as `{root}/a/b.txt`.
+/// Parent directories are created on write. `etag`s are content hashes (`chash`).
+///
+/// ```ignore
+/// let op = HiDriveBackend::new(hd, "/users/me/data").operator();
+/// op.write("a/b.txt", "hello").await?;
+/// ```
+pub struct HiDriveBackend {
+    hd: HiDrive,
+    root: String,
+}
+
+impl HiDriveBackend {
+    pub fn new(hd: HiDrive, root: impl Into<String>) -> HiDriveBackend {
+        let root = root.into();
+        HiDriveBackend {
+            hd,
+            root: root.trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// An `Operator` using this backend.
+    pub fn operator(self) -> Operator {
+        opendal::OperatorBuilder::new(self).finish()
+    }
+
+    /// Each call uses its own fork, as `Access` methods take `&self`.
+    fn hd(&self) -> HiDrive {
+        self.hd.fork()
+    }
+
+    fn remote(&self, path: &str) -> String {
+        let path = path.trim_matches('/');
+        if path.is_empty() && self.root.is_empty() {
+            "/".to_string()
+        } else if path.is_empty() {
+            self.root.clone()
+        } else {
+            format!("{}/{}", self.root, path)
+        }
+    }
+
+    /// The OpenDAL path of the item `it`; directories end in `/`.
+    fn path(&self, it: &Item) -> String {
+        let rel = it.path.strip_prefix(&self.root).unwrap_or(&it.path);
+        let rel = rel.trim_start_matches('/');
+        if is_dir(it) {
+            format!("{}/", rel)
+        } else {
+            rel.to_string()
+        }
+    }
+}
+
+impl fmt::Debug for HiDriveBackend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("HiDriveBackend")
+            .field("root", &self.root)
+            .finish_non_exhaustive()
+    }
+}
+
+fn is_dir(it: &Item) -> bool {
+    it.typ.as_deref() == Some("dir")
+}
+
+fn meta(it: &Item) -> Metadata {
+    if is_dir(it) {
+        return Metadata::new(EntryMode::DIR);
+    }
+    let mut m = Metadata::new(EntryMode::FILE).with_content_length(it.size.unwrap_or(0) as u64);
+    if let Some(t) = it
+        .mtime
+        .and_then(|t| DateTime::<Utc>::from_timestamp(t.unix_timestamp(), t.nanosecond()))
+    {
+        m = m.with_last_modified(t);
+    }
+    if let Some(ref h) = it.chash {
+        m = m.with_etag(h.to_string());
+    }
+    m
+}
+
+fn error(e: anyhow::Error, path: &str) -> Error {
+    let (kind, temporary) = match error_status(&e) {
+        Some(404) => (ErrorKind::NotFound, false),
+        Some(409) => (ErrorKind::AlreadyExists, false),
+        Some(401) | Some(403) => (ErrorKind::PermissionDenied, false),
+        Some(s) => (ErrorKind::Unexpected, s == 429 || s >= 500),
+        None => (ErrorKind::Unexpected, false),
+    };
+    let e = Error::new(kind, &format!("HiDrive: {}", path)).set_source(e);
+    if temporary {
+        e.set_temporary()
+    } else {
+        e
+    }
+}
+
+/// Create the directory `dir` and its parents below `root`. Existing directories fail with 409,
+/// which is ignored.
+async fn mkdirs(hd: &mut HiDrive, root: &str, dir: &str) -> Result<()> {
+    let rel = dir.strip_prefix(root).unwrap_or(dir);
+    let mut path = root.to_string();
+    for component in rel.split('/').filter(|c| !c.is_empty()) {
+        path.push('/');
+        path.push_str(component);
+        match hd.files().mkdir(Identifier::Path(path.clone()), None).await {
+            Ok(_) => {}
+            Err(e) if error_status(&e) == Some(409) => {}
+            Err(e) => return Err(error(e, &path)),
+        }
+    }
+    Ok(())
+}
+
+impl Access for HiDriveBackend {
+    type Reader = HiDriveReader;
+    type Writer = HiDriveWriter;
+    type Lister = HiDriveLister;
+    type BlockingReader = ();
+    type BlockingWriter = ();
+    type BlockingLister = ();
+
+    fn info(&self) -> AccessorInfo {
+        let mut info = AccessorInfo::default();
+        info.set_scheme(opendal::Scheme::Custom("hidrive"))
+            .set_root(&format!("{}/", self.root))
+            .set_native_capability(Capability {
+                stat: true,
+                read: true,
+                write: true,
+                create_dir: true,
+                delete: true,
+                list: true,
+                ..Default::default()
+            });
+        info
+    }
+
+    async fn create_dir(&self, path: &str, _: OpCreateDir) -> Result<RpCreateDir> {
+        mkdirs(&mut self.hd(), &self.root, &self.remote(path)).await?;
+        Ok(RpCreateDir::default())
+    }
+
+    async fn stat(&self, path: &str, _: OpStat) -> Result<RpStat> {
+        let remote = self.remote(path);
+        let it = self
+            .hd()
+            .files()
+            .metadata(Identifier::Path(remote.clone()), META_FIELDS, None)
+            .await
+            .map_err(|e| error(e, &remote))?;
+        Ok(RpStat::new(meta(&it)))
+    }
+
+    async fn read(&self, path: &str, args: OpRead) -> Result<(RpRead, HiDriveReader)> {
+        let remote = self.remote(path);
+        let id = Identifier::Path(remote.clone());
+        let range = match (args.range().offset(), args.range().size()) {
+            (_, Some(0)) => return Ok((RpRead::new(), HiDriveReader(None))),
+            (0, None) => None,
+            (offset, None) => Some(format!("bytes={}-", offset)),
+            (offset, Some(n)) => Some(format!("bytes={}-{}", offset, offset + n - 1)),
+        };
+        let mut hd = self.hd();
+        let mut files = hd.files();
+        if let Some(range) = range {
+            let v = HeaderValue::from_str(&range).map_err(|e| error(e.into(), &remote))?;
+            files = files.with_header(RANGE, v);
+        }
+        let mut data = vec![];
+        files
+            .get(id, &mut data, None)
+            .await
+            .map_err(|e| error(e, &remote))?;
+        Ok((RpRead::new(), HiDriveReader(Some(Buffer::from(data)))))
+    }
+
+    async fn write(&self, path: &str, _: OpWrite) -> Result<(RpWrite, HiDriveWriter)> {
+        let w = HiDriveWriter {
+            hd: self.hd(),
+            root: self.root.clone(),
+            remote: self.remote(path),
+            data: vec![],
+        };
+        Ok((RpWrite::default(), w))
+    }
+
+    async fn delete(&self, path: &str, _: OpDelete) -> Result<RpDelete> {
+        let remote = self.remote(path);
+        let id = Identifier::Path(remote.clone());
+        let mut hd = self.hd();
+        let r = if path.ends_with('/') {
+            let mut p = Params::new();
+            p.add_bool("recursive", true);
+            hd.files().delete_dir(id, Some(&p)).await.map(|_| ())
+        } else {
+            hd.files().delete(id, None).await
+        };
+        match r {
+            // Deleting is idempotent.
+            Err(e) if error_status(&e) != Some(404) => Err(error(e, &remote)),
+            _ => Ok(RpDelete::default()),
+        }
+    }
+
+    async fn list(&self, path: &str, _: OpList) -> Result<(RpList, HiDriveLister)> {
+        let remote = self.remote(path);
+        let mut p = Params::new();
+        p.add_str("members", "all").add_str("fields", LIST_FIELDS);
+        let dir = match self
+            .hd()
+            .files()
+            .get_dir(Identifier::Path(remote.clone()), Some(&p))
+            .await
+        {
+            Ok(dir) => dir,
+            Err(e) if error_status(&e) == Some(404) => Item::default(),
+            Err(e) => return Err(error(e, &remote)),
+        };
+        let entries = dir
+            .members
+            .iter()
+            .map(|it| oio::Entry::new(&self.path(it), meta(it)))
+            .collect();
+        Ok((RpList::default(), HiDriveLister(entries)))
+    }
+}
+
+/// Returns the contents of a file, read in one request.
+pub struct HiDriveReader(Option<Buffer>);
+
+impl oio::Read for HiDriveReader {
+    async fn read(&mut self) -> Result<Buffer> {
+        Ok(self.0.take().unwrap_or_else(Buffer::new))
+    }
+}
+
+/// Collects the contents of a file in memory, and uploads them on `close()`.
+pub struct HiDriveWriter {
+    hd: HiDrive,
+    root: String,
+    remote: String,
+    data: Vec<u8>,
+}
+
+impl oio::Write for HiDriveWriter {
+    async fn write(&mut self, bs: Buffer) -> Result<()> {
+        self.data.extend_from_slice(&bs.to_bytes());
+        Ok(())
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        let (dir, name) = self.remote.rsplit_once('/').unwrap_or(("", &self.remote));
+        let mut created = false;
+        loop {
+            let data = self.data.clone();
+            match self
+                .hd
+                .files()
+                .upload(Identifier::Path(dir.into()), name, data, None)
+                .await
+            {
+                Ok(_) => return Ok(()),
+                Err(e) if !created && error_status(&e) == Some(404) => {
+                    mkdirs(&mut self.hd, &self.root, dir).await?;
+                    created = true;
+                }
+                Err(e) => return Err(error(e, &self.remote)),
+            }
+        }
+    }
+
+    async fn abort(&mut self) -> Result<()> {
+        self.data.clear();
+        Ok(())
+    }
+}
+
+/// The entries of a directory, listed in one request.
+pub struct HiDriveLister(VecDeque<oio::Entry>);
+
+impl oio::List for HiDriveLister {
+    async fn next(&mut self) -> Result<Option<oio::Entry>> {
+        Ok(self.0.pop_front())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::mock::{hidrive, MockTransport};
+
+    use std::sync::Arc;
+
+    use opendal::raw::oio::{List as _, Read as _, Write as _};
+    use opendal::raw::BytesRange;
+
+    fn backend(t: &Arc<MockTransport>) -> HiDriveBackend {
+        HiDriveBackend::new(hidrive(t.clone()), "/root/")
+    }
+
+    #[tokio::test]
+    async fn test_backend() {
+        let t = MockTransport::new();
+        let b = backend(&t);
+
+        // The parent directory is missing at first.
+        t.push(404, r#"{"code": 404, "msg": "Not Found"}"#);
+        t.push(201, r#"{"path": "/root/a", "type": "dir"}"#);
+        t.push(
+            201,
+            r#"{"path": "/root/a/b.txt", "type": "file", "size": 5}"#,
+        );
+        let (_, mut w) = b.write("a/b.txt", OpWrite::new()).await.unwrap();
+        w.write(Buffer::from(b"hel".to_vec())).await.unwrap();
+        w.write(Buffer::from(b"lo".to_vec())).await.unwrap();
+        w.close().await.unwrap();
+        let rq = t.requests();
+        assert_eq!(Some("/root/a"), rq[0].param("dir").as_deref());
+        assert_eq!(Some("b.txt"), rq[0].param("name").as_deref());
+        assert_eq!(Some(b"hello".to_vec()), rq[0].body);
+        assert_eq!(Some("/root/a"), rq[1].param("path").as_deref());
+        assert_eq!(Some(b"hello".to_vec()), rq[2].body);
+
+        t.push(
+            200,
+            r#"{"path": "/root/a/b.txt", "type": "file", "size": 5, "mtime": 1700000000}"#,
+        );
+        let m = b
+            .stat("a/b.txt", OpStat::new())
+            .await
+            .unwrap()
+            .into_metadata();
+        assert_eq!(EntryMode::FILE, m.mode());
+        assert_eq!(5, m.content_length());
+        assert_eq!(1700000000, m.last_modified().unwrap().timestamp());
+
+        t.push(200, "hello");
+        let (_, mut r) = b.read("a/b.txt", OpRead::new()).await.unwrap();
+        assert_eq!(b"hello".to_vec(), r.read().await.unwrap().to_vec());
+        assert!(r.read().await.unwrap().is_empty());
+        assert_eq!(None, t.last().headers.get("range"));
+
+        t.push(206, "llo");
+        let args = OpRead::new().with_range(BytesRange::new(2, None));
+        let (_, mut r) = b.read("a/b.txt", args).await.unwrap();
+        assert_eq!(b"llo".to_vec(), r.read().await.unwrap().to_vec());
+        assert_eq!("bytes=2-", t.last().headers["range"]);
+
+        t.push(404, r#"{"code": 404, "msg": "Not Found"}"#);
+        let err = b.stat("c", OpStat::new()).await.unwrap_err();
+        assert_eq!(ErrorKind::NotFound, err.kind());
+
+        t.push(
+            200,
+            r#"{"path": "/root", "type": "dir", "members": [
+                {"path": "/root/a", "name": "a", "type": "dir"},
+                {"path": "/root/c.txt", "name": "c.txt", "type": "file", "size": 3}]}"#,
+        );
+        let (_, mut l) = b.list("/", OpList::new()).await.unwrap();
+        assert_eq!("a/", l.next().await.unwrap().unwrap().path());
+        let e = l.next().await.unwrap().unwrap();
+        assert_eq!("c.txt", e.path());
+        assert_eq!(3, e.metadata().content_length());
+        assert!(l.next().await.unwrap().is_none());
+        assert_eq!(Some("/root"), t.last().param("path").as_deref());
+
+        t.push(404, r#"{"code": 404, "msg": "Not Found"}"#);
+        b.delete("a/", OpDelete::new()).await.unwrap();
+        assert_eq!("/2.1/dir", t.last().url.path());
+        assert_eq!(Some("true"), t.last().param("recursive").as_deref());
+    }
+}
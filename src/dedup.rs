@@ -0,0 +1,285 @@
+//! An index of the chunks (see `chunking`) contained in local files, to find existing local
+//! copies of data before downloading it again, e.g. by `backup::Repository::restore()`.
+//!
+//! The index is maintained incrementally: files are only chunked again if their `mhash` (name,
+//! size, mtime) changed. It is persisted as JSON, like the hash cache in `hashing`.
+
+use crate::chunking::{chunk_hash, Chunker, ChunkingConfig};
+use crate::hashing::{self, Hash};
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+
+use anyhow::{self, Context, Result};
+use futures_util::StreamExt;
+use log::info;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+const DEDUP_INDEX_VERSION: u32 = 1;
+
+/// Where a chunk is found locally.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkLocation {
+    pub path: PathBuf,
+    pub offset: u64,
+    pub len: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct FileEntry {
+    mhash: Hash,
+    /// Hash, offset and length of each chunk.
+    chunks: Vec<(Hash, u64, usize)>,
+}
+
+/// The on-disk format of a `DedupIndex`.
+#[derive(Serialize, Deserialize)]
+struct IndexFile {
+    version: u32,
+    config: ChunkingConfig,
+    files: Vec<(PathBuf, FileEntry)>,
+}
+
+/// Maps chunk hashes to the local files containing them.
+#[derive(Debug)]
+pub struct DedupIndex {
+    config: ChunkingConfig,
+    files: BTreeMap<PathBuf, FileEntry>,
+    chunks: HashMap<Hash, Vec<ChunkLocation>>,
+}
+
+impl DedupIndex {
+    /// An empty index, chunking files with `config`.
+    pub fn new(config: ChunkingConfig) -> DedupIndex {
+        DedupIndex {
+            config,
+            files: BTreeMap::new(),
+            chunks: HashMap::new(),
+        }
+    }
+
+    /// Load an index stored by `save()`.
+    pub async fn load(path: impl AsRef<Path>) -> Result<DedupIndex> {
+        let b = fs::read(path.as_ref())
+            .await
+            .context("DedupIndex::load: reading index file")?;
+        let f: IndexFile = serde_json::from_slice(&b)?;
+        if f.version != DEDUP_INDEX_VERSION {
+            return Err(anyhow::Error::msg(format!(
+                "DedupIndex::load: unsupported version {}",
+                f.version
+            )));
+        }
+        let mut idx = DedupIndex::new(f.config);
+        for (p, e) in f.files {
+            idx.insert(p, e);
+        }
+        Ok(idx)
+    }
+
+    /// Store the index in the file `path`. Paths that aren't valid UTF-8 can't be stored.
+    pub async fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let f = IndexFile {
+            version: DEDUP_INDEX_VERSION,
+            config: self.config.clone(),
+            files: self
+                .files
+                .iter()
+                .map(|(p, e)| (p.clone(), e.clone()))
+                .collect(),
+        };
+        fs::write(path.as_ref(), serde_json::to_vec(&f)?)
+            .await
+            .context("DedupIndex::save: writing index file")
+    }
+
+    pub fn config(&self) -> &ChunkingConfig {
+        &self.config
+    }
+
+    /// Number of files indexed.
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    fn insert(&mut self, path: PathBuf, e: FileEntry) {
+        for (hash, offset, len) in e.chunks.iter() {
+            self.chunks
+                .entry(hash.clone())
+                .or_default()
+                .push(ChunkLocation {
+                    path: path.clone(),
+                    offset: *offset,
+                    len: *len,
+                });
+        }
+        self.files.insert(path, e);
+    }
+
+    /// Remove `path` from the index.
+    pub fn remove_file(&mut self, path: impl AsRef<Path>) {
+        let path = path.as_ref();
+        let e = match self.files.remove(path) {
+            Some(e) => e,
+            None => return,
+        };
+        for (hash, _, _) in e.chunks {
+            if let Some(locs) = self.chunks.get_mut(&hash) {
+                locs.retain(|l| l.path != path);
+                if locs.is_empty() {
+                    self.chunks.remove(&hash);
+                }
+            }
+        }
+    }
+
+    /// Index the file at `path`, unless it is indexed already and hasn't changed since. Returns
+    /// true if the file was (re-)chunked.
+    pub async fn update_file(&mut self, path: impl AsRef<Path>) -> Result<bool> {
+        let path = path.as_ref();
+        let mhash = hashing::mhash_file(path).await?;
+        if self.files.get(path).is_some_and(|e| e.mhash == mhash) {
+            return Ok(false);
+        }
+        let f = fs::File::open(path)
+            .await
+            .with_context(|| format!("opening {:?}", path))?;
+        let mut stream = std::pin::pin!(self.config.chunker()?.stream(f));
+        let mut chunks = vec![];
+        while let Some(c) = stream.next().await {
+            let c = c?;
+            chunks.push((c.hash, c.offset, c.data.len()));
+        }
+        self.remove_file(path);
+        self.insert(path.to_path_buf(), FileEntry { mhash, chunks });
+        Ok(true)
+    }
+
+    /// Index all files below the directory `root` (not following symbolic links), and remove
+    /// files below it that don't exist anymore. Returns the number of files (re-)chunked.
+    pub async fn update_tree(&mut self, root: impl AsRef<Path>) -> Result<usize> {
+        let root = root.as_ref();
+        let mut seen = vec![];
+        let mut dirs = vec![root.to_path_buf()];
+        let mut n = 0;
+        while let Some(dir) = dirs.pop() {
+            let mut rd = fs::read_dir(&dir)
+                .await
+                .with_context(|| format!("reading directory {:?}", dir))?;
+            while let Some(de) = rd.next_entry().await? {
+                let ft = de.file_type().await?;
+                if ft.is_dir() {
+                    dirs.push(de.path());
+                } else if ft.is_file() {
+                    if self.update_file(de.path()).await? {
+                        n += 1;
+                    }
+                    seen.push(de.path());
+                }
+            }
+        }
+        seen.sort();
+        let gone: Vec<PathBuf> = self
+            .files
+            .keys()
+            .filter(|p| p.starts_with(root) && seen.binary_search(p).is_err())
+            .cloned()
+            .collect();
+        for p in gone {
+            self.remove_file(p);
+        }
+        info!(
+            target: "hd_api::dedup",
+            "update_tree: {:?}: {} files indexed, {} changed",
+            root,
+            seen.len(),
+            n
+        );
+        Ok(n)
+    }
+
+    /// Where the chunk with `hash` is found, if anywhere.
+    pub fn lookup(&self, hash: &Hash) -> Option<&ChunkLocation> {
+        self.chunks.get(hash).and_then(|l| l.first())
+    }
+
+    /// Read the chunk with `hash` from a local file containing it. Returns `None` if it isn't
+    /// indexed, or if the files have changed since they were indexed.
+    pub async fn read_chunk(&self, hash: &Hash) -> Result<Option<Vec<u8>>> {
+        for loc in self.chunks.get(hash).map_or(&[][..], |l| l.as_slice()) {
+            let mut f = match fs::File::open(&loc.path).await {
+                Ok(f) => f,
+                Err(_) => continue,
+            };
+            let mut data = vec![0; loc.len];
+            f.seek(SeekFrom::Start(loc.offset)).await?;
+            if f.read_exact(&mut data).await.is_ok() && chunk_hash(&data) == *hash {
+                return Ok(Some(data));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(len: usize, seed: u32) -> Vec<u8> {
+        let mut x = seed;
+        (0..len)
+            .map(|_| {
+                x = x.wrapping_mul(1103515245).wrapping_add(12345);
+                (x >> 16) as u8
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_dedup_index() {
+        let dir = std::env::temp_dir().join("hd_api_test_dedup_index");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        let shared = data(50_000, 1);
+        let mut other = data(30_000, 2);
+        other.extend_from_slice(&shared);
+        std::fs::write(dir.join("a"), &shared).unwrap();
+        std::fs::write(dir.join("sub/b"), &other).unwrap();
+
+        let mut idx = DedupIndex::new(ChunkingConfig::small_files());
+        assert_eq!(2, idx.update_tree(&dir).await.unwrap());
+        assert_eq!(0, idx.update_tree(&dir).await.unwrap());
+        assert_eq!(2, idx.len());
+
+        let last = idx.files[&dir.join("a")].chunks.last().unwrap().clone();
+        let h = last.0;
+        assert_eq!(dir.join("a"), idx.lookup(&h).unwrap().path);
+        assert_eq!(
+            &shared[last.1 as usize..],
+            &idx.read_chunk(&h).await.unwrap().unwrap()[..]
+        );
+
+        // The chunk is still found in the other file.
+        std::fs::remove_file(dir.join("a")).unwrap();
+        assert_eq!(0, idx.update_tree(&dir).await.unwrap());
+        assert_eq!(1, idx.len());
+        assert_eq!(dir.join("sub/b"), idx.lookup(&h).unwrap().path);
+
+        let path = dir.join("index.json");
+        idx.save(&path).await.unwrap();
+        let loaded = DedupIndex::load(&path).await.unwrap();
+        assert_eq!(idx.lookup(&h), loaded.lookup(&h));
+
+        // Changed files are detected when reading.
+        std::fs::write(dir.join("sub/b"), b"changed").unwrap();
+        assert_eq!(None, loaded.read_chunk(&h).await.unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,219 @@
+//! Disk usage of remote directories, like `du`, see `disk_usage()`.
+
+use crate::hidrive::HiDrive;
+use crate::types::{Identifier, Item, Params};
+
+use std::collections::HashMap;
+
+use anyhow::Result;
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use log::info;
+
+const DU_FIELDS: &str = "path,name,members.path,members.name,members.type,members.size";
+
+#[derive(Debug, Clone)]
+pub struct DuOptions {
+    /// Directories listed at once. Default: 4.
+    pub concurrency: usize,
+    /// Levels of subdirectories included in the returned tree; sizes and counts always cover
+    /// the whole subtree. `None` (the default) includes all.
+    pub max_depth: Option<usize>,
+}
+
+impl Default for DuOptions {
+    fn default() -> DuOptions {
+        DuOptions {
+            concurrency: 4,
+            max_depth: None,
+        }
+    }
+}
+
+/// The disk usage of a directory.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DuNode {
+    pub path: String,
+    pub name: String,
+    /// The total size of the files below this directory.
+    pub size: u64,
+    /// The number of files below this directory.
+    pub files: u64,
+    /// The number of directories below this directory.
+    pub dirs: u64,
+    /// Subdirectories, largest first.
+    pub children: Vec<DuNode>,
+}
+
+impl DuNode {
+    /// This directory and all directories in the tree below it, largest first. Subdirectories
+    /// are included in their parents' sizes.
+    pub fn by_size(&self) -> Vec<&DuNode> {
+        let mut all = vec![self];
+        let mut i = 0;
+        while i < all.len() {
+            let n = all[i];
+            all.extend(n.children.iter());
+            i += 1;
+        }
+        all.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.path.cmp(&b.path)));
+        all
+    }
+}
+
+/// The files of one directory.
+struct Listing {
+    name: String,
+    size: u64,
+    files: u64,
+    subdirs: Vec<String>,
+}
+
+/// Compute the disk usage of the remote directory `root` and its subdirectories. Issues one
+/// request per directory, up to `opts.concurrency` at once.
+pub async fn disk_usage(hd: &mut HiDrive, root: Identifier, opts: &DuOptions) -> Result<DuNode> {
+    let mut p = Params::new();
+    p.add_str("members", "all").add_str("fields", DU_FIELDS);
+    let p = &p;
+    let list = |mut hd: HiDrive, id: Identifier| async move {
+        let r = hd.files().get_dir(id, Some(p)).await;
+        (hd, r)
+    };
+
+    let mut hubs: Vec<HiDrive> = (1..opts.concurrency.max(1)).map(|_| hd.fork()).collect();
+    let (hub, it) = list(hd.fork(), root).await;
+    hubs.push(hub);
+    let root_path = it.as_ref().map(|it| it.path.clone()).unwrap_or_default();
+    let mut todo = vec![];
+    let mut listings: HashMap<String, Listing> = HashMap::new();
+    let mut add = |it: Item, todo: &mut Vec<String>| {
+        let l = listing(&it);
+        todo.extend(l.subdirs.iter().cloned());
+        listings.insert(it.path, l);
+    };
+    add(it?, &mut todo);
+    let mut running = FuturesUnordered::new();
+    loop {
+        while !hubs.is_empty() {
+            match todo.pop() {
+                Some(path) => {
+                    let hub = hubs.pop().expect("a free hub");
+                    running.push(list(hub, Identifier::Path(path)));
+                }
+                None => break,
+            }
+        }
+        let (hub, r) = match running.next().await {
+            Some(done) => done,
+            None => break,
+        };
+        hubs.push(hub);
+        add(r?, &mut todo);
+    }
+    let n = listings.len();
+    let root = build(&mut listings, &root_path, opts.max_depth);
+    info!(
+        target: "hd_api::du",
+        "disk_usage: {}: {} bytes, {} files, {} directories listed",
+        root.path,
+        root.size,
+        root.files,
+        n
+    );
+    Ok(root)
+}
+
+fn listing(it: &Item) -> Listing {
+    let mut l = Listing {
+        name: it.name.clone().unwrap_or_default(),
+        size: 0,
+        files: 0,
+        subdirs: vec![],
+    };
+    for m in it.members.iter() {
+        if m.typ.as_deref() == Some("dir") {
+            l.subdirs.push(m.path.clone());
+        } else {
+            l.files += 1;
+            l.size += m.size.unwrap_or(0) as u64;
+        }
+    }
+    l
+}
+
+fn build(listings: &mut HashMap<String, Listing>, path: &str, depth: Option<usize>) -> DuNode {
+    let l = listings.remove(path).unwrap_or(Listing {
+        name: String::new(),
+        size: 0,
+        files: 0,
+        subdirs: vec![],
+    });
+    let mut node = DuNode {
+        path: path.to_string(),
+        name: l.name,
+        size: l.size,
+        files: l.files,
+        dirs: 0,
+        children: vec![],
+    };
+    for sub in l.subdirs.iter() {
+        let child = build(listings, sub, depth.map(|d| d.saturating_sub(1)));
+        node.size += child.size;
+        node.files += child.files;
+        node.dirs += child.dirs + 1;
+        node.children.push(child);
+    }
+    if depth == Some(0) {
+        node.children.clear();
+    }
+    node.children
+        .sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.path.cmp(&b.path)));
+    node
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::MockHiDrive;
+
+    #[tokio::test]
+    async fn test_disk_usage() {
+        let (fake, mut hd) = MockHiDrive::start().await;
+        fake.put("a.txt", "aaaa", 0).await;
+        fake.put("small/b.txt", "b", 0).await;
+        fake.put("big/c.txt", "cccccc", 0).await;
+        fake.put("big/deep/d.txt", "dd", 0).await;
+        fake.put("big/deep/e.txt", "eee", 0).await;
+        let root = || Identifier::Path("/m".into());
+
+        let du = disk_usage(&mut hd, root(), &DuOptions::default())
+            .await
+            .unwrap();
+        assert_eq!("/m", du.path);
+        assert_eq!((16, 5, 3), (du.size, du.files, du.dirs));
+        assert_eq!(
+            vec!["big", "small"],
+            du.children
+                .iter()
+                .map(|c| c.name.as_str())
+                .collect::<Vec<_>>()
+        );
+        let big = &du.children[0];
+        assert_eq!((11, 3, 1), (big.size, big.files, big.dirs));
+        assert_eq!((5, 2), (big.children[0].size, big.children[0].files));
+        assert_eq!(
+            vec!["/m", "/m/big", "/m/big/deep", "/m/small"],
+            du.by_size()
+                .iter()
+                .map(|n| n.path.as_str())
+                .collect::<Vec<_>>()
+        );
+
+        let opts = DuOptions {
+            concurrency: 1,
+            max_depth: Some(1),
+        };
+        let du = disk_usage(&mut hd, root(), &opts).await.unwrap();
+        assert_eq!((16, 5, 3), (du.size, du.files, du.dirs));
+        assert!(du.children[0].children.is_empty());
+    }
+}
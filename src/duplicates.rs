@@ -0,0 +1,202 @@
+//! Finding remote files with identical content, see `dedupe()`.
+//!
+//! Files are grouped by content hash (`chash`) and size, as provided by the server, so nothing
+//! is downloaded. Not to be confused with `dedup`, which indexes local files.
+
+use crate::hashing::Hash;
+use crate::hidrive::HiDrive;
+use crate::ignore::IgnoreRules;
+use crate::sync::{remote_tree, tree_entries};
+use crate::types::{Identifier, OnExist, Params};
+
+use std::collections::HashMap;
+
+use anyhow::Result;
+use log::info;
+
+/// What `dedupe()` does with duplicates.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DedupeAction {
+    /// Only report them.
+    #[default]
+    Report,
+    /// Delete all but the kept file of each group.
+    Delete,
+    /// Replace each duplicate by a server-side copy of the kept file, keeping all paths. The
+    /// mtimes of the duplicates are preserved.
+    Copy,
+}
+
+#[derive(Debug, Clone)]
+pub struct DedupeOptions {
+    pub action: DedupeAction,
+    /// Smaller files are left out. Default: 1, leaving out empty files.
+    pub min_size: u64,
+}
+
+impl Default for DedupeOptions {
+    fn default() -> DedupeOptions {
+        DedupeOptions {
+            action: DedupeAction::default(),
+            min_size: 1,
+        }
+    }
+}
+
+/// Files with the same content.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DuplicateGroup {
+    pub chash: Hash,
+    pub size: u64,
+    /// Remote paths, sorted; the first is the one kept.
+    pub paths: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DedupeReport {
+    pub groups: Vec<DuplicateGroup>,
+    /// The size of all duplicates, not counting the kept files.
+    pub duplicate_bytes: u64,
+    /// Duplicates deleted or replaced.
+    pub resolved: Vec<String>,
+    /// Duplicates that couldn't be resolved, with the error.
+    pub failed: Vec<(String, String)>,
+}
+
+/// Find files with identical content below the remote directory `root` and handle them
+/// according to `opts.action`. Files without content hash are left out.
+pub async fn dedupe(
+    hd: &mut HiDrive,
+    root: Identifier,
+    opts: &DedupeOptions,
+) -> Result<DedupeReport> {
+    let tree = remote_tree(hd, root, &IgnoreRules::new()).await?;
+    let mut by_content: HashMap<(Hash, u64), Vec<String>> = HashMap::new();
+    for (_, it) in tree_entries(&tree) {
+        let size = it.size.unwrap_or(0) as u64;
+        if it.typ.as_deref() == Some("dir") || size < opts.min_size {
+            continue;
+        }
+        if let Some(ref h) = it.chash {
+            by_content
+                .entry((h.clone(), size))
+                .or_default()
+                .push(it.path.clone());
+        }
+    }
+    let mut report = DedupeReport::default();
+    for ((chash, size), mut paths) in by_content {
+        if paths.len() < 2 {
+            continue;
+        }
+        paths.sort();
+        report.duplicate_bytes += size * (paths.len() as u64 - 1);
+        report.groups.push(DuplicateGroup { chash, size, paths });
+    }
+    report.groups.sort_by(|a, b| a.paths.cmp(&b.paths));
+    for g in report.groups.iter() {
+        let keep = &g.paths[0];
+        for dup in g.paths[1..].iter() {
+            let r = match opts.action {
+                DedupeAction::Report => break,
+                DedupeAction::Delete => {
+                    hd.files().delete(Identifier::Path(dup.clone()), None).await
+                }
+                DedupeAction::Copy => replace_by_copy(hd, keep, dup).await,
+            };
+            match r {
+                Ok(()) => report.resolved.push(dup.clone()),
+                Err(e) => report.failed.push((dup.clone(), format!("{:#}", e))),
+            }
+        }
+    }
+    info!(
+        target: "hd_api::duplicates",
+        "dedupe: {} groups, {} duplicate bytes, {} resolved, {} failed",
+        report.groups.len(),
+        report.duplicate_bytes,
+        report.resolved.len(),
+        report.failed.len()
+    );
+    Ok(report)
+}
+
+async fn replace_by_copy(hd: &mut HiDrive, keep: &str, dup: &str) -> Result<()> {
+    let dup_id = Identifier::Path(dup.to_string());
+    let mtime = hd
+        .files()
+        .metadata(dup_id.clone(), "mtime", None)
+        .await?
+        .mtime;
+    // Overwriting instead of deleting first keeps the duplicate if copying fails.
+    let mut p = Params::new();
+    p.add_on_exist(OnExist::Overwrite);
+    hd.files()
+        .copy(Identifier::Path(keep.to_string()), dup_id.clone(), Some(&p))
+        .await?;
+    if let Some(t) = mtime {
+        hd.files().set_mtime(dup_id, t.unix_timestamp()).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::MockHiDrive;
+
+    #[tokio::test]
+    async fn test_dedupe() {
+        let (fake, mut hd) = MockHiDrive::start().await;
+        fake.put("a.txt", "same", 1000).await;
+        fake.put("sub/b.txt", "same", 2000).await;
+        fake.put("sub/c.txt", "same", 3000).await;
+        fake.put("d.txt", "different", 1000).await;
+        fake.put("e.txt", "", 1000).await;
+        fake.put("f.txt", "", 1000).await;
+        let root = || Identifier::Path("/m".into());
+
+        let r = dedupe(&mut hd, root(), &DedupeOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(1, r.groups.len());
+        assert_eq!(
+            vec!["/m/a.txt", "/m/sub/b.txt", "/m/sub/c.txt"],
+            r.groups[0].paths
+        );
+        assert_eq!(8, r.duplicate_bytes);
+        assert!(r.resolved.is_empty());
+        assert_eq!(6, fake.files().await.len());
+
+        let opts = DedupeOptions {
+            action: DedupeAction::Delete,
+            ..Default::default()
+        };
+        let r = dedupe(&mut hd, root(), &opts).await.unwrap();
+        assert_eq!(vec!["/m/sub/b.txt", "/m/sub/c.txt"], r.resolved);
+        assert_eq!(vec!["a.txt", "d.txt", "e.txt", "f.txt"], fake.files().await);
+        assert!(dedupe(&mut hd, root(), &opts)
+            .await
+            .unwrap()
+            .groups
+            .is_empty());
+
+        fake.put("g.txt", "different", 5000).await;
+        let opts = DedupeOptions {
+            action: DedupeAction::Copy,
+            ..Default::default()
+        };
+        let r = dedupe(&mut hd, root(), &opts).await.unwrap();
+        assert_eq!(vec!["/m/g.txt"], r.resolved);
+        assert!(r.failed.is_empty());
+        assert_eq!(Some("different".into()), fake.read("g.txt").await);
+
+        // A failed copy leaves the duplicate in place.
+        fake.put("h.txt", "different", 6000).await;
+        fake.fail_once("POST", "d.txt").await;
+        let r = dedupe(&mut hd, root(), &opts).await.unwrap();
+        assert_eq!(vec!["/m/h.txt"], r.resolved);
+        assert_eq!("/m/g.txt", r.failed[0].0);
+        assert_eq!(Some("different".into()), fake.read("g.txt").await);
+    }
+}
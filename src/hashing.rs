@@ -1,29 +1,50 @@
 use crate::types;
 
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{BTreeSet, VecDeque};
+use std::ffi::OsStr;
 use std::fmt::{self, Display, Formatter};
+use std::ops::Range;
 use std::path::Path;
+#[cfg(feature = "hashing")]
 use std::time;
 
 #[cfg(target_family = "unix")]
 use std::os::unix::ffi::OsStrExt;
 
+#[cfg(feature = "hashing")]
+use anyhow::Context;
 use anyhow::{self, Result};
 use digest;
 use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use sha1::{Digest, Sha1};
+#[cfg(feature = "hashing")]
 use tokio::fs;
 use tokio::io::{AsyncRead, AsyncReadExt};
 
 // We are using SHA-1 everywhere, thus 20 bytes = 160 bits.
 const HASH_BYTES: usize = 20;
-const BLOCK_SIZE: usize = 4096;
-const LEVEL_GROUP: usize = 256;
+pub(crate) const BLOCK_SIZE: usize = 4096;
+pub(crate) const LEVEL_GROUP: usize = 256;
+
+static ZERO_HASH: Hash = Hash([0; HASH_BYTES]);
 
 /// A SHA1 hash.
-#[derive(Clone, Default)]
+#[derive(Clone, Default, PartialEq, Eq, Hash)]
 pub struct Hash([u8; HASH_BYTES]);
 
+impl AsRef<[u8]> for Hash {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<[u8; HASH_BYTES]> for Hash {
+    fn from(b: [u8; HASH_BYTES]) -> Hash {
+        Hash(b)
+    }
+}
+
 impl Hash {
     fn new() -> Hash {
         Hash([0; HASH_BYTES])
@@ -35,16 +56,25 @@ impl Hash {
         h
     }
 
+    /// Parse 40 hexadecimal digits. Fails with a `types::ParseError` otherwise.
     pub fn parse<S: AsRef<str>>(sha1: S) -> Result<Hash> {
         let sha1 = sha1.as_ref();
+        let err = |reason| anyhow::Error::new(types::ParseError::new("SHA-1 hash", sha1, reason));
         if sha1.len() != 2 * HASH_BYTES {
-            return Err(anyhow::Error::msg(
-                "Hash::parse: SHA-1 string must have 40 characters",
-            ));
+            return Err(err("must have 40 characters"));
         }
+        let digit = |c: u8| match c {
+            b'0'..=b'9' => Some(c - b'0'),
+            b'a'..=b'f' => Some(c - b'a' + 10),
+            b'A'..=b'F' => Some(c - b'A' + 10),
+            _ => None,
+        };
         let mut h = Hash::new();
-        for i in 0..HASH_BYTES {
-            h.0[i] = u8::from_str_radix(&sha1[2 * i..2 * i + 2], 16)?;
+        for (i, pair) in sha1.as_bytes().chunks_exact(2).enumerate() {
+            match (digit(pair[0]), digit(pair[1])) {
+                (Some(hi), Some(lo)) => h.0[i] = (hi << 4) | lo,
+                _ => return Err(err("not a hexadecimal digit")),
+            }
         }
         Ok(h)
     }
@@ -54,10 +84,6 @@ impl Hash {
         h.update(s.as_ref());
         Hash::new_from_sha1(h.finalize())
     }
-
-    fn is_zero_hash(&self) -> bool {
-        !self.0.iter().any(|e| *e != 0)
-    }
 }
 
 impl Serialize for Hash {
@@ -78,7 +104,7 @@ impl<'de> Deserialize<'de> for Hash {
         impl<'d> de::Visitor<'d> for HV {
             type Value = Hash;
             fn expecting(&self, f: &mut Formatter) -> fmt::Result {
-                write!(f, "String containing 20 hexadecimal digits")
+                write!(f, "String containing 40 hexadecimal digits")
             }
             fn visit_str<E: de::Error>(self, v: &str) -> Result<Hash, E> {
                 Hash::parse(v).map_err(E::custom)
@@ -88,6 +114,13 @@ impl<'de> Deserialize<'de> for Hash {
     }
 }
 
+impl std::str::FromStr for Hash {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Hash> {
+        Hash::parse(s)
+    }
+}
+
 // Format an SHA-1 hash.
 impl Display for Hash {
     fn fmt(&self, m: &mut Formatter) -> Result<(), fmt::Error> {
@@ -121,10 +154,81 @@ impl fmt::Debug for Hash {
     }
 }
 
+/// A node of a hash tree, i.e. the output of a digest. Hash trees (`Hashes`) are generic over
+/// this, in order to support other digests than the SHA-1 used by HiDrive's `chash`. The default
+/// value must consist of zero bytes.
+pub trait TreeHash: Clone + Default + PartialEq + fmt::Debug {
+    type Digest: Digest;
+
+    fn from_output(o: digest::Output<Self::Digest>) -> Self;
+    fn bytes(&self) -> &[u8];
+    fn bytes_mut(&mut self) -> &mut [u8];
+
+    fn is_zero(&self) -> bool {
+        !self.bytes().iter().any(|e| *e != 0)
+    }
+}
+
+impl TreeHash for Hash {
+    type Digest = Sha1;
+
+    fn from_output(o: digest::Output<Sha1>) -> Hash {
+        Hash::new_from_sha1(o)
+    }
+    fn bytes(&self) -> &[u8] {
+        &self.0
+    }
+    fn bytes_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+/// The output of an arbitrary digest `D`, for hash trees using a digest other than SHA-1.
+pub struct DigestHash<D: Digest>(pub digest::Output<D>);
+
+impl<D: Digest> Clone for DigestHash<D> {
+    fn clone(&self) -> Self {
+        DigestHash(self.0.clone())
+    }
+}
+
+impl<D: Digest> Default for DigestHash<D> {
+    fn default() -> Self {
+        DigestHash(Default::default())
+    }
+}
+
+impl<D: Digest> PartialEq for DigestHash<D> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl<D: Digest> fmt::Debug for DigestHash<D> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        self.0.iter().try_for_each(|b| write!(f, "{:02x}", b))
+    }
+}
+
+impl<D: Digest> TreeHash for DigestHash<D> {
+    type Digest = D;
+
+    fn from_output(o: digest::Output<D>) -> Self {
+        DigestHash(o)
+    }
+    fn bytes(&self) -> &[u8] {
+        self.0.as_slice()
+    }
+    fn bytes_mut(&mut self) -> &mut [u8] {
+        self.0.as_mut_slice()
+    }
+}
+
 /// A Hash level (see HiDrive documentation). Contains one hash per block.
-#[derive(Debug)]
-pub struct HashLevel {
-    h: Vec<Hash>,
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct HashLevel<H = Hash> {
+    h: Vec<H>,
 }
 
 // See uint_macros module in std.
@@ -134,95 +238,436 @@ fn carrying_add_u8(a: u8, b: u8, carry: bool) -> (u8, bool) {
     (d, c || e)
 }
 
-fn add_hashes(h1: Hash, h2: &[u8]) -> Hash {
-    assert_eq!(h1.0.len(), h2.len());
-    let h1 = &h1.0;
-    let mut r = Hash::new();
+/// Add `h2` to `h1`, both taken as big-endian numbers, discarding overflow.
+fn add_into(h1: &mut [u8], h2: &[u8]) {
+    assert_eq!(h1.len(), h2.len());
     let mut carry = false;
     for i in (0..h1.len()).rev() {
         let (s, c) = carrying_add_u8(h1[i], h2[i], carry);
-        r.0[i] = s;
+        h1[i] = s;
         carry = c;
     }
+}
 
-    r
+fn add_hashes(mut h1: Hash, h2: &[u8]) -> Hash {
+    add_into(&mut h1.0, h2);
+    h1
 }
 
-impl HashLevel {
-    fn new(cap: usize) -> HashLevel {
+impl<H: TreeHash> HashLevel<H> {
+    fn new(cap: usize) -> HashLevel<H> {
         HashLevel {
             h: Vec::with_capacity(cap),
         }
     }
 
-    fn collapse(&self) -> HashLevel {
-        let mut nhl = HashLevel::new(self.h.len() / LEVEL_GROUP + 1);
-        let mut current_sum = Hash::new();
-        for i in 0..self.h.len() {
-            if i % LEVEL_GROUP == 0 && i > 0 {
-                nhl.h.push(current_sum);
-                current_sum = Hash::new();
-            }
-            if self.h[i].is_zero_hash() {
+    /// Sum of the hashes of group `j`, i.e. the hash at index `j` of the next level.
+    fn group_sum(&self, j: usize) -> H {
+        let mut sum = H::default();
+        let end = usize::min(self.h.len(), (j + 1) * LEVEL_GROUP);
+        for i in j * LEVEL_GROUP..end {
+            if self.h[i].is_zero() {
                 continue;
             }
-            let mut h = Sha1::new();
-            h.update(self.h[i].0);
+            let mut h = H::Digest::new();
+            h.update(self.h[i].bytes());
             h.update([i as u8]);
             let hash = h.finalize();
-            current_sum = add_hashes(current_sum, hash.as_slice());
+            add_into(sum.bytes_mut(), hash.as_slice());
+        }
+        sum
+    }
+
+    /// Number of hashes in the next level.
+    fn groups(&self) -> usize {
+        usize::max(1, self.h.len().div_ceil(LEVEL_GROUP))
+    }
+
+    fn collapse(&self) -> HashLevel<H> {
+        HashLevel {
+            h: (0..self.groups()).map(|j| self.group_sum(j)).collect(),
         }
-        nhl.h.push(current_sum);
-        nhl
     }
 }
 
-/// A HiDrive hashing tree. See "HiDrive_Synchronization-v3.3-rev28.pdf".
-#[derive(Debug)]
-pub struct Hashes {
-    l: Vec<HashLevel>,
+/// A range of blocks `[start, end)` (block indices at level 0).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockRange {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl BlockRange {
+    /// Byte range `[start, end)` of the blocks in the file.
+    pub fn bytes(&self) -> (u64, u64) {
+        (
+            (self.start * BLOCK_SIZE) as u64,
+            (self.end * BLOCK_SIZE) as u64,
+        )
+    }
+}
+
+/// Number of level-0 blocks covered by one hash at `level`.
+fn blocks_per_entry(level: usize) -> usize {
+    LEVEL_GROUP.saturating_pow(level as u32)
+}
+
+/// Convert sorted entry indices at `level` to merged ranges of blocks.
+pub(crate) fn entries_to_ranges(level: usize, entries: &[usize]) -> Vec<BlockRange> {
+    let n = blocks_per_entry(level);
+    let mut ranges: Vec<BlockRange> = vec![];
+    for e in entries {
+        let (start, end) = (e * n, (e + 1) * n);
+        match ranges.last_mut() {
+            Some(r) if r.end == start => r.end = end,
+            _ => ranges.push(BlockRange { start, end }),
+        }
+    }
+    ranges
+}
+
+/// A HiDrive hashing tree. See "HiDrive_Synchronization-v3.3-rev28.pdf". By default, it
+/// consists of SHA-1 hashes as used by HiDrive; see `TreeHash` and `chash_with()` for other
+/// digests.
+///
+/// Serializes to a list of levels (starting with the block hashes), each a list of hex-encoded
+/// hashes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Hashes<H = Hash> {
+    l: Vec<HashLevel<H>>,
 }
 
 impl Display for Hashes {
     fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
-        self.l[self.l.len() - 1].h[0].fmt(f)
+        self.top_hash().fmt(f)
+    }
+}
+
+impl<H: TreeHash> Hashes<H> {
+    /// The hash of the entire tree, if it isn't empty (as partial trees may be).
+    pub fn root(&self) -> Option<&H> {
+        self.l.last().and_then(|l| l.h.first())
+    }
+
+    /// Number of levels in the tree; the top level contains only the `chash`.
+    pub fn levels(&self) -> usize {
+        self.l.len()
+    }
+
+    /// Number of blocks hashed.
+    pub fn blocks(&self) -> usize {
+        self.l.first().map_or(0, |l| l.h.len())
+    }
+
+    /// The hashes at `level` (0 being the block hashes), if the tree has that level.
+    pub fn level(&self, level: usize) -> Option<&[H]> {
+        self.l.get(level).map(|l| l.h.as_slice())
+    }
+
+    /// The hash of block `i`. Blocks consisting only of zeros have the zero hash.
+    pub fn block_hash(&self, i: usize) -> Option<&H> {
+        self.l.first().and_then(|l| l.h.get(i))
+    }
+
+    /// Iterate over `(block index, hash)` of all blocks.
+    pub fn block_hashes(&self) -> impl Iterator<Item = (usize, &H)> {
+        self.l
+            .first()
+            .into_iter()
+            .flat_map(|l| l.h.iter().enumerate())
+    }
+
+    /// Iterate over `(index, hash)` at `level`. Empty if the tree doesn't have that level. The
+    /// hash at index `j` of level `k` covers blocks `j * 256^k .. (j + 1) * 256^k`.
+    pub fn level_hashes(&self, level: usize) -> impl Iterator<Item = (usize, &H)> {
+        self.level(level).unwrap_or_default().iter().enumerate()
+    }
+
+    /// Ranges of blocks consisting only of zeros. They needn't be transferred, and can be left
+    /// as holes in sparse files.
+    pub fn zero_ranges(&self) -> Vec<BlockRange> {
+        let zero: Vec<usize> = self
+            .level_hashes(0)
+            .filter(|(_, h)| h.is_zero())
+            .map(|(i, _)| i)
+            .collect();
+        entries_to_ranges(0, &zero)
+    }
+
+    /// Ranges of blocks containing data, i.e. all except `zero_ranges()`.
+    pub fn data_ranges(&self) -> Vec<BlockRange> {
+        let data: Vec<usize> = self
+            .level_hashes(0)
+            .filter(|(_, h)| !h.is_zero())
+            .map(|(i, _)| i)
+            .collect();
+        entries_to_ranges(0, &data)
+    }
+
+    /// The tree of a file of `blocks` blocks containing only zeros. Compare it to another tree
+    /// (e.g. using `HiDriveFiles::diff_hashes()`) to find the ranges containing data.
+    pub fn zeros(blocks: usize) -> Hashes<H> {
+        Self::from_block_hashes(vec![H::default(); blocks])
+    }
+
+    /// Compare the hashes at `level` and return the blocks covered by differing hashes. Hashes
+    /// missing in one of the trees (because the file is shorter, or the tree has fewer levels)
+    /// count as zero hashes. Ranges end at the end of the longer file.
+    pub fn diff(&self, other: &Hashes<H>, level: usize) -> Vec<BlockRange> {
+        let len = usize::max(
+            self.level(level).map_or(0, |l| l.len()),
+            other.level(level).map_or(0, |l| l.len()),
+        );
+        self.diff_range(other, level, 0..len)
+    }
+
+    /// Index of the first block whose hash differs between `self` and `other`, or `None` if the
+    /// trees are equal.
+    pub fn first_mismatch(&self, other: &Hashes<H>) -> Option<usize> {
+        self.diff(other, 0).first().map(|r| r.start)
+    }
+
+    /// Byte ranges `[start, end)` of blocks differing between `self` and `other`, with adjacent
+    /// blocks coalesced. Ranges are block-aligned; the last one may extend past the end of file.
+    pub fn mismatching_ranges(&self, other: &Hashes<H>) -> Vec<(u64, u64)> {
+        self.diff(other, 0).iter().map(BlockRange::bytes).collect()
+    }
+
+    /// Like `diff()`, but only compare the hashes at `level` with an index in `entries`. Use this
+    /// with partial trees built from `hash()` responses for some ranges.
+    pub fn diff_range(
+        &self,
+        other: &Hashes<H>,
+        level: usize,
+        entries: Range<usize>,
+    ) -> Vec<BlockRange> {
+        let a = self.level(level).unwrap_or_default();
+        let b = other.level(level).unwrap_or_default();
+        let zero = H::default();
+        let entries: Vec<usize> = entries
+            .filter(|i| a.get(*i).unwrap_or(&zero) != b.get(*i).unwrap_or(&zero))
+            .collect();
+        let blocks = usize::max(self.blocks(), other.blocks());
+        let mut ranges = entries_to_ranges(level, &entries);
+        if blocks > 0 {
+            for r in ranges.iter_mut() {
+                r.end = usize::min(r.end, blocks);
+            }
+            ranges.retain(|r| r.start < r.end);
+        }
+        ranges
+    }
+
+    /// Replace the hashes of blocks `(index, hash)` and recompute only the affected branches of
+    /// the tree. Blocks beyond the current end of file extend it, with zero hashes for skipped
+    /// blocks.
+    pub fn update_blocks(&mut self, changed: &[(usize, H)]) {
+        if changed.is_empty() {
+            return;
+        }
+        let mut dirty = BTreeSet::new();
+        for (i, h) in changed {
+            let l0 = &mut self.l[0].h;
+            if *i >= l0.len() {
+                l0.resize(*i + 1, H::default());
+            }
+            l0[*i] = h.clone();
+            dirty.insert(*i / LEVEL_GROUP);
+        }
+        let mut level = 0;
+        while self.l[level].h.len() > 1 {
+            let groups = self.l[level].groups();
+            if self.l.len() == level + 1 {
+                // The new level holds no sums yet: compute all of them.
+                self.l.push(HashLevel::new(groups));
+                dirty = (0..groups).collect();
+            }
+            let sums: Vec<(usize, H)> = dirty
+                .iter()
+                .map(|j| (*j, self.l[level].group_sum(*j)))
+                .collect();
+            let next = &mut self.l[level + 1].h;
+            next.resize(groups, H::default());
+            dirty = sums.iter().map(|(j, _)| j / LEVEL_GROUP).collect();
+            for (j, h) in sums {
+                next[j] = h;
+            }
+            level += 1;
+        }
+        self.l.truncate(level + 1);
+    }
+
+    /// Check that the tree is complete and consistent: each level holds the sums of the groups of
+    /// the level below, and only the top level has a single hash. Use this on trees loaded from
+    /// caches or other untrusted sources. Partial trees (see `Hashes::from_api_hashes()`) fail.
+    pub fn validate(&self) -> Result<()> {
+        let err = |msg: String| Err(anyhow::Error::msg(format!("Hashes::validate: {}", msg)));
+        let top = match self.l.len().checked_sub(1) {
+            Some(top) => top,
+            None => return err("no levels".into()),
+        };
+        for k in 0..top {
+            let (lower, upper) = (&self.l[k], &self.l[k + 1]);
+            if lower.h.len() == 1 {
+                return err(format!("level {} has a single hash, but isn't the top", k));
+            }
+            if upper.h.len() != lower.groups() {
+                return err(format!(
+                    "level {} has {} hashes, expected {}",
+                    k + 1,
+                    upper.h.len(),
+                    lower.groups()
+                ));
+            }
+            if let Some(j) = (0..upper.h.len()).find(|j| upper.h[*j] != lower.group_sum(*j)) {
+                return err(format!(
+                    "hash {} of level {} doesn't match level {}",
+                    j,
+                    k + 1,
+                    k
+                ));
+            }
+        }
+        if self.l[top].h.len() != 1 {
+            return err(format!("top level has {} hashes", self.l[top].h.len()));
+        }
+        Ok(())
+    }
+
+    /// Build the tree from block hashes computed elsewhere, e.g. while chunking or encrypting.
+    /// Blocks consisting only of zeros must have the zero hash (`Default`).
+    pub fn from_block_hashes(blocks: Vec<H>) -> Hashes<H> {
+        Self::from_level0(HashLevel { h: blocks })
+    }
+
+    /// Build the hash tree on top of the block hashes `l0`.
+    fn from_level0(l0: HashLevel<H>) -> Hashes<H> {
+        let mut hashes = Hashes { l: vec![l0] };
+        while hashes.l[hashes.l.len() - 1].h.len() != 1 {
+            let level = hashes.l[hashes.l.len() - 1].collapse();
+            hashes.l.push(level);
+        }
+        hashes
     }
 }
 
 impl Hashes {
     /// Return the hash of the entire file's hash tree, which is used as `chash` in the API.
     pub fn top_hash(&self) -> &Hash {
-        &self.l[self.l.len() - 1].h[0]
+        self.root().unwrap_or(&ZERO_HASH)
     }
 
+    /// Return the indices of hashes at `level` in `[first, last]` differing from `remote` (as
+    /// returned by `HiDriveFiles::hash()`). Blocks missing from `remote` have the zero hash.
+    pub fn diff_remote(
+        &self,
+        level: usize,
+        (first, last): (usize, usize),
+        remote: &[types::HashedBlock],
+    ) -> Vec<usize> {
+        let local = self.level(level).unwrap_or_default();
+        let zero = Hash::new();
+        (first..=last)
+            .filter(|i| {
+                let r = remote
+                    .iter()
+                    .find(|hb| hb.level == level && hb.block == *i)
+                    .map_or(&zero, |hb| &hb.hash);
+                local.get(*i).unwrap_or(&zero) != r
+            })
+            .collect()
+    }
+
+    /// Build a (possibly partial) tree from hashes returned by `HiDriveFiles::hash()`. Hashes are
+    /// placed at their block index; blocks and levels missing from `ah` (e.g. because only some
+    /// ranges or levels were requested) are treated as zero hashes, so compare partial trees
+    /// only within the ranges they were requested for (see `diff_range()`).
     pub fn from_api_hashes(ah: &[types::HashedBlock]) -> Result<Hashes> {
-        let mut by_level: HashMap<usize, Vec<(usize, Hash)>> = HashMap::new();
-        let mut max_level = 0;
-        for hb in ah.iter() {
-            by_level
-                .entry(hb.level)
-                .and_modify(|e| e.push((hb.block, hb.hash.clone())))
-                .or_insert_with(|| vec![(hb.block, hb.hash.clone())]);
-            max_level = usize::max(max_level, hb.level);
-        }
-        let mut hash_levels = vec![];
-        for i in 0..max_level + 1 {
-            if let Some(mut hashes) = by_level.remove(&i) {
-                hashes.sort_by(|(ref k, ref _v), (ref kk, ref _vv)| k.cmp(kk));
-                hash_levels.push(HashLevel {
-                    h: hashes.into_iter().map(|(_, v)| v).collect(),
-                });
-            } else {
-                return Err(anyhow::Error::msg(
-                    "Missing hash level in API response: this is an API error",
-                ));
+        Ok(Self::from_hashed_blocks(ah.iter()))
+    }
+
+    /// Like `from_api_hashes()`, for all ranges of a `hash()` response.
+    pub fn from_file_hash(fh: &types::FileHash) -> Result<Hashes> {
+        Ok(Self::from_hashed_blocks(fh.list.iter().flatten()))
+    }
+
+    fn from_hashed_blocks<'a>(ah: impl Iterator<Item = &'a types::HashedBlock> + Clone) -> Hashes {
+        let max_level = ah.clone().map(|hb| hb.level).max().unwrap_or(0);
+        let mut l: Vec<HashLevel> = (0..max_level + 1).map(|_| HashLevel::new(0)).collect();
+        for hb in ah {
+            let h = &mut l[hb.level].h;
+            if hb.block >= h.len() {
+                h.resize(hb.block + 1, Hash::new());
             }
+            h[hb.block] = hb.hash.clone();
         }
-        Ok(Hashes { l: hash_levels })
+        Hashes { l }
+    }
+}
+
+/// Version of the hash cache format written by `save_hashes()`.
+#[cfg(feature = "hashing")]
+const HASH_CACHE_VERSION: u32 = 1;
+
+/// A hash tree as stored by `save_hashes()`. `mhash` identifies the file state (name, size, mtime)
+/// the tree was computed for.
+#[cfg(feature = "hashing")]
+#[derive(Debug, Serialize, Deserialize)]
+struct HashCacheEntry {
+    version: u32,
+    mhash: Hash,
+    hashes: Hashes,
+}
+
+/// Store `hashes` of a file with `mhash` in the cache file `path`.
+#[cfg(feature = "hashing")]
+pub async fn save_hashes<P: AsRef<Path>>(path: P, mhash: &Hash, hashes: &Hashes) -> Result<()> {
+    let e = HashCacheEntry {
+        version: HASH_CACHE_VERSION,
+        mhash: mhash.clone(),
+        hashes: hashes.clone(),
+    };
+    fs::write(path, serde_json::to_vec(&e)?)
+        .await
+        .context("save_hashes: writing cache file")
+}
+
+/// Load a hash tree from the cache file `path`. Returns `None` if it was computed for a different
+/// `mhash` (i.e., the file has changed) or by an incompatible version.
+#[cfg(feature = "hashing")]
+pub async fn load_hashes<P: AsRef<Path>>(path: P, mhash: &Hash) -> Result<Option<Hashes>> {
+    let b = fs::read(path)
+        .await
+        .context("load_hashes: reading cache file")?;
+    let e: HashCacheEntry = serde_json::from_slice(&b)?;
+    if e.version != HASH_CACHE_VERSION || e.mhash != *mhash {
+        return Ok(None);
     }
+    e.hashes
+        .validate()
+        .context("load_hashes: malformed hash tree")?;
+    Ok(Some(e.hashes))
+}
+
+/// Like `chash_file`, but reuse the hash tree stored in `cache` if the file hasn't changed since
+/// (judging by its `mhash`). Otherwise, the tree is computed and stored in `cache`.
+#[cfg(feature = "hashing")]
+pub async fn chash_file_cached<P: AsRef<Path>, C: AsRef<Path>>(
+    path: P,
+    cache: C,
+) -> Result<Hashes> {
+    let mh = mhash_file(&path).await?;
+    if let Ok(Some(h)) = load_hashes(&cache, &mh).await {
+        return Ok(h);
+    }
+    let h = chash_file(&path).await?;
+    save_hashes(&cache, &mh, &h).await?;
+    Ok(h)
 }
 
 /// Calculate `nhash`, `mhash`, `chash` at once and return them.
+#[cfg(feature = "hashing")]
 pub async fn file_hashes<S: AsRef<Path>>(path: S) -> Result<(Hash, Hash, Hash)> {
     let nh = nhash(&path);
     let mh = mhash_file(&path).await?;
@@ -230,16 +675,94 @@ pub async fn file_hashes<S: AsRef<Path>>(path: S) -> Result<(Hash, Hash, Hash)>
     Ok((nh, mh, ch.top_hash().clone()))
 }
 
+/// The bytes of a file name as hashed by `nhash`. On UNIX, names are used as-is.
+#[cfg(target_family = "unix")]
+fn name_bytes(name: &OsStr) -> Cow<'_, [u8]> {
+    Cow::Borrowed(name.as_bytes())
+}
+
+/// The bytes of a file name as hashed by `nhash`. Elsewhere (i.e., Windows), names are converted
+/// to UTF-8, which is how HiDrive stores them; unpaired surrogates, which can't be represented,
+/// are replaced by U+FFFD.
+#[cfg(not(target_family = "unix"))]
+fn name_bytes(name: &OsStr) -> Cow<'_, [u8]> {
+    match name.to_string_lossy() {
+        Cow::Borrowed(s) => Cow::Borrowed(s.as_bytes()),
+        Cow::Owned(s) => Cow::Owned(s.into_bytes()),
+    }
+}
+
+/// Result of comparing a local file to a remote item, see `verify_item()`.
+#[cfg(feature = "hashing")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// Content, name, size and mtime are equal.
+    Match,
+    /// The content differs.
+    ContentDiffers,
+    /// The content is equal, but name, size or mtime differ.
+    MetadataDiffers,
+}
+
+/// Compare the local file at `path` to the remote `item`, using the item's `chash`, `mhash` and
+/// `nhash` (request them using the `fields` parameter). The `chash` is required; if `mhash` is
+/// missing, the item's `size` and `mtime` are compared instead.
+#[cfg(feature = "hashing")]
+pub async fn verify_item<S: AsRef<Path>>(path: S, item: &types::Item) -> Result<Verdict> {
+    let remote_chash = item.chash.as_ref().ok_or_else(|| {
+        anyhow::Error::msg("verify_item: item has no chash; request it using the fields parameter")
+    })?;
+    if chash_file(&path).await?.top_hash() != remote_chash {
+        return Ok(Verdict::ContentDiffers);
+    }
+    if let Some(ref nh) = item.nhash {
+        if *nh != nhash(&path) {
+            return Ok(Verdict::MetadataDiffers);
+        }
+    }
+    let metadata_equal = match item.mhash {
+        Some(ref mh) => *mh == mhash_file(&path).await?,
+        None => {
+            let md = fs::metadata(&path).await?;
+            let mtime = md
+                .modified()?
+                .duration_since(time::SystemTime::UNIX_EPOCH)?
+                .as_secs() as i64;
+            item.size.map_or(true, |s| s as u64 == md.len())
+                && item.mtime.map_or(true, |t| t.unix_timestamp() == mtime)
+        }
+    };
+    if metadata_equal {
+        Ok(Verdict::Match)
+    } else {
+        Ok(Verdict::MetadataDiffers)
+    }
+}
+
 /// Calculate nhash for file name.
 pub fn nhash<S: AsRef<Path>>(filename: S) -> Hash {
     // To do: handle error when parsing file name.
-    Hash::for_string(filename.as_ref().file_name().unwrap().as_bytes())
+    nhash_bytes(name_bytes(filename.as_ref().file_name().unwrap()))
+}
+
+/// Calculate nhash for a raw file name (basename), which doesn't need to be valid UTF-8.
+pub fn nhash_bytes<B: AsRef<[u8]>>(name: B) -> Hash {
+    Hash::for_string(name)
 }
 
 /// Calculate mhash for a given filename and access time (in seconds since epoch).
 pub fn mhash<S: AsRef<Path>>(filename: S, mtime: i64, size: Option<u64>) -> Hash {
+    mhash_bytes(
+        name_bytes(filename.as_ref().file_name().unwrap()),
+        mtime,
+        size,
+    )
+}
+
+/// Calculate mhash for a raw file name (basename), see `mhash()` and `nhash_bytes()`.
+pub fn mhash_bytes<B: AsRef<[u8]>>(name: B, mtime: i64, size: Option<u64>) -> Hash {
     let mut h = Sha1::new();
-    let nh = nhash(filename);
+    let nh = nhash_bytes(name);
     h.update(nh.0);
     if let Some(s) = size {
         h.update(s.to_le_bytes());
@@ -250,6 +773,7 @@ pub fn mhash<S: AsRef<Path>>(filename: S, mtime: i64, size: Option<u64>) -> Hash
 
 /// Hashes a file at the given path to obtain the mhash. This hash goes over file name (basename),
 /// file size, and mtime.
+#[cfg(feature = "hashing")]
 pub async fn mhash_file<S: AsRef<Path>>(path: S) -> Result<Hash> {
     let md = fs::metadata(&path).await?;
     let mtime = md
@@ -260,41 +784,175 @@ pub async fn mhash_file<S: AsRef<Path>>(path: S) -> Result<Hash> {
     Ok(mhash(path, mtime_s as i64, Some(fsize)))
 }
 
-/// Calculate content hash for file at path. A shortcut for opening a file and using `chash`.
+/// Calculate content hash for file at path. A shortcut for opening a file and using
+/// `chash_parallel` with one task per CPU.
+#[cfg(feature = "hashing")]
 pub async fn chash_file<S: AsRef<Path>>(path: S) -> Result<Hashes> {
     let f = fs::OpenOptions::new().read(true).open(path).await?;
-    chash(f).await
+    let tasks = std::thread::available_parallelism().map_or(1, |n| n.get());
+    chash_parallel(f, tasks).await
+}
+
+/// Hash one block. Blocks shorter than `BLOCK_SIZE` (at the end of a file) are padded with
+/// zeros; blocks consisting only of zeros have the zero hash.
+fn hash_block<H: TreeHash>(block: &[u8]) -> H {
+    if !block.iter().any(|e| *e != 0) {
+        return H::default();
+    }
+    let mut h = H::Digest::new();
+    h.update(block);
+    if block.len() < BLOCK_SIZE {
+        h.update(&[0_u8; BLOCK_SIZE][block.len()..]);
+    }
+    H::from_output(h.finalize())
+}
+
+/// Read into `buf` until it is full or the end of input is reached. Returns the number of bytes
+/// read.
+async fn read_full<R: AsyncRead + Unpin>(r: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        let m = r.read(&mut buf[n..]).await?;
+        if m == 0 {
+            break;
+        }
+        n += m;
+    }
+    Ok(n)
+}
+
+/// Hashes a file's content. Input is read in batches of 1 MiB.
+pub async fn chash<R: AsyncRead + Unpin>(r: R) -> Result<Hashes> {
+    chash_with(r).await
+}
+
+/// Hashes content like `chash`, building the tree from a different digest; e.g.
+/// `chash_with::<_, DigestHash<Sha256>>(r)`.
+pub async fn chash_with<R: AsyncRead + Unpin, H: TreeHash>(r: R) -> Result<Hashes<H>> {
+    chash_readers(std::iter::once(r)).await
+}
+
+/// Hashes the concatenation of `readers` as one file, e.g. a file assembled from chunks.
+pub async fn chash_concat<R: AsyncRead + Unpin, I: IntoIterator<Item = R>>(
+    readers: I,
+) -> Result<Hashes> {
+    chash_readers(readers).await
 }
 
-/// Hashes a file's content.
-pub async fn chash<R: AsyncRead + Unpin>(mut r: R) -> Result<Hashes> {
+async fn chash_readers<R: AsyncRead + Unpin, I: IntoIterator<Item = R>, H: TreeHash>(
+    readers: I,
+) -> Result<Hashes<H>> {
     let mut l0 = HashLevel { h: vec![] };
+    let mut buf = vec![0_u8; READ_BATCH_BLOCKS * BLOCK_SIZE];
+    let mut filled = 0;
+    for mut r in readers {
+        loop {
+            filled += read_full(&mut r, &mut buf[filled..]).await?;
+            if filled < buf.len() {
+                // `r` is exhausted; continue filling the buffer from the next reader.
+                break;
+            }
+            l0.h.extend(buf.chunks(BLOCK_SIZE).map(hash_block::<H>));
+            filled = 0;
+        }
+    }
+    l0.h.extend(buf[..filled].chunks(BLOCK_SIZE).map(hash_block::<H>));
+    Ok(Hashes::from_level0(l0))
+}
+
+/// Number of blocks read at once by `chash`, and hashed by one task in `chash_parallel`.
+const READ_BATCH_BLOCKS: usize = LEVEL_GROUP;
+
+/// Hashes a file's content like `chash`, but on up to `tasks` blocking threads in parallel. Input
+/// is read in batches of 1 MiB; the result is identical to `chash`.
+pub async fn chash_parallel<R: AsyncRead + Unpin>(mut r: R, tasks: usize) -> Result<Hashes> {
+    let tasks = tasks.max(1);
+    let mut l0 = HashLevel { h: vec![] };
+    let mut pending = VecDeque::with_capacity(tasks);
     loop {
-        let mut buf = [0_u8; BLOCK_SIZE];
-        let n = r.read(&mut buf).await?;
+        let mut batch = vec![0_u8; READ_BATCH_BLOCKS * BLOCK_SIZE];
+        let n = read_full(&mut r, &mut batch).await?;
         if n == 0 {
             break;
         }
-        let mut hash_arr = Hash::new();
-        // Only hash a block if it has non-zero bytes in it.
-        if buf.iter().any(|e| *e != 0) {
-            let mut h = Sha1::new();
-            h.update(buf);
-            let hash = h.finalize();
-            hash_arr.0.copy_from_slice(hash.as_slice());
+        batch.truncate(n);
+        pending.push_back(tokio::task::spawn_blocking(move || {
+            batch
+                .chunks(BLOCK_SIZE)
+                .map(hash_block)
+                .collect::<Vec<Hash>>()
+        }));
+        if pending.len() >= tasks {
+            l0.h.extend(pending.pop_front().unwrap().await?);
+        }
+        if n < READ_BATCH_BLOCKS * BLOCK_SIZE {
+            break;
         }
-        l0.h.push(hash_arr);
     }
+    while let Some(t) = pending.pop_front() {
+        l0.h.extend(t.await?);
+    }
+    Ok(Hashes::from_level0(l0))
+}
 
-    let mut hashes = Hashes { l: vec![l0] };
-    loop {
-        if hashes.l[hashes.l.len() - 1].h.len() == 1 {
-            break;
+/// Synchronous versions of the file hashing functions, for callers without a tokio runtime. They
+/// share the tree logic with the async functions and return identical results.
+#[cfg(feature = "blocking")]
+pub mod blocking {
+    use super::{hash_block, mhash, nhash, Hash, HashLevel, Hashes, BLOCK_SIZE};
+
+    use std::fs;
+    use std::io::Read;
+    use std::path::Path;
+    use std::time;
+
+    use anyhow::Result;
+
+    /// Hashes content read from `r`.
+    pub fn chash<R: Read>(mut r: R) -> Result<Hashes> {
+        let mut l0 = HashLevel { h: vec![] };
+        loop {
+            let mut buf = [0_u8; BLOCK_SIZE];
+            let mut n = 0;
+            while n < BLOCK_SIZE {
+                let m = r.read(&mut buf[n..])?;
+                if m == 0 {
+                    break;
+                }
+                n += m;
+            }
+            if n == 0 {
+                break;
+            }
+            l0.h.push(hash_block(&buf));
+            if n < BLOCK_SIZE {
+                break;
+            }
         }
-        let level = hashes.l[hashes.l.len() - 1].collapse();
-        hashes.l.push(level);
+        Ok(Hashes::from_level0(l0))
+    }
+
+    /// Calculate content hash for file at path.
+    pub fn chash_file<S: AsRef<Path>>(path: S) -> Result<Hashes> {
+        chash(std::io::BufReader::new(fs::File::open(path)?))
+    }
+
+    /// Calculate the mhash of the file at `path`, from its name, size, and mtime.
+    pub fn mhash_file<S: AsRef<Path>>(path: S) -> Result<Hash> {
+        let md = fs::metadata(&path)?;
+        let mtime = md
+            .modified()?
+            .duration_since(time::SystemTime::UNIX_EPOCH)?;
+        Ok(mhash(path, mtime.as_secs() as i64, Some(md.len())))
+    }
+
+    /// Calculate `nhash`, `mhash`, `chash` at once and return them.
+    pub fn file_hashes<S: AsRef<Path>>(path: S) -> Result<(Hash, Hash, Hash)> {
+        let nh = nhash(&path);
+        let mh = mhash_file(&path)?;
+        let ch = chash_file(&path)?;
+        Ok((nh, mh, ch.top_hash().clone()))
     }
-    Ok(hashes)
 }
 
 /// Calculate a `chash` for a directory.
@@ -386,11 +1044,342 @@ mod tests {
         assert_eq!("fd0da83a93d57dd4e514c8641088ba1322aa6947", h.to_string());
     }
 
+    #[tokio::test]
+    async fn test_hash_tree_parallel() {
+        for (f, want) in [
+            (
+                "testdata/test_hashes.txt",
+                "09f077820a8a41f34a639f2172f1133b1eafe4e6",
+            ),
+            (
+                "testdata/test_hashes_2M.txt",
+                "fd0da83a93d57dd4e514c8641088ba1322aa6947",
+            ),
+        ] {
+            for tasks in [1, 3] {
+                let f = fs::File::open(f).await.unwrap();
+                let h = super::chash_parallel(f, tasks).await.unwrap();
+                assert_eq!(want, h.to_string());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_update_blocks() {
+        let f = fs::File::open("testdata/test_hashes_2M.txt").await.unwrap();
+        let mut h = super::chash(f).await.unwrap();
+        let changed = [
+            (3, super::Hash::for_string("a")),
+            (300, super::Hash::for_string("b")),
+            (301, super::Hash::new()),
+            // Extends the file.
+            (600, super::Hash::for_string("c")),
+        ];
+        let mut l0 = h.l[0].h.clone();
+        l0.resize(601, super::Hash::new());
+        for (i, hash) in changed.iter() {
+            l0[*i] = hash.clone();
+        }
+        let want = super::Hashes::from_level0(super::HashLevel { h: l0 });
+
+        h.update_blocks(&changed);
+        assert_eq!(want.l.len(), h.l.len());
+        assert_eq!(want.to_string(), h.to_string());
+
+        // Growing a single-block file adds a level.
+        let mut h = super::Hashes::from_level0(super::HashLevel {
+            h: vec![super::Hash::for_string("a")],
+        });
+        h.update_blocks(&[(1, super::Hash::for_string("b"))]);
+        assert_eq!(2, h.l.len());
+    }
+
+    #[tokio::test]
+    async fn test_update_blocks_new_level() {
+        let a = vec![b'a'; super::BLOCK_SIZE];
+        let b = vec![b'b'; super::BLOCK_SIZE];
+        let mut h = super::chash(&a[..]).await.unwrap();
+        assert_eq!(1, h.l.len());
+        let hb = super::chash(&b[..]).await.unwrap().l[0].h[0].clone();
+        h.update_blocks(&[(256, hb)]);
+
+        let mut data = a.clone();
+        data.resize(256 * super::BLOCK_SIZE, 0);
+        data.extend_from_slice(&b);
+        let want = super::chash(&data[..]).await.unwrap();
+        assert_eq!(want.to_string(), h.to_string());
+        h.validate().unwrap();
+    }
+
+    #[cfg(feature = "hashing")]
+    #[tokio::test]
+    async fn test_hash_cache() {
+        let cache = std::env::temp_dir().join("hd_api_test_hash_cache.json");
+        let _ = fs::remove_file(&cache).await;
+        let h = super::chash_file_cached("testdata/sample.bin", &cache)
+            .await
+            .unwrap();
+        assert_eq!("fd0da83a93d57dd4e514c8641088ba1322aa6947", h.to_string());
+
+        let mh = super::mhash_file("testdata/sample.bin").await.unwrap();
+        let cached = super::load_hashes(&cache, &mh).await.unwrap().unwrap();
+        assert_eq!(h.l.len(), cached.l.len());
+        assert_eq!(h.l[0].h.len(), cached.l[0].h.len());
+        assert_eq!(h.to_string(), cached.to_string());
+        let other = super::Hash::for_string("other");
+        assert!(super::load_hashes(&cache, &other).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_zero_ranges() {
+        let mut data = vec![0u8; 6 * 4096];
+        data[10] = 1;
+        data[4 * 4096] = 1;
+        let h = super::chash(&data[..]).await.unwrap();
+        let r = |start, end| super::BlockRange { start, end };
+        assert_eq!(vec![r(1, 4), r(5, 6)], h.zero_ranges());
+        assert_eq!(vec![r(0, 1), r(4, 5)], h.data_ranges());
+        let zeros = super::Hashes::<super::Hash>::zeros(6);
+        assert_eq!(h.data_ranges(), h.diff(&zeros, 0));
+        assert!(zeros.data_ranges().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_diff() {
+        let f = fs::File::open("testdata/test_hashes_2M.txt").await.unwrap();
+        let a = super::chash(f).await.unwrap();
+        let mut b = a.clone();
+        assert!(a.diff(&b, 0).is_empty());
+        b.update_blocks(&[
+            (3, super::Hash::for_string("a")),
+            (4, super::Hash::for_string("b")),
+            (300, super::Hash::for_string("c")),
+        ]);
+        assert_eq!(
+            vec![
+                super::BlockRange { start: 3, end: 5 },
+                super::BlockRange {
+                    start: 300,
+                    end: 301
+                }
+            ],
+            a.diff(&b, 0)
+        );
+        // Level 1: the first two groups of 256 blocks differ.
+        assert_eq!(
+            vec![super::BlockRange { start: 0, end: 512 }],
+            a.diff(&b, 1)
+        );
+        assert_eq!(
+            vec![super::BlockRange { start: 0, end: 515 }],
+            a.diff(&b, 2)
+        );
+
+        assert_eq!(Some(3), a.first_mismatch(&b));
+        assert_eq!(None, a.first_mismatch(&a));
+        assert_eq!(
+            vec![(3 * 4096, 5 * 4096), (300 * 4096, 301 * 4096)],
+            a.mismatching_ranges(&b)
+        );
+        assert!(a.mismatching_ranges(&a.clone()).is_empty());
+
+        let remote: Vec<crate::types::HashedBlock> = (0..10)
+            .filter(|i| *i != 3)
+            .map(|i| crate::types::HashedBlock {
+                hash: b.l[0].h[i].clone(),
+                level: 0,
+                block: i,
+            })
+            .collect();
+        // Block 3 is missing from the remote list, i.e. zero; block 4 differs.
+        assert_eq!(vec![3, 4], a.diff_remote(0, (0, 9), &remote));
+    }
+
+    /// Hash `h` together with its index within the level group, as done when collapsing a level.
+    fn entry_hash(h: &super::Hash, i: usize) -> super::Hash {
+        let mut s = Sha1::new();
+        s.update(h.0);
+        s.update([i as u8]);
+        super::Hash::new_from_sha1(s.finalize())
+    }
+
+    #[tokio::test]
+    async fn test_hash_tree_four_levels() {
+        use tokio::io::AsyncReadExt;
+
+        // 256*256 + 1 blocks, of which the first and the last are non-zero. This requires four
+        // levels: 65537 -> 257 -> 2 -> 1.
+        let block = vec![7_u8; super::BLOCK_SIZE];
+        let zeros = tokio::io::repeat(0).take((256 * 256 - 1) * super::BLOCK_SIZE as u64);
+        let r = (&block[..]).chain(zeros).chain(&block[..]);
+        let h = super::chash(r).await.unwrap();
+        assert_eq!(4, h.levels());
+        assert_eq!(256 * 256 + 1, h.blocks());
+        assert_eq!(257, h.l[1].h.len());
+        assert_eq!(2, h.l[2].h.len());
+
+        let b = super::hash_block(&block);
+        let l1 = entry_hash(&b, 0);
+        assert_eq!(l1, h.l[1].h[0]);
+        assert_eq!(l1, h.l[1].h[256]);
+        let l2 = entry_hash(&l1, 0);
+        assert_eq!(l2, h.l[2].h[0]);
+        assert_eq!(l2, h.l[2].h[1]);
+        let top = super::add_hashes(entry_hash(&l2, 0), entry_hash(&l2, 1).0.as_slice());
+        assert_eq!(&top, h.top_hash());
+
+        let zeros = tokio::io::repeat(0).take((256 * 256 - 1) * super::BLOCK_SIZE as u64);
+        let r = (&block[..]).chain(zeros).chain(&block[..]);
+        let hp = super::chash_parallel(r, 4).await.unwrap();
+        assert_eq!(h.to_string(), hp.to_string());
+
+        // Zeroing the last block removes the second branch of every level.
+        let mut hu = h.clone();
+        hu.update_blocks(&[(256 * 256, super::Hash::new())]);
+        let top = super::add_hashes(super::Hash::new(), entry_hash(&l2, 0).0.as_slice());
+        assert_eq!(&top, hu.top_hash());
+    }
+
+    #[cfg(feature = "blocking")]
+    #[tokio::test]
+    async fn test_blocking() {
+        let (nh, mh, ch) = super::blocking::file_hashes("testdata/sample.bin").unwrap();
+        let (anh, amh, ach) = super::file_hashes("testdata/sample.bin").await.unwrap();
+        assert_eq!((anh, amh, ach), (nh, mh, ch.clone()));
+        assert_eq!("fd0da83a93d57dd4e514c8641088ba1322aa6947", ch.to_string());
+    }
+
+    #[cfg(feature = "hashing")]
+    #[tokio::test]
+    async fn test_verify_item() {
+        let path = "testdata/sample.bin";
+        let mut item = crate::types::Item {
+            chash: Some(super::Hash::parse("fd0da83a93d57dd4e514c8641088ba1322aa6947").unwrap()),
+            nhash: Some(super::nhash(path)),
+            mhash: Some(super::mhash_file(path).await.unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(
+            super::Verdict::Match,
+            super::verify_item(path, &item).await.unwrap()
+        );
+
+        item.mhash = None;
+        item.size = Some(2107392);
+        assert_eq!(
+            super::Verdict::Match,
+            super::verify_item(path, &item).await.unwrap()
+        );
+        item.size = Some(1);
+        assert_eq!(
+            super::Verdict::MetadataDiffers,
+            super::verify_item(path, &item).await.unwrap()
+        );
+
+        item.chash = Some(super::Hash::for_string("other"));
+        assert_eq!(
+            super::Verdict::ContentDiffers,
+            super::verify_item(path, &item).await.unwrap()
+        );
+        item.chash = None;
+        assert!(super::verify_item(path, &item).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_level_accessors() {
+        let f = fs::File::open("testdata/test_hashes_2M.txt").await.unwrap();
+        let h = super::chash(f).await.unwrap();
+        assert_eq!(3, h.levels());
+        assert_eq!(515, h.blocks());
+        assert_eq!(515, h.block_hashes().count());
+        assert!(h
+            .block_hashes()
+            .filter(|(i, _)| (384..400).contains(i))
+            .all(|(_, b)| *b == super::Hash::new()));
+        assert_eq!(Some(&h.l[0].h[7]), h.block_hash(7));
+        assert!(h.block_hash(515).is_none());
+        assert_eq!(3, h.level_hashes(1).count());
+        assert_eq!(
+            vec![(0, h.top_hash())],
+            h.level_hashes(2).collect::<Vec<_>>()
+        );
+        assert_eq!(0, h.level_hashes(3).count());
+    }
+
+    #[tokio::test]
+    async fn test_chash_with_digest() {
+        use super::TreeHash;
+
+        let f = fs::File::open("testdata/test_hashes_2M.txt").await.unwrap();
+        let h = super::chash_with::<_, super::DigestHash<Sha1>>(f)
+            .await
+            .unwrap();
+        assert_eq!(3, h.levels());
+        assert_eq!(
+            super::Hash::parse("fd0da83a93d57dd4e514c8641088ba1322aa6947")
+                .unwrap()
+                .bytes(),
+            h.root().unwrap().bytes()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_chash_concat() {
+        let data = tokio::fs::read("testdata/test_hashes_2M.txt")
+            .await
+            .unwrap();
+        let parts = [
+            &data[..1000],
+            &data[1000..1048576 + 5],
+            &data[1048576 + 5..],
+        ];
+        let h = super::chash_concat(parts).await.unwrap();
+        assert_eq!("fd0da83a93d57dd4e514c8641088ba1322aa6947", h.to_string());
+        let h = super::chash_concat(Vec::<&[u8]>::new()).await.unwrap();
+        assert_eq!(super::Hash::new(), *h.top_hash());
+    }
+
+    #[tokio::test]
+    async fn test_from_block_hashes() {
+        let data = tokio::fs::read("testdata/test_hashes_2M.txt")
+            .await
+            .unwrap();
+        let blocks = data
+            .chunks(super::BLOCK_SIZE)
+            .map(super::hash_block::<super::Hash>)
+            .collect();
+        let h = super::Hashes::from_block_hashes(blocks);
+        assert_eq!("fd0da83a93d57dd4e514c8641088ba1322aa6947", h.to_string());
+        assert_eq!(
+            super::Hash::new(),
+            *super::Hashes::<super::Hash>::from_block_hashes(vec![]).top_hash()
+        );
+    }
+
     #[test]
     fn test_hash_parse() {
         let hs = "4f450fa02257ea368179557f482e73b2fb80b566";
         let h = super::Hash::parse(hs).unwrap();
         assert_eq!(hs, h.to_string());
+        assert_eq!(h, hs.to_uppercase().parse().unwrap());
+
+        // Non-ASCII input of the right byte length must not panic.
+        for bad in [
+            "",
+            "4f450fa02257ea368179557f482e73b2fb80b56",
+            "4f450fa02257ea368179557f482e73b2fb80b566a",
+            "4f450fa02257ea368179557f482e73b2fb80b5äa",
+            "ä4f450fa02257ea368179557f482e73b2fb80b5a",
+            "+f450fa02257ea368179557f482e73b2fb80b566",
+            "4f450fa02257ea368179557f482e73b2fb80b5 6",
+        ] {
+            let e = super::Hash::parse(bad).unwrap_err();
+            assert!(
+                e.downcast_ref::<crate::types::ParseError>().is_some(),
+                "{}",
+                bad
+            );
+        }
     }
 
     #[test]
@@ -408,6 +1397,54 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_nhash_mhash_bytes() {
+        let mtime = 1456789012;
+        for name in ["HiDrive ☁", "sample.bin", "a", "Ärger über Öl.txt"] {
+            assert_eq!(super::nhash(name), super::nhash_bytes(name.as_bytes()));
+            assert_eq!(
+                super::mhash(name, mtime, Some(123)),
+                super::mhash_bytes(name.as_bytes(), mtime, Some(123))
+            );
+        }
+        // Only the basename is hashed.
+        assert_eq!(
+            super::nhash("dir/sub/sample.bin"),
+            super::nhash("sample.bin")
+        );
+        assert_eq!(
+            "449fee596b27c879052e9d82366cb5d63ebaf6f6",
+            super::mhash_bytes(b"sample.bin", 1234567890, Some(2107392)).to_string()
+        );
+    }
+
+    #[test]
+    fn test_nhash_non_utf8() {
+        // Latin-1 "Ärger", as created by legacy systems; not valid UTF-8.
+        let name: &[u8] = b"\xc4rger";
+        assert!(std::str::from_utf8(name).is_err());
+        let expected = {
+            let mut h = Sha1::new();
+            h.update(name);
+            super::Hash::new_from_sha1(h.finalize())
+        };
+        assert_eq!(expected, super::nhash_bytes(name));
+        // Distinct from the UTF-8 spelling.
+        assert_ne!(super::nhash_bytes(name), super::nhash("Ärger"));
+
+        #[cfg(target_family = "unix")]
+        {
+            use std::ffi::OsStr;
+            use std::os::unix::ffi::OsStrExt;
+            let path = std::path::Path::new("/tmp").join(OsStr::from_bytes(name));
+            assert_eq!(expected, super::nhash(&path));
+            assert_eq!(
+                super::mhash_bytes(name, 1234567890, Some(17)),
+                super::mhash(&path, 1234567890, Some(17))
+            );
+        }
+    }
+
     #[test]
     fn test_dirchash() {
         let fname = "sample.bin";
@@ -455,6 +1492,7 @@ mod tests {
     // Only works with correct mtime, i.e. not in CI.
     // Set mtime using `touch -m --date=@1234567890 testdata/sample.bin`
     //#[tokio::test]
+    #[cfg(feature = "hashing")]
     #[allow(dead_code)]
     async fn test_mhash_file() {
         assert_eq!(
@@ -466,6 +1504,7 @@ mod tests {
         );
     }
 
+    #[cfg(feature = "hashing")]
     #[tokio::test]
     async fn test_file_hashes() {
         let (nh, _mh, ch) = super::file_hashes("testdata/sample.bin").await.unwrap();
@@ -510,6 +1549,180 @@ mod tests {
 
         let hashes = super::Hashes::from_api_hashes(&ah.list[0]).unwrap();
         assert_eq!(1, hashes.l.len());
-        assert_eq!(4, hashes.l[0].h.len());
+        // Missing blocks are zero hashes.
+        assert_eq!(9, hashes.l[0].h.len());
+        assert_eq!(super::Hash::new(), hashes.l[0].h[2]);
+        assert_eq!(
+            "a40a462a40337331c40734b3d999483401adef3c",
+            hashes.l[0].h[3].to_string()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_partial_api_hashes() {
+        let f = fs::File::open("testdata/test_hashes_2M.txt").await.unwrap();
+        let local = super::chash(f).await.unwrap();
+        // A response for level 1 only, range 1-2, where entry 2 differs.
+        let fh = crate::types::FileHash {
+            level: 1,
+            chash: local.top_hash().clone(),
+            list: vec![vec![
+                crate::types::HashedBlock {
+                    hash: local.l[1].h[1].clone(),
+                    level: 1,
+                    block: 1,
+                },
+                crate::types::HashedBlock {
+                    hash: super::Hash::for_string("x"),
+                    level: 1,
+                    block: 2,
+                },
+            ]],
+        };
+        let remote = super::Hashes::from_file_hash(&fh).unwrap();
+        assert_eq!(2, remote.levels());
+        assert_eq!(0, remote.blocks());
+        assert_eq!(
+            vec![super::BlockRange {
+                start: 512,
+                end: 515
+            }],
+            local.diff_range(&remote, 1, 1..3)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_validate() {
+        let f = fs::File::open("testdata/test_hashes_2M.txt").await.unwrap();
+        let mut h = super::chash(f).await.unwrap();
+        h.validate().unwrap();
+        assert!(super::Hashes::from_file_hash(&Default::default())
+            .unwrap()
+            .validate()
+            .is_err());
+        h.l[1].h[0] = super::Hash::for_string("x");
+        assert!(h.validate().is_err());
+        h.l.pop();
+        assert!(h.validate().is_err());
+    }
+
+    /// Property-based tests of the tree invariants.
+    mod props {
+        use super::super::{
+            chash, chash_concat, chash_parallel, Hash, Hashes, BLOCK_SIZE, LEVEL_GROUP,
+        };
+
+        use proptest::prelude::*;
+        use proptest::sample::Index;
+
+        /// Random bytes, or files of up to a few MiB of zeros with random patches, covering
+        /// zero blocks and trees of several levels.
+        fn content() -> impl Strategy<Value = Vec<u8>> {
+            let patch = (any::<Index>(), prop::collection::vec(1..=255u8, 1..64));
+            prop_oneof![
+                prop::collection::vec(any::<u8>(), 0..3 * BLOCK_SIZE),
+                (
+                    0..(LEVEL_GROUP + 8) * BLOCK_SIZE,
+                    prop::collection::vec(patch, 0..16)
+                )
+                    .prop_map(|(len, patches)| {
+                        let mut data = vec![0; len];
+                        for (i, p) in patches.iter().filter(|_| len > 0) {
+                            let at = i.index(len);
+                            let n = p.len().min(len - at);
+                            data[at..at + n].copy_from_slice(&p[..n]);
+                        }
+                        data
+                    }),
+            ]
+        }
+
+        fn levels(h: &Hashes) -> Vec<Vec<Hash>> {
+            (0..h.levels())
+                .map(|k| h.level(k).unwrap().to_vec())
+                .collect()
+        }
+
+        fn block_on<F: std::future::Future>(f: F) -> F::Output {
+            tokio::runtime::Builder::new_current_thread()
+                .build()
+                .unwrap()
+                .block_on(f)
+        }
+
+        proptest! {
+            #![proptest_config(ProptestConfig::with_cases(32))]
+
+            #[test]
+            fn prop_tree_invariants(data in content()) {
+                let h = block_on(chash(&data[..])).unwrap();
+                prop_assert!(h.validate().is_ok());
+                prop_assert_eq!(data.len().div_ceil(BLOCK_SIZE), h.blocks());
+                for k in 1..h.levels() {
+                    let below = h.level(k - 1).unwrap().len();
+                    prop_assert_eq!(below.div_ceil(LEVEL_GROUP).max(1), h.level(k).unwrap().len());
+                }
+                prop_assert_eq!(1, h.level(h.levels() - 1).unwrap().len());
+                for (i, block) in data.chunks(BLOCK_SIZE).enumerate() {
+                    let zero = block.iter().all(|b| *b == 0);
+                    prop_assert_eq!(zero, *h.block_hash(i).unwrap() == Hash::default());
+                }
+                let all_zero = data.iter().all(|b| *b == 0);
+                prop_assert_eq!(all_zero, *h.top_hash() == Hash::default());
+            }
+
+            #[test]
+            fn prop_chunked_reads(
+                data in content(),
+                cuts in prop::collection::vec(any::<Index>(), 0..8),
+                tasks in 1..4usize,
+            ) {
+                let whole = block_on(chash(&data[..])).unwrap();
+                let mut cuts: Vec<usize> = cuts.iter().map(|i| i.index(data.len() + 1)).collect();
+                cuts.sort();
+                let mut parts = vec![];
+                let mut start = 0;
+                for c in cuts.into_iter().chain([data.len()]) {
+                    parts.push(&data[start..c]);
+                    start = c;
+                }
+                let concat = block_on(chash_concat(parts)).unwrap();
+                prop_assert_eq!(levels(&whole), levels(&concat));
+                let parallel = block_on(chash_parallel(&data[..], tasks)).unwrap();
+                prop_assert_eq!(levels(&whole), levels(&parallel));
+            }
+
+            #[test]
+            fn prop_update_blocks(
+                n in 0..3 * LEVEL_GROUP * LEVEL_GROUP / 2,
+                seed in any::<u32>(),
+                changes in prop::collection::vec((any::<Index>(), any::<bool>()), 0..8),
+            ) {
+                let hash = |i: usize| match (i + seed as usize) % 5 {
+                    0 => Hash::default(),
+                    _ => Hash::for_string(format!("{}-{}", seed, i)),
+                };
+                let mut blocks: Vec<Hash> = (0..n).map(hash).collect();
+                let mut h = Hashes::from_block_hashes(blocks.clone());
+                prop_assert!(h.validate().is_ok());
+
+                let changed: Vec<(usize, Hash)> = changes
+                    .iter()
+                    .map(|(i, zero)| {
+                        let i = i.index(n + LEVEL_GROUP + 1);
+                        (i, if *zero { Hash::default() } else { hash(i + 1) })
+                    })
+                    .collect();
+                for (i, c) in changed.iter() {
+                    if *i >= blocks.len() {
+                        blocks.resize(*i + 1, Hash::default());
+                    }
+                    blocks[*i] = c.clone();
+                }
+                h.update_blocks(&changed);
+                prop_assert!(h.validate().is_ok());
+                prop_assert_eq!(levels(&Hashes::from_block_hashes(blocks)), levels(&h));
+            }
+        }
     }
 }
@@ -5,26 +5,90 @@
 //! of pairs, such as `&[(T0, T1)]` or `BTreeMap<T0, T1>`.
 //!
 
-use crate::http::Client;
+#[cfg(feature = "hashing")]
+use crate::hashing::{self, BLOCK_SIZE};
+use crate::hashing::{BlockRange, Hashes, LEVEL_GROUP};
+use crate::http::{Client, Request, RequestDump, TransferEvent, Transport};
 use crate::oauth2;
+use crate::remote::{RangeBuffer, RemoteFile, RemoteWriter};
+#[cfg(feature = "hashing")]
+use crate::resume::{self, PartState, Segment};
+use crate::throttle::Throttle;
 use crate::types::*;
 
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
 use anyhow::{self, Context, Result};
 use futures_util::StreamExt;
-use hyper::Method;
 use log::info;
 use reqwest;
-use tokio::io::{AsyncRead, AsyncWrite};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::Method;
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite};
+#[cfg(feature = "hashing")]
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
 use tokio_tungstenite::tungstenite::protocol::Message;
+use tokio_util::sync::CancellationToken;
 
 pub const NO_BODY: Option<reqwest::Body> = None;
 /// Use this if you don't want to supply options to a method. This prevents type errors due to
 /// unknown inner type of Option.
 pub const NO_PARAMS: Option<&Params> = None;
 
-const DEFAULT_API_BASE_URL: &str = "https://api.hidrive.strato.com/2.1";
+pub(crate) const DEFAULT_API_BASE_URL: &str = "https://api.hidrive.strato.com/2.1";
+/// Maximum number of block ranges requested at once by `HiDriveFiles::diff_hashes()`.
+const HASH_RANGES_PER_REQUEST: usize = 32;
+/// Maximum size of a single write in `upload_sparse()`.
+#[cfg(feature = "hashing")]
+const SPARSE_PATCH_SIZE: u64 = 16 << 20;
 const DEFAULT_WS_BASE_URL: &str = "wss://api.hidrive.strato.com/2.1/subscribe";
 
+/// The URLs a `HiDrive` hub talks to. Override them (see `HiDriveBuilder::endpoints()`) to use a
+/// staging environment or a local mock server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Endpoints {
+    /// Base URL for API calls.
+    pub api: String,
+    /// URL of the notification websocket.
+    pub websocket: String,
+    /// OAuth2 token endpoint.
+    pub token: String,
+}
+
+impl Default for Endpoints {
+    fn default() -> Endpoints {
+        Endpoints {
+            api: DEFAULT_API_BASE_URL.into(),
+            websocket: DEFAULT_WS_BASE_URL.into(),
+            token: oauth2::DEFAULT_TOKEN_URL.into(),
+        }
+    }
+}
+
+impl Endpoints {
+    /// Endpoints of a single server serving both the API and OAuth2, using the paths of the
+    /// HiDrive API. E.g., `Endpoints::for_host("http://127.0.0.1:8080")`.
+    pub fn for_host(base: impl AsRef<str>) -> Endpoints {
+        let base = base.as_ref().trim_end_matches('/');
+        let ws = if let Some(rest) = base.strip_prefix("https://") {
+            format!("wss://{}", rest)
+        } else if let Some(rest) = base.strip_prefix("http://") {
+            format!("ws://{}", rest)
+        } else {
+            base.to_string()
+        };
+        Endpoints {
+            api: format!("{}/2.1", base),
+            websocket: format!("{}/2.1/subscribe", ws),
+            token: format!("{}/oauth2/token", base),
+        }
+    }
+}
+
 /// The HiDrive API hub.
 ///
 /// API documentation can be found at
@@ -35,6 +99,9 @@ const DEFAULT_WS_BASE_URL: &str = "wss://api.hidrive.strato.com/2.1/subscribe";
 pub struct HiDrive {
     client: Client,
     base_url: String,
+    ws_url: String,
+    /// Cached by `HiDriveUser::home()`.
+    home: Option<Home>,
 }
 
 impl HiDrive {
@@ -42,23 +109,316 @@ impl HiDrive {
         HiDrive {
             client: Client::new(c, a),
             base_url: DEFAULT_API_BASE_URL.into(),
+            ws_url: DEFAULT_WS_BASE_URL.into(),
+            home: None,
+        }
+    }
+
+    /// Create a HiDrive hub sending all API requests through `t`.
+    pub fn new_with_transport(t: Arc<dyn Transport>, a: oauth2::Authorizer) -> HiDrive {
+        HiDrive {
+            client: Client::new_with_transport(t, a),
+            base_url: DEFAULT_API_BASE_URL.into(),
+            ws_url: DEFAULT_WS_BASE_URL.into(),
+            home: None,
+        }
+    }
+
+    /// Limit the size of JSON response bodies; larger responses fail with a `TooLarge` error.
+    /// Downloads are not affected. `None` removes the limit (default:
+    /// `http::DEFAULT_MAX_BODY_SIZE`).
+    pub fn set_max_body_size(&mut self, limit: Option<usize>) {
+        self.client.set_max_body_size(limit);
+    }
+
+    /// Cache metadata responses using ETags; see the `cache` module. Write operations issued
+    /// through this `HiDrive` invalidate affected entries.
+    pub fn set_cache(&mut self, cache: Option<Arc<dyn crate::cache::ResponseCache>>) {
+        self.client.set_cache(cache);
+    }
+
+    /// Serve `get_dir()` and `metadata()` responses from `cache` while fresh, without asking the
+    /// API; see the `cache` module. Write operations issued through this `HiDrive` and its forks
+    /// invalidate affected entries.
+    pub fn set_metadata_cache(&mut self, cache: Option<Arc<crate::cache::MetadataCache>>) {
+        self.client.set_metadata_cache(cache);
+    }
+
+    /// Fail API calls with `DeadlineExceeded` if their response doesn't arrive within `budget`,
+    /// including token refresh and retries. Downloading response bodies is not limited.
+    pub fn set_request_deadline(&mut self, budget: Option<Duration>) {
+        self.client.set_request_deadline(budget);
+    }
+
+    /// Limit the bandwidth of downloads and uploads to `throttle`, which may be shared between
+    /// several hubs. Streamed uploads (e.g. of a `tokio::fs::File`) must be wrapped using
+    /// `Throttle::stream()`.
+    pub fn set_throttle(&mut self, throttle: Option<Arc<Throttle>>) {
+        self.client.set_throttle(throttle);
+    }
+
+    pub fn throttle(&self) -> Option<Arc<Throttle>> {
+        self.client.throttle()
+    }
+
+    /// Report the server's rate limit headers to `metrics`, see `Client::set_metrics()`.
+    pub fn set_metrics(&mut self, metrics: Option<Arc<dyn crate::metrics::Metrics>>) {
+        self.client.set_metrics(metrics);
+    }
+
+    /// The rate limit headers of the latest response having any, across this hub and its forks.
+    /// After a failed call, use `types::error_rate_limit()` instead.
+    pub fn rate_limit(&self) -> Option<RateLimit> {
+        self.client.rate_limit()
+    }
+
+    /// Allow at most `limit` API requests in flight at once across this hub and its forks.
+    pub fn set_concurrency_limit(&mut self, limit: Option<usize>) {
+        self.client.set_concurrency_limit(limit);
+    }
+
+    /// A hub with the same settings and credentials, for issuing requests concurrently with this
+    /// one (the methods of a hub take `&mut self`). See `Client::fork()`.
+    pub fn fork(&self) -> HiDrive {
+        HiDrive {
+            client: self.client.fork(),
+            base_url: self.base_url.clone(),
+            ws_url: self.ws_url.clone(),
+            home: self.home.clone(),
         }
     }
 
+    /// Add `params` to every API call returning JSON, unless given explicitly. E.g., a default
+    /// `fields` selection.
+    pub fn set_default_params(&mut self, params: Params) {
+        self.client.set_default_params(params);
+    }
+
+    /// Dump every outgoing request as curl command line, with credentials masked.
+    pub fn set_request_dump(&mut self, dump: Option<RequestDump>) {
+        self.client.set_request_dump(dump);
+    }
+
+    /// Subscribe to upload and download events, e.g. for displaying transfer activity.
+    pub fn transfer_events(&mut self) -> tokio::sync::broadcast::Receiver<TransferEvent> {
+        self.client.transfer_events()
+    }
+
+    /// Access the low-level HTTP client, for calling endpoints not covered by the typed API.
+    pub fn client(&mut self) -> &mut Client {
+        &mut self.client
+    }
+
+    /// The API client as `tower::Service`, see the `service` module.
+    #[cfg(feature = "tower")]
+    pub fn service(&self) -> crate::service::ApiService {
+        crate::service::ApiService::new(self.client.fork(), self.base_url.clone())
+    }
+
+    /// The base URL for API calls, e.g. `https://api.hidrive.strato.com/2.1`.
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// Configure a new HiDrive hub. Use this instead of `new()` to adjust HTTP client settings.
+    pub fn builder(a: oauth2::Authorizer) -> HiDriveBuilder {
+        HiDriveBuilder::new(a)
+    }
+
     pub fn user(&mut self) -> HiDriveUser<'_> {
-        HiDriveUser { hd: self }
+        HiDriveUser {
+            hd: self,
+            headers: HeaderMap::new(),
+        }
+    }
+
+    pub fn app(&mut self) -> HiDriveApp<'_> {
+        HiDriveApp {
+            hd: self,
+            headers: HeaderMap::new(),
+        }
     }
 
     pub fn permissions(&mut self) -> HiDrivePermission<'_> {
-        HiDrivePermission { hd: self }
+        HiDrivePermission {
+            hd: self,
+            headers: HeaderMap::new(),
+        }
     }
 
     pub fn files(&mut self) -> HiDriveFiles<'_> {
-        HiDriveFiles { hd: self }
+        HiDriveFiles {
+            hd: self,
+            cancel: None,
+            headers: HeaderMap::new(),
+        }
     }
 
     pub async fn notifications(&mut self) -> Result<HiDriveNotifications<'_, SecureWSStream>> {
-        HiDriveNotifications::new(self, DEFAULT_WS_BASE_URL).await
+        let url = self.ws_url.clone();
+        HiDriveNotifications::new(self, url).await
+    }
+}
+
+/// HiDriveBuilder configures the HTTP client used by a `HiDrive` hub.
+///
+/// ```ignore
+/// let hd = HiDrive::builder(authorizer).brotli(false).build()?;
+/// ```
+pub struct HiDriveBuilder {
+    authz: oauth2::Authorizer,
+    transport: Option<Arc<dyn Transport>>,
+    gzip: bool,
+    brotli: bool,
+    max_body_size: Option<usize>,
+    request_deadline: Option<Duration>,
+    endpoints: Option<Endpoints>,
+    resolve: Vec<(String, SocketAddr)>,
+
+    pool_max_idle_per_host: Option<usize>,
+    pool_idle_timeout: Option<Option<Duration>>,
+    tcp_keepalive: Option<Duration>,
+    http2: bool,
+
+    default_params: Params,
+}
+
+impl HiDriveBuilder {
+    fn new(authz: oauth2::Authorizer) -> HiDriveBuilder {
+        HiDriveBuilder {
+            authz,
+            transport: None,
+            gzip: true,
+            brotli: true,
+            max_body_size: Some(crate::http::DEFAULT_MAX_BODY_SIZE),
+            request_deadline: None,
+            endpoints: None,
+            resolve: vec![],
+            pool_max_idle_per_host: None,
+            pool_idle_timeout: None,
+            tcp_keepalive: None,
+            http2: true,
+            default_params: Params::new(),
+        }
+    }
+
+    /// Send requests through `t` instead of a `reqwest::Client`. HTTP client settings of this
+    /// builder are ignored in that case.
+    pub fn transport(mut self, t: Arc<dyn Transport>) -> Self {
+        self.transport = Some(t);
+        self
+    }
+
+    /// Accept gzip-compressed responses (default: true).
+    pub fn gzip(mut self, enable: bool) -> Self {
+        self.gzip = enable;
+        self
+    }
+
+    /// Accept brotli-compressed responses (default: true).
+    pub fn brotli(mut self, enable: bool) -> Self {
+        self.brotli = enable;
+        self
+    }
+
+    /// See `HiDrive::set_max_body_size()`.
+    pub fn max_body_size(mut self, limit: Option<usize>) -> Self {
+        self.max_body_size = limit;
+        self
+    }
+
+    /// See `HiDrive::set_request_deadline()`.
+    pub fn request_deadline(mut self, budget: Duration) -> Self {
+        self.request_deadline = Some(budget);
+        self
+    }
+
+    /// Talk to `endpoints` instead of the HiDrive production servers.
+    pub fn endpoints(mut self, endpoints: Endpoints) -> Self {
+        self.endpoints = Some(endpoints);
+        self
+    }
+
+    /// Connect to `addr` for requests to `host`, bypassing DNS. Also applies to token requests.
+    /// Has no effect if a custom transport is used.
+    pub fn resolve(mut self, host: impl Into<String>, addr: SocketAddr) -> Self {
+        self.resolve.push((host.into(), addr));
+        self
+    }
+
+    /// Maximum number of idle connections kept open per host (default: unlimited).
+    pub fn pool_max_idle_per_host(mut self, max: usize) -> Self {
+        self.pool_max_idle_per_host = Some(max);
+        self
+    }
+
+    /// Close idle connections after `timeout`; `None` keeps them open indefinitely (default: 90
+    /// seconds).
+    pub fn pool_idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.pool_idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Enable TCP keepalive probes at the given interval (default: disabled).
+    pub fn tcp_keepalive(mut self, interval: Duration) -> Self {
+        self.tcp_keepalive = Some(interval);
+        self
+    }
+
+    /// Allow negotiating HTTP/2 (default: true). If false, only HTTP/1.1 is used.
+    pub fn http2(mut self, enable: bool) -> Self {
+        self.http2 = enable;
+        self
+    }
+
+    /// Parameters added to every call returning JSON unless given explicitly, most usefully a
+    /// default `fields` selection.
+    pub fn default_params(mut self, params: Params) -> Self {
+        self.default_params = params;
+        self
+    }
+
+    pub fn build(self) -> Result<HiDrive> {
+        let mut authz = self.authz;
+        if let Some(ref e) = self.endpoints {
+            authz.set_token_url(e.token.as_str());
+        }
+        let mut hd = match self.transport {
+            Some(t) => HiDrive::new_with_transport(t, authz),
+            None => {
+                let mut cb = reqwest::Client::builder()
+                    .gzip(self.gzip)
+                    .brotli(self.brotli);
+                if let Some(max) = self.pool_max_idle_per_host {
+                    cb = cb.pool_max_idle_per_host(max);
+                }
+                if let Some(timeout) = self.pool_idle_timeout {
+                    cb = cb.pool_idle_timeout(timeout);
+                }
+                if let Some(interval) = self.tcp_keepalive {
+                    cb = cb.tcp_keepalive(interval);
+                }
+                if !self.http2 {
+                    cb = cb.http1_only();
+                }
+                for (host, addr) in self.resolve.iter() {
+                    cb = cb.resolve(host, *addr);
+                }
+                let cl = cb.build().context("HiDriveBuilder: building HTTP client")?;
+                if !self.resolve.is_empty() {
+                    authz.set_client(cl.clone());
+                }
+                HiDrive::new(cl, authz)
+            }
+        };
+        hd.set_max_body_size(self.max_body_size);
+        hd.set_request_deadline(self.request_deadline);
+        if let Some(e) = self.endpoints {
+            hd.base_url = e.api;
+            hd.ws_url = e.websocket;
+        }
+        hd.set_default_params(self.default_params);
+        Ok(hd)
     }
 }
 
@@ -101,6 +461,7 @@ impl<S: AsyncRead + AsyncWrite + Unpin> HiDriveNotifications<'_, S> {
 /// Interact with user information.
 pub struct HiDriveUser<'a> {
     hd: &'a mut HiDrive,
+    headers: HeaderMap,
 }
 
 /// The /user/ API.
@@ -108,24 +469,154 @@ pub struct HiDriveUser<'a> {
 /// This will be extended in future to allow for administration. For now, it only contains
 /// bare-bones features.
 impl<'a> HiDriveUser<'a> {
+    /// Send `k: v` with every request made through this object, e.g. for tracing.
+    pub fn with_header(mut self, k: HeaderName, v: HeaderValue) -> Self {
+        self.headers.append(k, v);
+        self
+    }
+
     pub async fn me(&mut self, params: Option<&Params>) -> Result<User> {
         let u = format!("{}/user/me", self.hd.base_url);
         self.hd
             .client
             .request(Method::GET, u, &Params::new(), params)
             .await?
+            .set_headers(self.headers.clone())
             .go()
             .await
             .context("/user/me")
     }
+
+    /// GET /user/me/quota
+    pub async fn quota(&mut self) -> Result<Quota> {
+        let u = format!("{}/user/me/quota", self.hd.base_url);
+        self.hd
+            .client
+            .request(Method::GET, u, &Params::new(), None)
+            .await?
+            .set_headers(self.headers.clone())
+            .go()
+            .await
+            .context("/user/me/quota")
+    }
+
+    /// The home directory of the user. It is requested once and then cached by the `HiDrive`
+    /// handle (and forks created afterwards).
+    pub async fn home(&mut self) -> Result<Home> {
+        if let Some(ref h) = self.hd.home {
+            return Ok(h.clone());
+        }
+        let mut p = Params::new();
+        p.add_str("fields", "home,home_id");
+        let home = Home::from_user(&self.me(Some(&p)).await?);
+        if home.id.is_empty() {
+            return Err(anyhow::Error::msg("/user/me: no home directory returned"));
+        }
+        self.hd.home = Some(home.clone());
+        Ok(home)
+    }
+
+    /// PATCH /user
+    ///
+    /// Change the profile fields set in `patch`. Returns the updated user.
+    pub async fn update(&mut self, patch: &UserPatch) -> Result<User> {
+        let u = format!("{}/user", self.hd.base_url);
+        let mut rqp = Params::new();
+        patch.to_params(&mut rqp);
+        self.hd
+            .client
+            .request(Method::PATCH, u, &rqp, None)
+            .await?
+            .set_headers(self.headers.clone())
+            .go()
+            .await
+            .context("PATCH /user")
+    }
+
+    /// Set the language of the own account, e.g. `de` or `en`.
+    pub async fn set_language(&mut self, language: impl Into<String>) -> Result<User> {
+        self.update(&UserPatch {
+            language: Some(language.into()),
+            ..Default::default()
+        })
+        .await
+    }
+
+    /// Set the description (usually the full name) of the own account.
+    pub async fn set_descr(&mut self, descr: impl Into<String>) -> Result<User> {
+        self.update(&UserPatch {
+            descr: Some(descr.into()),
+            ..Default::default()
+        })
+        .await
+    }
+
+    /// Set the email address of the own account. The server may require it to be verified.
+    pub async fn set_email(&mut self, email: impl Into<String>) -> Result<User> {
+        self.update(&UserPatch {
+            email: Some(email.into()),
+            ..Default::default()
+        })
+        .await
+    }
+}
+
+/// Information about the application and its access token.
+pub struct HiDriveApp<'a> {
+    hd: &'a mut HiDrive,
+    headers: HeaderMap,
+}
+
+impl<'a> HiDriveApp<'a> {
+    /// Send `k: v` with every request made through this object, e.g. for tracing.
+    pub fn with_header(mut self, k: HeaderName, v: HeaderValue) -> Self {
+        self.headers.append(k, v);
+        self
+    }
+
+    /// GET /app/me
+    pub async fn me(&mut self, p: Option<&Params>) -> Result<App> {
+        let u = format!("{}/app/me", self.hd.base_url);
+        self.hd
+            .client
+            .request(Method::GET, u, &Params::new(), p)
+            .await?
+            .set_headers(self.headers.clone())
+            .go()
+            .await
+            .context("/app/me")
+    }
+
+    /// GET /app/me/token
+    ///
+    /// The scope and remaining lifetime of the current access token. Use `TokenInfo::grants()`
+    /// to check whether the app needs to be authorized with a wider scope.
+    pub async fn token(&mut self) -> Result<TokenInfo> {
+        let u = format!("{}/app/me/token", self.hd.base_url);
+        self.hd
+            .client
+            .request(Method::GET, u, &Params::new(), None)
+            .await?
+            .set_headers(self.headers.clone())
+            .go()
+            .await
+            .context("/app/me/token")
+    }
 }
 
 /// Interact with object permissions.
 pub struct HiDrivePermission<'a> {
     hd: &'a mut HiDrive,
+    headers: HeaderMap,
 }
 
 impl<'a> HiDrivePermission<'a> {
+    /// Send `k: v` with every request made through this object, e.g. for tracing.
+    pub fn with_header(mut self, k: HeaderName, v: HeaderValue) -> Self {
+        self.headers.append(k, v);
+        self
+    }
+
     /// GET /permission
     ///
     /// Optional parameters: `pid, account, fields`.
@@ -141,6 +632,7 @@ impl<'a> HiDrivePermission<'a> {
             .client
             .request(Method::GET, u, &rqp, p)
             .await?
+            .set_headers(self.headers.clone())
             .go()
             .await
             .context("/permission")
@@ -161,10 +653,142 @@ impl<'a> HiDrivePermission<'a> {
             .client
             .request(Method::PUT, u, &rqp, p)
             .await?
+            .set_headers(self.headers.clone())
             .go()
             .await
             .context("/permission")
     }
+
+    /// Find out what the current account may do with `path` (an absolute path, which doesn't
+    /// need to exist), and why. The flags are read from `path` or its closest existing parent;
+    /// parents are then checked for team folders, explicit permissions and sharelinks. Walking
+    /// up stops at the first parent not visible to the account.
+    pub async fn effective(&mut self, path: &str) -> Result<EffectivePermissions> {
+        let path = format!("/{}", path.trim_matches('/'));
+        let mut ancestors = vec![];
+        let mut p = path.as_str();
+        while p.len() > 1 {
+            ancestors.push(p.to_string());
+            p = &p[..p.rfind('/').unwrap_or(0)];
+        }
+        ancestors.push("/".to_string());
+        let home = self.hd.user().home().await?;
+
+        let mut flags: Option<EffectivePermissions> = None;
+        let mut teamfolder = None;
+        let mut shared = None;
+        let mut visible = vec![];
+        for a in ancestors.iter() {
+            let it = match self
+                .hd
+                .files()
+                .metadata(
+                    Identifier::Path(a.clone()),
+                    EFFECTIVE_PERMISSION_FIELDS,
+                    None,
+                )
+                .await
+            {
+                Ok(it) => it,
+                Err(e) if flags.is_none() && error_status(&e) == Some(404) => continue,
+                Err(e) if matches!(error_status(&e), Some(403) | Some(404)) => break,
+                Err(e) => return Err(e),
+            };
+            if flags.is_none() {
+                flags = Some(EffectivePermissions {
+                    readable: it.readable.unwrap_or(false),
+                    writable: it.writable.unwrap_or(false),
+                    shareable: it.shareable.unwrap_or(false),
+                    from: a.clone(),
+                    source: PermissionSource::Other,
+                    shared: None,
+                });
+            }
+            if teamfolder.is_none() && it.teamfolder == Some(true) {
+                teamfolder = Some(a.clone());
+            }
+            if shared.is_none() && it.rshare.is_some() {
+                shared = Some(a.clone());
+            }
+            visible.push(a.clone());
+        }
+        let mut perms = flags.ok_or_else(|| {
+            anyhow::Error::msg(format!(
+                "effective: neither {} nor a parent is visible",
+                path
+            ))
+        })?;
+        perms.shared = shared;
+
+        let home_dir = home.path.trim_end_matches('/');
+        perms.source = if path == home_dir || path.starts_with(&format!("{}/", home_dir)) {
+            PermissionSource::Home
+        } else if let Some(path) = teamfolder {
+            PermissionSource::TeamFolder { path }
+        } else {
+            let mut source = PermissionSource::Other;
+            for a in visible {
+                match self.get_permission(Identifier::Path(a.clone()), None).await {
+                    Ok(perm) if perm.readable || perm.writable => {
+                        source = PermissionSource::Granted { path: a };
+                        break;
+                    }
+                    Ok(_) => {}
+                    Err(e) if matches!(error_status(&e), Some(403) | Some(404)) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            source
+        };
+        info!(target: "hd_api::hidrive", "effective permissions of {}: {:?}", path, perms);
+        Ok(perms)
+    }
+}
+
+/// Where the permissions found by `HiDrivePermission::effective()` come from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PermissionSource {
+    /// Inside the account's home directory.
+    Home,
+    /// Inside the team folder at `path`.
+    TeamFolder { path: String },
+    /// Granted on `path` (itself or a parent) by its owner, see `get_permission()`.
+    Granted { path: String },
+    /// None of the above; the server didn't grant access, or the reason isn't visible to this
+    /// account.
+    Other,
+}
+
+/// The permissions of the current account on a path, see `HiDrivePermission::effective()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EffectivePermissions {
+    pub readable: bool,
+    pub writable: bool,
+    /// Whether the item may be shared, e.g. using a sharelink.
+    pub shareable: bool,
+    /// The item the permissions were read from: the path itself or, if it doesn't exist (yet),
+    /// its closest existing parent.
+    pub from: String,
+    pub source: PermissionSource,
+    /// The closest parent (or the path itself) that is shared by a sharelink, so that anyone
+    /// with the link can read it.
+    pub shared: Option<String>,
+}
+
+/// Fields requested for each parent by `HiDrivePermission::effective()`.
+const EFFECTIVE_PERMISSION_FIELDS: &str = "path,readable,writable,shareable,teamfolder,rshare.id";
+
+/// How `HiDriveFiles::upload_dedup()` transferred a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UploadMethod {
+    /// The remote file already had the same content.
+    Unchanged,
+    /// Copied on the server from the file with the same content at `from`.
+    Copied { from: String },
+    /// Only `bytes` differing from the existing remote file were uploaded.
+    Delta { bytes: u64 },
+    /// Uploaded completely.
+    Full,
 }
 
 /// Interact with files.
@@ -178,9 +802,44 @@ impl<'a> HiDrivePermission<'a> {
 ///
 pub struct HiDriveFiles<'a> {
     hd: &'a mut HiDrive,
+    cancel: Option<CancellationToken>,
+    headers: HeaderMap,
 }
 
 impl<'a> HiDriveFiles<'a> {
+    /// Abort transfers and other calls made through this object with a `Cancelled` error once
+    /// `cancel` is triggered.
+    pub fn with_cancellation(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    /// Send `k: v` with every request made through this object, e.g. for feature flags or
+    /// tracing propagation. Can be given several times.
+    pub fn with_header(mut self, k: HeaderName, v: HeaderValue) -> Self {
+        self.headers.append(k, v);
+        self
+    }
+
+    async fn request(
+        &mut self,
+        method: Method,
+        u: String,
+        rqp: &Params,
+        p: Option<&Params>,
+    ) -> Result<Request<'_>> {
+        let rq = self
+            .hd
+            .client
+            .request(method, u, rqp, p)
+            .await?
+            .set_headers(self.headers.clone());
+        Ok(match self.cancel {
+            Some(ref c) => rq.set_cancellation(c.clone()),
+            None => rq,
+        })
+    }
+
     /// Download file.
     ///
     /// Parameters: `pid, path, snapshot, snaptime`.
@@ -193,24 +852,270 @@ impl<'a> HiDriveFiles<'a> {
         let u = format!("{}/file", self.hd.base_url);
         let mut rqp = Params::new();
         id.to_params(&mut rqp, "pid", "path");
-        self.hd
-            .client
-            .request(Method::GET, u, &rqp, p)
+        self.request(Method::GET, u, &rqp, p)
             .await?
             .download_file(out)
             .await
             .context("GET /file")
     }
 
+    /// Download the bytes `range` of a file, writing them at the same offsets of `out`.
+    pub async fn get_range<D: AsyncWrite + AsyncSeek + Unpin>(
+        &mut self,
+        id: Identifier,
+        out: D,
+        range: Range<u64>,
+    ) -> Result<usize> {
+        let u = format!("{}/file", self.hd.base_url);
+        let mut rqp = Params::new();
+        id.to_params(&mut rqp, "pid", "path");
+        self.request(Method::GET, u, &rqp, None)
+            .await?
+            .download_range(out, range)
+            .await
+            .context("GET /file")
+    }
+
+    /// Download the bytes `range` of a file into memory.
+    pub async fn read_range(&mut self, id: Identifier, range: Range<u64>) -> Result<Vec<u8>> {
+        let mut buf = RangeBuffer {
+            buf: vec![],
+            start: range.start,
+        };
+        if !range.is_empty() {
+            self.get_range(id, &mut buf, range).await?;
+        }
+        Ok(buf.buf)
+    }
+
+    /// Open the file `id` for random access, see `RemoteFile`.
+    pub async fn open(&mut self, id: Identifier) -> Result<RemoteFile> {
+        let it = self.metadata(id.clone(), "id,size", None).await?;
+        let size = it
+            .size
+            .ok_or_else(|| anyhow::Error::msg("open: remote item has no size"))?;
+        let id = match it.id {
+            Some(id) => Identifier::Id(id),
+            None => id,
+        };
+        Ok(RemoteFile::new(self.hd.fork(), id, size as u64))
+    }
+
+    /// Create the file `name` in `dir` for writing, see `RemoteWriter`. No request is made until
+    /// data is written.
+    pub fn create(&mut self, dir: Identifier, name: impl Into<String>) -> RemoteWriter {
+        RemoteWriter::new(self.hd.fork(), dir, name.into())
+    }
+
+    /// Download file to the local file `path`. If the download fails or is cancelled, the
+    /// partially written file is removed.
+    pub async fn download_to_path(
+        &mut self,
+        id: Identifier,
+        path: impl AsRef<Path>,
+        p: Option<&Params>,
+    ) -> Result<usize> {
+        let path = path.as_ref();
+        let f = tokio::fs::File::create(path)
+            .await
+            .with_context(|| format!("creating {:?}", path))?;
+        match self.get(id, f, p).await {
+            Ok(n) => Ok(n),
+            Err(e) => {
+                let _ = tokio::fs::remove_file(path).await;
+                Err(e)
+            }
+        }
+    }
+
+    /// Update the local file `path` to match the remote file `id`, downloading only blocks whose
+    /// hashes differ (see `diff_hashes()`) using range requests, and writing them into the file
+    /// in place. The file is extended or truncated to the remote size. Returns the number of
+    /// bytes downloaded.
+    ///
+    /// If the download fails, the file is left partially updated; calling this again resumes it.
+    #[cfg(feature = "hashing")]
+    pub async fn download_delta(
+        &mut self,
+        id: Identifier,
+        path: impl AsRef<Path>,
+    ) -> Result<usize> {
+        let path = path.as_ref();
+        let remote_size = self
+            .metadata(id.clone(), "size", None)
+            .await?
+            .size
+            .ok_or_else(|| anyhow::Error::msg("download_delta: remote item has no size"))?
+            as u64;
+        let local_size = tokio::fs::metadata(path)
+            .await
+            .with_context(|| format!("reading metadata of {:?}", path))?
+            .len();
+
+        let mut ranges = vec![];
+        if local_size > 0 {
+            let local = hashing::chash_file(path).await?;
+            ranges = self
+                .diff_hashes(id.clone(), &local)
+                .await?
+                .iter()
+                .map(BlockRange::bytes)
+                .collect();
+        }
+        // Blocks beyond the end of the local file aren't compared.
+        let tail = local_size.div_ceil(BLOCK_SIZE as u64) * BLOCK_SIZE as u64;
+        if remote_size > tail {
+            match ranges.last_mut() {
+                Some((_, end)) if *end == tail => *end = remote_size,
+                _ => ranges.push((tail, remote_size)),
+            }
+        }
+
+        let mut f = tokio::fs::OpenOptions::new()
+            .write(true)
+            .open(path)
+            .await
+            .with_context(|| format!("opening {:?}", path))?;
+        f.set_len(remote_size).await?;
+        let mut n = 0;
+        for (start, end) in ranges {
+            let end = u64::min(end, remote_size);
+            if start >= end {
+                continue;
+            }
+            info!(target: "hd_api::hidrive", "download_delta: fetching bytes {}-{}", start, end);
+            n += self.get_range(id.clone(), &mut f, start..end).await?;
+        }
+        f.sync_all().await?;
+        Ok(n)
+    }
+
+    /// Download the remote file `id` to `path` as a sparse file: ranges consisting only of zeros
+    /// (found by comparing the remote hash tree to that of an all-zero file) are neither
+    /// downloaded nor written, leaving holes on file systems supporting them. Returns the number
+    /// of bytes downloaded.
+    #[cfg(feature = "hashing")]
+    pub async fn download_sparse(
+        &mut self,
+        id: Identifier,
+        path: impl AsRef<Path>,
+    ) -> Result<usize> {
+        let path = path.as_ref();
+        let size = self
+            .metadata(id.clone(), "size", None)
+            .await?
+            .size
+            .ok_or_else(|| anyhow::Error::msg("download_sparse: remote item has no size"))?
+            as u64;
+        let mut f = tokio::fs::File::create(path)
+            .await
+            .with_context(|| format!("creating {:?}", path))?;
+        f.set_len(size).await?;
+        if size == 0 {
+            return Ok(0);
+        }
+        let blocks = size.div_ceil(BLOCK_SIZE as u64) as usize;
+        let ranges = self.diff_hashes(id.clone(), &Hashes::zeros(blocks)).await?;
+        let mut n = 0;
+        for r in ranges {
+            let (start, end) = r.bytes();
+            let end = u64::min(end, size);
+            let u = format!("{}/file", self.hd.base_url);
+            let mut rqp = Params::new();
+            id.to_params(&mut rqp, "pid", "path");
+            n += self
+                .request(Method::GET, u, &rqp, None)
+                .await?
+                .download_range(&mut f, start..end)
+                .await
+                .context("GET /file")?;
+        }
+        info!(target: "hd_api::hidrive", "download_sparse: downloaded {} of {} bytes", n, size);
+        Ok(n)
+    }
+
+    /// Download the remote file `id` to `path`, resumably: data is written to `<path>.part`, and
+    /// the ranges already downloaded are recorded in `<path>.part.json`. If the download is
+    /// interrupted, even by a restart of the process, calling this again continues where it
+    /// stopped, as long as the remote file hasn't changed. Once complete, the file's `chash` is
+    /// verified and it is moved to `path`. Returns the number of bytes downloaded by this call.
+    #[cfg(feature = "hashing")]
+    pub async fn download_resumable(
+        &mut self,
+        id: Identifier,
+        path: impl AsRef<Path>,
+    ) -> Result<usize> {
+        let path = path.as_ref();
+        let it = self.metadata(id.clone(), "size,chash", None).await?;
+        let (size, chash) = match (it.size, it.chash) {
+            (Some(size), Some(chash)) => (size as u64, chash),
+            _ => {
+                return Err(anyhow::Error::msg(
+                    "download_resumable: remote item has no size or chash",
+                ))
+            }
+        };
+        let mut state = match PartState::load(path, size, &chash).await {
+            Ok(Some(st)) => st,
+            _ => PartState::new(size, chash.clone()),
+        };
+        let part = resume::part_path(path);
+        let mut f = tokio::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(state.done.is_empty())
+            .open(&part)
+            .await
+            .with_context(|| format!("opening {:?}", part))?;
+        f.set_len(size).await?;
+
+        let mut n = 0;
+        let mut start = 0;
+        while start < size {
+            let end = u64::min(start + resume::SEGMENT_SIZE, size);
+            if !state.is_done(start, end) {
+                let u = format!("{}/file", self.hd.base_url);
+                let mut rqp = Params::new();
+                id.to_params(&mut rqp, "pid", "path");
+                n += self
+                    .request(Method::GET, u, &rqp, None)
+                    .await?
+                    .download_range(&mut f, start..end)
+                    .await
+                    .context("GET /file")?;
+                f.sync_data().await?;
+                let hash = resume::hash_range(&mut f, start, end).await?;
+                state.done.push(Segment { start, end, hash });
+                state.save(path).await?;
+            }
+            start = end;
+        }
+        drop(f);
+
+        let state_path = resume::state_path(path);
+        if hashing::chash_file(&part).await?.top_hash() != &chash {
+            let _ = tokio::fs::remove_file(&part).await;
+            let _ = tokio::fs::remove_file(&state_path).await;
+            return Err(anyhow::Error::msg(format!(
+                "download_resumable: downloaded file doesn't match chash {}",
+                chash
+            )));
+        }
+        tokio::fs::rename(&part, path)
+            .await
+            .with_context(|| format!("renaming {:?}", part))?;
+        let _ = tokio::fs::remove_file(&state_path).await;
+        Ok(n)
+    }
+
     /// Obtain a public URL valid for 6 hours.
     ///
     pub async fn url(&mut self, id: Identifier, p: Option<&Params>) -> Result<Url> {
         let u = format!("{}/file/url", self.hd.base_url);
         let mut rqp = Params::new();
         id.to_params(&mut rqp, "pid", "path");
-        self.hd
-            .client
-            .request(Method::GET, u, &rqp, p)
+        self.request(Method::GET, u, &rqp, p)
             .await?
             .go()
             .await
@@ -242,36 +1147,226 @@ impl<'a> HiDriveFiles<'a> {
     /// Parameter `name` specifies the file name to be acted on.
     pub async fn upload<S: AsRef<str>, R: Into<reqwest::Body>>(
         &mut self,
-        dir: Identifier,
-        name: S,
-        src: R,
-        p: Option<&Params>,
-    ) -> Result<Item> {
-        self.upload_(dir, name, src, p, Method::PUT).await
+        dir: Identifier,
+        name: S,
+        src: R,
+        p: Option<&Params>,
+    ) -> Result<Item> {
+        self.upload_(dir, name, src, p, Method::PUT).await
+    }
+
+    async fn upload_(
+        &mut self,
+        id: Identifier,
+        name: impl AsRef<str>,
+        src: impl Into<reqwest::Body>,
+        p: Option<&Params>,
+        method: Method,
+    ) -> Result<Item> {
+        let u = format!("{}/file", self.hd.base_url);
+        let mut rqp = Params::new();
+        id.to_params(&mut rqp, "dir_id", "dir");
+        rqp.add_str("name", name.as_ref());
+        let method_ = method.clone();
+        let ctx = || format!("{} /file", method_);
+        self.request(method, u, &rqp, p)
+            .await?
+            .set_attachment(src)
+            .go()
+            .await
+            .with_context(ctx)
+    }
+
+    /// Overwrite the file `id` starting at `offset` with the data from `src`.
+    pub async fn patch_file<R: Into<reqwest::Body>>(
+        &mut self,
+        id: Identifier,
+        offset: u64,
+        src: R,
+        p: Option<&Params>,
+    ) -> Result<()> {
+        let u = format!("{}/file", self.hd.base_url);
+        let mut rqp = Params::new();
+        id.to_params(&mut rqp, "pid", "path");
+        rqp.add_str("offset", offset.to_string());
+        let _: Item = self
+            .request(Method::PATCH, u, &rqp, p)
+            .await?
+            .set_attachment(src)
+            .go()
+            .await
+            .context("PATCH /file")?;
+        Ok(())
+    }
+
+    /// Set the modification time of the file `id` to `mtime` (seconds since the epoch) without
+    /// changing its content, by patching zero bytes.
+    pub async fn set_mtime(&mut self, id: Identifier, mtime: i64) -> Result<()> {
+        let mut p = Params::new();
+        p.add_int("mtime", mtime as isize);
+        self.patch_file(id, 0, Vec::<u8>::new(), Some(&p))
+            .await
+            .context("setting mtime")
+    }
+
+    /// Upload the local file `path` as `name` into `dir`, skipping blocks consisting only of
+    /// zeros: an empty file is uploaded and extended to the full size (which creates a sparse
+    /// file on the server), then the ranges containing data are written. Returns the number of
+    /// bytes uploaded.
+    #[cfg(feature = "hashing")]
+    pub async fn upload_sparse(
+        &mut self,
+        dir: Identifier,
+        name: impl AsRef<str>,
+        path: impl AsRef<Path>,
+    ) -> Result<u64> {
+        let path = path.as_ref();
+        let local = hashing::chash_file(path).await?;
+        self.upload_sparse_(dir, name.as_ref(), path, &local).await
+    }
+
+    #[cfg(feature = "hashing")]
+    async fn upload_sparse_(
+        &mut self,
+        dir: Identifier,
+        name: &str,
+        path: &Path,
+        local: &Hashes,
+    ) -> Result<u64> {
+        let size = tokio::fs::metadata(path)
+            .await
+            .with_context(|| format!("reading metadata of {:?}", path))?
+            .len();
+        let target = dir.join(name);
+        self.upload(dir, name, Vec::<u8>::new(), None).await?;
+        self.truncate(target.clone(), size as usize, None).await?;
+        let mut f = tokio::fs::File::open(path).await?;
+        let mut bytes = 0;
+        for r in local.data_ranges() {
+            let (start, end) = r.bytes();
+            let end = u64::min(end, size);
+            bytes += self.patch_from(&target, &mut f, start, end).await?;
+        }
+        info!(
+            target: "hd_api::hidrive",
+            "upload_sparse: uploaded {} of {} bytes of {}",
+            bytes,
+            size,
+            name
+        );
+        Ok(bytes)
+    }
+
+    /// Write the bytes `start..end` of `f` to the same range of `target`, returning how many
+    /// bytes were written. Large ranges are sent in several requests, to limit memory use.
+    #[cfg(feature = "hashing")]
+    async fn patch_from(
+        &mut self,
+        target: &Identifier,
+        f: &mut tokio::fs::File,
+        start: u64,
+        end: u64,
+    ) -> Result<u64> {
+        let mut offset = start;
+        while offset < end {
+            let len = u64::min(end - offset, SPARSE_PATCH_SIZE);
+            let mut data = vec![0; len as usize];
+            f.seek(std::io::SeekFrom::Start(offset)).await?;
+            f.read_exact(&mut data).await?;
+            self.patch_file(target.clone(), offset, data, None).await?;
+            offset += len;
+        }
+        Ok(end.saturating_sub(start))
     }
 
-    async fn upload_(
+    /// Upload the local file `path` as `name` into `dir`, avoiding transferring data the server
+    /// already has:
+    ///
+    /// 1. If `dir` or one of the `probe` directories contains a file with the same `chash`, e.g.
+    ///    a prior version under a different name, it is copied on the server.
+    /// 2. Otherwise, if `name` exists in `dir`, only blocks differing from it are uploaded (see
+    ///    `diff_hashes()` and `patch_file()`).
+    /// 3. Otherwise, the file is uploaded completely, skipping zero blocks (see
+    ///    `upload_sparse()`).
+    #[cfg(feature = "hashing")]
+    pub async fn upload_dedup(
         &mut self,
-        id: Identifier,
+        dir: Identifier,
         name: impl AsRef<str>,
-        src: impl Into<reqwest::Body>,
-        p: Option<&Params>,
-        method: Method,
-    ) -> Result<Item> {
-        let u = format!("{}/file", self.hd.base_url);
-        let mut rqp = Params::new();
-        id.to_params(&mut rqp, "dir_id", "dir");
-        rqp.add_str("name", name.as_ref());
-        let method_ = method.clone();
-        let ctx = || format!("{} /file", method_);
-        self.hd
-            .client
-            .request(method, u, &rqp, p)
-            .await?
-            .set_attachment(src)
-            .go()
+        path: impl AsRef<Path>,
+        probe: &[Identifier],
+    ) -> Result<UploadMethod> {
+        let (name, path) = (name.as_ref(), path.as_ref());
+        let size = tokio::fs::metadata(path)
             .await
-            .with_context(ctx)
+            .with_context(|| format!("reading metadata of {:?}", path))?
+            .len();
+        let local = hashing::chash_file(path).await?;
+        let chash = local.top_hash();
+        let target = dir.join(name);
+
+        let mut p = Params::new();
+        p.add_str("members", "file").add_str(
+            "fields",
+            "members.path,members.name,members.size,members.chash",
+        );
+        let listing = self.get_dir(dir.clone(), Some(&p)).await?;
+        let existing = listing
+            .members
+            .iter()
+            .find(|m| m.name.as_deref() == Some(name))
+            .map(|m| (m.chash.clone(), m.size.unwrap_or(0) as u64));
+        if let Some((Some(ref h), _)) = existing {
+            if h == chash {
+                return Ok(UploadMethod::Unchanged);
+            }
+        }
+
+        let mut candidates = listing.members;
+        for d in probe {
+            candidates.extend(self.get_dir(d.clone(), Some(&p)).await?.members);
+        }
+        let same = candidates
+            .into_iter()
+            .find(|m| m.chash.as_ref() == Some(chash) && m.size.map(|s| s as u64) == Some(size));
+        if let Some(m) = same {
+            info!(target: "hd_api::hidrive", "upload_dedup: copying {} to {}", m.path, name);
+            let mut cp = Params::new();
+            cp.add_on_exist(OnExist::Overwrite);
+            self.copy(Identifier::Path(m.path.clone()), target, Some(&cp))
+                .await?;
+            return Ok(UploadMethod::Copied { from: m.path });
+        }
+
+        if let (Some((_, remote_size)), true) = (existing, size > 0) {
+            let ranges = self.diff_hashes(target.clone(), &local).await?;
+            if remote_size != size {
+                self.truncate(target.clone(), size as usize, None).await?;
+            }
+            let mut f = tokio::fs::File::open(path).await?;
+            let mut bytes = 0;
+            for r in ranges {
+                let (start, end) = r.bytes();
+                let end = u64::min(end, size);
+                bytes += self.patch_from(&target, &mut f, start, end).await?;
+            }
+            info!(
+                target: "hd_api::hidrive",
+                "upload_dedup: uploaded {} of {} bytes of {}",
+                bytes,
+                size,
+                name
+            );
+            return Ok(UploadMethod::Delta { bytes });
+        }
+
+        if local.zero_ranges().is_empty() {
+            let f = tokio::fs::File::open(path).await?;
+            self.upload(dir, name, f, None).await?;
+        } else {
+            self.upload_sparse_(dir, name, path, &local).await?;
+        }
+        Ok(UploadMethod::Full)
     }
 
     /// Truncate a file to the specified size. If `size` is greater than the current size, a sparse
@@ -286,9 +1381,7 @@ impl<'a> HiDriveFiles<'a> {
         let mut rqp = Params::new();
         rqp.add_uint("size", size);
         id.to_params(&mut rqp, "pid", "path");
-        self.hd
-            .client
-            .request(Method::POST, u, &rqp, p)
+        self.request(Method::POST, u, &rqp, p)
             .await?
             .go()
             .await
@@ -310,9 +1403,7 @@ impl<'a> HiDriveFiles<'a> {
         let mut rqp = Params::new();
         from.to_params(&mut rqp, "src_id", "src");
         to.to_params(&mut rqp, "dst_id", "dst");
-        self.hd
-            .client
-            .request(Method::POST, u, &rqp, p)
+        self.request(Method::POST, u, &rqp, p)
             .await?
             .go()
             .await
@@ -332,9 +1423,7 @@ impl<'a> HiDriveFiles<'a> {
         let mut rqp = Params::new();
         from.to_params(&mut rqp, "src_id", "src");
         to.to_params(&mut rqp, "dst_id", "dst");
-        self.hd
-            .client
-            .request(Method::POST, u, &rqp, p)
+        self.request(Method::POST, u, &rqp, p)
             .await?
             .go()
             .await
@@ -355,9 +1444,7 @@ impl<'a> HiDriveFiles<'a> {
         let mut rqp = Params::new();
         rqp.add_str("name", name);
         id.to_params(&mut rqp, "pid", "path");
-        self.hd
-            .client
-            .request(Method::GET, u, &rqp, p)
+        self.request(Method::GET, u, &rqp, p)
             .await?
             .go()
             .await
@@ -369,9 +1456,7 @@ impl<'a> HiDriveFiles<'a> {
         let u = format!("{}/file", self.hd.base_url);
         let mut rqp = Params::new();
         id.to_params(&mut rqp, "pid", "path");
-        self.hd
-            .client
-            .request(Method::DELETE, u, &rqp, p)
+        self.request(Method::DELETE, u, &rqp, p)
             .await?
             .go()
             .await
@@ -390,9 +1475,7 @@ impl<'a> HiDriveFiles<'a> {
         let u = format!("{}/file/thumbnail", self.hd.base_url);
         let mut rqp = Params::new();
         id.to_params(&mut rqp, "pid", "path");
-        self.hd
-            .client
-            .request(Method::GET, u, &rqp, p)
+        self.request(Method::GET, u, &rqp, p)
             .await?
             .download_file(dst)
             .await
@@ -410,10 +1493,9 @@ impl<'a> HiDriveFiles<'a> {
         let mut rqp = Params::new();
         id.to_params(&mut rqp, "pid", "path");
         rqp.add_str("fields", fields);
-        self.hd
-            .client
-            .request(Method::GET, u, &rqp, p)
+        self.request(Method::GET, u, &rqp, p)
             .await?
+            .set_cacheable()
             .go()
             .await
             .context("/meta")
@@ -432,8 +1514,6 @@ impl<'a> HiDriveFiles<'a> {
             rqp.add_str("fields", fields);
         }
         let r: SearchResult = self
-            .hd
-            .client
             .request(Method::GET, u, &rqp, p)
             .await?
             .go()
@@ -451,10 +1531,9 @@ impl<'a> HiDriveFiles<'a> {
         let u = format!("{}/dir", self.hd.base_url);
         let mut rqp = Params::new();
         id.to_params(&mut rqp, "pid", "path");
-        self.hd
-            .client
-            .request(Method::GET, u, &rqp, p)
+        self.request(Method::GET, u, &rqp, p)
             .await?
+            .set_cacheable()
             .go()
             .await
             .context("GET /dir")
@@ -465,9 +1544,7 @@ impl<'a> HiDriveFiles<'a> {
     /// Further parameters: `members, limit, snapshot, snaptime, fields, sort`.
     pub async fn get_home_dir(&mut self, p: Option<&Params>) -> Result<Item> {
         let u = format!("{}/dir/home", self.hd.base_url);
-        self.hd
-            .client
-            .request(Method::GET, u, &Params::new(), p)
+        self.request(Method::GET, u, &Params::new(), p)
             .await?
             .go()
             .await
@@ -483,9 +1560,7 @@ impl<'a> HiDriveFiles<'a> {
         let u = format!("{}/dir", self.hd.base_url);
         let mut rqp = Params::new();
         id.to_params(&mut rqp, "pid", "path");
-        self.hd
-            .client
-            .request(Method::POST, u, &rqp, p)
+        self.request(Method::POST, u, &rqp, p)
             .await?
             .go()
             .await
@@ -499,9 +1574,7 @@ impl<'a> HiDriveFiles<'a> {
         let u = format!("{}/dir", self.hd.base_url);
         let mut rqp = Params::new();
         id.to_params(&mut rqp, "pid", "path");
-        self.hd
-            .client
-            .request(Method::DELETE, u, &rqp, p)
+        self.request(Method::DELETE, u, &rqp, p)
             .await?
             .go()
             .await
@@ -522,9 +1595,7 @@ impl<'a> HiDriveFiles<'a> {
         let mut rqp = Params::new();
         from.to_params(&mut rqp, "src_id", "src");
         to.to_params(&mut rqp, "dst_id", "dst");
-        self.hd
-            .client
-            .request(Method::POST, u, &rqp, p)
+        self.request(Method::POST, u, &rqp, p)
             .await?
             .go()
             .await
@@ -545,9 +1616,7 @@ impl<'a> HiDriveFiles<'a> {
         let mut rqp = Params::new();
         from.to_params(&mut rqp, "src_id", "src");
         to.to_params(&mut rqp, "dst_id", "dst");
-        self.hd
-            .client
-            .request(Method::POST, u, &rqp, p)
+        self.request(Method::POST, u, &rqp, p)
             .await?
             .go()
             .await
@@ -568,9 +1637,7 @@ impl<'a> HiDriveFiles<'a> {
         let mut rqp = Params::new();
         rqp.add_str("name", name);
         dir.to_params(&mut rqp, "pid", "path");
-        self.hd
-            .client
-            .request(Method::POST, u, &rqp, p)
+        self.request(Method::POST, u, &rqp, p)
             .await?
             .go()
             .await
@@ -603,12 +1670,1153 @@ impl<'a> HiDriveFiles<'a> {
                 .fold(String::new(), |s, e| (s + ",") + &e);
             rqp.add_str("ranges", &r[1..]);
         }
-        self.hd
-            .client
-            .request(Method::GET, u, &rqp, p)
+        self.request(Method::GET, u, &rqp, p)
             .await?
             .go()
             .await
             .context("/file/hash")
     }
+
+    /// Compare the local hash tree `local` (see `hashing::chash()`) to that of the remote file
+    /// `id`, and return the blocks that differ. Starting at the top, only differing branches of
+    /// the tree are requested. Blocks beyond the end of the local file are not reported.
+    pub async fn diff_hashes(&mut self, id: Identifier, local: &Hashes) -> Result<Vec<BlockRange>> {
+        let mut level = local.levels() - 1;
+        let mut candidates = vec![(0, 0)];
+        loop {
+            let mut differing = vec![];
+            for batch in candidates.chunks(HASH_RANGES_PER_REQUEST) {
+                let fh = self.hash(id.clone(), level, batch, None).await?;
+                for (i, range) in batch.iter().enumerate() {
+                    let remote = fh.list.get(i).map_or(&[][..], |l| l.as_slice());
+                    differing.extend(local.diff_remote(level, *range, remote));
+                }
+            }
+            if level == 0 || differing.is_empty() {
+                return Ok(crate::hashing::entries_to_ranges(level, &differing));
+            }
+            level -= 1;
+            let len = local.level(level).map_or(0, |l| l.len());
+            candidates = differing
+                .into_iter()
+                .map(|j| (j * LEVEL_GROUP, usize::min((j + 1) * LEVEL_GROUP, len) - 1))
+                .collect();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::mock::{hidrive, MockTransport, TOKEN_RESPONSE};
+
+    /// A `/file/hash` response listing the blocks `first..=last` of `level` of `tree`.
+    fn hash_listing(tree: &Hashes, level: usize, first: usize, last: usize) -> String {
+        let list = (first..=last)
+            .map(|i| HashedBlock {
+                hash: tree.level(level).unwrap()[i].clone(),
+                level,
+                block: i,
+            })
+            .collect();
+        serde_json::to_string(&FileHash {
+            level,
+            chash: tree.top_hash().clone(),
+            list: vec![list],
+        })
+        .unwrap()
+    }
+
+    /// A path in the temporary directory, unique to this test process.
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("hd_api_test_{}_{}", name, std::process::id()))
+    }
+
+    #[tokio::test]
+    async fn test_user_me() {
+        let t = MockTransport::new();
+        t.push(
+            200,
+            r#"{"account": "acc", "home": "root/users/me", "home_id": "b1.4"}"#,
+        );
+        let mut hd = hidrive(t.clone());
+        let me = hd.user().me(None).await.unwrap();
+        assert_eq!("root/users/me", me.home);
+        assert_eq!("b1.4", me.home_id);
+        let rq = t.last();
+        assert_eq!(Method::GET, rq.method);
+        assert_eq!("/2.1/user/me", rq.url.path());
+    }
+
+    #[tokio::test]
+    async fn test_user_update() {
+        let t = MockTransport::new();
+        t.push(200, r#"{"account": "acc", "language": "de"}"#);
+        let mut hd = hidrive(t.clone());
+        let u = hd.user().set_language("de").await.unwrap();
+        assert_eq!("de", u.language);
+        let rq = t.last();
+        assert_eq!(Method::PATCH, rq.method);
+        assert_eq!("/2.1/user", rq.url.path());
+        assert_eq!(Some("de"), rq.param("language").as_deref());
+        assert_eq!(None, rq.param("descr"));
+
+        t.push(200, r#"{"account": "other", "descr": "A B"}"#);
+        let patch = UserPatch {
+            account: Some("other".into()),
+            descr: Some("A B".into()),
+            ..Default::default()
+        };
+        let u = hd.user().update(&patch).await.unwrap();
+        assert_eq!("A B", u.descr);
+        let rq = t.last();
+        assert_eq!(Some("other"), rq.param("account").as_deref());
+        assert_eq!(Some("A B"), rq.param("descr").as_deref());
+
+        t.push(403, r#"{"code": 403, "msg": "Forbidden"}"#);
+        let err = hd.user().set_descr("x").await.unwrap_err();
+        assert_eq!(Some(403), error_status(&err));
+    }
+
+    #[tokio::test]
+    async fn test_app() {
+        let t = MockTransport::new();
+        t.push(200, r#"{"id": "app1", "name": "hd4linux"}"#);
+        t.push(
+            200,
+            r#"{"app_id": "app1", "scope": "ro,user", "expires_in": 60}"#,
+        );
+        let mut hd = hidrive(t.clone());
+        assert_eq!("hd4linux", hd.app().me(None).await.unwrap().name);
+        assert_eq!("/2.1/app/me", t.last().url.path());
+        let tok = hd.app().token().await.unwrap();
+        assert_eq!("/2.1/app/me/token", t.last().url.path());
+        assert_eq!(60, tok.expires_in);
+        assert!(!tok.grants(&oauth2::Scope {
+            role: oauth2::Role::User,
+            access: oauth2::Access::Rw,
+        }));
+    }
+
+    #[tokio::test]
+    async fn test_home() {
+        let t = MockTransport::new();
+        t.push(
+            200,
+            r#"{"account": "acc", "home": "root/users/me", "home_id": "b1.4"}"#,
+        );
+        let mut hd = hidrive(t.clone());
+        let home = hd.user().home().await.unwrap();
+        assert_eq!("/users/me", home.path);
+        assert_eq!("b1.4", home.id);
+        assert_eq!(Some("home,home_id"), t.last().param("fields").as_deref());
+
+        // Cached, also for forks.
+        assert_eq!(home, hd.fork().user().home().await.unwrap());
+        assert_eq!(1, t.requests().len());
+
+        t.push(200, r#"{"path": "/users/me/a"}"#);
+        hd.files().get_dir(home.join("a/"), None).await.unwrap();
+        assert_eq!(Some("b1.4"), t.last().param("pid").as_deref());
+        assert_eq!(Some("a"), t.last().param("path").as_deref());
+        assert!(matches!(home.join(""), Identifier::Id(ref id) if id == "b1.4"));
+    }
+
+    #[tokio::test]
+    async fn test_effective_permissions() {
+        let t = MockTransport::new();
+        let not_found = r#"{"code": 404, "msg": "Not Found"}"#;
+        let forbidden = r#"{"code": 403, "msg": "Forbidden"}"#;
+        t.push(200, r#"{"home": "root/users/me", "home_id": "b1.4"}"#);
+        t.push(404, not_found);
+        t.push(
+            200,
+            r#"{"path": "/public/team/docs", "readable": true, "writable": true}"#,
+        );
+        t.push(200, r#"{"path": "/public/team", "teamfolder": true}"#);
+        t.push(403, forbidden);
+        let mut hd = hidrive(t.clone());
+        let e = hd
+            .permissions()
+            .effective("/public/team/docs/new.txt")
+            .await
+            .unwrap();
+        assert!(e.readable && e.writable && !e.shareable);
+        assert_eq!("/public/team/docs", e.from);
+        assert_eq!(
+            PermissionSource::TeamFolder {
+                path: "/public/team".into()
+            },
+            e.source
+        );
+        assert_eq!(None, e.shared);
+        let paths: Vec<String> = t
+            .requests()
+            .iter()
+            .skip(1)
+            .map(|r| r.param("path").unwrap())
+            .collect();
+        assert_eq!(
+            vec![
+                "/public/team/docs/new.txt",
+                "/public/team/docs",
+                "/public/team",
+                "/public"
+            ],
+            paths
+        );
+
+        // Shared with us by another user; the home directory is cached.
+        t.push(200, r#"{"path": "/users/o/s/a.txt", "readable": true}"#);
+        t.push(200, r#"{"path": "/users/o/s", "rshare": {"id": "s1"}}"#);
+        t.push(403, forbidden);
+        t.push(200, r#"{"path": "/users/o/s/a.txt"}"#);
+        t.push(
+            200,
+            r#"{"path": "/users/o/s", "readable": true, "account": "me"}"#,
+        );
+        let e = hd
+            .permissions()
+            .effective("users/o/s/a.txt/")
+            .await
+            .unwrap();
+        assert!(e.readable && !e.writable);
+        assert_eq!(
+            PermissionSource::Granted {
+                path: "/users/o/s".into()
+            },
+            e.source
+        );
+        assert_eq!(Some("/users/o/s".into()), e.shared);
+        assert_eq!("/2.1/permission", t.last().url.path());
+
+        t.push(
+            200,
+            r#"{"path": "/users/me/x", "readable": true, "writable": true}"#,
+        );
+        t.push(200, r#"{"path": "/users/me"}"#);
+        t.push(403, forbidden);
+        let e = hd.permissions().effective("/users/me/x").await.unwrap();
+        assert_eq!(PermissionSource::Home, e.source);
+
+        t.push(403, forbidden);
+        assert!(hd.permissions().effective("/users/o").await.is_err());
+
+        // The root is checked, too.
+        t.push(200, r#"{"path": "/", "readable": true}"#);
+        t.push(403, forbidden);
+        let e = hd.permissions().effective("/").await.unwrap();
+        assert!(e.readable && !e.writable);
+        assert_eq!("/", e.from);
+        assert_eq!(PermissionSource::Other, e.source);
+        let rqs = t.requests();
+        assert_eq!(Some("/".into()), rqs[rqs.len() - 2].param("path"));
+    }
+
+    #[tokio::test]
+    async fn test_quota() {
+        let t = MockTransport::new();
+        t.push(200, r#"{"limit": 100, "used": 130}"#);
+        let mut hd = hidrive(t.clone());
+        let q = hd.user().quota().await.unwrap();
+        assert_eq!(100, q.limit);
+        assert_eq!(0, q.free());
+        assert_eq!("/2.1/user/me/quota", t.last().url.path());
+    }
+
+    #[tokio::test]
+    async fn test_get_dir() {
+        let t = MockTransport::new();
+        t.push(
+            200,
+            r#"{"path": "/users/me/dir", "members": [{"path": "/users/me/dir/a", "name": "a", "size": 3}]}"#,
+        );
+        let mut hd = hidrive(t.clone());
+        let mut p = Params::new();
+        p.add_str("fields", "members");
+        let dir = hd
+            .files()
+            .get_dir(Identifier::Path("/users/me/dir".into()), Some(&p))
+            .await
+            .unwrap();
+        assert_eq!(1, dir.members.len());
+        assert_eq!(Some(3), dir.members[0].size);
+        let rq = t.last();
+        assert_eq!("/2.1/dir", rq.url.path());
+        assert_eq!(Some("/users/me/dir".into()), rq.param("path"));
+        assert_eq!(Some("members".into()), rq.param("fields"));
+    }
+
+    #[test]
+    fn test_endpoints_for_host() {
+        let e = Endpoints::for_host("http://127.0.0.1:8080/");
+        assert_eq!("http://127.0.0.1:8080/2.1", e.api);
+        assert_eq!("ws://127.0.0.1:8080/2.1/subscribe", e.websocket);
+        assert_eq!("http://127.0.0.1:8080/oauth2/token", e.token);
+        assert_eq!(
+            "wss://staging.example.com/2.1/subscribe",
+            Endpoints::for_host("https://staging.example.com").websocket
+        );
+    }
+
+    #[tokio::test]
+    async fn test_endpoints() {
+        let t = MockTransport::new();
+        let cred: oauth2::Credentials = serde_json::from_str(TOKEN_RESPONSE).unwrap();
+        let authz = oauth2::Authorizer::new_with_transport(
+            cred,
+            oauth2::ClientSecret::default(),
+            t.clone(),
+        );
+        let mut hd = HiDrive::builder(authz)
+            .transport(t.clone())
+            .endpoints(Endpoints::for_host("http://127.0.0.1:8080"))
+            .build()
+            .unwrap();
+        assert_eq!("http://127.0.0.1:8080/2.1", hd.base_url());
+        hd.user().me(None).await.unwrap();
+        assert_eq!("http://127.0.0.1:8080/2.1/user/me", t.last().url.as_str());
+    }
+
+    #[tokio::test]
+    async fn test_default_params() {
+        let t = MockTransport::new();
+        let mut defaults = Params::new();
+        defaults.add_str("fields", "path,id").add_uint("limit", 100);
+        let mut hd = hidrive(t.clone());
+        hd.set_default_params(defaults);
+        let mut p = Params::new();
+        p.add_str("fields", "name");
+        hd.files()
+            .get_dir(Identifier::Path("/users/me".into()), Some(&p))
+            .await
+            .unwrap();
+        let rq = t.last();
+        assert_eq!(Some("name".into()), rq.param("fields"));
+        assert_eq!(Some("100".into()), rq.param("limit"));
+        assert_eq!(
+            1,
+            rq.url.query_pairs().filter(|(k, _)| k == "fields").count()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_custom_headers() {
+        let t = MockTransport::new();
+        let mut hd = hidrive(t.clone());
+        hd.files()
+            .with_header(
+                HeaderName::from_static("traceparent"),
+                HeaderValue::from_static("00-abc-def-01"),
+            )
+            .get_dir(Identifier::Path("/users/me".into()), NO_PARAMS)
+            .await
+            .unwrap();
+        let rq = t.last();
+        assert_eq!("00-abc-def-01", rq.headers["traceparent"]);
+        assert!(rq.headers.contains_key(reqwest::header::AUTHORIZATION));
+    }
+
+    #[tokio::test]
+    async fn test_api_error() {
+        let t = MockTransport::new();
+        t.push(404, r#"{"code": 404, "msg": "Not Found"}"#);
+        let mut hd = hidrive(t.clone());
+        let err = hd
+            .files()
+            .metadata(Identifier::Id("b1.4".into()), "path", None)
+            .await
+            .unwrap_err();
+        let api_err = err.downcast_ref::<ApiError>().unwrap();
+        assert_eq!(404, api_err.code);
+    }
+
+    #[tokio::test]
+    async fn test_max_body_size() {
+        let t = MockTransport::new();
+        t.push(200, r#"{"path": "/a/very/long/path"}"#);
+        let mut hd = hidrive(t.clone());
+        hd.set_max_body_size(Some(16));
+        let err = hd
+            .files()
+            .get_dir(Identifier::Path("/a".into()), None)
+            .await
+            .unwrap_err();
+        assert_eq!(16, err.downcast_ref::<TooLarge>().unwrap().limit);
+    }
+
+    #[tokio::test]
+    async fn test_raw_client() {
+        let t = MockTransport::new();
+        t.push(200, r#"{"some": "thing"}"#);
+        let mut hd = hidrive(t.clone());
+        let u = format!("{}/app/me", hd.base_url());
+        let v: serde_json::Value = hd
+            .client()
+            .request(Method::GET, u, &[("fields", "id")], NO_PARAMS)
+            .await
+            .unwrap()
+            .go()
+            .await
+            .unwrap();
+        assert_eq!("thing", v["some"]);
+        assert_eq!("/2.1/app/me", t.last().url.path());
+
+        t.push(204, "");
+        let status = hd
+            .client()
+            .request(
+                Method::DELETE,
+                "https://example.com/x",
+                &Params::new(),
+                NO_PARAMS,
+            )
+            .await
+            .unwrap()
+            .go_cb(|rp| async move { Ok(rp.status().as_u16()) })
+            .await
+            .unwrap();
+        assert_eq!(204, status);
+    }
+
+    #[tokio::test]
+    async fn test_etag_cache() {
+        let t = MockTransport::new();
+        let mut hd = hidrive(t.clone());
+        hd.set_cache(Some(Arc::new(crate::cache::MemoryCache::new(16))));
+        let dir = || Identifier::Path("/a".into());
+
+        t.push_with_headers(
+            200,
+            &[("etag", "\"v1\"")],
+            r#"{"path": "/a", "nmembers": 1}"#,
+        );
+        hd.files().get_dir(dir(), None).await.unwrap();
+        assert!(t.last().headers.get("if-none-match").is_none());
+
+        t.push(304, "");
+        let d = hd.files().get_dir(dir(), None).await.unwrap();
+        assert_eq!("\"v1\"", t.last().headers["if-none-match"]);
+        assert_eq!(Some(1), d.nmembers);
+
+        // Uploading into /a invalidates the listing.
+        t.push(200, "{}");
+        hd.files()
+            .upload(dir(), "b.txt", "abc", None)
+            .await
+            .unwrap();
+        t.push(200, r#"{"path": "/a", "nmembers": 2}"#);
+        let d = hd.files().get_dir(dir(), None).await.unwrap();
+        assert!(t.last().headers.get("if-none-match").is_none());
+        assert_eq!(Some(2), d.nmembers);
+    }
+
+    #[tokio::test]
+    async fn test_metadata_cache() {
+        let t = MockTransport::new();
+        let mut hd = hidrive(t.clone());
+        hd.set_metadata_cache(Some(Arc::new(crate::cache::MetadataCache::new(
+            Duration::from_secs(60),
+            16,
+        ))));
+        let dir = || Identifier::Path("/a".into());
+
+        t.push(200, r#"{"path": "/a", "nmembers": 1}"#);
+        hd.files().get_dir(dir(), None).await.unwrap();
+        let d = hd.files().get_dir(dir(), None).await.unwrap();
+        assert_eq!(Some(1), d.nmembers);
+        assert_eq!(1, t.requests().len());
+
+        // Writes through a fork invalidate the listing, too.
+        t.push(200, "{}");
+        hd.fork()
+            .files()
+            .upload(dir(), "b.txt", "abc", None)
+            .await
+            .unwrap();
+        t.push(200, r#"{"path": "/a", "nmembers": 2}"#);
+        let d = hd.files().get_dir(dir(), None).await.unwrap();
+        assert_eq!(Some(2), d.nmembers);
+        assert_eq!(3, t.requests().len());
+    }
+
+    #[tokio::test]
+    async fn test_compressed_response() {
+        use hyper::service::{make_service_fn, service_fn};
+        use std::convert::Infallible;
+
+        // gzip-compressed `{"path": "/a", "nmembers": 3}`
+        const GZ: &[u8] = &[
+            0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0xab, 0x56, 0x2a, 0x48,
+            0x2c, 0xc9, 0x50, 0xb2, 0x52, 0x50, 0xd2, 0x4f, 0x54, 0xd2, 0x51, 0x50, 0xca, 0xcb,
+            0x4d, 0xcd, 0x4d, 0x4a, 0x2d, 0x2a, 0x06, 0x8a, 0x18, 0xd7, 0x02, 0x00, 0x33, 0x3a,
+            0xda, 0x5d, 0x1d, 0x00, 0x00, 0x00,
+        ];
+        let mk = make_service_fn(|_: &hyper::server::conn::AddrStream| async {
+            Ok::<_, Infallible>(service_fn(|rq: hyper::Request<hyper::Body>| async move {
+                let accepts_gzip = rq
+                    .headers()
+                    .get("accept-encoding")
+                    .map(|v| v.to_str().unwrap().contains("gzip"))
+                    .unwrap_or(false);
+                let rb = hyper::Response::builder().header("content-type", "application/json");
+                Ok::<_, Infallible>(if accepts_gzip {
+                    rb.header("content-encoding", "gzip")
+                        .body(hyper::Body::from(GZ))
+                        .unwrap()
+                } else {
+                    rb.status(406).body(hyper::Body::empty()).unwrap()
+                })
+            }))
+        });
+        let srv = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(mk);
+        let addr = srv.local_addr();
+        tokio::spawn(srv);
+
+        let t = MockTransport::new();
+        let cred: oauth2::Credentials = serde_json::from_str(TOKEN_RESPONSE).unwrap();
+        let authz =
+            oauth2::Authorizer::new_with_transport(cred, oauth2::ClientSecret::default(), t);
+        let mut hd = HiDrive::builder(authz).build().unwrap();
+        let it: Item = hd
+            .client()
+            .request(
+                Method::GET,
+                format!("http://{}/dir", addr),
+                &Params::new(),
+                NO_PARAMS,
+            )
+            .await
+            .unwrap()
+            .go()
+            .await
+            .unwrap();
+        assert_eq!(Some(3), it.nmembers);
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_download() {
+        let t = MockTransport::new();
+        t.push(200, "file contents");
+        let mut hd = hidrive(t.clone());
+        let path = temp_path("cancelled_download");
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let err = hd
+            .files()
+            .with_cancellation(cancel)
+            .download_to_path(Identifier::Id("b1.4".into()), &path, None)
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<Cancelled>().is_some());
+        assert!(!path.exists());
+
+        let n = hd
+            .files()
+            .download_to_path(Identifier::Id("b1.4".into()), &path, None)
+            .await
+            .unwrap();
+        assert_eq!(13, n);
+        assert_eq!("file contents", std::fs::read_to_string(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_transfer_events() {
+        use crate::http::TransferKind;
+
+        let t = MockTransport::new();
+        let mut hd = hidrive(t.clone());
+        let mut rx = hd.transfer_events();
+
+        t.push(200, "file contents");
+        hd.files()
+            .get(Identifier::Id("b1.4".into()), &mut Vec::<u8>::new(), None)
+            .await
+            .unwrap();
+        match rx.try_recv().unwrap() {
+            TransferEvent::Started { id: 1, kind, url } => {
+                assert_eq!(TransferKind::Download, kind);
+                assert!(url.contains("/2.1/file?pid=b1.4"));
+            }
+            e => panic!("unexpected event {:?}", e),
+        }
+        assert_eq!(
+            TransferEvent::Progressed { id: 1, bytes: 13 },
+            rx.try_recv().unwrap()
+        );
+        assert_eq!(
+            TransferEvent::Finished {
+                id: 1,
+                bytes: Some(13)
+            },
+            rx.try_recv().unwrap()
+        );
+
+        t.push(500, r#"{"code": 500, "msg": "Internal Error"}"#);
+        hd.files()
+            .upload(Identifier::Id("b1.4".into()), "x", "abc", None)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            rx.try_recv().unwrap(),
+            TransferEvent::Started {
+                id: 2,
+                kind: TransferKind::Upload,
+                ..
+            }
+        ));
+        assert!(matches!(
+            rx.try_recv().unwrap(),
+            TransferEvent::Failed { id: 2, .. }
+        ));
+
+        // Metadata calls are not transfers.
+        hd.files()
+            .get_dir(Identifier::Id("b1.4".into()), None)
+            .await
+            .unwrap();
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_retry_after_401() {
+        let t = MockTransport::new();
+        t.push(401, r#"{"code": 401, "msg": "Unauthorized"}"#);
+        t.push(200, r#"{"path": "/a"}"#);
+        let mut hd = hidrive(t.clone());
+        let it = hd
+            .files()
+            .get_dir(Identifier::Path("/a".into()), None)
+            .await
+            .unwrap();
+        assert_eq!("/a", it.path);
+        let rqs = t.requests();
+        assert_eq!(2, rqs.len());
+        assert_eq!("Bearer ssklnLKwerlnc9sal", rqs[1].headers["authorization"]);
+
+        // Only retried once.
+        t.push(401, r#"{"code": 401, "msg": "Unauthorized"}"#);
+        t.push(401, r#"{"code": 401, "msg": "Unauthorized"}"#);
+        let err = hd
+            .files()
+            .get_dir(Identifier::Path("/a".into()), None)
+            .await
+            .unwrap_err();
+        assert_eq!(401, err.downcast_ref::<ApiError>().unwrap().code);
+        assert_eq!(4, t.requests().len());
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let t = MockTransport::new();
+        let metrics = Arc::new(crate::metrics::PrometheusMetrics::new());
+        let mut hd = hidrive(t.clone());
+        hd.set_metrics(Some(metrics.clone()));
+        t.push_with_headers(
+            200,
+            &[("x-ratelimit-limit", "10"), ("x-ratelimit-remaining", "9")],
+            r#"{"path": "/a"}"#,
+        );
+        hd.files()
+            .get_dir(Identifier::Path("/a".into()), None)
+            .await
+            .unwrap();
+        assert_eq!(Some(9), hd.fork().rate_limit().unwrap().remaining);
+        assert_eq!(Some(9.), metrics.get("hd_api_rate_limit_remaining"));
+
+        t.push_with_headers(
+            429,
+            &[("retry-after", "12"), ("x-ratelimit-remaining", "0")],
+            r#"{"code": 429, "msg": "Too Many Requests"}"#,
+        );
+        let err = hd
+            .files()
+            .get_dir(Identifier::Path("/a".into()), None)
+            .await
+            .unwrap_err();
+        assert_eq!(Some(429), error_status(&err));
+        let rl = error_rate_limit(&err).unwrap();
+        assert_eq!(Some(Duration::from_secs(12)), rl.wait());
+        assert_eq!(Some(rl), hd.rate_limit().as_ref());
+        assert_eq!(Some(1.), metrics.get("hd_api_rate_limited_total"));
+        assert_eq!(Some(12.), metrics.get("hd_api_retry_after_seconds"));
+
+        // Failed downloads carry the headers, too.
+        t.push_with_headers(
+            429,
+            &[("retry-after", "3"), ("x-ratelimit-remaining", "0")],
+            r#"{"code": 429, "msg": "Too Many Requests"}"#,
+        );
+        let err = hd
+            .files()
+            .get(Identifier::Path("/a/f".into()), Vec::new(), None)
+            .await
+            .unwrap_err();
+        let rl = error_rate_limit(&err).unwrap();
+        assert_eq!(Some(Duration::from_secs(3)), rl.wait());
+
+        // Responses without the headers keep the last state.
+        t.push(200, r#"{"path": "/a"}"#);
+        hd.files()
+            .get_dir(Identifier::Path("/a".into()), None)
+            .await
+            .unwrap();
+        assert_eq!(Some(0), hd.rate_limit().unwrap().remaining);
+    }
+
+    #[tokio::test]
+    async fn test_request_deadline() {
+        struct SlowTransport(Arc<MockTransport>);
+
+        #[async_trait::async_trait]
+        impl Transport for SlowTransport {
+            async fn execute(&self, rq: reqwest::Request) -> Result<reqwest::Response> {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                self.0.execute(rq).await
+            }
+        }
+
+        let t = MockTransport::new();
+        t.push(401, r#"{"code": 401, "msg": "Unauthorized"}"#);
+        let cred: oauth2::Credentials = serde_json::from_str(TOKEN_RESPONSE).unwrap();
+        let slow = Arc::new(SlowTransport(t.clone()));
+        let authz = oauth2::Authorizer::new_with_transport(
+            cred,
+            oauth2::ClientSecret::default(),
+            slow.clone(),
+        );
+        let mut hd = HiDrive::new_with_transport(slow, authz);
+        hd.set_request_deadline(Some(Duration::from_millis(250)));
+        let err = hd
+            .files()
+            .get_dir(Identifier::Path("/a".into()), None)
+            .await
+            .unwrap_err();
+        let e = err.downcast_ref::<DeadlineExceeded>().unwrap();
+        assert_eq!(Duration::from_millis(250), e.budget);
+        // The retry was abandoned while refreshing the token.
+        assert_eq!(1, t.requests().len());
+
+        hd.set_request_deadline(Some(Duration::from_secs(10)));
+        hd.files()
+            .get_dir(Identifier::Path("/a".into()), None)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_get_file() {
+        let t = MockTransport::new();
+        t.push(200, "file contents");
+        let mut hd = hidrive(t.clone());
+        let mut out = vec![];
+        let n = hd
+            .files()
+            .get(
+                Identifier::Relative {
+                    id: "b1.4".into(),
+                    path: "a/b.txt".into(),
+                },
+                &mut out,
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(13, n);
+        assert_eq!(b"file contents".to_vec(), out);
+        let rq = t.last();
+        assert_eq!(Some("b1.4".into()), rq.param("pid"));
+        assert_eq!(Some("a/b.txt".into()), rq.param("path"));
+    }
+
+    #[tokio::test]
+    async fn test_upload() {
+        let t = MockTransport::new();
+        t.push(200, r#"{"path": "/users/me/x.txt", "name": "x.txt"}"#);
+        let mut hd = hidrive(t.clone());
+        let it = hd
+            .files()
+            .upload(Identifier::Id("b1.4".into()), "x.txt", "abc", None)
+            .await
+            .unwrap();
+        assert_eq!(Some("x.txt".into()), it.name);
+        let rq = t.last();
+        assert_eq!(Method::PUT, rq.method);
+        assert_eq!(Some("b1.4".into()), rq.param("dir_id"));
+        assert_eq!(Some("x.txt".into()), rq.param("name"));
+        assert_eq!(Some(b"abc".to_vec()), rq.body);
+    }
+
+    #[tokio::test]
+    async fn test_copy_move_rename_delete() {
+        let t = MockTransport::new();
+        let mut hd = hidrive(t.clone());
+        let from = || Identifier::Path("/a".into());
+        let to = || Identifier::Path("/b".into());
+
+        hd.files().copy(from(), to(), None).await.unwrap();
+        let rq = t.last();
+        assert_eq!("/2.1/file/copy", rq.url.path());
+        assert_eq!(Some("/a".into()), rq.param("src"));
+        assert_eq!(Some("/b".into()), rq.param("dst"));
+
+        hd.files().mv(from(), to(), None).await.unwrap();
+        assert_eq!("/2.1/file/move", t.last().url.path());
+
+        hd.files().rename(from(), "c", None).await.unwrap();
+        let rq = t.last();
+        assert_eq!("/2.1/file/rename", rq.url.path());
+        assert_eq!(Some("c".into()), rq.param("name"));
+
+        hd.files().delete(from(), None).await.unwrap();
+        let rq = t.last();
+        assert_eq!(Method::DELETE, rq.method);
+        assert_eq!("/2.1/file", rq.url.path());
+
+        hd.files().mkdir(to(), None).await.unwrap();
+        let rq = t.last();
+        assert_eq!(Method::POST, rq.method);
+        assert_eq!("/2.1/dir", rq.url.path());
+
+        hd.files().delete_dir(to(), None).await.unwrap();
+        assert_eq!(Method::DELETE, t.last().method);
+
+        assert_eq!(6, t.requests().len());
+    }
+
+    #[tokio::test]
+    async fn test_search() {
+        let t = MockTransport::new();
+        t.push(200, r#"{"result": [{"path": "/a"}, {"path": "/b"}]}"#);
+        let mut hd = hidrive(t.clone());
+        let r = hd
+            .files()
+            .search(Identifier::Id("b1.4".into()), "", None)
+            .await
+            .unwrap();
+        assert_eq!(2, r.len());
+        assert_eq!(None, t.last().param("fields"));
+    }
+
+    #[tokio::test]
+    async fn test_hash() {
+        let t = MockTransport::new();
+        t.push(
+            200,
+            r#"{"level": 0, "chash": "126c9798b09a51d069a8f5bcef5174a41ef9e7ea", "list": []}"#,
+        );
+        let mut hd = hidrive(t.clone());
+        let h = hd
+            .files()
+            .hash(Identifier::Id("b1.4".into()), 0, &[(0, 3), (8, 9)], None)
+            .await
+            .unwrap();
+        assert_eq!(
+            "126c9798b09a51d069a8f5bcef5174a41ef9e7ea",
+            h.chash.to_string()
+        );
+        let rq = t.last();
+        assert_eq!(Some("0-3,8-9".into()), rq.param("ranges"));
+        assert_eq!(Some("0".into()), rq.param("level"));
+    }
+
+    #[tokio::test]
+    async fn test_diff_hashes() {
+        let mut data = vec![1_u8; 300 * 4096];
+        let local = crate::hashing::chash(&data[..]).await.unwrap();
+        data[260 * 4096] = 2;
+        let remote = crate::hashing::chash(&data[..]).await.unwrap();
+        let t = MockTransport::new();
+        t.push(200, hash_listing(&remote, 2, 0, 0));
+        t.push(200, hash_listing(&remote, 1, 0, 1));
+        t.push(200, hash_listing(&remote, 0, 256, 299));
+        let mut hd = hidrive(t.clone());
+        let diff = hd
+            .files()
+            .diff_hashes(Identifier::Path("/a".into()), &local)
+            .await
+            .unwrap();
+        assert_eq!(
+            vec![BlockRange {
+                start: 260,
+                end: 261
+            }],
+            diff
+        );
+        let rqs = t.requests();
+        assert_eq!(3, rqs.len());
+        assert_eq!(Some("256-299".into()), rqs[2].param("ranges"));
+    }
+
+    #[cfg(feature = "hashing")]
+    #[tokio::test]
+    async fn test_download_delta() {
+        let local_data = vec![b'a'; 3 * 4096 + 100];
+        let mut remote_data = vec![b'a'; 5 * 4096 + 50];
+        remote_data[5000] = b'b';
+        let remote = crate::hashing::chash(&remote_data[..]).await.unwrap();
+        let part = |r: std::ops::Range<usize>| String::from_utf8(remote_data[r].to_vec()).unwrap();
+
+        let t = MockTransport::new();
+        t.push(200, r#"{"size": 20530}"#);
+        t.push(200, hash_listing(&remote, 1, 0, 0));
+        t.push(200, hash_listing(&remote, 0, 0, 3));
+        t.push(206, part(4096..8192));
+        t.push(206, part(12288..20530));
+        let mut hd = hidrive(t.clone());
+        let path = temp_path("download_delta");
+        std::fs::write(&path, &local_data).unwrap();
+        let n = hd
+            .files()
+            .download_delta(Identifier::Path("/a".into()), &path)
+            .await
+            .unwrap();
+        assert_eq!(4096 + 8242, n);
+        assert_eq!(remote_data, std::fs::read(&path).unwrap());
+
+        let rqs = t.requests();
+        assert_eq!(5, rqs.len());
+        assert_eq!(Some("0-3".into()), rqs[2].param("ranges"));
+        assert_eq!("bytes=4096-8191", rqs[3].headers["range"]);
+        assert_eq!("bytes=12288-20529", rqs[4].headers["range"]);
+
+        // A server ignoring the range header is detected.
+        std::fs::write(&path, &local_data).unwrap();
+        t.push(200, r#"{"size": 20530}"#);
+        t.push(200, hash_listing(&remote, 1, 0, 0));
+        t.push(200, hash_listing(&remote, 0, 0, 3));
+        t.push(200, part(0..20530));
+        assert!(hd
+            .files()
+            .download_delta(Identifier::Path("/a".into()), &path)
+            .await
+            .is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_get_range_empty() {
+        let t = MockTransport::new();
+        let mut hd = hidrive(t.clone());
+        let mut out = std::io::Cursor::new(vec![]);
+        let n = hd
+            .files()
+            .get_range(Identifier::Path("/a".into()), &mut out, 5..5)
+            .await
+            .unwrap();
+        assert_eq!(0, n);
+        assert!(t.requests().is_empty());
+    }
+
+    #[cfg(feature = "hashing")]
+    #[tokio::test]
+    async fn test_download_resumable() {
+        let segment = resume::SEGMENT_SIZE as usize;
+        let data: String = (0..segment + 1000)
+            .map(|i| (b'a' + (i % 26) as u8) as char)
+            .collect();
+        let chash = crate::hashing::chash(data.as_bytes()).await.unwrap();
+        let meta = format!(
+            r#"{{"size": {}, "chash": "{}"}}"#,
+            data.len(),
+            chash.top_hash()
+        );
+        let path = temp_path("download_resumable");
+        let _ = std::fs::remove_file(&path);
+
+        let t = MockTransport::new();
+        let mut hd = hidrive(t.clone());
+        // Interrupted after the first segment.
+        t.push(200, meta.clone());
+        t.push(206, &data[..segment]);
+        t.push(500, "");
+        assert!(hd
+            .files()
+            .download_resumable(Identifier::Id("b1.4".into()), &path)
+            .await
+            .is_err());
+        assert!(resume::state_path(&path).exists());
+        assert!(!path.exists());
+
+        // Resumed: only the second segment is requested.
+        t.push(200, meta.clone());
+        t.push(206, &data[segment..]);
+        let n = hd
+            .files()
+            .download_resumable(Identifier::Id("b1.4".into()), &path)
+            .await
+            .unwrap();
+        assert_eq!(1000, n);
+        assert_eq!(
+            format!("bytes={}-{}", segment, segment + 999),
+            t.last().headers["range"].to_str().unwrap()
+        );
+        assert_eq!(data, std::fs::read_to_string(&path).unwrap());
+        assert!(!resume::part_path(&path).exists());
+        assert!(!resume::state_path(&path).exists());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "hashing")]
+    #[tokio::test]
+    async fn test_upload_dedup() {
+        let path = temp_path("upload_dedup");
+        let local_data = vec![b'a'; 3 * 4096 + 100];
+        std::fs::write(&path, &local_data).unwrap();
+        let local = crate::hashing::chash(&local_data[..]).await.unwrap();
+        let dir = Identifier::Path("/d".into());
+        let t = MockTransport::new();
+        let mut hd = hidrive(t.clone());
+
+        // Identical content under a different name in a probed directory.
+        t.push(200, r#"{"path": "/d", "members": []}"#);
+        t.push(
+            200,
+            format!(
+                r#"{{"path": "/e", "members": [{{"path": "/e/old.bin", "name": "old.bin", "size": {}, "chash": "{}"}}]}}"#,
+                local_data.len(),
+                local.top_hash()
+            ),
+        );
+        let m = hd
+            .files()
+            .upload_dedup(
+                dir.clone(),
+                "new.bin",
+                &path,
+                &[Identifier::Path("/e".into())],
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            UploadMethod::Copied {
+                from: "/e/old.bin".into()
+            },
+            m
+        );
+        let rq = t.last();
+        assert_eq!("/2.1/file/copy", rq.url.path());
+        assert_eq!(Some("/e/old.bin".into()), rq.param("src"));
+        assert_eq!(Some("/d/new.bin".into()), rq.param("dst"));
+
+        // A prior version under the same name: only differing blocks are uploaded.
+        let mut remote_data = vec![b'a'; 3 * 4096 + 10];
+        remote_data[5000] = b'b';
+        let remote = crate::hashing::chash(&remote_data[..]).await.unwrap();
+        t.push(
+            200,
+            format!(
+                r#"{{"path": "/d", "members": [{{"path": "/d/new.bin", "name": "new.bin", "size": {}, "chash": "{}"}}]}}"#,
+                remote_data.len(),
+                remote.top_hash()
+            ),
+        );
+        t.push(200, hash_listing(&remote, 1, 0, 0));
+        t.push(200, hash_listing(&remote, 0, 0, 3));
+        let n = t.requests().len();
+        let m = hd
+            .files()
+            .upload_dedup(dir.clone(), "new.bin", &path, &[])
+            .await
+            .unwrap();
+        assert_eq!(UploadMethod::Delta { bytes: 4096 + 100 }, m);
+        let rqs = &t.requests()[n..];
+        assert_eq!(6, rqs.len());
+        assert_eq!("/2.1/file/truncate", rqs[3].url.path());
+        assert_eq!(Some("12388".into()), rqs[3].param("size"));
+        assert_eq!(Method::PATCH, rqs[4].method);
+        assert_eq!(Some("4096".into()), rqs[4].param("offset"));
+        assert_eq!(Some(4096), rqs[4].body.as_ref().map(|b| b.len()));
+        assert_eq!(Some("12288".into()), rqs[5].param("offset"));
+        assert_eq!(Some(100), rqs[5].body.as_ref().map(|b| b.len()));
+
+        // Unchanged, and not present at all.
+        t.push(
+            200,
+            format!(
+                r#"{{"path": "/d", "members": [{{"path": "/d/new.bin", "name": "new.bin", "chash": "{}"}}]}}"#,
+                local.top_hash()
+            ),
+        );
+        let m = hd
+            .files()
+            .upload_dedup(dir.clone(), "new.bin", &path, &[])
+            .await
+            .unwrap();
+        assert_eq!(UploadMethod::Unchanged, m);
+        t.push(200, r#"{"path": "/d", "members": []}"#);
+        let m = hd
+            .files()
+            .upload_dedup(dir, "new.bin", &path, &[])
+            .await
+            .unwrap();
+        assert_eq!(UploadMethod::Full, m);
+        assert_eq!(Method::PUT, t.last().method);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "hashing")]
+    #[tokio::test]
+    async fn test_sparse_transfers() {
+        let mut data = vec![0u8; 4 * 4096 + 100];
+        data[..4096].fill(b'a');
+        data[4 * 4096..].fill(b'b');
+        let tree = crate::hashing::chash(&data[..]).await.unwrap();
+        let t = MockTransport::new();
+        let mut hd = hidrive(t.clone());
+        let path = temp_path("sparse_transfers");
+
+        t.push(200, r#"{"size": 16484}"#);
+        t.push(200, hash_listing(&tree, 1, 0, 0));
+        t.push(200, hash_listing(&tree, 0, 0, 4));
+        t.push(206, "a".repeat(4096));
+        t.push(206, "b".repeat(100));
+        let n = hd
+            .files()
+            .download_sparse(Identifier::Id("b1.4".into()), &path)
+            .await
+            .unwrap();
+        assert_eq!(4196, n);
+        assert_eq!(data, std::fs::read(&path).unwrap());
+        assert_eq!(
+            "bytes=16384-16483",
+            t.last().headers["range"].to_str().unwrap()
+        );
+
+        let n0 = t.requests().len();
+        let n = hd
+            .files()
+            .upload_sparse(Identifier::Path("/d".into()), "sparse.img", &path)
+            .await
+            .unwrap();
+        assert_eq!(4196, n);
+        let rqs = &t.requests()[n0..];
+        assert_eq!(4, rqs.len());
+        assert_eq!(Method::PUT, rqs[0].method);
+        assert_eq!(Some("16484".into()), rqs[1].param("size"));
+        assert_eq!(Some("/d/sparse.img".into()), rqs[2].param("path"));
+        assert_eq!(Some("0".into()), rqs[2].param("offset"));
+        assert_eq!(Some(b"a".repeat(4096)), rqs[2].body);
+        assert_eq!(Some("16384".into()), rqs[3].param("offset"));
+        assert_eq!(Some(b"b".repeat(100)), rqs[3].body);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_permission() {
+        let t = MockTransport::new();
+        t.push(
+            200,
+            r#"{"account": "acc", "readable": true, "writable": false}"#,
+        );
+        let mut hd = hidrive(t.clone());
+        let p = hd
+            .permissions()
+            .get_permission(Identifier::Path("/a".into()), None)
+            .await
+            .unwrap();
+        assert!(p.readable);
+        assert!(!p.writable);
+    }
 }
@@ -1,40 +1,227 @@
+//! Low-level HTTP client for the HiDrive API.
+//!
+//! The typed API in `hidrive` is built on top of `Client` and `Request`. They can also be used
+//! directly to call endpoints not (yet) covered by the typed API:
+//!
+//! ```ignore
+//! let rq = hd.client().request(Method::GET, url, &params, NO_PARAMS).await?;
+//! let item: serde_json::Value = rq.go().await?;
+//! ```
+
+use std::future::Future;
+use std::io::SeekFrom;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
 use anyhow::{Context, Error, Result};
 use futures_util::StreamExt;
-use log::{error, info, warn};
-use reqwest::header::{HeaderName, HeaderValue};
-use reqwest::RequestBuilder;
+use log::{debug, error, info, warn};
+use reqwest::header::{
+    HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, CONTENT_TYPE, COOKIE, ETAG, IF_NONE_MATCH,
+    RANGE,
+};
+use reqwest::{Method, RequestBuilder, StatusCode};
 use serde::{de::DeserializeOwned, Serialize};
-use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::io::{AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{broadcast, Semaphore};
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
 
+use crate::cache::{identifiers, CachedResponse, MetadataCache, ResponseCache};
+use crate::metrics::Metrics;
 use crate::oauth2::Authorizer;
+use crate::throttle::Throttle;
 use crate::types::*;
 
-/// This is a callback for gen_call_cb, deserializing the response to JSON.
-async fn read_body_to_json<RT: Default + DeserializeOwned + ?Sized>(
+/// Default limit for bodies of JSON responses.
+pub const DEFAULT_MAX_BODY_SIZE: usize = 64 * 1024 * 1024;
+
+const TRANSFER_EVENTS_CAPACITY: usize = 256;
+/// Maximum length of the body snippet in `HttpStatusError`.
+const ERROR_SNIPPET_LEN: usize = 256;
+
+/// Query parameters and form fields whose values are masked in debug output and cassettes.
+pub(crate) const SECRET_PARAMS: &[&str] = &[
+    "access_token",
+    "refresh_token",
+    "client_secret",
+    "code",
+    "password",
+];
+pub(crate) const REDACTED: &str = "REDACTED";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferKind {
+    Upload,
+    Download,
+}
+
+/// Events describing the progress of uploads and downloads, see `Client::transfer_events()`.
+/// Transfers are identified by an `id` unique within a client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransferEvent {
+    Started {
+        id: u64,
+        kind: TransferKind,
+        url: String,
+    },
+    /// Total number of bytes transferred so far. Only reported for downloads.
+    Progressed { id: u64, bytes: u64 },
+    /// The request is sent again (`attempt` counts from 1).
+    Retried { id: u64, attempt: usize },
+    /// `bytes` is None for uploads of streaming bodies.
+    Finished { id: u64, bytes: Option<u64> },
+    /// The transfer failed, or was cancelled.
+    Failed { id: u64, error: String },
+}
+
+/// Emits events for one transfer. If dropped before `finish()` (e.g. because the transfer was
+/// cancelled), a `Failed` event is sent.
+struct TransferReporter {
+    id: u64,
+    tx: broadcast::Sender<TransferEvent>,
+    done: bool,
+}
+
+impl TransferReporter {
+    fn send(&self, ev: TransferEvent) {
+        // Fails only if there are no receivers.
+        let _ = self.tx.send(ev);
+    }
+
+    fn progress(&self, bytes: u64) {
+        self.send(TransferEvent::Progressed { id: self.id, bytes });
+    }
+
+    fn finish<T>(mut self, r: &Result<T>, bytes: Option<u64>) {
+        self.done = true;
+        self.send(match r {
+            Ok(_) => TransferEvent::Finished { id: self.id, bytes },
+            Err(e) => TransferEvent::Failed {
+                id: self.id,
+                error: format!("{:#}", e),
+            },
+        });
+    }
+}
+
+impl Drop for TransferReporter {
+    fn drop(&mut self) {
+        if !self.done {
+            self.send(TransferEvent::Failed {
+                id: self.id,
+                error: "transfer aborted".into(),
+            });
+        }
+    }
+}
+
+/// Read a response body, failing with `TooLarge` once it exceeds `limit` bytes.
+async fn read_body_limited(rp: reqwest::Response, limit: Option<usize>) -> Result<String> {
+    if let (Some(limit), Some(len)) = (limit, rp.content_length()) {
+        if len > limit as u64 {
+            return Err(Error::new(TooLarge { limit }));
+        }
+    }
+    let mut body = vec![];
+    let mut stream = rp.bytes_stream();
+    while let Some(chunk) = stream.next().await {
+        body.extend_from_slice(chunk?.as_ref());
+        if let Some(limit) = limit {
+            if body.len() > limit {
+                return Err(Error::new(TooLarge { limit }));
+            }
+        }
+    }
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+/// Deserialize a JSON body; an empty body results in `RT::default()`.
+fn parse_json<RT: Default + DeserializeOwned + ?Sized>(body: &str) -> Result<RT> {
+    if body.is_empty() {
+        Ok(Default::default())
+    } else {
+        Ok(serde_json::from_reader(body.as_bytes())?)
+    }
+}
+
+/// Convert an error response to an `ApiError` or, if the body isn't one, an `HttpStatusError`.
+/// An `ApiError` whose code isn't an HTTP status gets the status of the response.
+pub fn error_from_body(status: StatusCode, content_type: Option<String>, body: &str) -> Error {
+    match serde_json::from_str::<ApiError>(body) {
+        Ok(mut e) if e.code != 0 || !e.msg.is_empty() => {
+            if !(100..=599).contains(&e.code) {
+                e.code = status.as_u16() as usize;
+            }
+            error!(target: "hd_api::http", "ApiError is {:?}", e);
+            Error::new(e)
+        }
+        _ => {
+            let mut end = body.len().min(ERROR_SNIPPET_LEN);
+            while !body.is_char_boundary(end) {
+                end -= 1;
+            }
+            Error::new(HttpStatusError {
+                status: status.as_u16(),
+                content_type,
+                snippet: body[..end].to_string(),
+                rate_limit: None,
+            })
+        }
+    }
+}
+
+/// Read an error response into an `ApiError` or `HttpStatusError`, see `error_from_body()`.
+/// Rate limit headers are attached to the error.
+pub(crate) async fn error_response(rp: reqwest::Response, limit: Option<usize>) -> Error {
+    let status = rp.status();
+    let ct = content_type(&rp);
+    let rate_limit = RateLimit::from_headers(rp.headers());
+    match read_body_limited(rp, limit).await {
+        Ok(body) => {
+            warn!(target: "hd_api::http", "Received HTTP error {}: with body {}", status, body);
+            let mut e = error_from_body(status, ct, &body);
+            if let Some(ae) = e.downcast_mut::<ApiError>() {
+                ae.rate_limit = rate_limit;
+            } else if let Some(he) = e.downcast_mut::<HttpStatusError>() {
+                he.rate_limit = rate_limit;
+            }
+            e
+        }
+        Err(e) => e,
+    }
+}
+
+fn content_type(rp: &reqwest::Response) -> Option<String> {
+    rp.headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(String::from)
+}
+
+/// This is a callback for `Request::go_cb()`, deserializing the response to JSON.
+pub(crate) async fn read_body_to_json<RT: Default + DeserializeOwned + ?Sized>(
     rp: reqwest::Response,
+    limit: Option<usize>,
 ) -> Result<RT> {
     let status = rp.status();
     if status.is_success() {
-        let body = rp.text().await?;
+        let body = read_body_limited(rp, limit).await?;
         info!(target: "hd_api::http", "Received HTTP response 200, body: {}", body);
-        if body.is_empty() {
-            Ok(Default::default())
-        } else {
-            Ok(serde_json::from_reader(body.as_bytes())?)
-        }
+        parse_json(&body)
     } else {
-        let body = rp.text().await?;
-        warn!(target: "hd_api::http", "Received HTTP error {}: with body {}", status, body);
-        let e: ApiError = serde_json::from_reader(body.as_bytes())?;
-        error!(target: "hd_api::http", "ApiError is {:?}", e);
-        Err(Error::new(e))
+        Err(error_response(rp, limit).await)
     }
 }
 
 /// A wrapped callback for writing an HTTP response body to a file.
-async fn write_response_to_file<D: AsyncWrite + Unpin>(
+pub(crate) async fn write_response_to_file<D: AsyncWrite + Unpin>(
     rp: reqwest::Response,
     mut d: D,
+    throttle: Option<&Throttle>,
+    mut progress: impl FnMut(u64),
 ) -> Result<usize> {
     if rp.status().is_success() {
         let mut stream = rp.bytes_stream();
@@ -43,40 +230,394 @@ async fn write_response_to_file<D: AsyncWrite + Unpin>(
             let chunk = chunk?;
             d.write_all(chunk.as_ref()).await?;
             i += chunk.len();
+            progress(i as u64);
+            if let Some(t) = throttle {
+                t.consume(chunk.len() as u64).await;
+            }
         }
+        d.flush().await?;
         Ok(i)
     } else {
-        let body = rp.text().await?;
-        let e: ApiError = serde_json::from_reader(body.as_bytes())?;
-        Err(Error::new(e))
+        Err(error_response(rp, Some(DEFAULT_MAX_BODY_SIZE)).await)
     }
 }
 
+/// Run `f` until it completes or `cancel` is triggered. Dropping `f` aborts any HTTP transfer in
+/// progress.
+async fn cancellable<T, F: Future<Output = Result<T>>>(
+    cancel: Option<CancellationToken>,
+    f: F,
+) -> Result<T> {
+    match cancel {
+        None => f.await,
+        Some(c) => tokio::select! {
+            biased;
+            _ = c.cancelled() => Err(Error::new(Cancelled)),
+            r = f => r,
+        },
+    }
+}
+
+/// The point in time by which a request must have been answered.
+#[derive(Clone, Copy)]
+struct Deadline {
+    at: Instant,
+    budget: Duration,
+}
+
+/// Run `f`, failing with `DeadlineExceeded` if it doesn't complete before `deadline`.
+async fn within<T, F: Future<Output = Result<T>>>(deadline: Option<Deadline>, f: F) -> Result<T> {
+    match deadline {
+        None => f.await,
+        Some(d) => match tokio::time::timeout_at(d.at, f).await {
+            Ok(r) => r,
+            Err(_) => Err(Error::new(DeadlineExceeded { budget: d.budget })),
+        },
+    }
+}
+
+/// Return `u` with the values of secret query parameters replaced by `REDACTED`.
+pub(crate) fn redact_url(u: &reqwest::Url) -> String {
+    let mut u = u.clone();
+    if u.query().is_some() {
+        let pairs: Vec<(String, String)> = u
+            .query_pairs()
+            .map(|(k, v)| {
+                if SECRET_PARAMS.contains(&k.as_ref()) {
+                    (k.into_owned(), REDACTED.to_string())
+                } else {
+                    (k.into_owned(), v.into_owned())
+                }
+            })
+            .collect();
+        u.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+    u.to_string()
+}
+
+/// Return the form-encoded or JSON body `b` with the values of secret fields replaced by
+/// `REDACTED`. Other bodies are returned unchanged.
+fn redact_body(content_type: Option<&str>, b: &str) -> String {
+    let ct = content_type.unwrap_or_default();
+    if ct.starts_with("application/x-www-form-urlencoded") {
+        b.split('&')
+            .map(|kv| match kv.split_once('=') {
+                Some((k, _)) if SECRET_PARAMS.contains(&k) => format!("{}={}", k, REDACTED),
+                _ => kv.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("&")
+    } else if ct.starts_with("application/json") {
+        match serde_json::from_str(b) {
+            Ok(serde_json::Value::Object(mut m)) => {
+                for k in SECRET_PARAMS {
+                    if let Some(v) = m.get_mut(*k) {
+                        *v = serde_json::Value::String(REDACTED.into());
+                    }
+                }
+                serde_json::Value::Object(m).to_string()
+            }
+            _ => b.to_string(),
+        }
+    } else {
+        b.to_string()
+    }
+}
+
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Render `rq` as an equivalent curl command line. Credentials (the `Authorization` and `Cookie`
+/// headers, and secret query parameters and form or JSON body fields) are masked. Streaming
+/// bodies can't be rendered and are replaced by a placeholder reading from stdin.
+pub fn curl_command(rq: &reqwest::Request) -> String {
+    let mut cmd = format!("curl -X {}", rq.method());
+    for (k, v) in rq.headers() {
+        let v = if k == AUTHORIZATION || k == COOKIE {
+            REDACTED
+        } else {
+            v.to_str().unwrap_or(REDACTED)
+        };
+        cmd.push_str(&format!(" -H {}", shell_quote(&format!("{}: {}", k, v))));
+    }
+    match rq.body().map(|b| b.as_bytes()) {
+        Some(Some(b)) => match std::str::from_utf8(b) {
+            Ok(b) => {
+                let ct = rq.headers().get(CONTENT_TYPE).and_then(|v| v.to_str().ok());
+                let b = redact_body(ct, b);
+                cmd.push_str(&format!(" --data-binary {}", shell_quote(&b)))
+            }
+            Err(_) => cmd.push_str(&format!(
+                " --data-binary @- # <{} bytes of binary data>",
+                b.len()
+            )),
+        },
+        Some(None) => cmd.push_str(" --data-binary @- # <streaming body>"),
+        None => {}
+    }
+    cmd.push_str(&format!(" {}", shell_quote(&redact_url(rq.url()))));
+    cmd
+}
+
+/// Where to send curl renderings of outgoing requests, see `Client::set_request_dump()`.
+#[derive(Clone)]
+pub enum RequestDump {
+    /// Log at debug level, with target `hd_api::curl`.
+    Log,
+    Callback(Arc<dyn Fn(&str) + Send + Sync>),
+}
+
+/// Transport executes HTTP requests. The default transport is a `reqwest::Client`; tests (or
+/// applications with special needs) can supply their own implementation, e.g. to return canned
+/// responses without touching the network.
+#[async_trait::async_trait]
+pub trait Transport: Send + Sync {
+    async fn execute(&self, rq: reqwest::Request) -> Result<reqwest::Response>;
+}
+
+#[async_trait::async_trait]
+impl Transport for reqwest::Client {
+    async fn execute(&self, rq: reqwest::Request) -> Result<reqwest::Response> {
+        Ok(reqwest::Client::execute(self, rq).await?)
+    }
+}
+
+/// Client issues authorized requests to the HiDrive API.
 pub struct Client {
     cl: reqwest::Client,
+    transport: Arc<dyn Transport>,
     authz: Authorizer,
+    max_body_size: Option<usize>,
+    cache: Option<Arc<dyn ResponseCache>>,
+    metadata_cache: Option<Arc<MetadataCache>>,
+    events: Option<broadcast::Sender<TransferEvent>>,
+    /// Shared with forks, so that transfer IDs stay unique.
+    next_transfer_id: Arc<AtomicU64>,
+    dump: Option<RequestDump>,
+    default_params: Params,
+    deadline: Option<Duration>,
+    throttle: Option<Arc<Throttle>>,
+    limiter: Option<Arc<Semaphore>>,
+    metrics: Option<Arc<dyn Metrics>>,
+    /// Shared with forks.
+    rate_limit: Arc<Mutex<Option<RateLimit>>>,
 }
 
-pub struct Request {
+/// An authorized request, ready to be sent using one of the `go*()` or `download_file()` methods.
+pub struct Request<'a> {
+    client: &'a mut Client,
     rqb: RequestBuilder,
+    cancel: Option<CancellationToken>,
+    transfer: Option<TransferKind>,
+    deadline: Option<Deadline>,
+    cacheable: bool,
 }
 
 impl Client {
     pub fn new(cl: reqwest::Client, authz: Authorizer) -> Client {
-        Client { cl, authz }
+        let transport = Arc::new(cl.clone());
+        Client {
+            cl,
+            transport,
+            authz,
+            max_body_size: Some(DEFAULT_MAX_BODY_SIZE),
+            cache: None,
+            metadata_cache: None,
+            events: None,
+            next_transfer_id: Arc::new(AtomicU64::new(0)),
+            dump: None,
+            default_params: Params::new(),
+            deadline: None,
+            throttle: None,
+            limiter: None,
+            metrics: None,
+            rate_limit: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Create a client sending all requests through `transport`. The `reqwest::Client` is only
+    /// used to construct requests.
+    pub fn new_with_transport(transport: Arc<dyn Transport>, authz: Authorizer) -> Client {
+        Client {
+            cl: reqwest::Client::new(),
+            transport,
+            authz,
+            max_body_size: Some(DEFAULT_MAX_BODY_SIZE),
+            cache: None,
+            metadata_cache: None,
+            events: None,
+            next_transfer_id: Arc::new(AtomicU64::new(0)),
+            dump: None,
+            default_params: Params::new(),
+            deadline: None,
+            throttle: None,
+            limiter: None,
+            metrics: None,
+            rate_limit: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Set the maximum size of JSON response bodies. `None` disables the limit.
+    pub fn set_max_body_size(&mut self, limit: Option<usize>) {
+        self.max_body_size = limit;
+    }
+
+    /// Cache responses of GET requests in `cache`, using ETags. See the `cache` module.
+    pub fn set_cache(&mut self, cache: Option<Arc<dyn ResponseCache>>) {
+        self.cache = cache;
+    }
+
+    /// Serve responses of requests marked with `Request::set_cacheable()` from `cache` while
+    /// fresh. See the `cache` module.
+    pub fn set_metadata_cache(&mut self, cache: Option<Arc<MetadataCache>>) {
+        self.metadata_cache = cache;
+    }
+
+    /// Render every outgoing request as curl command (see `curl_command()`), e.g. to reproduce
+    /// API issues outside of this library. `None` disables the dump.
+    pub fn set_request_dump(&mut self, dump: Option<RequestDump>) {
+        self.dump = dump;
+    }
+
+    /// Limit the time from creating a request until its response headers arrive, including token
+    /// refresh and retries, to `budget`. Exceeding it results in a `DeadlineExceeded` error.
+    /// Reading response bodies (e.g. downloads) is not limited. `None` disables the deadline.
+    pub fn set_request_deadline(&mut self, budget: Option<Duration>) {
+        self.deadline = budget;
+    }
+
+    /// Limit the bandwidth of downloads and uploads with in-memory bodies to `throttle`. Streamed
+    /// uploads aren't throttled automatically; wrap them with `Throttle::stream()`.
+    pub fn set_throttle(&mut self, throttle: Option<Arc<Throttle>>) {
+        self.throttle = throttle;
+    }
+
+    pub fn throttle(&self) -> Option<Arc<Throttle>> {
+        self.throttle.clone()
+    }
+
+    /// Allow at most `limit` requests in flight at once, counted across this client and its
+    /// forks. A request holds its slot until the response headers arrive; uploads are complete
+    /// by then. `None` removes the limit.
+    pub fn set_concurrency_limit(&mut self, limit: Option<usize>) {
+        self.limiter = limit.map(|n| Arc::new(Semaphore::new(n.max(1))));
+    }
+
+    /// A client with the same settings, sharing the transport, caches, event channel, throttle
+    /// and concurrency limit, for sending requests concurrently with this one.
+    pub fn fork(&self) -> Client {
+        Client {
+            cl: self.cl.clone(),
+            transport: self.transport.clone(),
+            authz: self.authz.clone(),
+            max_body_size: self.max_body_size,
+            cache: self.cache.clone(),
+            metadata_cache: self.metadata_cache.clone(),
+            events: self.events.clone(),
+            next_transfer_id: self.next_transfer_id.clone(),
+            dump: self.dump.clone(),
+            default_params: self.default_params.clone(),
+            deadline: self.deadline,
+            throttle: self.throttle.clone(),
+            limiter: self.limiter.clone(),
+            metrics: self.metrics.clone(),
+            rate_limit: self.rate_limit.clone(),
+        }
+    }
+
+    /// Report the server's rate limit headers to `metrics`. Counter: `hd_api_rate_limited_total`
+    /// (responses with status 429). Gauges: `hd_api_rate_limit_limit`,
+    /// `hd_api_rate_limit_remaining`, `hd_api_retry_after_seconds`.
+    pub fn set_metrics(&mut self, metrics: Option<Arc<dyn Metrics>>) {
+        self.metrics = metrics;
+    }
+
+    /// The rate limit headers of the latest response having any, also from forks of this client.
+    /// Useful to slow down before the server starts rejecting requests.
+    pub fn rate_limit(&self) -> Option<RateLimit> {
+        self.rate_limit.lock().unwrap().clone()
+    }
+
+    /// Record the rate limit headers of `resp`.
+    fn observe(&self, resp: &reqwest::Response) {
+        let rl = RateLimit::from_headers(resp.headers());
+        let limited = resp.status() == StatusCode::TOO_MANY_REQUESTS;
+        if limited {
+            warn!(target: "hd_api::http", "Rate limited for {}: {:?}", redact_url(resp.url()), rl);
+        }
+        if let Some(ref m) = self.metrics {
+            if limited {
+                m.counter("hd_api_rate_limited_total", "Responses with status 429.", 1);
+            }
+            let rl = rl.as_ref();
+            if let Some(l) = rl.and_then(|rl| rl.limit) {
+                m.gauge(
+                    "hd_api_rate_limit_limit",
+                    "Requests allowed per window.",
+                    l as f64,
+                );
+            }
+            if let Some(r) = rl.and_then(|rl| rl.remaining) {
+                m.gauge(
+                    "hd_api_rate_limit_remaining",
+                    "Requests left in the window.",
+                    r as f64,
+                );
+            }
+            if let Some(d) = rl.and_then(|rl| rl.retry_after) {
+                m.gauge(
+                    "hd_api_retry_after_seconds",
+                    "Last Retry-After.",
+                    d.as_secs_f64(),
+                );
+            }
+        }
+        if rl.is_some() {
+            *self.rate_limit.lock().unwrap() = rl;
+        }
+    }
+
+    /// Add `params` to every call returning JSON (see `Request::go()`), unless the call already
+    /// has a parameter of the same name. Useful for a client-wide `fields` selection.
+    pub fn set_default_params(&mut self, params: Params) {
+        self.default_params = params;
+    }
+
+    fn apply_default_params(&self, u: &mut reqwest::Url) {
+        let present: Vec<String> = u.query_pairs().map(|(k, _)| k.into_owned()).collect();
+        let missing: Vec<(&str, String)> = self
+            .default_params
+            .iter()
+            .filter(|(k, _)| !present.iter().any(|p| p == k))
+            .collect();
+        if !missing.is_empty() {
+            u.query_pairs_mut().extend_pairs(missing);
+        }
+    }
+
+    fn dump_request(&self, rq: &reqwest::Request) {
+        match self.dump {
+            Some(RequestDump::Log) => debug!(target: "hd_api::curl", "{}", curl_command(rq)),
+            Some(RequestDump::Callback(ref cb)) => cb(&curl_command(rq)),
+            None => {}
+        }
     }
 
-    /// Generic call to an API endpoint.
+    /// Generic call to an API endpoint. `required` and `optional` are serialized into the query
+    /// string.
     pub async fn request<U: reqwest::IntoUrl, P: Serialize + ?Sized, RP: Serialize + ?Sized>(
         &mut self,
         method: reqwest::Method,
         url: U,
         required: &RP,
         optional: Option<&P>,
-    ) -> Result<Request> {
-        let rqb = self
-            .authz
-            .authorize(self.cl.request(method, url))
+    ) -> Result<Request<'_>> {
+        let deadline = self.deadline.map(|budget| Deadline {
+            at: Instant::now() + budget,
+            budget,
+        });
+        let rqb = within(deadline, self.authz.authorize(self.cl.request(method, url)))
             .await
             .context("HiDrive::new_request: Building authorized RequestBuilder")?;
         let rqb = rqb.query(required);
@@ -85,49 +626,560 @@ impl Client {
         } else {
             rqb
         };
-        Ok(Request { rqb })
+        Ok(Request {
+            client: self,
+            rqb,
+            cancel: None,
+            transfer: None,
+            deadline,
+            cacheable: false,
+        })
     }
 
     pub async fn access_token(&mut self) -> Result<String> {
         self.authz.token().await
     }
+
+    /// Subscribe to events about uploads and downloads made through this client. Events are only
+    /// generated once there is at least one subscriber.
+    pub fn transfer_events(&mut self) -> broadcast::Receiver<TransferEvent> {
+        match self.events {
+            Some(ref tx) => tx.subscribe(),
+            None => {
+                let (tx, rx) = broadcast::channel(TRANSFER_EVENTS_CAPACITY);
+                self.events = Some(tx);
+                rx
+            }
+        }
+    }
+
+    fn start_transfer(
+        &mut self,
+        kind: TransferKind,
+        url: &reqwest::Url,
+    ) -> Option<TransferReporter> {
+        let tx = self.events.clone()?;
+        let reporter = TransferReporter {
+            id: self.next_transfer_id.fetch_add(1, Ordering::Relaxed) + 1,
+            tx,
+            done: false,
+        };
+        reporter.send(TransferEvent::Started {
+            id: reporter.id,
+            kind,
+            url: url.to_string(),
+        });
+        Some(reporter)
+    }
+
+    /// Send a request. Every request passes through here.
+    ///
+    /// If the API rejects the access token (401), e.g. because it was revoked or expired right at
+    /// the boundary, the token is refreshed and the request sent once more. Requests with
+    /// streaming bodies can't be repeated and are not retried.
+    async fn execute(
+        &mut self,
+        rq: reqwest::Request,
+        reporter: Option<&TransferReporter>,
+        deadline: Option<Deadline>,
+    ) -> Result<reqwest::Response> {
+        if let Some(ref cache) = self.cache {
+            if rq.method() != Method::GET {
+                cache.invalidate(&identifiers(rq.url())).await;
+            }
+        }
+        if let Some(ref cache) = self.metadata_cache {
+            if rq.method() != Method::GET {
+                cache.invalidate(&identifiers(rq.url()));
+            }
+        }
+        let _permit = match self.limiter.clone() {
+            Some(l) => Some(within(deadline, async { Ok(l.acquire_owned().await?) }).await?),
+            None => None,
+        };
+        let retry = rq.try_clone();
+        self.dump_request(&rq);
+        let resp = within(deadline, self.transport.execute(rq)).await?;
+        self.observe(&resp);
+        let mut rq = match retry {
+            Some(rq) if resp.status() == StatusCode::UNAUTHORIZED => rq,
+            _ => return Ok(resp),
+        };
+        warn!(
+            target: "hd_api::http",
+            "Received 401 for {}: refreshing token and retrying",
+            rq.url()
+        );
+        let token = within(deadline, self.authz.refresh_token())
+            .await
+            .context("Client::execute: refreshing token after 401")?;
+        rq.headers_mut().insert(
+            AUTHORIZATION,
+            HeaderValue::from_str(&format!("Bearer {}", token))?,
+        );
+        if let Some(reporter) = reporter {
+            reporter.send(TransferEvent::Retried {
+                id: reporter.id,
+                attempt: 1,
+            });
+        }
+        self.dump_request(&rq);
+        let resp = within(deadline, self.transport.execute(rq)).await?;
+        self.observe(&resp);
+        Ok(resp)
+    }
 }
 
-#[allow(unused)]
-impl Request {
+impl Request<'_> {
+    async fn send(self) -> Result<reqwest::Response> {
+        let rq = self.rqb.build()?;
+        self.client.execute(rq, None, self.deadline).await
+    }
+
+    /// Send the request and deserialize a JSON response. An empty response body results in
+    /// `RT::default()`; error responses are returned as `ApiError`, or `HttpStatusError` if the
+    /// body isn't a JSON API error. The client's default parameters are added.
     pub async fn go<RT: Default + DeserializeOwned + ?Sized>(self) -> Result<RT> {
         info!(target: "hd_api::http", "sending http request: {:?}", self.rqb);
-        let resp = self.rqb.send().await?;
-        read_body_to_json(resp).await
+        let cancel = self.cancel.clone();
+        cancellable(cancel, self.go_()).await
+    }
+
+    async fn go_<RT: Default + DeserializeOwned + ?Sized>(self) -> Result<RT> {
+        let limit = self.client.max_body_size;
+        let mut rq = self.rqb.build()?;
+        self.client.apply_default_params(rq.url_mut());
+        match self.client.metadata_cache.clone() {
+            Some(mc) if self.cacheable && rq.method() == Method::GET => {
+                let key = rq.url().to_string();
+                if let Some(body) = mc.get(&key) {
+                    info!(target: "hd_api::http", "serving {} from metadata cache", key);
+                    return parse_json(&body);
+                }
+                let ids = identifiers(rq.url());
+                let epoch = mc.epoch();
+                let resp = self.client.execute(rq, None, self.deadline).await?;
+                if !resp.status().is_success() {
+                    return read_body_to_json(resp, limit).await;
+                }
+                let body = read_body_limited(resp, limit).await?;
+                let rt = parse_json(&body)?;
+                mc.insert(&key, body, ids, epoch);
+                return Ok(rt);
+            }
+            _ => (),
+        }
+        let cache = match self.client.cache.clone() {
+            Some(cache) if rq.method() == Method::GET && self.transfer.is_none() => cache,
+            _ => {
+                let reporter = match self.transfer {
+                    Some(kind) => self.client.start_transfer(kind, rq.url()),
+                    None => None,
+                };
+                let bytes = rq.body().and_then(|b| b.as_bytes()).map(|b| b.len() as u64);
+                if let (Some(t), Some(n)) = (self.client.throttle.as_ref(), bytes) {
+                    if self.transfer.is_some() {
+                        t.consume(n).await;
+                    }
+                }
+                let r = match self
+                    .client
+                    .execute(rq, reporter.as_ref(), self.deadline)
+                    .await
+                {
+                    Ok(resp) => read_body_to_json(resp, limit).await,
+                    Err(e) => Err(e),
+                };
+                if let Some(reporter) = reporter {
+                    reporter.finish(&r, bytes);
+                }
+                return r;
+            }
+        };
+
+        let key = rq.url().to_string();
+        let ids = identifiers(rq.url());
+        let cached = cache.get(&key).await;
+        if let Some(ref c) = cached {
+            rq.headers_mut()
+                .insert(IF_NONE_MATCH, HeaderValue::from_str(&c.etag)?);
+        }
+        let resp = self.client.execute(rq, None, self.deadline).await?;
+        if resp.status() == StatusCode::NOT_MODIFIED {
+            if let Some(c) = cached {
+                info!(target: "hd_api::http", "not modified, serving {} from cache", key);
+                return parse_json(&c.body);
+            }
+        }
+        let etag = resp
+            .headers()
+            .get(ETAG)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from);
+        match etag {
+            Some(etag) if resp.status().is_success() => {
+                let body = read_body_limited(resp, limit).await?;
+                let rt = parse_json(&body)?;
+                cache.put(&key, CachedResponse { etag, body, ids }).await;
+                Ok(rt)
+            }
+            _ => read_body_to_json(resp, limit).await,
+        }
     }
 
+    /// Send the request and return the response body, regardless of status.
     pub async fn go_raw(self) -> Result<String> {
         info!(target: "hd_api::http", "sending http request: {:?}", self.rqb);
-        let resp = self.rqb.send().await?;
-        Ok(resp.text().await?)
+        let limit = self.client.max_body_size;
+        let cancel = self.cancel.clone();
+        cancellable(cancel, async move {
+            let resp = self.send().await?;
+            read_body_limited(resp, limit).await
+        })
+        .await
+    }
+
+    /// Send the request and hand the response to `cb`, e.g. for streaming or custom parsing.
+    pub async fn go_cb<T, F, Fut>(self, cb: F) -> Result<T>
+    where
+        F: FnOnce(reqwest::Response) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        info!(target: "hd_api::http", "sending http request: {:?}", self.rqb);
+        let cancel = self.cancel.clone();
+        cancellable(cancel, async move { cb(self.send().await?).await }).await
     }
 
+    /// Send the request and write the response body to `dst`. Returns the number of bytes
+    /// written.
     pub async fn download_file<W: AsyncWrite + Unpin>(self, dst: W) -> Result<usize> {
         info!(target: "hd_api::http", "sending http request for download: {:?}", self.rqb);
-        write_response_to_file(self.rqb.send().await?, dst).await
+        let cancel = self.cancel.clone();
+        cancellable(cancel, async move {
+            let rq = self.rqb.build()?;
+            let reporter = self.client.start_transfer(TransferKind::Download, rq.url());
+            let throttle = self.client.throttle.clone();
+            let r = match self
+                .client
+                .execute(rq, reporter.as_ref(), self.deadline)
+                .await
+            {
+                Ok(resp) => {
+                    write_response_to_file(resp, dst, throttle.as_deref(), |n| {
+                        if let Some(ref reporter) = reporter {
+                            reporter.progress(n);
+                        }
+                    })
+                    .await
+                }
+                Err(e) => Err(e),
+            };
+            if let Some(reporter) = reporter {
+                let bytes = r.as_ref().ok().map(|n| *n as u64);
+                reporter.finish(&r, bytes);
+            }
+            r
+        })
+        .await
+    }
+
+    /// Request the bytes `range` of the resource and write them to `dst` at offset
+    /// `range.start`. Fails if the server doesn't answer with exactly that range (`206 Partial
+    /// Content`). Returns the number of bytes written; an empty range is not requested.
+    pub async fn download_range<W: AsyncWrite + AsyncSeek + Unpin>(
+        self,
+        mut dst: W,
+        range: Range<u64>,
+    ) -> Result<usize> {
+        if range.is_empty() {
+            return Ok(0);
+        }
+        let rq = self.set_header(RANGE, format!("bytes={}-{}", range.start, range.end - 1));
+        info!(target: "hd_api::http", "sending http request for range download: {:?}", rq.rqb);
+        let cancel = rq.cancel.clone();
+        let throttle = rq.client.throttle.clone();
+        cancellable(cancel, async move {
+            let resp = rq.send().await?;
+            if resp.status().is_success() && resp.status() != StatusCode::PARTIAL_CONTENT {
+                return Err(Error::msg(format!(
+                    "download_range: expected 206 Partial Content, got {}",
+                    resp.status()
+                )));
+            }
+            dst.seek(SeekFrom::Start(range.start)).await?;
+            let n = write_response_to_file(resp, &mut dst, throttle.as_deref(), |_| {}).await?;
+            if n as u64 != range.end - range.start {
+                return Err(Error::msg(format!(
+                    "download_range: expected {} bytes, got {}",
+                    range.end - range.start,
+                    n
+                )));
+            }
+            Ok(n)
+        })
+        .await
+    }
+
+    /// Abort the request with a `Cancelled` error once `cancel` is triggered.
+    pub fn set_cancellation(self, cancel: CancellationToken) -> Self {
+        Self {
+            cancel: Some(cancel),
+            ..self
+        }
+    }
+
+    /// Serve the response from the client's `MetadataCache`, if any, and store it there.
+    pub fn set_cacheable(self) -> Self {
+        Self {
+            cacheable: true,
+            ..self
+        }
     }
 
     pub fn set_body<B: Into<reqwest::Body>>(self, b: B) -> Self {
         Self {
             rqb: self.rqb.body(b),
+            ..self
         }
     }
 
+    /// Set a header. Panics if `v` is not a valid header value.
     pub fn set_header<K: Into<HeaderName>, V: AsRef<str>>(self, k: K, v: V) -> Self {
         Self {
             rqb: self
                 .rqb
                 .header(k, HeaderValue::from_str(v.as_ref()).unwrap()),
+            ..self
         }
     }
 
+    /// Add `headers` to the request, replacing previously set headers with the same names.
+    pub fn set_headers(self, headers: HeaderMap) -> Self {
+        Self {
+            rqb: self.rqb.headers(headers),
+            ..self
+        }
+    }
+
+    /// Set `b` as body with content type `application/octet-stream`.
     pub fn set_attachment<B: Into<reqwest::Body>>(self, b: B) -> Self {
-        self.set_header(reqwest::header::CONTENT_TYPE, "application/octet-stream")
-            .set_body(b)
+        let rq = self
+            .set_header(reqwest::header::CONTENT_TYPE, "application/octet-stream")
+            .set_body(b);
+        Self {
+            transfer: Some(TransferKind::Upload),
+            ..rq
+        }
+    }
+}
+
+/// A transport returning canned responses, for use in tests.
+#[cfg(test)]
+pub(crate) mod mock {
+    use super::Transport;
+
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+
+    use anyhow::Result;
+
+    pub const TOKEN_RESPONSE: &str = r#"{
+  "refresh_token": "rt-abcdeabcde",
+  "expires_in": 3600,
+  "userid": "12345.12345.12345",
+  "access_token": "ssklnLKwerlnc9sal",
+  "alias": "uvwxyz",
+  "token_type": "Bearer",
+  "scope": "rw,user"
+}"#;
+
+    /// A request as seen by the `MockTransport`.
+    #[derive(Debug, Clone)]
+    pub struct Recorded {
+        pub method: reqwest::Method,
+        pub url: reqwest::Url,
+        pub headers: reqwest::header::HeaderMap,
+        pub body: Option<Vec<u8>>,
+    }
+
+    impl Recorded {
+        /// Return the value of query parameter `k`, if present.
+        pub fn param(&self, k: &str) -> Option<String> {
+            self.url
+                .query_pairs()
+                .find(|(kk, _)| kk == k)
+                .map(|(_, v)| v.into_owned())
+        }
+    }
+
+    /// MockTransport answers requests to the OAuth2 token endpoint with `TOKEN_RESPONSE`, and all
+    /// other requests with queued responses (or an empty 200 response if none are queued).
+    #[derive(Default)]
+    pub struct MockTransport {
+        responses: Mutex<VecDeque<(u16, Vec<(String, String)>, String)>>,
+        requests: Mutex<Vec<Recorded>>,
+    }
+
+    impl MockTransport {
+        pub fn new() -> Arc<MockTransport> {
+            Arc::new(MockTransport::default())
+        }
+
+        pub fn push(&self, status: u16, body: impl Into<String>) {
+            self.push_with_headers(status, &[], body)
+        }
+
+        pub fn push_with_headers(
+            &self,
+            status: u16,
+            headers: &[(&str, &str)],
+            body: impl Into<String>,
+        ) {
+            let headers = headers
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            self.responses
+                .lock()
+                .unwrap()
+                .push_back((status, headers, body.into()));
+        }
+
+        pub fn requests(&self) -> Vec<Recorded> {
+            self.requests.lock().unwrap().clone()
+        }
+
+        pub fn last(&self) -> Recorded {
+            self.requests.lock().unwrap().last().cloned().unwrap()
+        }
+    }
+
+    /// A `HiDrive` hub sending all requests, including token refreshes, through `t`.
+    pub(crate) fn hidrive(t: Arc<MockTransport>) -> crate::hidrive::HiDrive {
+        let cred: crate::oauth2::Credentials = serde_json::from_str(TOKEN_RESPONSE).unwrap();
+        let authz = crate::oauth2::Authorizer::new_with_transport(
+            cred,
+            crate::oauth2::ClientSecret::default(),
+            t.clone(),
+        );
+        crate::hidrive::HiDrive::new_with_transport(t, authz)
+    }
+
+    pub fn response(status: u16, body: impl Into<String>) -> reqwest::Response {
+        response_with_headers(status, &[], body)
+    }
+
+    pub fn response_with_headers(
+        status: u16,
+        headers: &[(String, String)],
+        body: impl Into<String>,
+    ) -> reqwest::Response {
+        let mut rb = hyper::Response::builder().status(status);
+        for (k, v) in headers {
+            rb = rb.header(k.as_str(), v.as_str());
+        }
+        rb.body(body.into()).unwrap().into()
+    }
+
+    #[async_trait::async_trait]
+    impl Transport for MockTransport {
+        async fn execute(&self, rq: reqwest::Request) -> Result<reqwest::Response> {
+            if rq.url().path().ends_with("/oauth2/token") {
+                return Ok(response(200, TOKEN_RESPONSE));
+            }
+            self.requests.lock().unwrap().push(Recorded {
+                method: rq.method().clone(),
+                url: rq.url().clone(),
+                headers: rq.headers().clone(),
+                body: rq.body().and_then(|b| b.as_bytes()).map(|b| b.to_vec()),
+            });
+            let (status, headers, body) =
+                self.responses
+                    .lock()
+                    .unwrap()
+                    .pop_front()
+                    .unwrap_or((200, vec![], String::new()));
+            Ok(response_with_headers(status, &headers, body))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_from_body() {
+        let e = error_from_body(
+            StatusCode::NOT_FOUND,
+            Some("application/json".into()),
+            r#"{"code": 404, "msg": "Not Found"}"#,
+        );
+        assert_eq!(404, e.downcast_ref::<ApiError>().unwrap().code);
+        let e = error_from_body(
+            StatusCode::FORBIDDEN,
+            None,
+            r#"{"code": "403", "msg": "x"}"#,
+        );
+        assert_eq!(403, e.downcast_ref::<ApiError>().unwrap().code);
+        let e = error_from_body(
+            StatusCode::CONFLICT,
+            None,
+            r#"{"code": 0, "msg": "exists"}"#,
+        );
+        assert_eq!(Some(409), error_status(&e));
+        let e = error_from_body(StatusCode::BAD_REQUEST, None, r#"{"code": -1, "msg": [1]}"#);
+        assert_eq!(Some(400), error_status(&e));
+
+        let html = format!("<html>{}</html>", "ä".repeat(200));
+        let e = error_from_body(StatusCode::BAD_GATEWAY, Some("text/html".into()), &html);
+        let e = e.downcast_ref::<HttpStatusError>().unwrap();
+        assert_eq!(502, e.status);
+        assert_eq!(Some("text/html"), e.content_type.as_deref());
+        assert!(e.snippet.starts_with("<html>ää"));
+        assert!(e.snippet.len() <= ERROR_SNIPPET_LEN);
+
+        let e = error_from_body(StatusCode::SERVICE_UNAVAILABLE, None, "");
+        assert_eq!(503, e.downcast_ref::<HttpStatusError>().unwrap().status);
+    }
+
+    #[test]
+    fn test_curl_command() {
+        let cl = reqwest::Client::new();
+        let rq = cl
+            .post("https://api.hidrive.strato.com/2.1/file?dir=/a&name=x.txt&access_token=abc")
+            .header(AUTHORIZATION, "Bearer abc")
+            .body("it's")
+            .build()
+            .unwrap();
+        assert_eq!(
+            "curl -X POST -H 'authorization: REDACTED' --data-binary 'it'\\''s' 'https://api.hidrive.strato.com/2.1/file?dir=%2Fa&name=x.txt&access_token=REDACTED'",
+            curl_command(&rq)
+        );
+    }
+
+    #[test]
+    fn test_curl_command_redacts_body() {
+        let cl = reqwest::Client::new();
+        let rq = cl
+            .post("https://my.hidrive.com/oauth2/token")
+            .form(&[
+                ("client_id", "abc"),
+                ("client_secret", "def"),
+                ("code", "x y"),
+            ])
+            .build()
+            .unwrap();
+        assert!(curl_command(&rq)
+            .contains("--data-binary 'client_id=abc&client_secret=REDACTED&code=REDACTED'"));
+        let rq = cl
+            .post("https://api.hidrive.strato.com/2.1/share/token")
+            .header(CONTENT_TYPE, "application/json")
+            .body(r#"{"id": "Ab3dEf", "password": "secret"}"#)
+            .build()
+            .unwrap();
+        let cmd = curl_command(&rq);
+        assert!(cmd.contains(r#""password":"REDACTED""#));
+        assert!(!cmd.contains("secret"));
     }
 }
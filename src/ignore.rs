@@ -0,0 +1,261 @@
+//! Rules excluding files from synchronization, in the syntax of `.gitignore` files.
+//!
+//! Each line is a pattern; `*` and `?` match within a path component, `**` across components,
+//! and `[...]` a character class. Patterns containing a `/` (except at the end) are relative to
+//! the synchronized directory, others match names at any depth. A trailing `/` restricts a
+//! pattern to directories, a leading `!` re-includes what earlier patterns excluded. The last
+//! matching pattern wins. Contents of excluded directories are excluded, too.
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+/// The file in a synchronized local directory containing its ignore rules.
+pub const IGNORE_FILE: &str = ".hdignore";
+
+#[derive(Debug, Clone)]
+struct Rule {
+    pattern: String,
+    negate: bool,
+    dir_only: bool,
+    anchored: bool,
+}
+
+/// A list of ignore patterns, see the module documentation.
+#[derive(Debug, Clone, Default)]
+pub struct IgnoreRules {
+    rules: Vec<Rule>,
+}
+
+impl IgnoreRules {
+    pub fn new() -> IgnoreRules {
+        IgnoreRules::default()
+    }
+
+    /// Parse the contents of an ignore file. Empty lines and lines starting with `#` are
+    /// skipped.
+    pub fn parse(text: &str) -> IgnoreRules {
+        let mut r = IgnoreRules::new();
+        for l in text.lines() {
+            r.add(l);
+        }
+        r
+    }
+
+    /// Load the ignore file `path`. A missing file results in empty rules.
+    pub async fn load(path: impl AsRef<Path>) -> Result<IgnoreRules> {
+        let path = path.as_ref();
+        match tokio::fs::read_to_string(path).await {
+            Ok(s) => Ok(IgnoreRules::parse(&s)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(IgnoreRules::new()),
+            Err(e) => Err(e).with_context(|| format!("reading ignore file {:?}", path)),
+        }
+    }
+
+    /// Add a pattern. Prefix it with `!` to include paths excluded by earlier patterns.
+    pub fn add(&mut self, pattern: &str) -> &mut Self {
+        let mut p = pattern.trim_end_matches(['\r', '\n']);
+        if !p.ends_with("\\ ") {
+            p = p.trim_end_matches(' ');
+        }
+        if p.is_empty() || p.starts_with('#') {
+            return self;
+        }
+        let negate = p.starts_with('!');
+        if negate {
+            p = &p[1..];
+        } else if p.starts_with("\\!") || p.starts_with("\\#") {
+            p = &p[1..];
+        }
+        let dir_only = p.ends_with('/');
+        let p = p.trim_end_matches('/');
+        let anchored = p.contains('/');
+        let p = p.trim_start_matches('/');
+        if p.is_empty() {
+            return self;
+        }
+        self.rules.push(Rule {
+            pattern: p.to_string(),
+            negate,
+            dir_only,
+            anchored,
+        });
+        self
+    }
+
+    /// Append the patterns of `other`, which take precedence over those of `self`.
+    pub fn extend(&mut self, other: &IgnoreRules) -> &mut Self {
+        self.rules.extend(other.rules.iter().cloned());
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Returns true if `rel`, a path relative to the synchronized directory with `/` as
+    /// separator, is excluded. The parent directories of `rel` are assumed not to be excluded,
+    /// as is the case when walking a tree.
+    pub fn is_ignored(&self, rel: &str, is_dir: bool) -> bool {
+        let name = rel.rsplit('/').next().unwrap_or(rel);
+        let mut ignored = false;
+        for r in self.rules.iter() {
+            if r.negate != ignored || (r.dir_only && !is_dir) {
+                continue;
+            }
+            let subject = if r.anchored { rel } else { name };
+            if glob_match(&r.pattern, subject) {
+                ignored = !r.negate;
+            }
+        }
+        ignored
+    }
+}
+
+/// Match `s` against the glob pattern `p`. `*` and `?` don't match `/`.
+fn glob_match(p: &str, s: &str) -> bool {
+    let p: Vec<char> = p.chars().collect();
+    let s: Vec<char> = s.chars().collect();
+    let memo = vec![None; (p.len() + 1) * (s.len() + 1)];
+    Glob { p: &p, s: &s, memo }.at(0, 0)
+}
+
+/// A pattern being matched. Results are memoized per position in pattern and subject, so that
+/// patterns like `*a*a*b` don't take exponential time.
+struct Glob<'a> {
+    p: &'a [char],
+    s: &'a [char],
+    memo: Vec<Option<bool>>,
+}
+
+impl Glob<'_> {
+    /// Whether `s[j..]` matches `p[i..]`.
+    fn at(&mut self, i: usize, j: usize) -> bool {
+        let k = i * (self.s.len() + 1) + j;
+        if let Some(m) = self.memo[k] {
+            return m;
+        }
+        let m = self.compute(i, j);
+        self.memo[k] = Some(m);
+        m
+    }
+
+    fn compute(&mut self, i: usize, j: usize) -> bool {
+        let (p, s) = (self.p, self.s);
+        let c = s.get(j).copied();
+        match p.get(i) {
+            None => j == s.len(),
+            Some('*') if p.get(i + 1) == Some(&'*') => match p.get(i + 2) {
+                None => true,
+                // `**/` matches zero or more directories.
+                Some('/') => {
+                    self.at(i + 3, j) || (j..s.len()).any(|k| s[k] == '/' && self.at(i + 3, k + 1))
+                }
+                Some(_) => (j..=s.len()).any(|k| self.at(i + 2, k)),
+            },
+            Some('*') => {
+                for k in j..=s.len() {
+                    if self.at(i + 1, k) {
+                        return true;
+                    }
+                    if k < s.len() && s[k] == '/' {
+                        break;
+                    }
+                }
+                false
+            }
+            Some('?') => c.is_some_and(|c| c != '/') && self.at(i + 1, j + 1),
+            Some('[') => match match_class(&p[i + 1..], c) {
+                Some((true, len)) => self.at(i + 1 + len, j + 1),
+                Some((false, _)) => false,
+                // No closing bracket: a literal `[`.
+                None => c == Some('[') && self.at(i + 1, j + 1),
+            },
+            Some('\\') if i + 1 < p.len() => c == Some(p[i + 1]) && self.at(i + 2, j + 1),
+            Some(&pc) => c == Some(pc) && self.at(i + 1, j + 1),
+        }
+    }
+}
+
+/// Match `c` against the character class starting after `[` in `p`. Returns whether it matched
+/// and the length of the class including `]`, or `None` if the class isn't terminated.
+fn match_class(p: &[char], c: Option<char>) -> Option<(bool, usize)> {
+    let negate = matches!(p.first(), Some('!') | Some('^'));
+    let mut i = usize::from(negate);
+    let mut matched = false;
+    let mut first = true;
+    while i < p.len() {
+        if p[i] == ']' && !first {
+            let ok = c.is_some_and(|c| c != '/' && matched != negate);
+            return Some((ok, i + 1));
+        }
+        first = false;
+        let lo = p[i];
+        if i + 2 < p.len() && p[i + 1] == '-' && p[i + 2] != ']' {
+            matched |= c.is_some_and(|c| lo <= c && c <= p[i + 2]);
+            i += 3;
+        } else {
+            matched |= c == Some(lo);
+            i += 1;
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        let m = glob_match;
+        assert!(m("*.o", "main.o"));
+        assert!(!m("*.o", "src/main.o"));
+        assert!(m("src/*.o", "src/main.o"));
+        assert!(m("**/*.o", "main.o"));
+        assert!(m("**/*.o", "a/b/main.o"));
+        assert!(m("a/**/b", "a/b"));
+        assert!(m("a/**/b", "a/x/y/b"));
+        assert!(m("a/**", "a/x/y"));
+        assert!(m("file?.[ch]", "file1.c"));
+        assert!(!m("file?.[!ch]", "file1.c"));
+        assert!(m("[a-c]x", "bx"));
+        assert!(m("\\*", "*"));
+        assert!(!m("\\*", "x"));
+        assert!(m("a?c", "aäc"));
+        assert!(m("[äö]x", "öx"));
+        assert!(!m("[!ä]x", "äx"));
+        // Would take exponential time without memoization.
+        let long = "a".repeat(100);
+        assert!(!m("*a*a*a*a*a*a*a*a*a*a*a*a*b", &long));
+        assert!(!m("**a**a**a**a**a**a**a**a**b", &long));
+    }
+
+    #[test]
+    fn test_ignore_rules() {
+        let r = IgnoreRules::parse(
+            "# build output\n\
+             target/\n\
+             *.tmp\n\
+             !keep.tmp\n\
+             /local.cfg\n\
+             docs/*.pdf\n",
+        );
+        assert!(r.is_ignored("target", true));
+        assert!(r.is_ignored("sub/target", true));
+        assert!(!r.is_ignored("target", false));
+        assert!(r.is_ignored("a.tmp", false));
+        assert!(r.is_ignored("sub/a.tmp", false));
+        assert!(!r.is_ignored("sub/keep.tmp", false));
+        assert!(r.is_ignored("local.cfg", false));
+        assert!(!r.is_ignored("sub/local.cfg", false));
+        assert!(r.is_ignored("docs/x.pdf", false));
+        assert!(!r.is_ignored("docs/sub/x.pdf", false));
+
+        let mut r2 = IgnoreRules::new();
+        r2.add("!a.tmp");
+        let mut r3 = r.clone();
+        r3.extend(&r2);
+        assert!(!r3.is_ignored("a.tmp", false));
+    }
+}
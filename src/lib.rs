@@ -1,14 +1,67 @@
 //! This crate provides access to the HiDrive HTTP API, including OAuth flow.
 
-mod chunking;
-mod http;
+#[cfg(feature = "cassette")]
+pub mod cassette;
 
+pub mod accounts;
+#[cfg(feature = "sync")]
+pub mod archive;
+#[cfg(all(feature = "sync", feature = "chunking"))]
+pub mod backup;
+#[cfg(feature = "blocking")]
+pub mod blocking;
+pub mod cache;
+#[cfg(feature = "sync")]
+pub mod checksums;
+#[cfg(feature = "chunking")]
+pub mod chunking;
+#[cfg(feature = "chunking")]
+pub mod chunkstore;
+#[cfg(feature = "sync")]
+pub mod conflicts;
+#[cfg(all(feature = "daemon", unix))]
+pub mod daemon;
+#[cfg(feature = "opendal")]
+pub mod dal;
+#[cfg(feature = "chunking")]
+pub mod dedup;
+pub mod du;
+#[cfg(feature = "sync")]
+pub mod duplicates;
 pub mod hashing;
 pub mod hidrive;
+pub mod http;
+pub mod ignore;
+pub mod metrics;
 pub mod oauth2;
+#[cfg(feature = "chunking")]
+pub mod patch;
+#[cfg(feature = "sync")]
+pub mod planner;
+#[cfg(feature = "sync")]
+mod platform;
+#[cfg(feature = "hashing")]
+pub mod queue;
+pub mod remote;
+#[cfg(feature = "hashing")]
+mod resume;
+#[cfg(feature = "serve")]
+pub mod serve;
+#[cfg(feature = "tower")]
+pub mod service;
+pub mod sharelink;
+#[cfg(feature = "object_store")]
+pub mod store;
+#[cfg(feature = "sync")]
+pub mod sync;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
+pub mod throttle;
+pub mod thumbnails;
 pub mod types;
 
 pub use hidrive::HiDrive;
+pub use http::Transport;
 
 pub use oauth2::{Authorizer, ClientSecret, Credentials};
 pub use types::{Identifier, Params};
@@ -0,0 +1,208 @@
+//! Counters and gauges for monitoring long-running synchronizations.
+//!
+//! Components report to a `Metrics` implementation, e.g. `sync::MetricsObserver` for sync runs.
+//! `PrometheusMetrics` keeps the current values and renders them in the Prometheus text
+//! exposition format, and `serve()` exposes them to Prometheus; implement `Metrics` to forward
+//! them to another metrics library instead.
+//!
+//! Besides sync runs, backups (`backup::BackupOptions::metrics`) and transfer queues
+//! (`queue::TransferQueue::set_metrics()`) report metrics.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+
+#[cfg(feature = "serve")]
+use std::convert::Infallible;
+#[cfg(feature = "serve")]
+use std::net::TcpListener;
+
+#[cfg(feature = "serve")]
+use anyhow::{Context, Result};
+#[cfg(feature = "serve")]
+use hyper::service::{make_service_fn, service_fn};
+#[cfg(feature = "serve")]
+use hyper::{Body, Request, Response, StatusCode};
+#[cfg(feature = "serve")]
+use log::info;
+#[cfg(feature = "serve")]
+use tokio_util::sync::CancellationToken;
+
+/// Receives metric updates. Names follow the Prometheus conventions, e.g.
+/// `hd_sync_bytes_transferred_total`.
+pub trait Metrics: Send + Sync {
+    /// Add `inc` to the counter `name`.
+    fn counter(&self, name: &'static str, help: &'static str, inc: u64);
+    /// Set the gauge `name` to `value`.
+    fn gauge(&self, name: &'static str, help: &'static str, value: f64);
+}
+
+impl<M: Metrics + ?Sized> Metrics for Arc<M> {
+    fn counter(&self, name: &'static str, help: &'static str, inc: u64) {
+        (**self).counter(name, help, inc)
+    }
+
+    fn gauge(&self, name: &'static str, help: &'static str, value: f64) {
+        (**self).gauge(name, help, value)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Value {
+    Counter(u64),
+    Gauge(f64),
+}
+
+#[derive(Debug)]
+struct Metric {
+    help: &'static str,
+    value: Value,
+}
+
+/// Keeps the current value of each metric, for rendering with `render()`, e.g. when serving a
+/// `/metrics` endpoint.
+#[derive(Debug, Default)]
+pub struct PrometheusMetrics {
+    metrics: Mutex<BTreeMap<&'static str, Metric>>,
+}
+
+impl PrometheusMetrics {
+    pub fn new() -> PrometheusMetrics {
+        PrometheusMetrics::default()
+    }
+
+    /// The current value of `name`, if it has been reported.
+    pub fn get(&self, name: &str) -> Option<f64> {
+        self.metrics
+            .lock()
+            .unwrap()
+            .get(name)
+            .map(|m| match m.value {
+                Value::Counter(v) => v as f64,
+                Value::Gauge(v) => v,
+            })
+    }
+
+    /// All metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for (name, m) in self.metrics.lock().unwrap().iter() {
+            let (typ, value) = match m.value {
+                Value::Counter(v) => ("counter", v.to_string()),
+                Value::Gauge(v) => ("gauge", v.to_string()),
+            };
+            let _ = writeln!(
+                out,
+                "# HELP {0} {1}\n# TYPE {0} {2}\n{0} {3}",
+                name, m.help, typ, value
+            );
+        }
+        out
+    }
+}
+
+impl Metrics for PrometheusMetrics {
+    fn counter(&self, name: &'static str, help: &'static str, inc: u64) {
+        let mut metrics = self.metrics.lock().unwrap();
+        let m = metrics.entry(name).or_insert(Metric {
+            help,
+            value: Value::Counter(0),
+        });
+        if let Value::Counter(v) = &mut m.value {
+            *v += inc;
+        }
+    }
+
+    fn gauge(&self, name: &'static str, help: &'static str, value: f64) {
+        self.metrics.lock().unwrap().insert(
+            name,
+            Metric {
+                help,
+                value: Value::Gauge(value),
+            },
+        );
+    }
+}
+
+/// Serve `metrics` at `/metrics` to requests arriving on `listener`, until `cancel` is
+/// triggered.
+///
+/// ```ignore
+/// let listener = std::net::TcpListener::bind("127.0.0.1:9100")?;
+/// tokio::spawn(metrics::serve(metrics.clone(), listener, cancel.clone()));
+/// ```
+#[cfg(feature = "serve")]
+pub async fn serve(
+    metrics: Arc<PrometheusMetrics>,
+    listener: TcpListener,
+    cancel: CancellationToken,
+) -> Result<()> {
+    let mk = make_service_fn(move |_: &hyper::server::conn::AddrStream| {
+        let metrics = metrics.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |rq: Request<Body>| {
+                let rp = if rq.uri().path() == "/metrics" {
+                    Response::builder()
+                        .header("content-type", "text/plain; version=0.0.4")
+                        .body(metrics.render().into())
+                } else {
+                    Response::builder()
+                        .status(StatusCode::NOT_FOUND)
+                        .body(Body::empty())
+                };
+                async move { rp }
+            }))
+        }
+    });
+    let srv = hyper::Server::from_tcp(listener)
+        .context("metrics: listening")?
+        .serve(mk);
+    info!(target: "hd_api::metrics", "serving metrics on {}", srv.local_addr());
+    srv.with_graceful_shutdown(async move { cancel.cancelled().await })
+        .await
+        .context("metrics server")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let m = Arc::new(PrometheusMetrics::new());
+        m.counter("b_total", "Bees.", 2);
+        m.counter("b_total", "Bees.", 3);
+        m.gauge("a", "An A.", 1.5);
+        assert_eq!(Some(5.0), m.get("b_total"));
+        assert_eq!(None, m.get("c"));
+        assert_eq!(
+            "# HELP a An A.\n# TYPE a gauge\na 1.5\n# HELP b_total Bees.\n# TYPE b_total counter\nb_total 5\n",
+            m.render()
+        );
+    }
+
+    #[cfg(feature = "serve")]
+    #[tokio::test]
+    async fn test_serve() {
+        let m = Arc::new(PrometheusMetrics::new());
+        m.counter("a_total", "As.", 1);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let cancel = CancellationToken::new();
+        let srv = tokio::spawn(serve(m.clone(), listener, cancel.clone()));
+
+        m.counter("a_total", "As.", 1);
+        let body = reqwest::get(format!("{}/metrics", base))
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert_eq!(m.render(), body);
+        assert!(body.contains("a_total 2"));
+        let rp = reqwest::get(format!("{}/other", base)).await.unwrap();
+        assert_eq!(404, rp.status());
+        cancel.cancel();
+        srv.await.unwrap().unwrap();
+    }
+}
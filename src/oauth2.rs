@@ -4,21 +4,32 @@
 // Implement revocation
 
 use std::fmt::{self, Display, Formatter};
-use std::pin::pin;
-use std::time::Duration;
+use std::sync::Arc;
 
 use anyhow::{self, Context, Result};
-use log::{self, error, info};
+use log::{self, info};
 
-use futures_util::future::{select, FutureExt};
-use hyper::{server, service};
 use serde::{Deserialize, Serialize};
 use serde_json::{from_str, to_string_pretty};
 use time::ext::NumericalDuration;
 use tokio::fs;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+#[cfg(feature = "oauth-server")]
+use futures_util::future::{select, FutureExt};
+#[cfg(feature = "oauth-server")]
+use hyper::{server, service};
+#[cfg(feature = "oauth-server")]
+use log::error;
+#[cfg(feature = "oauth-server")]
+use std::pin::pin;
+#[cfg(feature = "oauth-server")]
+use std::time::Duration;
+#[cfg(feature = "oauth-server")]
 use tokio::sync::mpsc;
 
+use crate::http::Transport;
+
 /// An application's client secret.
 #[derive(Deserialize, Default, Clone, Debug)]
 pub struct ClientSecret {
@@ -95,12 +106,14 @@ impl Credentials {
 }
 
 /// Authorizer is responsible for issuing Bearer tokens to HTTP requests, refreshing the access
-/// token when necessary.
+/// token when necessary. Clones refresh their access tokens independently.
+#[derive(Clone)]
 pub struct Authorizer {
     cred: Credentials,
     cs: ClientSecret,
 
     http_cl: reqwest::Client,
+    transport: Arc<dyn Transport>,
 
     token_url: String,
     current_token: Option<(String, time::Instant)>,
@@ -109,29 +122,52 @@ pub struct Authorizer {
 impl Authorizer {
     /// Create a new Authorizer instance.
     pub fn new(cred: Credentials, cs: ClientSecret) -> Authorizer {
+        Self::new_with_client(cred, cs, reqwest::Client::new())
+    }
+
+    pub fn new_with_client(
+        cred: Credentials,
+        cs: ClientSecret,
+        http_cl: reqwest::Client,
+    ) -> Authorizer {
+        let transport = Arc::new(http_cl.clone());
         Authorizer {
             cred,
             cs,
-            http_cl: reqwest::Client::new(),
+            http_cl,
+            transport,
             token_url: DEFAULT_TOKEN_URL.into(),
             current_token: None,
         }
     }
 
-    pub fn new_with_client(
+    /// Create an Authorizer refreshing tokens via `transport`.
+    pub fn new_with_transport(
         cred: Credentials,
         cs: ClientSecret,
-        http_cl: reqwest::Client,
+        transport: Arc<dyn Transport>,
     ) -> Authorizer {
         Authorizer {
             cred,
             cs,
-            http_cl,
+            http_cl: reqwest::Client::new(),
+            transport,
             token_url: DEFAULT_TOKEN_URL.into(),
             current_token: None,
         }
     }
 
+    /// Obtain tokens from `url` instead of the HiDrive OAuth2 endpoint.
+    pub fn set_token_url(&mut self, url: impl Into<String>) {
+        self.token_url = url.into();
+    }
+
+    /// Refresh tokens using `cl`.
+    pub fn set_client(&mut self, cl: reqwest::Client) {
+        self.transport = Arc::new(cl.clone());
+        self.http_cl = cl;
+    }
+
     /// Returns a Bearer token for subsequent use.
     pub async fn token(&mut self) -> anyhow::Result<String> {
         // TODO: cache current token on disk and use it if not elapsed yet. This saves one oauth
@@ -152,6 +188,13 @@ impl Authorizer {
         Ok(self.current_token.as_ref().unwrap().0.clone())
     }
 
+    /// Discard the current access token and obtain a new one. Use this if the API rejected a
+    /// token that should still be valid.
+    pub async fn refresh_token(&mut self) -> anyhow::Result<String> {
+        self.current_token = None;
+        self.token().await
+    }
+
     async fn refresh(&mut self) -> anyhow::Result<(String, time::Instant)> {
         let t = time::Instant::now();
         let url = format!(
@@ -163,8 +206,8 @@ impl Authorizer {
                 anyhow::Error::new(e).context("Couldn't build token exchange request.")
             })?;
         info!(target: "hd_api::oauth2", "Refreshing OAuth2 access: {:?}", req);
-        let resp = match self.http_cl.execute(req).await {
-            Err(e) => return Err(anyhow::Error::new(e).context("Couldn't exchange code for token")),
+        let resp = match self.transport.execute(req).await {
+            Err(e) => return Err(e.context("Couldn't exchange code for token")),
             Ok(resp) => resp,
         };
         info!(target: "hd_api::oauth2", "Refresh request got response: {:?}", resp);
@@ -206,7 +249,9 @@ pub struct LogInFlow {
 
     lang: Lang,
 
+    #[cfg(feature = "oauth-server")]
     ok_body: String,
+    #[cfg(feature = "oauth-server")]
     err_body: String,
 
     state: LogInState,
@@ -286,7 +331,8 @@ impl Display for Lang {
 
 // TODO: These could be read from the client secret file.
 const DEFAULT_AUTHORIZATION_URL: &str = "https://my.hidrive.com/oauth2/authorize";
-const DEFAULT_TOKEN_URL: &str = "https://my.hidrive.com/oauth2/token";
+pub(crate) const DEFAULT_TOKEN_URL: &str = "https://my.hidrive.com/oauth2/token";
+#[cfg(feature = "oauth-server")]
 const DEFAULT_BODY_RESPONSE: &str = r"
 <html>
 <head><title>Authorization complete</title></head>
@@ -295,6 +341,7 @@ const DEFAULT_BODY_RESPONSE: &str = r"
 hd_api::oauth2 0.1
 </body>
 </html>";
+#[cfg(feature = "oauth-server")]
 const DEFAULT_ERROR_RESPONSE: &str = r"
 <html>
 <head><title>Authorization failed</title></head>
@@ -318,7 +365,9 @@ impl LogInFlow {
             cs,
             authorization_url,
             token_url,
+            #[cfg(feature = "oauth-server")]
             ok_body: DEFAULT_BODY_RESPONSE.into(),
+            #[cfg(feature = "oauth-server")]
             err_body: DEFAULT_ERROR_RESPONSE.into(),
 
             ..Default::default()
@@ -332,6 +381,7 @@ impl LogInFlow {
 
     /// Set the content displayed to a user upon encountering the redirect server operated by the
     /// LogInFlow.
+    #[cfg(feature = "oauth-server")]
     pub fn set_redirect_screen_body(&mut self, ok_body: String, err_body: String) {
         self.ok_body = ok_body;
         self.err_body = err_body;
@@ -355,6 +405,7 @@ impl LogInFlow {
     /// If your application is configured with a redirect-to-localhost scheme, this will
     /// start a web server on port 8087 (TO DO: make this adjustable) and wait for the redirect
     /// request.
+    #[cfg(feature = "oauth-server")]
     pub async fn wait_for_redirect(&mut self, abort_p: impl Fn() -> bool) -> anyhow::Result<()> {
         let rdr = RedirectHandlingServer::new(self.ok_body.clone(), self.err_body.clone());
         match rdr.start_and_wait_for_code(abort_p).await {
@@ -415,6 +466,7 @@ impl LogInFlow {
 
 /// An `AuthorizationHandler` is used by `authorize_user()` to perform some custom functionality,
 /// and give control to the calling application.
+#[cfg(feature = "oauth-server")]
 #[async_trait::async_trait]
 pub trait AuthorizationHandler: Send {
     /// Display the URL to the user, in order to start the authorization flow.
@@ -436,14 +488,17 @@ pub trait AuthorizationHandler: Send {
 }
 
 /// Authorization handler implementing the bare default functionality.
+#[cfg(feature = "oauth-server")]
 pub struct DefaultAuthorizationHandler;
 
+#[cfg(feature = "oauth-server")]
 #[async_trait::async_trait]
 impl AuthorizationHandler for DefaultAuthorizationHandler {}
 
 /// High level authorization function: Documents the typical OAuth flow, and can be used for most
 /// purposes. The `handler` is used to delegate some tasks and inform the application about the
 /// flow's progress.
+#[cfg(feature = "oauth-server")]
 pub async fn authorize_user(
     handler: &mut dyn AuthorizationHandler,
     client_secret: ClientSecret,
@@ -480,12 +535,14 @@ impl Display for OAuthError {
 }
 
 // So far only a normal Result, but can be extended.
+#[cfg(feature = "oauth-server")]
 #[derive(Debug, Clone)]
 enum LogInResult {
     Ok { code: String },
     Err { err: OAuthError },
 }
 
+#[cfg(feature = "oauth-server")]
 impl Display for LogInResult {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         match self {
@@ -495,12 +552,14 @@ impl Display for LogInResult {
     }
 }
 
+#[cfg(feature = "oauth-server")]
 struct RedirectHandlingServer {
     ok_body: String,
     err_body: String,
     port: u16,
 }
 
+#[cfg(feature = "oauth-server")]
 impl RedirectHandlingServer {
     fn new(ok_body: String, err_body: String) -> RedirectHandlingServer {
         RedirectHandlingServer {
@@ -635,6 +694,7 @@ impl RedirectHandlingServer {
 mod tests {
     use crate::oauth2;
 
+    #[cfg(feature = "oauth-server")]
     #[tokio::test]
     async fn test_code_flow() {
         let rdr = oauth2::RedirectHandlingServer::new(
@@ -669,6 +729,7 @@ mod tests {
         }
     }
 
+    #[cfg(feature = "oauth-server")]
     #[tokio::test]
     async fn manual_test() {
         // Enable this to check out the returned page manually.
@@ -680,6 +741,7 @@ mod tests {
         println!("{:?}", rdr.start_and_wait_for_code(|| false).await);
     }
 
+    #[cfg(feature = "oauth-server")]
     #[tokio::test]
     async fn manual_exchange_test() {
         return;
@@ -701,15 +763,14 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn manual_refresh_test() {
-        return;
-        let cs = oauth2::ClientSecret::load("clientsecret.json")
-            .await
-            .unwrap();
-        let cred = oauth2::Credentials::load("credentials.json").await.unwrap();
-
-        let mut authz = oauth2::Authorizer::new(cred, cs);
-        println!("first: {:?}", authz.token().await);
-        println!("repeat: {:?}", authz.token().await);
+    async fn test_refresh() {
+        let cs = oauth2::ClientSecret::default();
+        let cred: oauth2::Credentials =
+            serde_json::from_str(crate::http::mock::TOKEN_RESPONSE).unwrap();
+        let transport = crate::http::mock::MockTransport::new();
+
+        let mut authz = oauth2::Authorizer::new_with_transport(cred, cs, transport);
+        assert_eq!("ssklnLKwerlnc9sal", authz.token().await.unwrap());
+        assert_eq!("ssklnLKwerlnc9sal", authz.token().await.unwrap());
     }
 }
@@ -0,0 +1,296 @@
+//! Self-contained patches transforming one version of a file into another, e.g. for offline
+//! distribution or auditing of delta syncs.
+//!
+//! A patch is a list of operations, each either copying a range of the old file or inserting new
+//! data. It is built by chunking both versions (see `chunking`) and copying chunks that the old
+//! version already contains.
+//!
+//! File format (integers are little-endian):
+//!
+//! ```text
+//! magic      "HDPATCH\x01"
+//! new_size   u64
+//! new_hash   20 bytes, SHA-1 of the new file
+//! n_ops      u64
+//! n_ops times:
+//!   0u8, offset u64, len u64   copy len bytes from offset in the old file
+//!   1u8, len u64, data         insert data
+//! ```
+
+use crate::chunking::{Chunker, ChunkingConfig};
+use crate::hashing::Hash;
+
+use std::collections::HashMap;
+use std::io::SeekFrom;
+
+use anyhow::{self, Result};
+use futures_util::StreamExt;
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+
+const MAGIC: &[u8; 8] = b"HDPATCH\x01";
+const OP_COPY: u8 = 0;
+const OP_INSERT: u8 = 1;
+
+/// An operation of a `Patch`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PatchOp {
+    /// Copy `len` bytes at `offset` of the old file.
+    Copy { offset: u64, len: u64 },
+    /// Insert `data`.
+    Insert(Vec<u8>),
+}
+
+/// Describes how to build a new version of a file from an old one, see `make_patch()` and
+/// `apply_patch()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Patch {
+    pub new_size: u64,
+    /// SHA-1 of the new file, verified by `apply_patch()`.
+    pub new_hash: Hash,
+    pub ops: Vec<PatchOp>,
+}
+
+impl Patch {
+    /// Number of bytes copied from the old file.
+    pub fn copied_bytes(&self) -> u64 {
+        self.ops
+            .iter()
+            .map(|op| match op {
+                PatchOp::Copy { len, .. } => *len,
+                PatchOp::Insert(_) => 0,
+            })
+            .sum()
+    }
+
+    /// Number of bytes contained in the patch.
+    pub fn inserted_bytes(&self) -> u64 {
+        self.ops
+            .iter()
+            .map(|op| match op {
+                PatchOp::Copy { .. } => 0,
+                PatchOp::Insert(d) => d.len() as u64,
+            })
+            .sum()
+    }
+
+    fn push(&mut self, op: PatchOp) {
+        match (self.ops.last_mut(), op) {
+            (Some(PatchOp::Copy { offset, len }), PatchOp::Copy { offset: o, len: l })
+                if *offset + *len == o =>
+            {
+                *len += l
+            }
+            (Some(PatchOp::Insert(d)), PatchOp::Insert(mut data)) => d.append(&mut data),
+            (_, op) => self.ops.push(op),
+        }
+    }
+
+    /// Serialize the patch to `w`.
+    pub async fn write_to<W: AsyncWrite + Unpin>(&self, mut w: W) -> Result<()> {
+        w.write_all(MAGIC).await?;
+        w.write_u64_le(self.new_size).await?;
+        w.write_all(self.new_hash.as_ref()).await?;
+        w.write_u64_le(self.ops.len() as u64).await?;
+        for op in self.ops.iter() {
+            match op {
+                PatchOp::Copy { offset, len } => {
+                    w.write_u8(OP_COPY).await?;
+                    w.write_u64_le(*offset).await?;
+                    w.write_u64_le(*len).await?;
+                }
+                PatchOp::Insert(data) => {
+                    w.write_u8(OP_INSERT).await?;
+                    w.write_u64_le(data.len() as u64).await?;
+                    w.write_all(data).await?;
+                }
+            }
+        }
+        w.flush().await?;
+        Ok(())
+    }
+
+    /// Read a patch written by `write_to()`.
+    pub async fn read_from<R: AsyncRead + Unpin>(mut r: R) -> Result<Patch> {
+        let mut magic = [0u8; 8];
+        r.read_exact(&mut magic).await?;
+        if &magic != MAGIC {
+            return Err(anyhow::Error::msg("read_patch: not a patch file"));
+        }
+        let new_size = r.read_u64_le().await?;
+        let mut hash = [0u8; 20];
+        r.read_exact(&mut hash).await?;
+        let n_ops = r.read_u64_le().await?;
+        let mut patch = Patch {
+            new_size,
+            new_hash: Hash::from(hash),
+            ops: vec![],
+        };
+        let mut total = 0;
+        for _ in 0..n_ops {
+            let op = match r.read_u8().await? {
+                OP_COPY => {
+                    let offset = r.read_u64_le().await?;
+                    let len = r.read_u64_le().await?;
+                    PatchOp::Copy { offset, len }
+                }
+                OP_INSERT => {
+                    let len = r.read_u64_le().await?;
+                    if len > new_size - total {
+                        return Err(anyhow::Error::msg("read_patch: insert exceeds new size"));
+                    }
+                    let mut data = vec![0; len as usize];
+                    r.read_exact(&mut data).await?;
+                    PatchOp::Insert(data)
+                }
+                t => {
+                    return Err(anyhow::Error::msg(format!(
+                        "read_patch: unknown operation {}",
+                        t
+                    )))
+                }
+            };
+            total += match op {
+                PatchOp::Copy { len, .. } => len,
+                PatchOp::Insert(ref d) => d.len() as u64,
+            };
+            if total > new_size {
+                return Err(anyhow::Error::msg("read_patch: operations exceed new size"));
+            }
+            patch.ops.push(op);
+        }
+        Ok(patch)
+    }
+}
+
+/// Build a patch transforming `old` into `new`, using chunks as configured by `config`.
+/// Chunks of `new` that also occur in `old` are copied, all other data is inserted.
+pub async fn make_patch<R1: AsyncRead + Unpin, R2: AsyncRead + Unpin>(
+    old: R1,
+    new: R2,
+    config: &ChunkingConfig,
+) -> Result<Patch> {
+    let mut known = HashMap::new();
+    let mut chunks = std::pin::pin!(config.chunker()?.stream(old));
+    while let Some(c) = chunks.next().await {
+        let c = c?;
+        known
+            .entry(c.hash)
+            .or_insert((c.offset, c.data.len() as u64));
+    }
+
+    let mut patch = Patch {
+        new_size: 0,
+        new_hash: Hash::default(),
+        ops: vec![],
+    };
+    let mut h = Sha1::new();
+    let mut chunks = std::pin::pin!(config.chunker()?.stream(new));
+    while let Some(c) = chunks.next().await {
+        let c = c?;
+        h.update(&c.data);
+        patch.new_size += c.data.len() as u64;
+        match known.get(&c.hash) {
+            Some((offset, len)) => patch.push(PatchOp::Copy {
+                offset: *offset,
+                len: *len,
+            }),
+            None => patch.push(PatchOp::Insert(c.data.to_vec())),
+        }
+    }
+    patch.new_hash = Hash::new_from_sha1(h.finalize());
+    Ok(patch)
+}
+
+/// Apply `patch` to `old`, writing the new version to `out`. Fails if the result doesn't match
+/// the patch's `new_hash`, e.g. because `old` is not the version the patch was made for; `out`
+/// then contains garbage. Returns the number of bytes written.
+pub async fn apply_patch<R: AsyncRead + AsyncSeek + Unpin, W: AsyncWrite + Unpin>(
+    mut old: R,
+    patch: &Patch,
+    mut out: W,
+) -> Result<u64> {
+    let mut h = Sha1::new();
+    let mut buf = vec![0; 64 * 1024];
+    let mut n = 0;
+    for op in patch.ops.iter() {
+        match op {
+            PatchOp::Copy { offset, len } => {
+                old.seek(SeekFrom::Start(*offset)).await?;
+                let mut left = *len;
+                while left > 0 {
+                    let m = usize::min(buf.len(), left as usize);
+                    old.read_exact(&mut buf[..m]).await?;
+                    h.update(&buf[..m]);
+                    out.write_all(&buf[..m]).await?;
+                    left -= m as u64;
+                }
+                n += len;
+            }
+            PatchOp::Insert(data) => {
+                h.update(data);
+                out.write_all(data).await?;
+                n += data.len() as u64;
+            }
+        }
+    }
+    out.flush().await?;
+    if n != patch.new_size || Hash::new_from_sha1(h.finalize()) != patch.new_hash {
+        return Err(anyhow::Error::msg(
+            "apply_patch: result doesn't match the patch's hash",
+        ));
+    }
+    Ok(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(len: usize) -> Vec<u8> {
+        let mut x: u32 = 815;
+        (0..len)
+            .map(|_| {
+                x = x.wrapping_mul(1103515245).wrapping_add(12345);
+                (x >> 16) as u8
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_patch() {
+        let config = ChunkingConfig::small_files();
+        let old = data(300_000);
+        let mut new = old.clone();
+        new.splice(100_000..100_010, b"replacement text".iter().cloned());
+        new.extend_from_slice(b"appended");
+
+        let patch = make_patch(&old[..], &new[..], &config).await.unwrap();
+        assert_eq!(new.len() as u64, patch.new_size);
+        assert_eq!(
+            new.len() as u64,
+            patch.copied_bytes() + patch.inserted_bytes()
+        );
+        assert!(patch.inserted_bytes() < 100_000);
+
+        let mut file = vec![];
+        patch.write_to(&mut file).await.unwrap();
+        let read = Patch::read_from(&file[..]).await.unwrap();
+        assert_eq!(patch, read);
+
+        let mut out = vec![];
+        let n = apply_patch(std::io::Cursor::new(&old), &read, &mut out)
+            .await
+            .unwrap();
+        assert_eq!(new.len() as u64, n);
+        assert_eq!(new, out);
+
+        // Applying to the wrong base fails.
+        let mut other = old.clone();
+        other[5] ^= 1;
+        assert!(apply_patch(std::io::Cursor::new(&other), &read, vec![])
+            .await
+            .is_err());
+        assert!(Patch::read_from(&file[1..]).await.is_err());
+    }
+}
@@ -0,0 +1,684 @@
+//! Planning of synchronization between a local directory and a remote one.
+//!
+//! `plan()` compares a `Snapshot` of the local tree with the remote `Item` tree and, optionally,
+//! the snapshot taken after the last synchronization (the base), and produces a `Plan` of
+//! actions. Cheap hashes are used first: files with equal `mhash` (name, size, mtime) are
+//! skipped, and content hashes (`chash`) of local files are only computed where the `mhash`
+//! differs or to detect renames.
+//!
+//! Only files are planned; directories are created implicitly by uploads and downloads.
+
+use crate::hashing::{self, Hash};
+use crate::ignore::IgnoreRules;
+use crate::platform::{local_path, long_path};
+use crate::types::Item;
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::{self, Display, Formatter};
+use std::path::Path;
+
+use anyhow::{self, Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+/// Hashes and metadata of a file.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileState {
+    pub size: u64,
+    pub mtime: i64,
+    pub nhash: Hash,
+    pub mhash: Hash,
+    /// Only computed if needed, see `Snapshot::fill_chash()`.
+    pub chash: Option<Hash>,
+    /// The target of a symbolic link stored as placeholder (see `SymlinkPolicy::Placeholder`);
+    /// the file's content is the target path.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link: Option<String>,
+}
+
+impl FileState {
+    /// The content hash of this file, found at `path`.
+    pub async fn content_hash(&self, path: &Path) -> Result<Hash> {
+        let h = match self.link {
+            Some(ref target) => hashing::chash(target.as_bytes()).await?,
+            None => hashing::chash_file(path).await?,
+        };
+        Ok(h.top_hash().clone())
+    }
+}
+
+/// How `Snapshot::scan_with_links()` treats symbolic links.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Leave links out, as if they didn't exist.
+    #[default]
+    Skip,
+    /// Scan the file or directory a link points to as if it was at the link's path. Links to a
+    /// directory containing them fail the scan, as they would be followed forever.
+    Follow,
+    /// Fail the scan.
+    Error,
+    /// Treat a link as a file containing the link's target, see `FileState::link`.
+    Placeholder,
+}
+
+/// The files of a tree, by path relative to its root (with `/` as separator).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub files: BTreeMap<String, FileState>,
+    /// All directories below the root, including empty ones.
+    #[serde(default)]
+    pub dirs: BTreeSet<String>,
+    /// For a remote tree whose paths were changed to match local names spelled differently
+    /// (see `sync::NameMatching`): the remote spelling of the changed paths.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub remote_names: BTreeMap<String, String>,
+}
+
+impl Snapshot {
+    /// Scan the local directory `root`, computing `nhash` and `mhash` of all files (leaving out
+    /// symbolic links). Names that aren't valid UTF-8 are converted lossily.
+    pub async fn scan(root: impl AsRef<Path>) -> Result<Snapshot> {
+        Snapshot::scan_with(root, &IgnoreRules::new()).await
+    }
+
+    /// Like `scan()`, but skipping files and directories excluded by `ignore`.
+    pub async fn scan_with(root: impl AsRef<Path>, ignore: &IgnoreRules) -> Result<Snapshot> {
+        Snapshot::scan_with_links(root, ignore, SymlinkPolicy::Skip).await
+    }
+
+    /// Like `scan_with()`, treating symbolic links according to `links`.
+    pub async fn scan_with_links(
+        root: impl AsRef<Path>,
+        ignore: &IgnoreRules,
+        links: SymlinkPolicy,
+    ) -> Result<Snapshot> {
+        let root = &long_path(root.as_ref().to_path_buf());
+        let mut s = Snapshot::default();
+        let mut dirs = vec![(root.clone(), String::new())];
+        while let Some((dir, rel)) = dirs.pop() {
+            let mut rd = fs::read_dir(&dir)
+                .await
+                .with_context(|| format!("reading directory {:?}", dir))?;
+            while let Some(de) = rd.next_entry().await? {
+                let name = de.file_name().to_string_lossy().into_owned();
+                let rel = if rel.is_empty() {
+                    name
+                } else {
+                    format!("{}/{}", rel, name)
+                };
+                let path = de.path();
+                let mut md = de.metadata().await?;
+                let mut link = None;
+                if md.file_type().is_symlink() {
+                    match links {
+                        SymlinkPolicy::Skip => continue,
+                        SymlinkPolicy::Error => {
+                            anyhow::bail!("{:?} is a symbolic link", path)
+                        }
+                        SymlinkPolicy::Follow => {
+                            md = fs::metadata(&path)
+                                .await
+                                .with_context(|| format!("following symbolic link {:?}", path))?;
+                            if md.is_dir() && leads_back(root, &path).await? {
+                                anyhow::bail!(
+                                    "symbolic link {:?} points to a directory containing it",
+                                    path
+                                );
+                            }
+                        }
+                        SymlinkPolicy::Placeholder => {
+                            let target = fs::read_link(&path)
+                                .await
+                                .with_context(|| format!("reading symbolic link {:?}", path))?;
+                            link = Some(target.to_string_lossy().into_owned());
+                        }
+                    }
+                }
+                if ignore.is_ignored(&rel, md.is_dir()) {
+                    continue;
+                }
+                if md.is_dir() {
+                    s.dirs.insert(rel.clone());
+                    dirs.push((path, rel));
+                } else if md.is_file() || link.is_some() {
+                    // Converting via `OffsetDateTime` keeps mtimes before 1970 (negative).
+                    let mtime = time::OffsetDateTime::from(md.modified()?).unix_timestamp();
+                    let size = link.as_ref().map_or(md.len(), |l| l.len() as u64);
+                    s.files.insert(
+                        rel,
+                        FileState {
+                            size,
+                            mtime,
+                            nhash: hashing::nhash(&path),
+                            mhash: hashing::mhash(&path, mtime, Some(size)),
+                            chash: None,
+                            link,
+                        },
+                    );
+                }
+            }
+        }
+        Ok(s)
+    }
+
+    /// The remote spelling of `rel`, taking renamed parent directories into account (see
+    /// `remote_names`).
+    pub fn remote_path(&self, rel: &str) -> String {
+        let mut prefix = rel;
+        loop {
+            if let Some(r) = self.remote_names.get(prefix) {
+                return format!("{}{}", r, &rel[prefix.len()..]);
+            }
+            match prefix.rsplit_once('/') {
+                Some((parent, _)) => prefix = parent,
+                None => return rel.to_string(),
+            }
+        }
+    }
+
+    /// Compute missing content hashes of the files below `root`, e.g. before storing a snapshot
+    /// as base for the next `plan()`.
+    pub async fn fill_chash(&mut self, root: impl AsRef<Path>) -> Result<()> {
+        for (rel, st) in self.files.iter_mut() {
+            if st.chash.is_none() {
+                st.chash = Some(st.content_hash(&local_path(root.as_ref(), rel)).await?);
+            }
+        }
+        Ok(())
+    }
+
+    /// The files of a remote directory `item`, including those of subdirectories. `item` must
+    /// have been retrieved with members recursively, including the fields `name, type, size,
+    /// mtime, chash` (and preferably `mhash, nhash`).
+    pub fn from_item(item: &Item) -> Snapshot {
+        let mut s = Snapshot::default();
+        add_members(item, "", &mut s);
+        s
+    }
+}
+
+fn add_members(item: &Item, prefix: &str, s: &mut Snapshot) {
+    for m in item.members.iter() {
+        let name = match m.name {
+            Some(ref n) => n.as_str(),
+            None => m.path.rsplit('/').next().unwrap_or(""),
+        };
+        let rel = if prefix.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}/{}", prefix, name)
+        };
+        if m.typ.as_deref() == Some("dir") {
+            add_members(m, &rel, s);
+            s.dirs.insert(rel);
+            continue;
+        }
+        let size = m.size.unwrap_or(0) as u64;
+        let mtime = m.mtime.map_or(0, |t| t.unix_timestamp());
+        s.files.insert(
+            rel,
+            FileState {
+                size,
+                mtime,
+                nhash: m
+                    .nhash
+                    .clone()
+                    .unwrap_or_else(|| hashing::nhash_bytes(name)),
+                mhash: m
+                    .mhash
+                    .clone()
+                    .unwrap_or_else(|| hashing::mhash_bytes(name, mtime, Some(size))),
+                chash: m.chash.clone(),
+                link: None,
+            },
+        );
+    }
+}
+
+/// Where an action is carried out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Side {
+    Local,
+    Remote,
+}
+
+/// An action of a `Plan`. Paths are relative to the synchronized directories.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// Upload the local file.
+    Upload(String),
+    /// Download the remote file.
+    Download(String),
+    /// Delete the file on `side`, because it was deleted on the other one.
+    Delete { path: String, side: Side },
+    /// Rename the file on `side`, because it was renamed on the other one.
+    Rename {
+        from: String,
+        to: String,
+        side: Side,
+    },
+    /// Both sides have changed (or one changed and the other deleted the file).
+    Conflict(String),
+    /// Nothing to do.
+    Skip(String),
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let side = |s: &Side| match s {
+            Side::Local => "local",
+            Side::Remote => "remote",
+        };
+        match self {
+            Action::Upload(p) => write!(f, "upload    {}", p),
+            Action::Download(p) => write!(f, "download  {}", p),
+            Action::Delete { path, side: s } => write!(f, "delete    {} ({})", path, side(s)),
+            Action::Rename { from, to, side: s } => {
+                write!(f, "rename    {} -> {} ({})", from, to, side(s))
+            }
+            Action::Conflict(p) => write!(f, "conflict  {}", p),
+            Action::Skip(p) => write!(f, "skip      {}", p),
+        }
+    }
+}
+
+/// The result of `plan()`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Plan {
+    pub actions: Vec<Action>,
+}
+
+impl Plan {
+    /// All actions except `Skip`.
+    pub fn changes(&self) -> impl Iterator<Item = &Action> {
+        self.actions
+            .iter()
+            .filter(|a| !matches!(a, Action::Skip(_)))
+    }
+}
+
+impl Display for Plan {
+    /// One line per action, for dry runs.
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for a in self.changes() {
+            writeln!(f, "{}", a)?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether the symbolic link `path` below `root` points to one of the directories containing
+/// it, possibly via other links, so that following it would never end.
+async fn leads_back(root: &Path, path: &Path) -> Result<bool> {
+    let target = fs::canonicalize(path).await?;
+    let mut d = path.to_path_buf();
+    while d.pop() && d.starts_with(root) {
+        if fs::canonicalize(&d).await? == target {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Computes content hashes of local files on demand.
+struct LocalHashes<'a> {
+    root: &'a Path,
+    local: &'a Snapshot,
+    computed: HashMap<String, Hash>,
+}
+
+impl LocalHashes<'_> {
+    async fn chash(&mut self, rel: &str) -> Result<Hash> {
+        if let Some(h) = self.local.files.get(rel).and_then(|s| s.chash.clone()) {
+            return Ok(h);
+        }
+        if let Some(h) = self.computed.get(rel) {
+            return Ok(h.clone());
+        }
+        let path = local_path(self.root, rel);
+        let h = match self.local.files.get(rel) {
+            Some(st) => st.content_hash(&path).await?,
+            None => hashing::chash_file(&path).await?.top_hash().clone(),
+        };
+        self.computed.insert(rel.to_string(), h.clone());
+        Ok(h)
+    }
+}
+
+/// Plan the synchronization of the local directory `root`, scanned into `local`, with the remote
+/// directory `remote` (see `Snapshot::from_item()`). `base` is the local snapshot, with content
+/// hashes, taken after the last synchronization; without it, deletions and renames can't be
+/// detected, and all differing files are conflicts.
+pub async fn plan(
+    root: impl AsRef<Path>,
+    local: &Snapshot,
+    remote: &Item,
+    base: Option<&Snapshot>,
+) -> Result<Plan> {
+    plan_snapshots(root, local, &Snapshot::from_item(remote), base).await
+}
+
+/// Like `plan()`, with the remote tree given as snapshot.
+pub async fn plan_snapshots(
+    root: impl AsRef<Path>,
+    local: &Snapshot,
+    remote: &Snapshot,
+    base: Option<&Snapshot>,
+) -> Result<Plan> {
+    let empty = Snapshot::default();
+    let base = base.unwrap_or(&empty);
+    let mut hashes = LocalHashes {
+        root: root.as_ref(),
+        local,
+        computed: HashMap::new(),
+    };
+    let mut plan = Plan::default();
+
+    let mut new_local = vec![];
+    let mut new_remote = vec![];
+    let mut deleted_local = vec![];
+    let mut deleted_remote = vec![];
+    let paths: BTreeSet<&String> = local.files.keys().chain(remote.files.keys()).collect();
+    for p in paths {
+        let action = match (local.files.get(p), remote.files.get(p), base.files.get(p)) {
+            (Some(l), Some(r), b) => {
+                if l.mhash == r.mhash {
+                    Action::Skip(p.clone())
+                } else {
+                    let content_equal = match r.chash {
+                        Some(ref rc) => hashes.chash(p).await? == *rc,
+                        None => false,
+                    };
+                    let changed_local = b.map_or(true, |b| b.mhash != l.mhash);
+                    let changed_remote =
+                        r.chash.is_none() || b.map_or(true, |b| b.chash != r.chash);
+                    if content_equal {
+                        Action::Skip(p.clone())
+                    } else if changed_local && !changed_remote {
+                        Action::Upload(p.clone())
+                    } else if changed_remote && !changed_local {
+                        Action::Download(p.clone())
+                    } else {
+                        Action::Conflict(p.clone())
+                    }
+                }
+            }
+            (Some(_), None, None) => {
+                new_local.push(p.clone());
+                continue;
+            }
+            (Some(_), None, Some(_)) => {
+                deleted_remote.push(p.clone());
+                continue;
+            }
+            (None, Some(_), None) => {
+                new_remote.push(p.clone());
+                continue;
+            }
+            (None, Some(_), Some(_)) => {
+                deleted_local.push(p.clone());
+                continue;
+            }
+            (None, None, _) => unreachable!(),
+        };
+        plan.actions.push(action);
+    }
+
+    // A file deleted locally and a new local file with the same content: renamed locally. The
+    // size is compared first to avoid hashing.
+    let mut renamed = BTreeSet::new();
+    for from in deleted_local.iter() {
+        let b = &base.files[from];
+        for to in new_local.iter().filter(|t| !renamed.contains(*t)) {
+            if local.files[to].size == b.size
+                && b.chash.is_some()
+                && Some(hashes.chash(to).await?) == b.chash
+                && remote.files[from].chash == b.chash
+            {
+                plan.actions.push(Action::Rename {
+                    from: from.clone(),
+                    to: to.clone(),
+                    side: Side::Remote,
+                });
+                renamed.insert(from.clone());
+                renamed.insert(to.clone());
+                break;
+            }
+        }
+    }
+    // Likewise for files renamed remotely.
+    for from in deleted_remote.iter() {
+        let l = &local.files[from];
+        for to in new_remote.iter().filter(|t| !renamed.contains(*t)) {
+            let r = &remote.files[to];
+            if r.size == l.size
+                && r.chash.is_some()
+                && r.chash == base.files[from].chash
+                && Some(hashes.chash(from).await?) == r.chash
+            {
+                plan.actions.push(Action::Rename {
+                    from: from.clone(),
+                    to: to.clone(),
+                    side: Side::Local,
+                });
+                renamed.insert(from.clone());
+                renamed.insert(to.clone());
+                break;
+            }
+        }
+    }
+
+    for p in new_local.into_iter().filter(|p| !renamed.contains(p)) {
+        plan.actions.push(Action::Upload(p));
+    }
+    for p in new_remote.into_iter().filter(|p| !renamed.contains(p)) {
+        plan.actions.push(Action::Download(p));
+    }
+    for p in deleted_local.into_iter().filter(|p| !renamed.contains(p)) {
+        let r = &remote.files[&p];
+        if r.chash.is_some() && r.chash == base.files[&p].chash {
+            plan.actions.push(Action::Delete {
+                path: p,
+                side: Side::Remote,
+            });
+        } else {
+            plan.actions.push(Action::Conflict(p));
+        }
+    }
+    for p in deleted_remote.into_iter().filter(|p| !renamed.contains(p)) {
+        if local.files[&p].mhash == base.files[&p].mhash {
+            plan.actions.push(Action::Delete {
+                path: p,
+                side: Side::Local,
+            });
+        } else {
+            plan.actions.push(Action::Conflict(p));
+        }
+    }
+    Ok(plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn chash(s: &str) -> String {
+        let h = hashing::chash(s.as_bytes()).await.unwrap();
+        h.top_hash().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_plan() {
+        let root = std::env::temp_dir().join("hd_api_test_plan");
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        let files: &[(&str, &str)] = &[
+            ("same.txt", "same"),
+            ("touched.txt", "touched"),
+            ("new_local.txt", "new local"),
+            ("gone_remote.txt", "gone remote"),
+            ("sub/edited.txt", "edited locally"),
+            ("conflict.txt", "local version"),
+            ("new_name.txt", "renamed"),
+        ];
+        for (p, c) in files {
+            std::fs::write(root.join(p), c).unwrap();
+        }
+        let local = Snapshot::scan(&root).await.unwrap();
+        assert_eq!(files.len(), local.files.len());
+        assert!(local.dirs.contains("sub"));
+        let filtered = Snapshot::scan_with(&root, &IgnoreRules::parse("sub/\n*.txt\n!same.txt"))
+            .await
+            .unwrap();
+        assert!(filtered.dirs.is_empty());
+        assert_eq!(vec!["same.txt"], filtered.files.keys().collect::<Vec<_>>());
+
+        let mut base = Snapshot::default();
+        for (p, c) in [
+            ("same.txt", "same"),
+            ("touched.txt", "touched"),
+            ("gone_remote.txt", "gone remote"),
+            ("gone_local.txt", "gone local"),
+            ("sub/edited.txt", "original"),
+            ("conflict.txt", "original"),
+            ("old_name.txt", "renamed"),
+        ] {
+            let mut st = local.files.get(p).cloned().unwrap_or_else(|| FileState {
+                size: c.len() as u64,
+                mtime: 0,
+                nhash: hashing::nhash(p),
+                mhash: hashing::mhash(p, 0, Some(c.len() as u64)),
+                chash: None,
+                link: None,
+            });
+            if p == "sub/edited.txt" || p == "conflict.txt" {
+                st.mhash = Hash::default();
+            }
+            st.chash = Some(Hash::parse(chash(c).await).unwrap());
+            base.files.insert(p.to_string(), st);
+        }
+
+        let same_mtime = local.files["same.txt"].mtime;
+        let remote: Item = serde_json::from_str(&format!(
+            r#"{{"path": "/users/me/dir", "members": [
+                {{"path": "/users/me/dir/same.txt", "name": "same.txt", "type": "file", "size": 4, "mtime": {}, "chash": "{}"}},
+                {{"path": "/users/me/dir/touched.txt", "name": "touched.txt", "type": "file", "size": 7, "mtime": 1, "chash": "{}"}},
+                {{"path": "/users/me/dir/gone_local.txt", "name": "gone_local.txt", "type": "file", "size": 10, "mtime": 1, "chash": "{}"}},
+                {{"path": "/users/me/dir/new_remote.txt", "name": "new_remote.txt", "type": "file", "size": 3, "mtime": 1, "chash": "{}"}},
+                {{"path": "/users/me/dir/conflict.txt", "name": "conflict.txt", "type": "file", "size": 14, "mtime": 1, "chash": "{}"}},
+                {{"path": "/users/me/dir/old_name.txt", "name": "old_name.txt", "type": "file", "size": 7, "mtime": 1, "chash": "{}"}},
+                {{"path": "/users/me/dir/sub", "name": "sub", "type": "dir", "members": [
+                    {{"path": "/users/me/dir/sub/edited.txt", "name": "edited.txt", "type": "file", "size": 8, "mtime": 1, "chash": "{}"}}
+                ]}}
+            ]}}"#,
+            same_mtime,
+            chash("same").await,
+            chash("touched").await,
+            chash("gone local").await,
+            chash("new").await,
+            chash("remote version").await,
+            chash("renamed").await,
+            chash("original").await,
+        ))
+        .unwrap();
+
+        let plan = plan(&root, &local, &remote, Some(&base)).await.unwrap();
+        let mut actions = plan.actions.clone();
+        actions.sort_by_key(|a| format!("{:?}", a));
+        let mut expected = vec![
+            Action::Conflict("conflict.txt".into()),
+            Action::Delete {
+                path: "gone_local.txt".into(),
+                side: Side::Remote,
+            },
+            Action::Delete {
+                path: "gone_remote.txt".into(),
+                side: Side::Local,
+            },
+            Action::Download("new_remote.txt".into()),
+            Action::Rename {
+                from: "old_name.txt".into(),
+                to: "new_name.txt".into(),
+                side: Side::Remote,
+            },
+            Action::Skip("same.txt".into()),
+            Action::Skip("touched.txt".into()),
+            Action::Upload("new_local.txt".into()),
+            Action::Upload("sub/edited.txt".into()),
+        ];
+        expected.sort_by_key(|a| format!("{:?}", a));
+        assert_eq!(expected, actions);
+        assert_eq!(7, plan.changes().count());
+        assert!(plan
+            .to_string()
+            .contains("rename    old_name.txt -> new_name.txt (remote)\n"));
+
+        // Without a base, differing files are conflicts, and nothing is deleted.
+        let plan = super::plan(&root, &local, &remote, None).await.unwrap();
+        assert!(plan
+            .actions
+            .iter()
+            .all(|a| !matches!(a, Action::Delete { .. } | Action::Rename { .. })));
+        assert!(plan
+            .actions
+            .contains(&Action::Conflict("sub/edited.txt".into())));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_scan_old_mtime() {
+        let root = std::env::temp_dir().join("hd_api_test_scan_old_mtime");
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("old.txt"), "old").unwrap();
+        let t = filetime::FileTime::from_unix_time(-86400, 0);
+        filetime::set_file_mtime(root.join("old.txt"), t).unwrap();
+        let s = Snapshot::scan(&root).await.unwrap();
+        assert_eq!(-86400, s.files["old.txt"].mtime);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_scan_symlinks() {
+        use std::os::unix::fs::symlink;
+
+        let root = std::env::temp_dir().join("hd_api_test_scan_symlinks");
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("sub/a.txt"), "aaa").unwrap();
+        symlink("sub/a.txt", root.join("link.txt")).unwrap();
+        symlink("sub", root.join("dirlink")).unwrap();
+        let ignore = IgnoreRules::new();
+        let (r, i) = (&root, &ignore);
+        let scan = move |links| Snapshot::scan_with_links(r, i, links);
+
+        let s = scan(SymlinkPolicy::Skip).await.unwrap();
+        assert_eq!(vec!["sub/a.txt"], s.files.keys().collect::<Vec<_>>());
+        assert!(scan(SymlinkPolicy::Error).await.is_err());
+
+        let s = scan(SymlinkPolicy::Follow).await.unwrap();
+        assert_eq!(
+            vec!["dirlink/a.txt", "link.txt", "sub/a.txt"],
+            s.files.keys().collect::<Vec<_>>()
+        );
+        assert_eq!(3, s.files["link.txt"].size);
+
+        let mut s = scan(SymlinkPolicy::Placeholder).await.unwrap();
+        let l = &s.files["link.txt"];
+        assert_eq!(Some("sub/a.txt"), l.link.as_deref());
+        assert_eq!(9, l.size);
+        assert_eq!(Some("sub"), s.files["dirlink"].link.as_deref());
+        s.fill_chash(&root).await.unwrap();
+        assert_eq!(
+            chash("sub/a.txt").await,
+            s.files["link.txt"].chash.as_ref().unwrap().to_string()
+        );
+
+        // A link to a parent directory can't be followed.
+        symlink("..", root.join("sub/up")).unwrap();
+        assert!(scan(SymlinkPolicy::Follow).await.is_err());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
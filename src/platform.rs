@@ -0,0 +1,171 @@
+//! Differences between local file systems: paths on Windows are limited to `MAX_PATH`
+//! characters unless written in extended-length form, and some names can't be created there.
+//!
+//! Not handled here, on purpose:
+//!
+//! * Identifiers: remote paths always use `/`, whatever the platform. Local paths are never
+//!   turned into an `Identifier` directly, only via relative paths joined to a remote root.
+//! * File locking: the sync engine doesn't lock local files. Files opened without sharing by
+//!   another program on Windows fail to read or replace, are reported in the run's failures, and
+//!   are tried again by the next run.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+
+/// The local path of `rel`, a path relative to `dir` with `/` as separator (as used by `sync`
+/// and `backup`), in the platform's form; see `long_path()`.
+pub(crate) fn local_path(dir: &Path, rel: &str) -> PathBuf {
+    let mut p = dir.to_path_buf();
+    p.extend(rel.split('/').filter(|c| !c.is_empty()));
+    long_path(p)
+}
+
+/// Directories can't be created at paths longer than `MAX_PATH` (260) minus 12 characters.
+#[cfg(windows)]
+const MAX_DIR_PATH: usize = 248;
+
+/// On Windows, convert absolute paths too long for the `MAX_PATH` limit to the extended-length
+/// form (`\\?\C:\...`, `\\?\UNC\server\share\...`). Paths that can't be converted, such as
+/// relative ones, are returned unchanged. A no-op elsewhere.
+#[cfg(windows)]
+pub(crate) fn long_path(p: PathBuf) -> PathBuf {
+    use std::ffi::OsString;
+    use std::os::windows::ffi::OsStrExt;
+    use std::path::{Component, Prefix};
+
+    if p.as_os_str().encode_wide().count() < MAX_DIR_PATH {
+        return p;
+    }
+    let mut components = p.components();
+    let mut s = match components.next() {
+        Some(Component::Prefix(prefix)) => match prefix.kind() {
+            Prefix::Disk(d) => OsString::from(format!(r"\\?\{}:", d as char)),
+            Prefix::UNC(server, share) => {
+                let mut s = OsString::from(r"\\?\UNC\");
+                s.push(server);
+                s.push(r"\");
+                s.push(share);
+                s
+            }
+            // Verbatim or device paths already.
+            _ => return p,
+        },
+        _ => return p,
+    };
+    if components.next() != Some(Component::RootDir) {
+        return p;
+    }
+    for c in components {
+        match c {
+            Component::Normal(name) => {
+                s.push(r"\");
+                s.push(name);
+            }
+            // Not resolved in extended-length paths.
+            _ => return p,
+        }
+    }
+    PathBuf::from(s)
+}
+
+#[cfg(not(windows))]
+pub(crate) fn long_path(p: PathBuf) -> PathBuf {
+    p
+}
+
+/// Why `name` can't be used as file or directory name on Windows, if it can't.
+pub(crate) fn windows_name_error(name: &str) -> Option<&'static str> {
+    if name
+        .chars()
+        .any(|c| c < ' ' || matches!(c, '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*'))
+    {
+        return Some("contains a character not allowed on Windows");
+    }
+    if name.ends_with('.') || name.ends_with(' ') {
+        return Some("ends with a dot or space, not allowed on Windows");
+    }
+    let stem = name.split('.').next().unwrap_or_default().trim_end();
+    let device = matches!(
+        stem.to_ascii_uppercase().as_str(),
+        "CON" | "PRN" | "AUX" | "NUL"
+    ) || (stem.len() == 4
+        && ["COM", "LPT"]
+            .iter()
+            .any(|p| stem.get(..3).is_some_and(|s| s.eq_ignore_ascii_case(p)))
+        && matches!(stem.as_bytes()[3], b'1'..=b'9'));
+    if device {
+        return Some("is a device name on Windows");
+    }
+    None
+}
+
+/// Fail if a component of `rel` (with `/` as separator) can't be created on the local file
+/// system, so that the operation fails with a clear message instead of an obscure OS error.
+pub(crate) fn check_local_path(rel: &str) -> Result<()> {
+    if cfg!(windows) {
+        for name in rel.split('/') {
+            if let Some(e) = windows_name_error(name) {
+                anyhow::bail!("{:?} {}", rel, e);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_windows_name_error() {
+        for ok in [
+            "a.txt", "con.d", "COM0", "COM10", "console", ".hidden", "a b",
+        ] {
+            assert_eq!(None, windows_name_error(ok), "{}", ok);
+        }
+        for bad in [
+            "a:b", "a?", "x\\y", "tab\t", "dot.", "space ", "CON", "nul.txt", "Lpt3.x", "aux .c",
+        ] {
+            assert!(windows_name_error(bad).is_some(), "{}", bad);
+        }
+        let r = check_local_path("dir/a:b");
+        assert_eq!(cfg!(windows), r.is_err());
+    }
+
+    #[test]
+    fn test_local_path() {
+        let dir = std::env::temp_dir();
+        let p = local_path(&dir, "a/b/c.txt");
+        assert_eq!(dir.join("a").join("b").join("c.txt"), p);
+        assert_eq!(dir, local_path(&dir, ""));
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_long_path() {
+        let name = "x".repeat(100);
+        let p = PathBuf::from(r"C:\base");
+        let rel = format!("{}/{}/{}", name, name, name);
+        let long = local_path(&p, &rel);
+        assert_eq!(
+            PathBuf::from(format!(r"\\?\C:\base\{}\{}\{}", name, name, name)),
+            long
+        );
+        let unc = local_path(Path::new(r"\\server\share"), &rel);
+        assert!(unc
+            .to_str()
+            .unwrap()
+            .starts_with(r"\\?\UNC\server\share\xxx"));
+        assert_eq!(PathBuf::from(r"C:\base\a"), local_path(&p, "a"));
+
+        // Creating and writing files below the MAX_PATH limit works.
+        let dir = std::env::temp_dir().join("hd_api_test_long_path");
+        let _ = std::fs::remove_dir_all(long_path(dir.clone()));
+        let file = local_path(&dir, &format!("{}/f", rel));
+        std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+        std::fs::write(&file, "long").unwrap();
+        assert_eq!("long", std::fs::read_to_string(&file).unwrap());
+        std::fs::remove_dir_all(long_path(dir)).unwrap();
+    }
+}
@@ -0,0 +1,528 @@
+//! A persistent queue of uploads and downloads, processed in the background, see
+//! `TransferQueue`.
+
+use crate::hidrive::HiDrive;
+use crate::metrics::Metrics;
+use crate::types::{Cancelled, Identifier};
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{self, Context, Result};
+use futures_util::future::try_join_all;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex, Notify};
+use tokio_util::sync::CancellationToken;
+
+const QUEUE_VERSION: u32 = 1;
+
+/// What a queued transfer does.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum TransferSpec {
+    /// Upload the local file `local` as `name` into the directory `dir`, overwriting an existing
+    /// file.
+    Upload {
+        local: PathBuf,
+        dir: Identifier,
+        name: String,
+    },
+    /// Download `remote` to the local file `local`. Downloads are resumable (see
+    /// `HiDriveFiles::download_resumable()`), also across restarts.
+    Download { remote: Identifier, local: PathBuf },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TransferState {
+    Queued,
+    Running,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+impl TransferState {
+    fn finished(self) -> bool {
+        matches!(
+            self,
+            TransferState::Done | TransferState::Failed | TransferState::Cancelled
+        )
+    }
+}
+
+/// An entry of a `TransferQueue`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueuedTransfer {
+    pub id: u64,
+    pub spec: TransferSpec,
+    /// Transfers with a higher priority run first, those with equal priority in the order they
+    /// were enqueued.
+    pub priority: i32,
+    pub state: TransferState,
+    /// Why the transfer failed.
+    pub error: Option<String>,
+}
+
+/// The persisted part of a queue.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct QueueData {
+    version: u32,
+    next_id: u64,
+    transfers: Vec<QueuedTransfer>,
+}
+
+struct Inner {
+    data: QueueData,
+    /// Cancellation tokens of running transfers.
+    running: HashMap<u64, CancellationToken>,
+}
+
+/// A queue of uploads and downloads, optionally persisted in a local file so that transfers
+/// survive restarts: transfers interrupted by a restart are queued again. Transfers are processed
+/// by `run()`, several at once; `list()`, `cancel()` etc. can be called meanwhile.
+///
+/// ```ignore
+/// let q = Arc::new(TransferQueue::open("/home/me/.local/share/app/queue.json").await?);
+/// q.enqueue(TransferSpec::Download { remote, local }, 0).await?;
+/// tokio::spawn(async move { q.run(&hd, 4, cancel).await });
+/// ```
+pub struct TransferQueue {
+    path: Option<PathBuf>,
+    inner: Mutex<Inner>,
+    notify: Notify,
+    metrics: Option<Arc<dyn Metrics>>,
+}
+
+impl Default for TransferQueue {
+    fn default() -> TransferQueue {
+        TransferQueue::new()
+    }
+}
+
+impl TransferQueue {
+    /// A queue kept in memory only.
+    pub fn new() -> TransferQueue {
+        TransferQueue::with_data(None, QueueData::default())
+    }
+
+    /// A queue persisted in `path`, loading the transfers stored there. A missing file results
+    /// in an empty queue.
+    pub async fn open(path: impl AsRef<Path>) -> Result<TransferQueue> {
+        let path = path.as_ref();
+        let mut data: QueueData = match tokio::fs::read(path).await {
+            Ok(b) => serde_json::from_slice(&b)
+                .with_context(|| format!("reading transfer queue {:?}", path))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => QueueData::default(),
+            Err(e) => return Err(e).with_context(|| format!("reading transfer queue {:?}", path)),
+        };
+        if data.version > QUEUE_VERSION {
+            anyhow::bail!(
+                "transfer queue {:?} has unknown version {}",
+                path,
+                data.version
+            );
+        }
+        for t in data.transfers.iter_mut() {
+            if t.state == TransferState::Running {
+                t.state = TransferState::Queued;
+            }
+        }
+        Ok(TransferQueue::with_data(Some(path.to_path_buf()), data))
+    }
+
+    fn with_data(path: Option<PathBuf>, mut data: QueueData) -> TransferQueue {
+        data.version = QUEUE_VERSION;
+        TransferQueue {
+            path,
+            inner: Mutex::new(Inner {
+                data,
+                running: HashMap::new(),
+            }),
+            notify: Notify::new(),
+            metrics: None,
+        }
+    }
+
+    /// Report to `metrics`. Counters: `hd_queue_transfers_done_total`,
+    /// `hd_queue_transfers_failed_total`. Gauges: `hd_queue_queued`, `hd_queue_running`,
+    /// `hd_queue_failed`.
+    pub fn set_metrics(&mut self, metrics: Option<Arc<dyn Metrics>>) {
+        self.metrics = metrics;
+    }
+
+    fn report(&self, data: &QueueData) {
+        let m = match self.metrics {
+            Some(ref m) => m,
+            None => return,
+        };
+        let count = |state| data.transfers.iter().filter(|t| t.state == state).count() as f64;
+        m.gauge(
+            "hd_queue_queued",
+            "Transfers waiting to be started.",
+            count(TransferState::Queued),
+        );
+        m.gauge(
+            "hd_queue_running",
+            "Transfers in progress.",
+            count(TransferState::Running),
+        );
+        m.gauge(
+            "hd_queue_failed",
+            "Failed transfers not retried or removed yet.",
+            count(TransferState::Failed),
+        );
+    }
+
+    /// Write `data` to the queue file, if any. Called with the lock held, so that writes happen
+    /// in order.
+    async fn save(&self, data: &QueueData) -> Result<()> {
+        self.report(data);
+        let path = match self.path {
+            Some(ref p) => p,
+            None => return Ok(()),
+        };
+        let tmp = path.with_extension("tmp");
+        tokio::fs::write(&tmp, serde_json::to_vec(data)?)
+            .await
+            .with_context(|| format!("writing transfer queue {:?}", tmp))?;
+        tokio::fs::rename(&tmp, path).await?;
+        Ok(())
+    }
+
+    /// Add a transfer, returning its ID.
+    pub async fn enqueue(&self, spec: TransferSpec, priority: i32) -> Result<u64> {
+        let mut inner = self.inner.lock().await;
+        let id = inner.data.next_id;
+        inner.data.next_id += 1;
+        inner.data.transfers.push(QueuedTransfer {
+            id,
+            spec,
+            priority,
+            state: TransferState::Queued,
+            error: None,
+        });
+        self.save(&inner.data).await?;
+        self.notify.notify_one();
+        Ok(id)
+    }
+
+    /// All transfers, including finished ones (see `clear_finished()`), in the order they were
+    /// enqueued.
+    pub async fn list(&self) -> Vec<QueuedTransfer> {
+        self.inner.lock().await.data.transfers.clone()
+    }
+
+    pub async fn get(&self, id: u64) -> Option<QueuedTransfer> {
+        let inner = self.inner.lock().await;
+        inner.data.transfers.iter().find(|t| t.id == id).cloned()
+    }
+
+    /// Apply `f` to the transfer `id`, saving the queue if `f` returns true. Returns whether
+    /// `f` did.
+    async fn update(&self, id: u64, f: impl FnOnce(&mut QueuedTransfer) -> bool) -> Result<bool> {
+        let mut inner = self.inner.lock().await;
+        let changed = match inner.data.transfers.iter_mut().find(|t| t.id == id) {
+            Some(t) => f(t),
+            None => false,
+        };
+        if changed {
+            self.save(&inner.data).await?;
+        }
+        Ok(changed)
+    }
+
+    /// Change the priority of a transfer not started yet. Returns false if there is no such
+    /// transfer.
+    pub async fn set_priority(&self, id: u64, priority: i32) -> Result<bool> {
+        self.update(id, |t| {
+            if t.state == TransferState::Queued {
+                t.priority = priority;
+            }
+            t.state == TransferState::Queued
+        })
+        .await
+    }
+
+    /// Cancel a queued or running transfer. Returns false if there is no such transfer or it
+    /// has finished already.
+    pub async fn cancel(&self, id: u64) -> Result<bool> {
+        let mut inner = self.inner.lock().await;
+        let t = match inner.data.transfers.iter_mut().find(|t| t.id == id) {
+            Some(t) if !t.state.finished() => t,
+            _ => return Ok(false),
+        };
+        t.state = TransferState::Cancelled;
+        if let Some(token) = inner.running.get(&id) {
+            token.cancel();
+        }
+        self.save(&inner.data).await?;
+        info!(target: "hd_api::queue", "transfer {} cancelled", id);
+        Ok(true)
+    }
+
+    /// Queue a failed or cancelled transfer again. Returns false if there is no such transfer.
+    pub async fn retry(&self, id: u64) -> Result<bool> {
+        let retried = self
+            .update(id, |t| match t.state {
+                TransferState::Failed | TransferState::Cancelled => {
+                    t.state = TransferState::Queued;
+                    t.error = None;
+                    true
+                }
+                _ => false,
+            })
+            .await?;
+        if retried {
+            self.notify.notify_one();
+        }
+        Ok(retried)
+    }
+
+    /// Remove finished transfers from the queue, returning how many were removed.
+    pub async fn clear_finished(&self) -> Result<usize> {
+        let mut inner = self.inner.lock().await;
+        let before = inner.data.transfers.len();
+        inner.data.transfers.retain(|t| !t.state.finished());
+        let n = before - inner.data.transfers.len();
+        self.save(&inner.data).await?;
+        Ok(n)
+    }
+
+    /// Process transfers, up to `concurrency` at once, each on its own fork of `hd`, until
+    /// `cancel` is triggered. Running transfers are interrupted then and queued again.
+    ///
+    /// Fails if the queue file can't be written. All transfers are interrupted then; running ones
+    /// are queued again once the queue is opened next.
+    pub async fn run(
+        &self,
+        hd: &HiDrive,
+        concurrency: usize,
+        cancel: CancellationToken,
+    ) -> Result<()> {
+        self.run_with_shutdown(hd, concurrency, cancel.clone(), cancel)
+            .await
+    }
+
+    /// Like `run()`, but shutting down gracefully: once `stop` is triggered, no more transfers
+    /// are started, and running ones are finished unless `abort` is triggered, too.
+    pub async fn run_with_shutdown(
+        &self,
+        hd: &HiDrive,
+        concurrency: usize,
+        stop: CancellationToken,
+        abort: CancellationToken,
+    ) -> Result<()> {
+        try_join_all((0..concurrency.max(1)).map(|_| self.worker(hd.fork(), &stop, &abort, false)))
+            .await?;
+        Ok(())
+    }
+
+    /// Like `run()`, but return once no transfers are queued anymore.
+    pub async fn run_until_idle(&self, hd: &HiDrive, concurrency: usize) -> Result<()> {
+        let cancel = CancellationToken::new();
+        try_join_all(
+            (0..concurrency.max(1)).map(|_| self.worker(hd.fork(), &cancel, &cancel, true)),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn worker(
+        &self,
+        mut hd: HiDrive,
+        stop: &CancellationToken,
+        abort: &CancellationToken,
+        until_idle: bool,
+    ) -> Result<()> {
+        loop {
+            if stop.is_cancelled() {
+                return Ok(());
+            }
+            let (id, spec, token) = match self.start_next(abort).await? {
+                Some(next) => next,
+                None if until_idle => return Ok(()),
+                None => {
+                    tokio::select! {
+                        _ = stop.cancelled() => return Ok(()),
+                        _ = self.notify.notified() => continue,
+                    }
+                }
+            };
+            let r = execute(&mut hd, &spec, token).await;
+            self.finish(id, r).await?;
+        }
+    }
+
+    /// Mark the next queued transfer as running, interrupted once `abort` is triggered.
+    async fn start_next(
+        &self,
+        abort: &CancellationToken,
+    ) -> Result<Option<(u64, TransferSpec, CancellationToken)>> {
+        let mut inner = self.inner.lock().await;
+        let t = match inner
+            .data
+            .transfers
+            .iter_mut()
+            .filter(|t| t.state == TransferState::Queued)
+            .max_by_key(|t| (t.priority, Reverse(t.id)))
+        {
+            Some(t) => t,
+            None => return Ok(None),
+        };
+        t.state = TransferState::Running;
+        let (id, spec) = (t.id, t.spec.clone());
+        let token = abort.child_token();
+        inner.running.insert(id, token.clone());
+        self.save(&inner.data).await?;
+        info!(target: "hd_api::queue", "transfer {} started: {:?}", id, spec);
+        Ok(Some((id, spec, token)))
+    }
+
+    async fn finish(&self, id: u64, r: Result<()>) -> Result<()> {
+        let mut inner = self.inner.lock().await;
+        inner.running.remove(&id);
+        let t = match inner.data.transfers.iter_mut().find(|t| t.id == id) {
+            Some(t) => t,
+            // Removed meanwhile.
+            None => return Ok(()),
+        };
+        match r {
+            // Cancelled by `cancel()`.
+            _ if t.state == TransferState::Cancelled => (),
+            Ok(()) => {
+                t.state = TransferState::Done;
+                info!(target: "hd_api::queue", "transfer {} done", id);
+                if let Some(ref m) = self.metrics {
+                    m.counter("hd_queue_transfers_done_total", "Transfers completed.", 1);
+                }
+            }
+            // Interrupted by stopping the queue.
+            Err(e) if e.chain().any(|c| c.is::<Cancelled>()) => {
+                t.state = TransferState::Queued;
+            }
+            Err(e) => {
+                warn!(target: "hd_api::queue", "transfer {} failed: {:#}", id, e);
+                t.state = TransferState::Failed;
+                t.error = Some(format!("{:#}", e));
+                if let Some(ref m) = self.metrics {
+                    m.counter("hd_queue_transfers_failed_total", "Transfers failed.", 1);
+                }
+            }
+        }
+        self.save(&inner.data).await
+    }
+}
+
+async fn execute(hd: &mut HiDrive, spec: &TransferSpec, cancel: CancellationToken) -> Result<()> {
+    match spec {
+        TransferSpec::Upload { local, dir, name } => {
+            let f = tokio::fs::File::open(local)
+                .await
+                .with_context(|| format!("opening {:?}", local))?;
+            hd.files()
+                .with_cancellation(cancel)
+                .upload(dir.clone(), name, f, None)
+                .await?;
+        }
+        TransferSpec::Download { remote, local } => {
+            hd.files()
+                .with_cancellation(cancel)
+                .download_resumable(remote.clone(), local)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::mock::{hidrive, MockTransport};
+    use crate::metrics::PrometheusMetrics;
+
+    #[tokio::test]
+    async fn test_queue() {
+        let dir = std::env::temp_dir().join("hd_api_test_queue");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.txt"), "a").unwrap();
+        let t = MockTransport::new();
+        let hd = hidrive(t.clone());
+        let metrics = Arc::new(PrometheusMetrics::new());
+
+        let path = dir.join("queue.json");
+        let q = TransferQueue::open(&path).await.unwrap();
+        let upload = |name: &str| TransferSpec::Upload {
+            local: dir.join("a.txt"),
+            dir: Identifier::Path("/m".into()),
+            name: name.into(),
+        };
+        let low = q.enqueue(upload("low"), 0).await.unwrap();
+        let high = q.enqueue(upload("high"), 10).await.unwrap();
+        let cancelled = q.enqueue(upload("cancelled"), 5).await.unwrap();
+        let failing = q.enqueue(upload("failing"), -1).await.unwrap();
+        assert!(q.cancel(cancelled).await.unwrap());
+        assert!(!q.cancel(cancelled).await.unwrap());
+
+        // Survives a restart.
+        drop(q);
+        let mut q = TransferQueue::open(&path).await.unwrap();
+        q.set_metrics(Some(metrics.clone()));
+        assert_eq!(4, q.list().await.len());
+        assert!(q.set_priority(failing, -2).await.unwrap());
+
+        t.push(200, "{}");
+        t.push(200, "{}");
+        t.push(404, r#"{"code": 404, "msg": "not found"}"#);
+        q.run_until_idle(&hd, 1).await.unwrap();
+        let names: Vec<String> = t
+            .requests()
+            .iter()
+            .filter_map(|r| r.param("name"))
+            .collect();
+        assert_eq!(vec!["high", "low", "failing"], names);
+        assert_eq!(TransferState::Done, q.get(low).await.unwrap().state);
+        assert_eq!(TransferState::Done, q.get(high).await.unwrap().state);
+        let f = q.get(failing).await.unwrap();
+        assert_eq!(TransferState::Failed, f.state);
+        assert!(f.error.is_some());
+        assert_eq!(
+            TransferState::Cancelled,
+            q.get(cancelled).await.unwrap().state
+        );
+        assert_eq!(Some(2.0), metrics.get("hd_queue_transfers_done_total"));
+        assert_eq!(Some(1.0), metrics.get("hd_queue_failed"));
+        assert_eq!(Some(0.0), metrics.get("hd_queue_queued"));
+
+        assert!(q.retry(failing).await.unwrap());
+        t.push(200, "{}");
+        q.run_until_idle(&hd, 2).await.unwrap();
+        assert_eq!(TransferState::Done, q.get(failing).await.unwrap().state);
+        assert_eq!(4, q.clear_finished().await.unwrap());
+        let q = TransferQueue::open(&path).await.unwrap();
+        assert!(q.list().await.is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_queue_save_error() {
+        let dir = std::env::temp_dir().join("hd_api_test_queue_save_error");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let t = MockTransport::new();
+        let hd = hidrive(t.clone());
+
+        let q = TransferQueue::open(dir.join("queue.json")).await.unwrap();
+        let spec = TransferSpec::Download {
+            remote: Identifier::Path("/a".into()),
+            local: dir.join("a"),
+        };
+        let id = q.enqueue(spec, 0).await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(q.run_until_idle(&hd, 2).await.is_err());
+        assert!(t.requests().is_empty());
+        assert_eq!(TransferState::Running, q.get(id).await.unwrap().state);
+    }
+}
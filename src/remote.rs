@@ -0,0 +1,403 @@
+//! Remote files as tokio I/O objects, see `RemoteFile` and `RemoteWriter`.
+
+use crate::hidrive::HiDrive;
+use crate::types::Identifier;
+
+use std::future::Future;
+use std::io::{self, SeekFrom};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use anyhow::Result;
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf};
+
+/// Default minimum size of the range requests of a `RemoteFile`.
+pub const DEFAULT_READ_AHEAD: usize = 1 << 20;
+/// Default size of the requests of a `RemoteWriter`.
+pub const DEFAULT_WRITE_CHUNK: usize = 8 << 20;
+
+/// Collects a range download in memory. `HiDriveFiles::get_range()` writes at the offsets of
+/// the remote file, which are relative to `start` here.
+pub(crate) struct RangeBuffer {
+    pub buf: Vec<u8>,
+    pub start: u64,
+}
+
+impl AsyncWrite for RangeBuffer {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.buf).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.buf).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.buf).poll_shutdown(cx)
+    }
+}
+
+impl AsyncSeek for RangeBuffer {
+    fn start_seek(self: Pin<&mut Self>, pos: SeekFrom) -> io::Result<()> {
+        match pos {
+            SeekFrom::Start(p) if p == self.start + self.buf.len() as u64 => Ok(()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "RangeBuffer only appends",
+            )),
+        }
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(self.start + self.buf.len() as u64))
+    }
+}
+
+type Fetch = Pin<Box<dyn Future<Output = (HiDrive, Result<Vec<u8>>)> + Send>>;
+
+/// A remote file opened for reading with `HiDriveFiles::open()`, for random access without
+/// downloading the whole file, e.g. by media players or parsers of archive formats.
+///
+/// Reads are served from a buffer filled by range requests of at least `read_ahead` bytes, so
+/// that small sequential reads don't each cause a request; neither do seeks within the buffer.
+/// The file is expected not to change while open.
+pub struct RemoteFile {
+    hd: Option<HiDrive>,
+    id: Identifier,
+    size: u64,
+    pos: u64,
+    read_ahead: usize,
+    /// Data of the file starting at `buf_start`.
+    buf: Vec<u8>,
+    buf_start: u64,
+    /// A range request in flight, and where it starts. It owns `hd` meanwhile.
+    fetch: Option<(u64, Fetch)>,
+}
+
+impl RemoteFile {
+    pub(crate) fn new(hd: HiDrive, id: Identifier, size: u64) -> RemoteFile {
+        RemoteFile {
+            hd: Some(hd),
+            id,
+            size,
+            pos: 0,
+            read_ahead: DEFAULT_READ_AHEAD,
+            buf: vec![],
+            buf_start: 0,
+            fetch: None,
+        }
+    }
+
+    /// The size of the file when it was opened.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Set the minimum size of range requests (default: `DEFAULT_READ_AHEAD`).
+    pub fn set_read_ahead(&mut self, bytes: usize) {
+        self.read_ahead = bytes.max(1);
+    }
+
+    /// The buffered data at the current position, if any.
+    fn buffered(&self) -> Option<&[u8]> {
+        let end = self.buf_start + self.buf.len() as u64;
+        if self.pos >= self.buf_start && self.pos < end {
+            Some(&self.buf[(self.pos - self.buf_start) as usize..])
+        } else {
+            None
+        }
+    }
+}
+
+impl AsyncRead for RemoteFile {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        out: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if this.pos >= this.size || out.remaining() == 0 {
+                return Poll::Ready(Ok(()));
+            }
+            if let Some(data) = this.buffered() {
+                let n = data.len().min(out.remaining());
+                out.put_slice(&data[..n]);
+                this.pos += n as u64;
+                return Poll::Ready(Ok(()));
+            }
+            if this.fetch.is_none() {
+                let start = this.pos;
+                let len = this.read_ahead.max(out.remaining()) as u64;
+                let end = (start + len).min(this.size);
+                let mut hd = this.hd.take().expect("RemoteFile: no fetch in flight");
+                let id = this.id.clone();
+                let fetch: Fetch = Box::pin(async move {
+                    let r = hd.files().read_range(id, start..end).await;
+                    (hd, r)
+                });
+                this.fetch = Some((start, fetch));
+            }
+            let (start, fetch) = this.fetch.as_mut().unwrap();
+            let start = *start;
+            let (hd, r) = ready!(fetch.as_mut().poll(cx));
+            this.fetch = None;
+            this.hd = Some(hd);
+            this.buf = r.map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            this.buf_start = start;
+            if this.buf.is_empty() {
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+        }
+    }
+}
+
+impl AsyncSeek for RemoteFile {
+    fn start_seek(mut self: Pin<&mut Self>, pos: SeekFrom) -> io::Result<()> {
+        let pos = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(d) => self.size.checked_add_signed(d),
+            SeekFrom::Current(d) => self.pos.checked_add_signed(d),
+        };
+        self.pos = pos.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(self.pos))
+    }
+}
+
+type Upload = Pin<Box<dyn Future<Output = (HiDrive, Result<Identifier>)> + Send>>;
+
+/// A remote file being written, created with `HiDriveFiles::create()`, for streaming data from
+/// code written against generic writers (archivers, encoders) into HiDrive.
+///
+/// Data is buffered and sent in chunks of `chunk_size`: the first creates the file, replacing an
+/// existing one, and the following are appended (see `HiDriveFiles::patch_file()`). The file
+/// thus exists with partial content while being written. Flushing sends the buffered data; the
+/// writer must be shut down (`AsyncWriteExt::shutdown()`) to send the rest, which also creates
+/// the file if nothing was written. After an error, the writer can't be used anymore.
+pub struct RemoteWriter {
+    hd: Option<HiDrive>,
+    dir: Identifier,
+    name: String,
+    /// The file, once created.
+    target: Option<Identifier>,
+    chunk_size: usize,
+    buf: Vec<u8>,
+    /// Offset of `buf` in the file.
+    offset: u64,
+    /// A request in flight. It owns `hd` meanwhile.
+    upload: Option<Upload>,
+    failed: bool,
+}
+
+impl RemoteWriter {
+    pub(crate) fn new(hd: HiDrive, dir: Identifier, name: String) -> RemoteWriter {
+        RemoteWriter {
+            hd: Some(hd),
+            dir,
+            name,
+            target: None,
+            chunk_size: DEFAULT_WRITE_CHUNK,
+            buf: vec![],
+            offset: 0,
+            upload: None,
+            failed: false,
+        }
+    }
+
+    /// Set the size of upload requests (default: `DEFAULT_WRITE_CHUNK`).
+    pub fn set_chunk_size(&mut self, bytes: usize) {
+        self.chunk_size = bytes.max(1);
+    }
+
+    /// The number of bytes written so far, including buffered ones.
+    pub fn written(&self) -> u64 {
+        self.offset + self.buf.len() as u64
+    }
+
+    /// Send the buffered data, creating the file first if needed.
+    fn start_upload(&mut self) {
+        let data = std::mem::take(&mut self.buf);
+        let offset = self.offset;
+        self.offset += data.len() as u64;
+        let mut hd = self.hd.take().expect("RemoteWriter: no upload in flight");
+        let (dir, name, target) = (self.dir.clone(), self.name.clone(), self.target.clone());
+        self.upload = Some(Box::pin(async move {
+            let r = match target {
+                Some(t) => hd
+                    .files()
+                    .patch_file(t.clone(), offset, data, None)
+                    .await
+                    .map(|()| t),
+                None => {
+                    hd.files()
+                        .upload(dir.clone(), &name, data, None)
+                        .await
+                        .map(|it| match it.id {
+                            Some(id) => Identifier::Id(id),
+                            None => dir.join(&name),
+                        })
+                }
+            };
+            (hd, r)
+        }));
+    }
+
+    fn poll_upload(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.failed {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "RemoteWriter: an earlier upload failed",
+            )));
+        }
+        if let Some(upload) = self.upload.as_mut() {
+            let (hd, r) = ready!(upload.as_mut().poll(cx));
+            self.upload = None;
+            self.hd = Some(hd);
+            match r {
+                Ok(target) => self.target = Some(target),
+                Err(e) => {
+                    self.failed = true;
+                    return Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, e)));
+                }
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for RemoteWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        ready!(this.poll_upload(cx))?;
+        if this.buf.len() >= this.chunk_size {
+            this.start_upload();
+            ready!(this.poll_upload(cx))?;
+        }
+        let n = data.len().min(this.chunk_size - this.buf.len());
+        this.buf.extend_from_slice(&data[..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        ready!(this.poll_upload(cx))?;
+        if !this.buf.is_empty() {
+            this.start_upload();
+            ready!(this.poll_upload(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        let this = &mut *self;
+        if this.target.is_none() {
+            this.start_upload();
+            ready!(this.poll_upload(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::mock::{hidrive, MockTransport};
+
+    use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_remote_file() {
+        let t = MockTransport::new();
+        let mut hd = hidrive(t.clone());
+
+        t.push(200, r#"{"id": "b1.2", "size": 10}"#);
+        let mut f = hd
+            .files()
+            .open(Identifier::Path("/a.bin".into()))
+            .await
+            .unwrap();
+        f.set_read_ahead(4);
+        assert_eq!(10, f.size());
+
+        t.push(206, "0123");
+        let mut buf = [0; 3];
+        f.read_exact(&mut buf).await.unwrap();
+        assert_eq!(b"012", &buf);
+        assert_eq!("bytes=0-3", t.last().headers["range"]);
+        assert_eq!(Some("b1.2"), t.last().param("pid").as_deref());
+        // Served from the buffer.
+        let mut buf = [0; 1];
+        f.read_exact(&mut buf).await.unwrap();
+        assert_eq!(b"3", &buf);
+        assert_eq!(2, t.requests().len());
+
+        assert_eq!(7, f.seek(SeekFrom::End(-3)).await.unwrap());
+        t.push(206, "789");
+        let mut rest = vec![];
+        f.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(b"789".to_vec(), rest);
+        assert_eq!("bytes=7-9", t.last().headers["range"]);
+
+        assert_eq!(1, f.seek(SeekFrom::Start(1)).await.unwrap());
+        assert!(f.seek(SeekFrom::Current(-2)).await.is_err());
+        t.push(404, r#"{"code": 404, "msg": "not found"}"#);
+        assert!(f.read_exact(&mut buf).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_remote_writer() {
+        let t = MockTransport::new();
+        let mut hd = hidrive(t.clone());
+        let dir = || Identifier::Path("/d".into());
+
+        let mut w = hd.files().create(dir(), "a.txt");
+        w.set_chunk_size(4);
+        t.push(200, r#"{"id": "b1.2"}"#);
+        t.push(200, "{}");
+        t.push(200, "{}");
+        w.write_all(b"abcdefghij").await.unwrap();
+        assert_eq!(10, w.written());
+        w.shutdown().await.unwrap();
+        let rqs = t.requests();
+        assert_eq!(3, rqs.len());
+        assert_eq!(reqwest::Method::PUT, rqs[0].method);
+        assert_eq!(Some("a.txt"), rqs[0].param("name").as_deref());
+        assert_eq!(Some(b"abcd".to_vec()), rqs[0].body);
+        assert_eq!(reqwest::Method::PATCH, rqs[1].method);
+        assert_eq!(Some("b1.2"), rqs[1].param("pid").as_deref());
+        assert_eq!(Some("4"), rqs[1].param("offset").as_deref());
+        assert_eq!(Some(b"efgh".to_vec()), rqs[1].body);
+        assert_eq!(Some("8"), rqs[2].param("offset").as_deref());
+        assert_eq!(Some(b"ij".to_vec()), rqs[2].body);
+
+        // An empty file is created on shutdown.
+        let mut w = hd.files().create(dir(), "empty");
+        t.push(200, "{}");
+        w.shutdown().await.unwrap();
+        assert_eq!(Some("empty"), t.last().param("name").as_deref());
+
+        let mut w = hd.files().create(dir(), "b.txt");
+        t.push(500, "error");
+        w.write_all(b"abc").await.unwrap();
+        assert!(w.flush().await.is_err());
+        assert!(w.write_all(b"d").await.is_err());
+    }
+}
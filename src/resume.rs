@@ -0,0 +1,183 @@
+//! State of resumable downloads (see `HiDriveFiles::download_resumable()`). Data is written to
+//! `<file>.part`, and the byte ranges already downloaded, with their hashes, to the sidecar
+//! `<file>.part.json`. After a restart, ranges whose data still matches are skipped.
+
+use crate::hashing::Hash;
+
+use std::ffi::OsString;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+
+use anyhow::{self, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+const PART_STATE_VERSION: u32 = 1;
+/// Size of the ranges downloaded and recorded at once.
+pub(crate) const SEGMENT_SIZE: u64 = 8 << 20;
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut s = OsString::from(path.as_os_str());
+    s.push(suffix);
+    s.into()
+}
+
+/// Where data for a download to `path` is written until it is complete.
+pub(crate) fn part_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".part")
+}
+
+/// Where the `PartState` of a download to `path` is stored.
+pub(crate) fn state_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".part.json")
+}
+
+/// A downloaded byte range `[start, end)` with the SHA-1 of its data.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Segment {
+    pub start: u64,
+    pub end: u64,
+    pub hash: Hash,
+}
+
+/// Progress of a download. `size` and `chash` identify the version of the remote file.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct PartState {
+    version: u32,
+    pub size: u64,
+    pub chash: Hash,
+    pub done: Vec<Segment>,
+}
+
+/// Hash the bytes `[start, end)` of `f`.
+pub(crate) async fn hash_range(f: &mut fs::File, start: u64, end: u64) -> Result<Hash> {
+    if start > end {
+        anyhow::bail!("hash_range: invalid range {}..{}", start, end);
+    }
+    f.seek(SeekFrom::Start(start)).await?;
+    let mut r = f.take(end - start);
+    let mut buf = vec![0; 64 * 1024];
+    let mut h = Sha1::new();
+    let mut n = 0;
+    loop {
+        let m = r.read(&mut buf).await?;
+        if m == 0 {
+            break;
+        }
+        h.update(&buf[..m]);
+        n += m as u64;
+    }
+    if n != end - start {
+        return Err(anyhow::Error::msg("hash_range: file too short"));
+    }
+    Ok(Hash::new_from_sha1(h.finalize()))
+}
+
+impl PartState {
+    pub fn new(size: u64, chash: Hash) -> PartState {
+        PartState {
+            version: PART_STATE_VERSION,
+            size,
+            chash,
+            done: vec![],
+        }
+    }
+
+    /// Load the state of the download to `path`. Returns `None` if there is none, or if it was
+    /// recorded for a different version of the remote file. Segments that are empty, extend past
+    /// `size`, or whose data in the part file doesn't match their hash anymore are dropped.
+    pub async fn load(path: &Path, size: u64, chash: &Hash) -> Result<Option<PartState>> {
+        let b = match fs::read(state_path(path)).await {
+            Ok(b) => b,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut st: PartState = serde_json::from_slice(&b)?;
+        if st.version != PART_STATE_VERSION || st.size != size || st.chash != *chash {
+            return Ok(None);
+        }
+        let mut f = match fs::File::open(part_path(path)).await {
+            Ok(f) => f,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut verified = vec![];
+        for s in st.done.drain(..) {
+            if s.start >= s.end || s.end > size {
+                continue;
+            }
+            if let Ok(h) = hash_range(&mut f, s.start, s.end).await {
+                if h == s.hash {
+                    verified.push(s);
+                }
+            }
+        }
+        st.done = verified;
+        Ok(Some(st))
+    }
+
+    /// Store the state of the download to `path`.
+    pub async fn save(&self, path: &Path) -> Result<()> {
+        let sp = state_path(path);
+        let tmp = with_suffix(&sp, ".tmp");
+        fs::write(&tmp, serde_json::to_vec(self)?)
+            .await
+            .with_context(|| format!("writing {:?}", tmp))?;
+        fs::rename(&tmp, &sp).await?;
+        Ok(())
+    }
+
+    /// Returns true if `[start, end)` has been downloaded.
+    pub fn is_done(&self, start: u64, end: u64) -> bool {
+        self.done.iter().any(|s| s.start <= start && end <= s.end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_part_state() {
+        let path = std::env::temp_dir().join("hd_api_test_part_state");
+        assert_eq!(
+            std::env::temp_dir().join("hd_api_test_part_state.part.json"),
+            state_path(&path)
+        );
+        fs::write(part_path(&path), b"0123456789").await.unwrap();
+        let chash = Hash::for_string("remote");
+        assert!(PartState::load(&path, 10, &chash).await.unwrap().is_none());
+
+        let mut st = PartState::new(10, chash.clone());
+        st.done.push(Segment {
+            start: 0,
+            end: 5,
+            hash: Hash::for_string("01234"),
+        });
+        st.done.push(Segment {
+            start: 5,
+            end: 10,
+            hash: Hash::for_string("garbage"),
+        });
+        // Invalid ranges, e.g. from a corrupted sidecar.
+        for (start, end) in [(5, 5), (7, 3), (8, 12)] {
+            st.done.push(Segment {
+                start,
+                end,
+                hash: Hash::for_string(""),
+            });
+        }
+        st.save(&path).await.unwrap();
+
+        let st = PartState::load(&path, 10, &chash).await.unwrap().unwrap();
+        assert!(st.is_done(0, 5));
+        assert!(!st.is_done(5, 10));
+        assert_eq!(1, st.done.len());
+        assert!(PartState::load(&path, 11, &chash).await.unwrap().is_none());
+
+        fs::remove_file(part_path(&path)).await.unwrap();
+        fs::remove_file(state_path(&path)).await.unwrap();
+    }
+}
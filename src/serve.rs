@@ -0,0 +1,393 @@
+//! Serving a remote directory read-only over HTTP, e.g. to make files available to devices on
+//! the local network (TVs, other computers) without synchronizing them first. See
+//! `FolderServer`.
+
+use crate::hidrive::{HiDrive, NO_PARAMS};
+use crate::remote::RemoteFile;
+use crate::types::{error_status, Identifier, Item, Params};
+
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::io::SeekFrom;
+use std::net::TcpListener;
+use std::ops::Range;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use hyper::header::{ALLOW, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, LOCATION, RANGE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use log::{info, warn};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::sync::CancellationToken;
+
+const LIST_FIELDS: &str = "members.name,members.type,members.size,members.mtime";
+/// Size of the chunks of response bodies.
+const BODY_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Serves the files below a remote directory over HTTP: `GET /a/b.mp4` returns the file
+/// `{root}/a/b.mp4`, supporting range requests for seeking in media players, and directories are
+/// served as HTML index pages. Only `GET` and `HEAD` are allowed. There is no authentication:
+/// anyone who can connect can read all files below `root`.
+///
+/// ```ignore
+/// let listener = std::net::TcpListener::bind("0.0.0.0:8080")?;
+/// FolderServer::new(&hd, "/users/me/videos").serve(listener, cancel).await?;
+/// ```
+pub struct FolderServer {
+    hd: HiDrive,
+    root: String,
+}
+
+impl FolderServer {
+    pub fn new(hd: &HiDrive, root: impl Into<String>) -> FolderServer {
+        FolderServer {
+            hd: hd.fork(),
+            root: root.into().trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// Serve requests arriving on `listener` until `cancel` is triggered.
+    pub async fn serve(self, listener: TcpListener, cancel: CancellationToken) -> Result<()> {
+        let root = self.root.clone();
+        let this = Arc::new(self);
+        let mk = make_service_fn(move |_: &hyper::server::conn::AddrStream| {
+            let this = this.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |rq: Request<Body>| {
+                    let this = this.clone();
+                    async move { Ok::<_, Infallible>(this.handle(rq).await) }
+                }))
+            }
+        });
+        let srv = hyper::Server::from_tcp(listener)
+            .context("FolderServer: listening")?
+            .serve(mk);
+        info!(target: "hd_api::serve", "serving {} on {}", root, srv.local_addr());
+        srv.with_graceful_shutdown(async move { cancel.cancelled().await })
+            .await
+            .context("FolderServer")
+    }
+
+    async fn handle(&self, rq: Request<Body>) -> Response<Body> {
+        let head = match *rq.method() {
+            Method::GET => false,
+            Method::HEAD => true,
+            _ => {
+                return Response::builder()
+                    .status(StatusCode::METHOD_NOT_ALLOWED)
+                    .header(ALLOW, "GET, HEAD")
+                    .body(Body::empty())
+                    .unwrap()
+            }
+        };
+        let uri_path = rq.uri().path();
+        let rel = match decode_path(uri_path) {
+            Some(rel) => rel,
+            None => return text(StatusCode::BAD_REQUEST, "invalid path"),
+        };
+        let path = if rel.is_empty() && self.root.is_empty() {
+            "/".to_string()
+        } else if rel.is_empty() {
+            self.root.clone()
+        } else {
+            format!("{}/{}", self.root, rel)
+        };
+        info!(target: "hd_api::serve", "{} {}", rq.method(), path);
+        let mut hd = self.hd.fork();
+        let r = match hd
+            .files()
+            .metadata(Identifier::Path(path.clone()), "id,type,size", NO_PARAMS)
+            .await
+        {
+            Ok(it) if it.typ.as_deref() == Some("dir") => {
+                if !uri_path.ends_with('/') {
+                    return Response::builder()
+                        .status(StatusCode::MOVED_PERMANENTLY)
+                        .header(LOCATION, format!("{}/", uri_path))
+                        .body(Body::empty())
+                        .unwrap();
+                }
+                self.index(&mut hd, &path, &rel, head).await
+            }
+            Ok(it) => file(hd, &it, Identifier::Path(path), &rq, head).await,
+            Err(e) => Err(e),
+        };
+        r.unwrap_or_else(|e| {
+            if error_status(&e) == Some(404) {
+                return text(StatusCode::NOT_FOUND, "not found");
+            }
+            warn!(target: "hd_api::serve", "{}: {:#}", path, e);
+            text(StatusCode::BAD_GATEWAY, &format!("{:#}", e))
+        })
+    }
+
+    /// The HTML index page of the directory `path`.
+    async fn index(
+        &self,
+        hd: &mut HiDrive,
+        path: &str,
+        rel: &str,
+        head: bool,
+    ) -> Result<Response<Body>> {
+        let mut p = Params::new();
+        p.add_str("fields", LIST_FIELDS);
+        let mut members = hd
+            .files()
+            .get_dir(Identifier::Path(path.into()), Some(&p))
+            .await?
+            .members;
+        members.sort_by_key(|m| (m.typ.as_deref() != Some("dir"), m.name.clone()));
+        let title = html_escape(&format!("/{}", rel));
+        let mut html = format!(
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{0}</title></head>\
+             <body><h1>{0}</h1><ul>\n",
+            title
+        );
+        if !rel.is_empty() {
+            html.push_str("<li><a href=\"../\">../</a></li>\n");
+        }
+        for m in members {
+            let name = match m.name {
+                Some(ref n) => n,
+                None => continue,
+            };
+            let (slash, size) = match m.typ.as_deref() {
+                Some("dir") => ("/", String::new()),
+                _ => ("", format!(" ({} bytes)", m.size.unwrap_or(0))),
+            };
+            let _ = writeln!(
+                html,
+                "<li><a href=\"{}{}\">{}{}</a>{}</li>",
+                percent_encode(name),
+                slash,
+                html_escape(name),
+                slash,
+                size
+            );
+        }
+        html.push_str("</ul></body></html>\n");
+        let rp = Response::builder()
+            .header(CONTENT_TYPE, "text/html; charset=utf-8")
+            .header(CONTENT_LENGTH, html.len());
+        Ok(rp.body(if head { Body::empty() } else { html.into() })?)
+    }
+}
+
+/// The file `it`, or the byte range requested by `rq`.
+async fn file(
+    hd: HiDrive,
+    it: &Item,
+    path: Identifier,
+    rq: &Request<Body>,
+    head: bool,
+) -> Result<Response<Body>> {
+    let size = it.size.unwrap_or(0) as u64;
+    let mut rp = Response::builder()
+        .header(CONTENT_TYPE, content_type(&it.path))
+        .header("accept-ranges", "bytes");
+    let range = match rq.headers().get(RANGE).and_then(|v| v.to_str().ok()) {
+        None => 0..size,
+        Some(v) => match parse_range(v, size) {
+            Some(r) => {
+                rp = rp.status(StatusCode::PARTIAL_CONTENT).header(
+                    CONTENT_RANGE,
+                    format!("bytes {}-{}/{}", r.start, r.end - 1, size),
+                );
+                r
+            }
+            None => {
+                return Ok(Response::builder()
+                    .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                    .header(CONTENT_RANGE, format!("bytes */{}", size))
+                    .body(Body::empty())?)
+            }
+        },
+    };
+    let rp = rp.header(CONTENT_LENGTH, range.end - range.start);
+    if head || range.is_empty() {
+        return Ok(rp.body(Body::empty())?);
+    }
+    let id = match it.id {
+        Some(ref id) => Identifier::Id(id.clone()),
+        None => path,
+    };
+    let mut f = RemoteFile::new(hd, id, size);
+    f.seek(SeekFrom::Start(range.start)).await?;
+    let body =
+        futures_util::stream::try_unfold(f.take(range.end - range.start), |mut r| async move {
+            let mut buf = vec![0; BODY_CHUNK_SIZE];
+            let n = r.read(&mut buf).await?;
+            buf.truncate(n);
+            Ok::<_, std::io::Error>((n > 0).then(|| (Bytes::from(buf), r)))
+        });
+    Ok(rp.body(Body::wrap_stream(body))?)
+}
+
+fn text(status: StatusCode, msg: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(format!("{}\n", msg).into())
+        .unwrap()
+}
+
+/// The byte range of a file of `size` bytes requested by the `Range` header `v`. Only single
+/// ranges are supported; `None` if the range isn't satisfiable.
+fn parse_range(v: &str, size: u64) -> Option<Range<u64>> {
+    let (a, b) = v.trim().strip_prefix("bytes=")?.split_once('-')?;
+    let r = if a.is_empty() {
+        size.saturating_sub(b.parse().ok()?)..size
+    } else {
+        let start = a.parse().ok()?;
+        let end = if b.is_empty() {
+            size
+        } else {
+            b.parse::<u64>().ok()?.saturating_add(1).min(size)
+        };
+        start..end
+    };
+    (r.start < r.end).then_some(r)
+}
+
+/// The relative remote path of the request path `p`, percent-decoded. `None` if it contains `.`
+/// or `..` segments or is invalid otherwise.
+fn decode_path(p: &str) -> Option<String> {
+    let mut bytes = vec![];
+    let mut it = p.bytes();
+    while let Some(b) = it.next() {
+        if b == b'%' {
+            let hex = [it.next()?, it.next()?];
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            bytes.push(b);
+        }
+    }
+    let p = String::from_utf8(bytes).ok()?;
+    let segments: Vec<&str> = p.split('/').filter(|s| !s.is_empty()).collect();
+    if segments.iter().any(|s| *s == "." || *s == "..") {
+        return None;
+    }
+    Some(segments.join("/"))
+}
+
+fn percent_encode(s: &str) -> String {
+    let mut out = String::new();
+    for b in s.bytes() {
+        match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                out.push(b as char)
+            }
+            _ => {
+                let _ = write!(out, "%{:02X}", b);
+            }
+        }
+    }
+    out
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// A content type for the file `path`, by its extension.
+fn content_type(path: &str) -> &'static str {
+    let ext = path.rsplit_once('.').map(|(_, e)| e.to_ascii_lowercase());
+    match ext.as_deref() {
+        Some("html" | "htm") => "text/html",
+        Some("txt") => "text/plain",
+        Some("css") => "text/css",
+        Some("js") => "text/javascript",
+        Some("json") => "application/json",
+        Some("pdf") => "application/pdf",
+        Some("zip") => "application/zip",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("png") => "image/png",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("svg") => "image/svg+xml",
+        Some("mp3") => "audio/mpeg",
+        Some("flac") => "audio/flac",
+        Some("ogg") => "audio/ogg",
+        Some("mp4" | "m4v") => "video/mp4",
+        Some("mkv") => "video/x-matroska",
+        Some("webm") => "video/webm",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::MockHiDrive;
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(Some(2..5), parse_range("bytes=2-4", 10));
+        assert_eq!(Some(2..10), parse_range("bytes=2-", 10));
+        assert_eq!(Some(7..10), parse_range("bytes=-3", 10));
+        assert_eq!(Some(8..10), parse_range("bytes=8-100", 10));
+        assert_eq!(None, parse_range("bytes=10-", 10));
+        assert_eq!(None, parse_range("items=1-2", 10));
+        assert_eq!(Some("a b/c".into()), decode_path("/a%20b//c/"));
+        assert_eq!(None, decode_path("/a/%2E%2E/b"));
+        assert_eq!("a%20b%2Fc", percent_encode("a b/c"));
+    }
+
+    #[tokio::test]
+    async fn test_folder_server() {
+        let (fake, hd) = MockHiDrive::start().await;
+        fake.put("a.txt", "abcdef", 0).await;
+        fake.put("sub/b <c>.txt", "b", 0).await;
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let cancel = CancellationToken::new();
+        let srv = tokio::spawn(FolderServer::new(&hd, "/m/").serve(listener, cancel.clone()));
+
+        let cl = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+        let get = |path: &str| cl.get(format!("{}{}", base, path));
+        let rp = get("/").send().await.unwrap();
+        assert_eq!(200, rp.status());
+        let html = rp.text().await.unwrap();
+        assert!(html.find("href=\"sub/\"").unwrap() < html.find("href=\"a.txt\"").unwrap());
+
+        let rp = get("/sub").send().await.unwrap();
+        assert_eq!(301, rp.status());
+        assert_eq!("/sub/", rp.headers()[LOCATION]);
+        let html = get("/sub/").send().await.unwrap().text().await.unwrap();
+        assert!(html.contains("<a href=\"b%20%3Cc%3E.txt\">b &lt;c&gt;.txt</a>"));
+        assert!(html.contains("href=\"../\""));
+
+        let rp = get("/a.txt").send().await.unwrap();
+        assert_eq!("text/plain", rp.headers()[CONTENT_TYPE]);
+        assert_eq!("abcdef", rp.text().await.unwrap());
+        let rp = get("/a.txt")
+            .header(RANGE, "bytes=1-3")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(206, rp.status());
+        assert_eq!("bytes 1-3/6", rp.headers()[CONTENT_RANGE]);
+        assert_eq!("bcd", rp.text().await.unwrap());
+        let rp = get("/a.txt")
+            .header(RANGE, "bytes=6-")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(416, rp.status());
+
+        assert_eq!(404, get("/missing").send().await.unwrap().status());
+        let rp = cl.delete(format!("{}/a.txt", base)).send().await.unwrap();
+        assert_eq!(405, rp.status());
+
+        cancel.cancel();
+        srv.await.unwrap().unwrap();
+    }
+}
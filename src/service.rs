@@ -0,0 +1,201 @@
+//! The low-level API client as `tower::Service`, for wrapping HiDrive calls in tower middleware
+//! (retries, rate limits, load shedding, timeouts) or plugging them into existing service stacks.
+//!
+//! ```ignore
+//! let svc = tower::ServiceBuilder::new()
+//!     .timeout(Duration::from_secs(30))
+//!     .service(hd.service());
+//! let rp = svc.oneshot(ApiRequest::get("/dir").param("path", "/users/me")).await?;
+//! let dir: Item = rp.json()?;
+//! ```
+
+use crate::hidrive::NO_PARAMS;
+use crate::http::{self, Client};
+use crate::types::{Params, RateLimit};
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use anyhow::{Error, Result};
+use bytes::Bytes;
+use reqwest::header::HeaderMap;
+use reqwest::Method;
+use serde::de::DeserializeOwned;
+
+/// A call to an API endpoint.
+#[derive(Debug, Clone)]
+pub struct ApiRequest {
+    pub method: Method,
+    /// Relative to the API base URL (e.g. `/dir`), or an absolute URL.
+    pub path: String,
+    pub params: Params,
+    pub headers: HeaderMap,
+    pub body: Option<Bytes>,
+}
+
+impl ApiRequest {
+    pub fn new(method: Method, path: impl Into<String>) -> ApiRequest {
+        ApiRequest {
+            method,
+            path: path.into(),
+            params: Params::new(),
+            headers: HeaderMap::new(),
+            body: None,
+        }
+    }
+
+    pub fn get(path: impl Into<String>) -> ApiRequest {
+        ApiRequest::new(Method::GET, path)
+    }
+
+    pub fn param(mut self, k: &str, v: impl AsRef<str>) -> Self {
+        self.params.add_str(k, v);
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Bytes>) -> Self {
+        self.body = Some(body.into());
+        self
+    }
+}
+
+/// The response to a successful `ApiRequest`.
+#[derive(Debug, Clone)]
+pub struct ApiResponse {
+    pub status: u16,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl ApiResponse {
+    /// Deserialize the JSON body; an empty body results in `T::default()`.
+    pub fn json<T: Default + DeserializeOwned>(&self) -> Result<T> {
+        if self.body.is_empty() {
+            Ok(T::default())
+        } else {
+            Ok(serde_json::from_slice(&self.body)?)
+        }
+    }
+
+    /// The rate limit headers of the response, e.g. for a rate limiting layer.
+    pub fn rate_limit(&self) -> Option<RateLimit> {
+        RateLimit::from_headers(&self.headers)
+    }
+}
+
+/// Sends `ApiRequest`s using a `Client`. Obtain one with `HiDrive::service()`.
+///
+/// Error responses fail the call with an `ApiError` or `HttpStatusError`, like `Request::go()`,
+/// so that middleware can tell them apart using `types::error_status()` and find out how long to
+/// back off using `types::error_rate_limit()`. The service is always
+/// ready; the client's concurrency limit still applies. Each call runs on a fork of the client.
+pub struct ApiService {
+    client: Client,
+    base_url: String,
+}
+
+impl ApiService {
+    pub fn new(client: Client, base_url: impl Into<String>) -> ApiService {
+        ApiService {
+            client,
+            base_url: base_url.into(),
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        if path.starts_with("https://") || path.starts_with("http://") {
+            path.to_string()
+        } else {
+            format!("{}{}", self.base_url, path)
+        }
+    }
+}
+
+impl Clone for ApiService {
+    fn clone(&self) -> ApiService {
+        ApiService {
+            client: self.client.fork(),
+            base_url: self.base_url.clone(),
+        }
+    }
+}
+
+impl tower_service::Service<ApiRequest> for ApiService {
+    type Response = ApiResponse;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<ApiResponse>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, rq: ApiRequest) -> Self::Future {
+        let mut client = self.client.fork();
+        let url = self.url(&rq.path);
+        Box::pin(async move {
+            let mut r = client
+                .request(rq.method, url, &rq.params, NO_PARAMS)
+                .await?
+                .set_headers(rq.headers);
+            if let Some(body) = rq.body {
+                r = r.set_body(body);
+            }
+            r.go_cb(|rp| async move {
+                let status = rp.status();
+                if !status.is_success() {
+                    return Err(http::error_response(rp, Some(http::DEFAULT_MAX_BODY_SIZE)).await);
+                }
+                let headers = rp.headers().clone();
+                Ok(ApiResponse {
+                    status: status.as_u16(),
+                    headers,
+                    body: rp.bytes().await?,
+                })
+            })
+            .await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::mock::{hidrive, MockTransport};
+    use crate::types::{error_rate_limit, error_status, Item};
+
+    use futures_util::future::poll_fn;
+    use tower_service::Service;
+
+    #[tokio::test]
+    async fn test_service() {
+        let t = MockTransport::new();
+        let hd = hidrive(t.clone());
+        let mut svc = hd.service();
+
+        t.push(200, r#"{"path": "/a", "type": "dir"}"#);
+        poll_fn(|cx| svc.poll_ready(cx)).await.unwrap();
+        let rp = svc
+            .call(ApiRequest::get("/dir").param("path", "/a"))
+            .await
+            .unwrap();
+        assert_eq!(200, rp.status);
+        assert_eq!("/a", rp.json::<Item>().unwrap().path);
+        assert_eq!("/2.1/dir", t.last().url.path());
+        assert_eq!(Some("/a"), t.last().param("path").as_deref());
+
+        t.push_with_headers(
+            404,
+            &[("retry-after", "3")],
+            r#"{"code": 404, "msg": "Not Found"}"#,
+        );
+        let rq = ApiRequest::new(Method::PUT, "/file")
+            .param("dir", "/a")
+            .body("data");
+        let err = svc.clone().call(rq).await.unwrap_err();
+        assert_eq!(Some(404), error_status(&err));
+        let rl = error_rate_limit(&err).unwrap();
+        assert_eq!(Some(std::time::Duration::from_secs(3)), rl.retry_after);
+        assert_eq!(Some(b"data".to_vec()), t.last().body);
+    }
+}
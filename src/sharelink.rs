@@ -0,0 +1,342 @@
+//! Access to public sharelinks without a HiDrive account.
+//!
+//! A sharelink (`https://my.hidrive.com/lnk/<id>`) grants access to a single file or directory.
+//! `ShareLink` exchanges the link ID, and the password if the link has one, for a short-lived
+//! access token, and uses it to list and download the shared contents. No OAuth2 client secret or
+//! credentials are needed.
+//!
+//! ```ignore
+//! let mut link = ShareLink::new("https://my.hidrive.com/lnk/Ab3dEf")?.with_password("secret");
+//! for it in link.list("photos", None).await?.members {
+//!     println!("{:?}", it.name);
+//! }
+//! link.download("photos/a.jpg", tokio::fs::File::create("a.jpg").await?).await?;
+//! ```
+
+use crate::hidrive::DEFAULT_API_BASE_URL;
+use crate::http::{self, Transport, DEFAULT_MAX_BODY_SIZE};
+use crate::types::{Identifier, Item, Params, Share};
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use log::info;
+use reqwest::{Method, StatusCode};
+use serde::Deserialize;
+use tokio::io::AsyncWrite;
+
+/// Hosts serving sharelink URLs.
+const SHARE_HOSTS: &[&str] = &["my.hidrive.com", "hidrive.ionos.com", "free.hidrive.com"];
+/// Path prefixes of sharelink URLs, followed by the ID.
+const SHARE_PREFIXES: &[&str] = &["/lnk/", "/share/"];
+
+/// The response of `POST /share/token`.
+#[derive(Debug, Default, Deserialize)]
+struct ShareToken {
+    access_token: String,
+    #[serde(default)]
+    expires_in: u64,
+}
+
+/// A client for a single sharelink.
+pub struct ShareLink {
+    cl: reqwest::Client,
+    transport: Arc<dyn Transport>,
+    base_url: String,
+    id: String,
+    password: Option<String>,
+    token: Option<(String, Instant)>,
+    share: Option<Share>,
+}
+
+/// Extract the sharelink ID from a sharelink URL, or return `link` if it is a bare ID.
+pub fn parse_id(link: &str) -> Result<String> {
+    let link = link.trim();
+    if !link.contains('/') {
+        if link.is_empty() {
+            return Err(anyhow!("empty sharelink ID"));
+        }
+        return Ok(link.to_string());
+    }
+    let u = reqwest::Url::parse(link).with_context(|| format!("invalid sharelink {:?}", link))?;
+    if !u.host_str().is_some_and(|h| SHARE_HOSTS.contains(&h)) {
+        return Err(anyhow!("not a HiDrive sharelink: {}", link));
+    }
+    SHARE_PREFIXES
+        .iter()
+        .find_map(|p| u.path().strip_prefix(p))
+        .map(|id| id.trim_end_matches('/'))
+        .filter(|id| !id.is_empty() && !id.contains('/'))
+        .map(String::from)
+        .ok_or_else(|| anyhow!("no sharelink ID in {}", link))
+}
+
+impl ShareLink {
+    /// A client for `link`, either a sharelink URL or its ID.
+    pub fn new(link: &str) -> Result<ShareLink> {
+        let cl = reqwest::Client::new();
+        Ok(ShareLink {
+            transport: Arc::new(cl.clone()),
+            cl,
+            base_url: DEFAULT_API_BASE_URL.into(),
+            id: parse_id(link)?,
+            password: None,
+            token: None,
+            share: None,
+        })
+    }
+
+    /// Use `password` for a password-protected sharelink.
+    pub fn with_password(mut self, password: impl Into<String>) -> Self {
+        self.password = Some(password.into());
+        self.token = None;
+        self
+    }
+
+    /// Send all requests through `transport`.
+    pub fn with_transport(mut self, transport: Arc<dyn Transport>) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// Use `base_url` instead of the HiDrive API (see `Endpoints::api`).
+    pub fn with_base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = base_url.into();
+        self
+    }
+
+    /// The sharelink ID.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// An access token for the sharelink, obtained again shortly before it expires.
+    async fn token(&mut self) -> Result<String> {
+        if let Some((ref t, valid_until)) = self.token {
+            if Instant::now() < valid_until {
+                return Ok(t.clone());
+            }
+        }
+        info!(target: "hd_api::sharelink", "Obtaining access token for sharelink {}", self.id);
+        let mut rqp = Params::new();
+        rqp.add_str("id", &self.id);
+        if let Some(ref pw) = self.password {
+            rqp.add_str("password", pw);
+        }
+        // As form body: the password must not end up in access logs.
+        let rq = self
+            .cl
+            .post(format!("{}/share/token", self.base_url))
+            .form(&rqp)
+            .build()?;
+        let resp = self.transport.execute(rq).await?;
+        let tok: ShareToken = http::read_body_to_json(resp, Some(DEFAULT_MAX_BODY_SIZE))
+            .await
+            .context("POST /share/token")?;
+        if tok.access_token.is_empty() {
+            return Err(anyhow!("POST /share/token: no access token in response"));
+        }
+        let lifetime = Duration::from_secs(tok.expires_in.saturating_sub(30));
+        self.token = Some((tok.access_token.clone(), Instant::now() + lifetime));
+        Ok(tok.access_token)
+    }
+
+    /// Send a request with the sharelink's access token. If the token is rejected, a new one is
+    /// obtained and the request sent once more.
+    async fn send(
+        &mut self,
+        method: Method,
+        path: &str,
+        rqp: &Params,
+        p: Option<&Params>,
+    ) -> Result<reqwest::Response> {
+        let u = format!("{}{}", self.base_url, path);
+        let mut retried = false;
+        loop {
+            let token = self.token().await?;
+            let rqb = self
+                .cl
+                .request(method.clone(), &u)
+                .bearer_auth(token)
+                .query(rqp);
+            let rqb = if let Some(params) = p {
+                rqb.query(params)
+            } else {
+                rqb
+            };
+            info!(target: "hd_api::sharelink", "sending http request: {} {}", method, u);
+            let resp = self.transport.execute(rqb.build()?).await?;
+            if resp.status() != StatusCode::UNAUTHORIZED || retried {
+                return Ok(resp);
+            }
+            self.token = None;
+            retried = true;
+        }
+    }
+
+    /// Metadata of the sharelink. It is requested once and cached.
+    pub async fn info(&mut self) -> Result<Share> {
+        if let Some(ref share) = self.share {
+            return Ok(share.clone());
+        }
+        let mut rqp = Params::new();
+        rqp.add_str("id", &self.id);
+        let resp = self.send(Method::GET, "/sharelink", &rqp, None).await?;
+        let share: Share = http::read_body_to_json(resp, Some(DEFAULT_MAX_BODY_SIZE))
+            .await
+            .context("GET /sharelink")?;
+        self.share = Some(share.clone());
+        Ok(share)
+    }
+
+    /// The identifier of `path` relative to the shared item; an empty path is the item itself.
+    async fn identifier(&mut self, path: &str) -> Result<Identifier> {
+        let pid = self
+            .info()
+            .await?
+            .pid
+            .ok_or_else(|| anyhow!("sharelink {} has no item ID", self.id))?;
+        let path = path.trim_matches('/');
+        Ok(if path.is_empty() {
+            Identifier::Id(pid)
+        } else {
+            Identifier::Id(pid).join(path)
+        })
+    }
+
+    /// List the directory `path` within a shared directory.
+    ///
+    /// Further parameters: `members, limit, sort, fields`.
+    pub async fn list(&mut self, path: &str, p: Option<&Params>) -> Result<Item> {
+        let mut rqp = Params::new();
+        self.identifier(path)
+            .await?
+            .to_params(&mut rqp, "pid", "path");
+        let resp = self.send(Method::GET, "/dir", &rqp, p).await?;
+        http::read_body_to_json(resp, Some(DEFAULT_MAX_BODY_SIZE))
+            .await
+            .context("GET /dir")
+    }
+
+    /// Download the file `path` within a shared directory (or the shared file, if `path` is
+    /// empty) to `out`. Returns the number of bytes written.
+    pub async fn download<D: AsyncWrite + Unpin>(&mut self, path: &str, out: D) -> Result<usize> {
+        let mut rqp = Params::new();
+        self.identifier(path)
+            .await?
+            .to_params(&mut rqp, "pid", "path");
+        let resp = self.send(Method::GET, "/file", &rqp, None).await?;
+        http::write_response_to_file(resp, out, None, |_| {})
+            .await
+            .context("GET /file")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::mock::MockTransport;
+    use crate::types::ApiError;
+
+    const TOKEN: &str = r#"{"access_token": "lnk-token", "expires_in": 3600}"#;
+
+    fn link(t: Arc<MockTransport>) -> ShareLink {
+        ShareLink::new("https://my.hidrive.com/lnk/Ab3dEf")
+            .unwrap()
+            .with_transport(t)
+    }
+
+    #[test]
+    fn test_parse_id() {
+        assert_eq!(
+            "Ab3dEf",
+            parse_id("https://my.hidrive.com/lnk/Ab3dEf").unwrap()
+        );
+        assert_eq!(
+            "Ab3dEf",
+            parse_id("https://my.hidrive.com/share/Ab3dEf/").unwrap()
+        );
+        assert_eq!("Ab3dEf", parse_id(" Ab3dEf ").unwrap());
+        assert!(parse_id("https://example.com/lnk/Ab3dEf").is_err());
+        assert!(parse_id("https://my.hidrive.com/lnk/").is_err());
+        assert!(parse_id("").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_list_and_download() {
+        let t = MockTransport::new();
+        t.push(200, TOKEN);
+        t.push(
+            200,
+            r#"{"id": "Ab3dEf", "pid": "b123.4", "file_type": "dir"}"#,
+        );
+        t.push(200, r#"{"name": "photos", "members": [{"name": "a.jpg"}]}"#);
+        t.push(200, "jpeg");
+        let mut l = link(t.clone());
+
+        let dir = l.list("photos", None).await.unwrap();
+        assert_eq!(Some("a.jpg"), dir.members[0].name.as_deref());
+        let rqs = t.requests();
+        assert_eq!("/2.1/share/token", rqs[0].url.path());
+        assert_eq!(None, rqs[0].param("id"));
+        assert_eq!(Some(&b"id=Ab3dEf"[..]), rqs[0].body.as_deref());
+        assert_eq!("/2.1/sharelink", rqs[1].url.path());
+        assert_eq!("Bearer lnk-token", rqs[1].headers["authorization"]);
+        assert_eq!(Some("b123.4"), rqs[2].param("pid").as_deref());
+        assert_eq!(Some("photos"), rqs[2].param("path").as_deref());
+
+        let mut out = vec![];
+        assert_eq!(4, l.download("photos/a.jpg", &mut out).await.unwrap());
+        assert_eq!(b"jpeg", &out[..]);
+        // The token and the share's metadata are reused.
+        assert_eq!(4, t.requests().len());
+        assert_eq!(Some("photos/a.jpg"), t.last().param("path").as_deref());
+    }
+
+    #[tokio::test]
+    async fn test_password() {
+        let t = MockTransport::new();
+        t.push(403, r#"{"code": 403, "msg": "Wrong password"}"#);
+        let mut l = link(t.clone()).with_password("wrong");
+        let e = l.info().await.unwrap_err();
+        assert_eq!(403, e.downcast_ref::<ApiError>().unwrap().code);
+
+        t.push(200, TOKEN);
+        t.push(
+            200,
+            r#"{"id": "Ab3dEf", "pid": "b123.4", "has_password": true}"#,
+        );
+        let mut l = l.with_password("secret");
+        assert_eq!(Some(true), l.info().await.unwrap().has_password);
+        let rqs = t.requests();
+        assert_eq!(None, rqs[1].param("password"));
+        assert_eq!(
+            Some(&b"id=Ab3dEf&password=secret"[..]),
+            rqs[1].body.as_deref()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_token_missing() {
+        let t = MockTransport::new();
+        t.push(200, r#"{"expires_in": 3600}"#);
+        t.push(200, "");
+        let mut l = link(t.clone());
+        assert!(l.info().await.is_err());
+        assert!(l.info().await.is_err());
+        assert_eq!(2, t.requests().len());
+    }
+
+    #[tokio::test]
+    async fn test_token_retry() {
+        let t = MockTransport::new();
+        t.push(200, TOKEN);
+        t.push(401, r#"{"code": 401, "msg": "Unauthorized"}"#);
+        t.push(200, r#"{"access_token": "lnk-token2", "expires_in": 3600}"#);
+        t.push(200, r#"{"id": "Ab3dEf", "pid": "b123.4"}"#);
+        let mut l = link(t.clone());
+        assert_eq!(Some("b123.4".into()), l.info().await.unwrap().pid);
+        assert_eq!("Bearer lnk-token2", t.last().headers["authorization"]);
+    }
+}
@@ -0,0 +1,488 @@
+//! An `object_store::ObjectStore` backed by HiDrive, see `HiDriveStore`.
+//!
+//! Object paths map to file paths below a root directory; HiDrive directories appear as common
+//! prefixes when listing with a delimiter. Object contents are buffered in memory, both when
+//! uploading and downloading.
+
+use crate::hidrive::HiDrive;
+use crate::ignore::IgnoreRules;
+use crate::sync::remote_tree;
+use crate::types::{error_status, Identifier, Item, OnExist, Params};
+
+use std::fmt;
+use std::ops::Range;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures_util::stream::{self, BoxStream, StreamExt};
+use object_store::path::Path;
+use object_store::{
+    GetOptions, GetRange, GetResult, GetResultPayload, ListResult, MultipartUpload, ObjectMeta,
+    ObjectStore, PutMode, PutMultipartOpts, PutOptions, PutPayload, PutResult, UploadPart,
+};
+
+const STORE: &str = "HiDrive";
+const META_FIELDS: &str = "path,type,size,mtime,chash";
+const LIST_FIELDS: &str =
+    "path,type,members.path,members.name,members.type,members.size,members.mtime,members.chash";
+
+/// Stores objects as files below the directory `root`, e.g. the object `a/b.txt` as
+/// `{root}/a/b.txt`. Parent directories are created on upload.
+///
+/// `e_tag`s are content hashes (`chash`) and can be used as `if_match` preconditions;
+/// `PutMode::Update` is not supported.
+///
+/// ```ignore
+/// let store = HiDriveStore::new(hd, "/users/me/objects");
+/// store.put(&Path::from("a/b.txt"), PutPayload::from("hello")).await?;
+/// ```
+pub struct HiDriveStore {
+    hd: HiDrive,
+    root: String,
+}
+
+impl HiDriveStore {
+    pub fn new(hd: HiDrive, root: impl Into<String>) -> HiDriveStore {
+        let root = root.into();
+        HiDriveStore {
+            hd,
+            root: root.trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// Each call uses its own fork, as `ObjectStore` methods take `&self`.
+    fn hd(&self) -> HiDrive {
+        self.hd.fork()
+    }
+
+    fn remote(&self, location: &Path) -> String {
+        if location.as_ref().is_empty() {
+            self.root.clone()
+        } else {
+            format!("{}/{}", self.root, location)
+        }
+    }
+
+    fn location(&self, remote: &str) -> Path {
+        Path::from(remote.strip_prefix(&self.root).unwrap_or(remote))
+    }
+
+    fn meta(&self, it: &Item) -> ObjectMeta {
+        ObjectMeta {
+            location: self.location(&it.path),
+            last_modified: it
+                .mtime
+                .and_then(|t| DateTime::<Utc>::from_timestamp(t.unix_timestamp(), t.nanosecond()))
+                .unwrap_or_default(),
+            size: it.size.unwrap_or(0),
+            e_tag: it.chash.as_ref().map(|h| h.to_string()),
+            version: None,
+        }
+    }
+
+    async fn upload(&self, location: &Path, data: Bytes, mode: PutMode) -> Result<PutResult> {
+        let remote = self.remote(location);
+        let (dir, name) = remote.rsplit_once('/').unwrap_or(("", &remote));
+        let mut hd = self.hd();
+        let mut created = false;
+        loop {
+            let mut files = hd.files();
+            let r = match mode {
+                PutMode::Overwrite => {
+                    files
+                        .upload(Identifier::Path(dir.into()), name, data.clone(), None)
+                        .await
+                }
+                PutMode::Create => {
+                    files
+                        .upload_no_overwrite(Identifier::Path(dir.into()), name, data.clone(), None)
+                        .await
+                }
+                PutMode::Update(_) => return Err(Error::NotImplemented),
+            };
+            match r {
+                Ok(it) => {
+                    return Ok(PutResult {
+                        e_tag: it.chash.map(|h| h.to_string()),
+                        version: None,
+                    })
+                }
+                Err(e) if !created && error_status(&e) == Some(404) => {
+                    mkdirs(&mut hd, &self.root, dir).await;
+                    created = true;
+                }
+                Err(e) => return Err(error(e, &remote)),
+            }
+        }
+    }
+
+    /// Copy or move (if `mv`) the object `from` to `to`.
+    async fn transfer(
+        &self,
+        from: &Path,
+        to: &Path,
+        on_exist: Option<OnExist>,
+        mv: bool,
+    ) -> Result<()> {
+        let (src, dst) = (self.remote(from), self.remote(to));
+        let mut p = Params::new();
+        if let Some(on_exist) = on_exist {
+            p.add_str("on_exist", on_exist.to_string());
+        }
+        let mut hd = self.hd();
+        let mut created = false;
+        loop {
+            let (from, to) = (Identifier::Path(src.clone()), Identifier::Path(dst.clone()));
+            let r = if mv {
+                hd.files().mv(from, to, Some(&p)).await
+            } else {
+                hd.files().copy(from, to, Some(&p)).await
+            };
+            match r {
+                Ok(_) => return Ok(()),
+                // Either the source or the destination's parent is missing.
+                Err(e) if !created && error_status(&e) == Some(404) => {
+                    let dir = dst.rsplit_once('/').map(|(d, _)| d).unwrap_or("");
+                    mkdirs(&mut hd, &self.root, dir).await;
+                    created = true;
+                }
+                Err(e) => return Err(error(e, &src)),
+            }
+        }
+    }
+
+    async fn list_all(&self, prefix: Option<&Path>) -> Result<Vec<ObjectMeta>> {
+        let remote = self.remote(prefix.unwrap_or(&Path::default()));
+        let tree = match remote_tree(
+            &mut self.hd(),
+            Identifier::Path(remote.clone()),
+            &IgnoreRules::new(),
+        )
+        .await
+        {
+            Ok(tree) => tree,
+            Err(e) if error_status(&e) == Some(404) => return Ok(vec![]),
+            Err(e) => return Err(error(e, &remote)),
+        };
+        let mut objects = vec![];
+        let mut dirs = vec![tree];
+        while let Some(dir) = dirs.pop() {
+            for it in dir.members {
+                if is_dir(&it) {
+                    dirs.push(it);
+                } else {
+                    objects.push(self.meta(&it));
+                }
+            }
+        }
+        Ok(objects)
+    }
+}
+
+type Result<T> = object_store::Result<T>;
+type Error = object_store::Error;
+
+impl fmt::Display for HiDriveStore {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "HiDriveStore({})", self.root)
+    }
+}
+
+impl fmt::Debug for HiDriveStore {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("HiDriveStore")
+            .field("root", &self.root)
+            .finish_non_exhaustive()
+    }
+}
+
+fn is_dir(it: &Item) -> bool {
+    it.typ.as_deref() == Some("dir")
+}
+
+fn error(e: anyhow::Error, path: &str) -> Error {
+    let path = path.to_string();
+    match error_status(&e) {
+        Some(404) => Error::NotFound {
+            path,
+            source: e.into(),
+        },
+        Some(409) => Error::AlreadyExists {
+            path,
+            source: e.into(),
+        },
+        _ => Error::Generic {
+            store: STORE,
+            source: e.into(),
+        },
+    }
+}
+
+/// Create the directory `dir` and its parents below `root`. Errors are ignored; existing
+/// directories fail with 409, and other failures show up when retrying the original call.
+async fn mkdirs(hd: &mut HiDrive, root: &str, dir: &str) {
+    let rel = dir.strip_prefix(root).unwrap_or(dir);
+    let mut path = root.to_string();
+    for component in rel.split('/').filter(|c| !c.is_empty()) {
+        path.push('/');
+        path.push_str(component);
+        let _ = hd.files().mkdir(Identifier::Path(path.clone()), None).await;
+    }
+}
+
+/// The byte range `range` selects in an object of `size` bytes.
+fn resolve_range(range: &GetRange, size: usize) -> Result<Range<usize>> {
+    let r = match range {
+        GetRange::Bounded(r) => r.start..r.end.min(size),
+        GetRange::Offset(o) => *o..size,
+        GetRange::Suffix(n) => size.saturating_sub(*n)..size,
+    };
+    if r.start > r.end || (r.start >= size && size > 0) {
+        return Err(Error::Generic {
+            store: STORE,
+            source: format!(
+                "range {:?} is invalid for an object of {} bytes",
+                range, size
+            )
+            .into(),
+        });
+    }
+    Ok(r)
+}
+
+#[async_trait]
+impl ObjectStore for HiDriveStore {
+    async fn put_opts(
+        &self,
+        location: &Path,
+        payload: PutPayload,
+        opts: PutOptions,
+    ) -> Result<PutResult> {
+        self.upload(location, payload.into(), opts.mode).await
+    }
+
+    async fn put_multipart_opts(
+        &self,
+        location: &Path,
+        _opts: PutMultipartOpts,
+    ) -> Result<Box<dyn MultipartUpload>> {
+        Ok(Box::new(HiDriveUpload {
+            store: HiDriveStore::new(self.hd(), self.root.clone()),
+            location: location.clone(),
+            parts: vec![],
+        }))
+    }
+
+    async fn get_opts(&self, location: &Path, options: GetOptions) -> Result<GetResult> {
+        let remote = self.remote(location);
+        let mut hd = self.hd();
+        let it = hd
+            .files()
+            .metadata(Identifier::Path(remote.clone()), META_FIELDS, None)
+            .await
+            .map_err(|e| error(e, &remote))?;
+        if is_dir(&it) {
+            return Err(Error::NotFound {
+                path: remote,
+                source: "is a directory".into(),
+            });
+        }
+        let meta = self.meta(&it);
+        options.check_preconditions(&meta)?;
+        let range = match &options.range {
+            Some(r) => resolve_range(r, meta.size)?,
+            None => 0..meta.size,
+        };
+        let data = if options.head || range.is_empty() {
+            vec![]
+        } else if range == (0..meta.size) {
+            let mut buf = vec![];
+            hd.files()
+                .get(Identifier::Path(remote.clone()), &mut buf, None)
+                .await
+                .map_err(|e| error(e, &remote))?;
+            buf
+        } else {
+            hd.files()
+                .read_range(
+                    Identifier::Path(remote.clone()),
+                    range.start as u64..range.end as u64,
+                )
+                .await
+                .map_err(|e| error(e, &remote))?
+        };
+        let data = Bytes::from(data);
+        Ok(GetResult {
+            payload: GetResultPayload::Stream(stream::once(async move { Ok(data) }).boxed()),
+            meta,
+            range,
+            attributes: Default::default(),
+        })
+    }
+
+    async fn delete(&self, location: &Path) -> Result<()> {
+        let remote = self.remote(location);
+        self.hd()
+            .files()
+            .delete(Identifier::Path(remote.clone()), None)
+            .await
+            .map_err(|e| error(e, &remote))
+    }
+
+    fn list(&self, prefix: Option<&Path>) -> BoxStream<'_, Result<ObjectMeta>> {
+        let prefix = prefix.cloned();
+        stream::once(async move { self.list_all(prefix.as_ref()).await })
+            .flat_map(|r| {
+                stream::iter(match r {
+                    Ok(objects) => objects.into_iter().map(Ok).collect(),
+                    Err(e) => vec![Err(e)],
+                })
+            })
+            .boxed()
+    }
+
+    async fn list_with_delimiter(&self, prefix: Option<&Path>) -> Result<ListResult> {
+        let remote = self.remote(prefix.unwrap_or(&Path::default()));
+        let mut p = Params::new();
+        p.add_str("members", "all").add_str("fields", LIST_FIELDS);
+        let dir = match self
+            .hd()
+            .files()
+            .get_dir(Identifier::Path(remote.clone()), Some(&p))
+            .await
+        {
+            Ok(dir) => dir,
+            Err(e) if error_status(&e) == Some(404) => Item::default(),
+            Err(e) => return Err(error(e, &remote)),
+        };
+        let mut result = ListResult {
+            common_prefixes: vec![],
+            objects: vec![],
+        };
+        for it in dir.members {
+            if is_dir(&it) {
+                result.common_prefixes.push(self.location(&it.path));
+            } else {
+                result.objects.push(self.meta(&it));
+            }
+        }
+        Ok(result)
+    }
+
+    async fn copy(&self, from: &Path, to: &Path) -> Result<()> {
+        self.transfer(from, to, Some(OnExist::Overwrite), false)
+            .await
+    }
+
+    async fn copy_if_not_exists(&self, from: &Path, to: &Path) -> Result<()> {
+        self.transfer(from, to, None, false).await
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        self.transfer(from, to, Some(OnExist::Overwrite), true)
+            .await
+    }
+}
+
+/// A multipart upload to a `HiDriveStore`. Parts are kept in memory and uploaded as one file on
+/// `complete()`.
+#[derive(Debug)]
+struct HiDriveUpload {
+    store: HiDriveStore,
+    location: Path,
+    parts: Vec<PutPayload>,
+}
+
+#[async_trait]
+impl MultipartUpload for HiDriveUpload {
+    fn put_part(&mut self, data: PutPayload) -> UploadPart {
+        self.parts.push(data);
+        Box::pin(async { Ok(()) })
+    }
+
+    async fn complete(&mut self) -> Result<PutResult> {
+        let mut data = Vec::new();
+        for part in self.parts.drain(..) {
+            for chunk in part.iter() {
+                data.extend_from_slice(chunk);
+            }
+        }
+        self.store
+            .upload(&self.location, data.into(), PutMode::Overwrite)
+            .await
+    }
+
+    async fn abort(&mut self) -> Result<()> {
+        self.parts.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::mock::{hidrive, MockTransport};
+
+    use std::sync::Arc;
+
+    fn store(t: &Arc<MockTransport>) -> HiDriveStore {
+        HiDriveStore::new(hidrive(t.clone()), "/root/")
+    }
+
+    #[test]
+    fn test_resolve_range() {
+        assert_eq!(2..5, resolve_range(&GetRange::Bounded(2..8), 5).unwrap());
+        assert_eq!(3..5, resolve_range(&GetRange::Offset(3), 5).unwrap());
+        assert_eq!(1..5, resolve_range(&GetRange::Suffix(4), 5).unwrap());
+        assert_eq!(0..5, resolve_range(&GetRange::Suffix(9), 5).unwrap());
+        assert!(resolve_range(&GetRange::Offset(5), 5).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_store() {
+        let t = MockTransport::new();
+        let store = store(&t);
+        let loc = Path::from("a/b.txt");
+
+        // The parent directory is missing at first.
+        t.push(404, r#"{"code": 404, "msg": "Not Found"}"#);
+        t.push(201, r#"{"path": "/root/a", "type": "dir"}"#);
+        t.push(
+            201,
+            r#"{"path": "/root/a/b.txt", "type": "file", "size": 5}"#,
+        );
+        store.put(&loc, PutPayload::from("hello")).await.unwrap();
+        let rq = t.requests();
+        assert_eq!(Some("/root/a"), rq[0].param("dir").as_deref());
+        assert_eq!(Some("b.txt"), rq[0].param("name").as_deref());
+        assert_eq!(Some("/root/a"), rq[1].param("path").as_deref());
+        assert_eq!("POST", rq[1].method);
+
+        t.push(
+            200,
+            r#"{"path": "/root/a/b.txt", "type": "file", "size": 5, "mtime": 1700000000}"#,
+        );
+        t.push(200, "hello");
+        let r = store.get(&loc).await.unwrap();
+        assert_eq!(loc, r.meta.location);
+        assert_eq!(1700000000, r.meta.last_modified.timestamp());
+        assert_eq!(Bytes::from("hello"), r.bytes().await.unwrap());
+
+        t.push(404, r#"{"code": 404, "msg": "Not Found"}"#);
+        let err = store.head(&Path::from("c")).await.unwrap_err();
+        assert!(matches!(err, Error::NotFound { .. }), "{:?}", err);
+
+        t.push(
+            200,
+            r#"{"path": "/root", "type": "dir", "members": [
+                {"path": "/root/a", "name": "a", "type": "dir"},
+                {"path": "/root/c.txt", "name": "c.txt", "type": "file", "size": 3}]}"#,
+        );
+        let l = store.list_with_delimiter(None).await.unwrap();
+        assert_eq!(vec![Path::from("a")], l.common_prefixes);
+        assert_eq!(Path::from("c.txt"), l.objects[0].location);
+        assert_eq!(3, l.objects[0].size);
+        assert_eq!(Some("/root"), t.last().param("path").as_deref());
+    }
+}
@@ -0,0 +1,2460 @@
+//! Synchronization of local directories with remote ones.
+//!
+//! `mirror_up()` makes a remote directory an exact copy of a local one, `mirror_down()` a local
+//! directory a copy of a remote one. Files are compared by `mhash` first and by `chash` where
+//! that differs (see `hashing`), so that only changed files are transferred.
+//!
+//! `bisync()` synchronizes in both directions, using the state after the previous run to tell
+//! which side changed (see `planner`). Files changed on both sides are left alone and reported
+//! as conflicts.
+//!
+//! With the `notify` feature, `watch_up()` keeps uploading local changes as they happen.
+//! `watch_down()` polls the remote directory and downloads changes. For continuous two-way
+//! synchronization, run `bisync()` once, then both of them (each with its own `HiDrive`) with
+//! `initial_sync` unset.
+//!
+//! `SyncRunner` repeats sync runs on schedules, e.g. in a daemon.
+
+use crate::hidrive::HiDrive;
+use crate::ignore::{IgnoreRules, IGNORE_FILE};
+use crate::planner::{self, Action, FileState, Side, Snapshot, SymlinkPolicy};
+use crate::platform::{check_local_path, local_path};
+use crate::throttle::{BandwidthSchedule, Throttle};
+use crate::types::{BudgetExhausted, Identifier, Item, OnExist, Params};
+
+use std::collections::HashSet;
+use std::fmt::{self, Debug, Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{self, Context, Result};
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use journal::{HeaderRef, Journal};
+
+mod check;
+#[cfg(test)]
+mod e2e;
+mod journal;
+mod metrics;
+mod names;
+mod poll;
+mod quota;
+mod runner;
+#[cfg(feature = "notify")]
+mod watch;
+
+pub use check::{check, CheckMode, CheckReport};
+pub use metrics::MetricsObserver;
+pub use names::NameMatching;
+pub use poll::{watch_down, PollOptions, RemotePoller};
+pub use quota::QuotaPolicy;
+pub use runner::{Backoff, JobKind, JobSchedule, SyncJob, SyncRunner};
+#[cfg(feature = "notify")]
+pub use watch::{watch_up, WatchOptions};
+
+/// Fields requested for each directory listed by `remote_tree()`.
+const TREE_FIELDS: &str = concat!(
+    "path,name,type,size,mtime,ctime,chash,mhash,nhash,mohash,",
+    "members.path,members.name,members.type,members.size,members.mtime,members.ctime,",
+    "members.chash,members.mhash,members.nhash,members.mohash"
+);
+
+/// Options of `mirror_up()`, `mirror_down()` and `bisync()`.
+#[derive(Clone)]
+pub struct MirrorOptions {
+    /// Remove files and directories not present in the source; for `bisync()`, propagate
+    /// deletions. Default: true.
+    pub delete: bool,
+    /// With `delete`, only propagate deletions to this side in `bisync()`. For example,
+    /// `Side::Local` removes local files deleted remotely, but keeps remote files deleted locally.
+    /// Files renamed on the other side are copied instead. Default: both sides.
+    pub delete_side: Option<Side>,
+    /// Instead of deleting, move files and directories into this remote directory (an absolute
+    /// path outside of the mirrored directory), keeping their relative paths. Used by
+    /// `mirror_up()` and `bisync()`.
+    pub trash: Option<String>,
+    /// Like `trash`, but a local directory outside of the mirrored directory. Used by
+    /// `mirror_down()` and `bisync()`.
+    pub local_trash: Option<PathBuf>,
+    /// Only report what would be done.
+    pub dry_run: bool,
+    /// Files and directories not to synchronize, neither locally nor remotely. The rules in
+    /// `.hdignore` in the local directory are added to these.
+    pub ignore: IgnoreRules,
+    /// Receives progress events.
+    pub progress: Option<Arc<dyn SyncObserver>>,
+    /// Limit the transfer rate by time of day. The rate is updated before each transfer; during
+    /// the run, the schedule's throttle replaces one set with `HiDrive::set_throttle()`.
+    pub schedule: Option<BandwidthSchedule>,
+    /// Maximum number of bytes to transfer in one run. Transfers exceeding it fail with
+    /// `BudgetExhausted` and are retried by the next run.
+    pub budget: Option<u64>,
+    /// Record the planned operations and their progress in this local file. If it exists at the
+    /// start, the interrupted run is resumed from it instead of planning again. It is removed
+    /// once all operations have been carried out. Not used for dry runs.
+    pub journal: Option<PathBuf>,
+    /// Number of uploads and downloads carried out at once. Each runs on its own fork of the
+    /// `HiDrive` (see `HiDrive::fork()`), so a limit set with `HiDrive::set_concurrency_limit()`
+    /// applies to all of them. Default: 1.
+    pub transfers: usize,
+    /// Number of directory creations and deletions carried out at once. Default: 1.
+    pub metadata_ops: usize,
+    /// Before an upload overwrites a remote file that is newer than the local one, copy the
+    /// remote file to a new name next to it (e.g. `a (1).txt`), so that its content isn't lost.
+    pub conflict_copies: bool,
+    /// How local symbolic links are treated. Default: `SymlinkPolicy::Skip`. With
+    /// `SymlinkPolicy::Placeholder`, downloads replace placeholders by regular files.
+    pub symlinks: SymlinkPolicy,
+    /// Match local names with remote ones differing in case or Unicode normalization. Used by
+    /// `mirror_up()`, `mirror_down()` and `bisync()`. Default: exact matching.
+    pub names: NameMatching,
+    /// Check the account's free space before uploading. Uploads left out are reported as
+    /// failed. Used by `mirror_up()` and `bisync()`. Default: `QuotaPolicy::Ignore`.
+    pub quota: QuotaPolicy,
+}
+
+impl MirrorOptions {
+    /// Whether `bisync()` propagates deletions to `side`.
+    fn deletes_on(&self, side: Side) -> bool {
+        self.delete && self.delete_side.map_or(true, |s| s == side)
+    }
+}
+
+impl Debug for MirrorOptions {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("MirrorOptions")
+            .field("delete", &self.delete)
+            .field("delete_side", &self.delete_side)
+            .field("trash", &self.trash)
+            .field("local_trash", &self.local_trash)
+            .field("dry_run", &self.dry_run)
+            .field("ignore", &self.ignore)
+            .field("progress", &self.progress.is_some())
+            .field("schedule", &self.schedule)
+            .field("budget", &self.budget)
+            .field("journal", &self.journal)
+            .field("transfers", &self.transfers)
+            .field("metadata_ops", &self.metadata_ops)
+            .field("conflict_copies", &self.conflict_copies)
+            .field("symlinks", &self.symlinks)
+            .field("names", &self.names)
+            .field("quota", &self.quota)
+            .finish()
+    }
+}
+
+impl Default for MirrorOptions {
+    fn default() -> MirrorOptions {
+        MirrorOptions {
+            delete: true,
+            delete_side: None,
+            trash: None,
+            local_trash: None,
+            dry_run: false,
+            ignore: IgnoreRules::new(),
+            progress: None,
+            schedule: None,
+            budget: None,
+            journal: None,
+            transfers: 1,
+            metadata_ops: 1,
+            conflict_copies: false,
+            symlinks: SymlinkPolicy::Skip,
+            names: NameMatching::default(),
+            quota: QuotaPolicy::Ignore,
+        }
+    }
+}
+
+/// An operation of a `SyncPlan`. Paths are relative to the synchronized directories.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Operation {
+    Upload {
+        path: String,
+        bytes: u64,
+    },
+    /// Download a file; changed files are updated with `download_delta()`, so `bytes` is an upper
+    /// bound.
+    Download {
+        path: String,
+        bytes: u64,
+    },
+    CreateDir {
+        path: String,
+        side: Side,
+    },
+    /// Delete (or move to the trash) a file or directory.
+    Delete {
+        path: String,
+        side: Side,
+        dir: bool,
+    },
+    Rename {
+        from: String,
+        to: String,
+        side: Side,
+    },
+    /// Adopt the modification time of the other side for a file whose content is up to date.
+    SetMtime {
+        path: String,
+        mtime: i64,
+        #[serde(default = "local_side")]
+        side: Side,
+    },
+}
+
+fn local_side() -> Side {
+    Side::Local
+}
+
+impl Operation {
+    /// The path the operation applies to (the source of a rename).
+    pub fn path(&self) -> &str {
+        match self {
+            Operation::Upload { path, .. }
+            | Operation::Download { path, .. }
+            | Operation::CreateDir { path, .. }
+            | Operation::Delete { path, .. }
+            | Operation::SetMtime { path, .. } => path,
+            Operation::Rename { from, .. } => from,
+        }
+    }
+
+    /// Estimated number of bytes transferred.
+    pub fn bytes(&self) -> u64 {
+        match self {
+            Operation::Upload { bytes, .. } | Operation::Download { bytes, .. } => *bytes,
+            _ => 0,
+        }
+    }
+}
+
+impl Display for Operation {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let side = |s: &Side| match s {
+            Side::Local => "local",
+            Side::Remote => "remote",
+        };
+        match self {
+            Operation::Upload { path, bytes } => write!(f, "upload    {} ({} bytes)", path, bytes),
+            Operation::Download { path, bytes } => {
+                write!(f, "download  {} ({} bytes)", path, bytes)
+            }
+            Operation::CreateDir { path, side: s } => write!(f, "mkdir     {} ({})", path, side(s)),
+            Operation::Delete { path, side: s, .. } => {
+                write!(f, "delete    {} ({})", path, side(s))
+            }
+            Operation::Rename { from, to, side: s } => {
+                write!(f, "rename    {} -> {} ({})", from, to, side(s))
+            }
+            Operation::SetMtime { path, side: s, .. } => {
+                write!(f, "touch     {} ({})", path, side(s))
+            }
+        }
+    }
+}
+
+/// The operations a synchronization carries out, in order. With `MirrorOptions::dry_run`, they
+/// are only planned.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncPlan {
+    pub operations: Vec<Operation>,
+}
+
+impl SyncPlan {
+    /// Estimated number of bytes transferred.
+    pub fn bytes(&self) -> u64 {
+        self.operations.iter().map(|o| o.bytes()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+}
+
+impl Display for SyncPlan {
+    /// One line per operation, followed by the totals.
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for o in self.operations.iter() {
+            writeln!(f, "{}", o)?;
+        }
+        writeln!(
+            f,
+            "{} operations, {} bytes to transfer",
+            self.operations.len(),
+            self.bytes()
+        )
+    }
+}
+
+/// Progress of a synchronization, see `SyncObserver`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncEvent {
+    /// Both trees have been scanned.
+    Scanned {
+        local_files: usize,
+        remote_files: usize,
+    },
+    /// A file is up to date, or was left alone because of a conflict.
+    Skipped { path: String },
+    /// A file was changed on both sides and is left alone; follows its `Skipped` event.
+    Conflict { path: String },
+    /// The operations to carry out have been planned.
+    Planned(SyncPlan),
+    /// An operation is about to be carried out.
+    Started(Operation),
+    /// An operation has been carried out, or failed with `error`.
+    Finished {
+        op: Operation,
+        error: Option<String>,
+    },
+    /// The synchronization is complete.
+    Done(SyncSummary),
+}
+
+/// Receives the progress events of a synchronization, e.g. to show its status. Implement either
+/// `event()` for all events, or the callbacks of interest, which are called by the default
+/// `event()`.
+pub trait SyncObserver: Send + Sync {
+    fn event(&self, ev: &SyncEvent) {
+        match ev {
+            SyncEvent::Planned(plan) => self.on_plan(plan),
+            SyncEvent::Conflict { path } => self.on_conflict(path),
+            SyncEvent::Started(op) => self.on_file_start(op),
+            SyncEvent::Finished { op, error } => {
+                if let Some(e) = error {
+                    self.on_error(op, e);
+                }
+                self.on_file_finish(op, error.as_deref());
+            }
+            SyncEvent::Done(summary) => self.on_complete(summary),
+            SyncEvent::Scanned { .. } | SyncEvent::Skipped { .. } => (),
+        }
+    }
+
+    /// The operations about to be carried out; with `MirrorOptions::dry_run`, only planned.
+    fn on_plan(&self, _plan: &SyncPlan) {}
+    /// An operation is starting.
+    fn on_file_start(&self, _op: &Operation) {}
+    /// An operation succeeded, or failed with `error`.
+    fn on_file_finish(&self, _op: &Operation, _error: Option<&str>) {}
+    /// A file changed on both sides is left alone.
+    fn on_conflict(&self, _path: &str) {}
+    /// An operation failed; called before `on_file_finish()`.
+    fn on_error(&self, _op: &Operation, _error: &str) {}
+    /// The synchronization is complete.
+    fn on_complete(&self, _summary: &SyncSummary) {}
+}
+
+impl<F: Fn(&SyncEvent) + Send + Sync> SyncObserver for F {
+    fn event(&self, ev: &SyncEvent) {
+        self(ev)
+    }
+}
+
+/// Statistics of a synchronization.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncSummary {
+    /// Local and remote files compared.
+    pub files_scanned: usize,
+    pub transferred: usize,
+    /// Files up to date or left alone because of conflicts.
+    pub skipped: usize,
+    pub deleted: usize,
+    pub failed: usize,
+    /// Bytes of the transferred files.
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
+impl Display for SyncSummary {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} files scanned, {} transferred ({} bytes), {} skipped, {} deleted, {} failed in {:.1}s",
+            self.files_scanned,
+            self.transferred,
+            self.bytes,
+            self.skipped,
+            self.deleted,
+            self.failed,
+            self.elapsed.as_secs_f64()
+        )
+    }
+}
+
+/// What a mirror operation did. Paths are relative to the mirrored directories.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MirrorReport {
+    /// All operations, including failed ones.
+    pub plan: SyncPlan,
+    pub transferred: Vec<String>,
+    /// Files and directories deleted or moved to the trash.
+    pub deleted: Vec<String>,
+    pub created_dirs: Vec<String>,
+    /// Number of files that were up to date already.
+    pub unchanged: usize,
+    /// Bytes of the transferred files.
+    pub bytes: u64,
+    /// Paths that couldn't be synchronized, with the error.
+    pub failed: Vec<(String, String)>,
+    pub summary: SyncSummary,
+}
+
+impl MirrorReport {
+    fn record(&mut self, op: &Operation, r: Result<()>) {
+        let path = op.path().to_string();
+        if let Err(e) = r {
+            self.failed.push((path, format!("{:#}", e)));
+            return;
+        }
+        match op {
+            Operation::Upload { bytes, .. } | Operation::Download { bytes, .. } => {
+                self.bytes += bytes;
+                self.transferred.push(path);
+            }
+            Operation::CreateDir { .. } => self.created_dirs.push(path),
+            Operation::Delete { .. } => self.deleted.push(path),
+            Operation::Rename { .. } | Operation::SetMtime { .. } => (),
+        }
+    }
+}
+
+impl Display for MirrorReport {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} transferred ({} bytes), {} deleted, {} directories created, {} unchanged, {} failed",
+            self.transferred.len(),
+            self.bytes,
+            self.deleted.len(),
+            self.created_dirs.len(),
+            self.unchanged,
+            self.failed.len()
+        )
+    }
+}
+
+fn join(base: &str, rel: &str) -> String {
+    if base.is_empty() {
+        rel.to_string()
+    } else {
+        format!("{}/{}", base.trim_end_matches('/'), rel)
+    }
+}
+
+fn is_dir(it: &Item) -> bool {
+    it.typ.as_deref() == Some("dir")
+}
+
+/// The ignore rules for synchronizing `local_dir`: those of `opts`, followed by those of the
+/// local ignore file.
+async fn ignore_rules(local_dir: &Path, opts: &MirrorOptions) -> Result<IgnoreRules> {
+    let mut rules = opts.ignore.clone();
+    rules.extend(&IgnoreRules::load(local_dir.join(IGNORE_FILE)).await?);
+    Ok(rules)
+}
+
+/// Retrieve the remote directory `id` with the members of all subdirectories, as required by
+/// `Snapshot::from_item()`. Members excluded by `ignore` are left out. Issues one request per
+/// directory.
+pub async fn remote_tree(hd: &mut HiDrive, id: Identifier, ignore: &IgnoreRules) -> Result<Item> {
+    remote_tree_(hd, id, ignore, None).await
+}
+
+/// The entries of a tree returned by `remote_tree()` with their paths relative to it, parents
+/// before their members, sorted by name. Entries without a name are left out.
+pub fn tree_entries(tree: &Item) -> Vec<(String, &Item)> {
+    fn add<'a>(it: &'a Item, prefix: &str, out: &mut Vec<(String, &'a Item)>) {
+        let mut members: Vec<&Item> = it
+            .members
+            .iter()
+            .filter(|m| m.name.as_deref().is_some_and(|n| !n.is_empty()))
+            .collect();
+        members.sort_by(|a, b| a.name.cmp(&b.name));
+        for m in members {
+            let rel = join(prefix, m.name.as_deref().unwrap_or_default());
+            out.push((rel.clone(), m));
+            if is_dir(m) {
+                add(m, &rel, out);
+            }
+        }
+    }
+    let mut out = vec![];
+    add(tree, "", &mut out);
+    out
+}
+
+/// Returns true if `a` and `b` are directories with the same contents, judging by their hashes.
+fn same_hashes(a: &Item, b: &Item) -> bool {
+    a.chash.is_some() && a.chash == b.chash && a.mohash == b.mohash
+}
+
+/// The directory at `rel` below `tree`.
+fn find_dir<'a>(tree: &'a mut Item, rel: &str) -> Option<&'a mut Item> {
+    let mut it = tree;
+    for name in rel.split('/').filter(|n| !n.is_empty()) {
+        it = it
+            .members
+            .iter_mut()
+            .find(|m| is_dir(m) && m.name.as_deref() == Some(name))?;
+    }
+    Some(it)
+}
+
+/// `remote_tree()`, reusing the listings of subdirectories of `prev` (a tree returned earlier)
+/// whose hashes haven't changed.
+async fn remote_tree_(
+    hd: &mut HiDrive,
+    id: Identifier,
+    ignore: &IgnoreRules,
+    mut prev: Option<Item>,
+) -> Result<Item> {
+    let mut p = Params::new();
+    p.add_str("members", "all").add_str("fields", TREE_FIELDS);
+    // Directories in the order they were listed: parents before their subdirectories.
+    let mut listed: Vec<(String, Item)> = vec![];
+    let mut todo = vec![(id, String::new())];
+    while let Some((id, rel)) = todo.pop() {
+        let mut it = hd.files().get_dir(id, Some(&p)).await?;
+        it.members.retain(|m| {
+            let name = m.name.as_deref().unwrap_or_default();
+            !ignore.is_ignored(&join(&rel, name), is_dir(m))
+        });
+        for m in it.members.iter_mut().filter(|m| is_dir(m)) {
+            let sub = join(&rel, m.name.as_deref().unwrap_or_default());
+            match prev.as_mut().and_then(|t| find_dir(t, &sub)) {
+                Some(old) if same_hashes(old, m) => m.members = std::mem::take(&mut old.members),
+                _ => todo.push((Identifier::Path(m.path.clone()), sub)),
+            }
+        }
+        listed.push((rel, it));
+    }
+    // Attach the listings to their parents, from the bottom up.
+    while let Some((rel, it)) = listed.pop() {
+        if listed.is_empty() {
+            return Ok(it);
+        }
+        let (parent, name) = rel.rsplit_once('/').unwrap_or(("", &rel));
+        let (_, p) = listed
+            .iter_mut()
+            .rev()
+            .find(|(r, _)| r == parent)
+            .ok_or_else(|| anyhow::Error::msg("remote_tree: parent not listed"))?;
+        if let Some(m) = p
+            .members
+            .iter_mut()
+            .find(|m| is_dir(m) && m.name.as_deref() == Some(name))
+        {
+            m.members = it.members;
+        }
+    }
+    unreachable!()
+}
+
+/// Removes remote files and directories, either deleting them or moving them to the trash.
+pub(crate) struct RemoteRemover<'a> {
+    root: &'a str,
+    trash: Option<&'a str>,
+    trash_dirs: HashSet<String>,
+}
+
+impl<'a> RemoteRemover<'a> {
+    pub(crate) fn new(root: &'a str, trash: Option<&'a str>) -> RemoteRemover<'a> {
+        RemoteRemover {
+            root,
+            trash,
+            trash_dirs: HashSet::new(),
+        }
+    }
+
+    /// Remove `rel` below the root.
+    pub(crate) async fn remove(&mut self, hd: &mut HiDrive, rel: &str, dir: bool) -> Result<()> {
+        let id = Identifier::Path(join(self.root, rel));
+        let trash = match self.trash {
+            None if dir => {
+                let mut p = Params::new();
+                p.add_bool("recursive", true);
+                hd.files().delete_dir(id, Some(&p)).await?;
+                return Ok(());
+            }
+            None => return hd.files().delete(id, None).await,
+            Some(t) => t,
+        };
+        // Create the parent directories in the trash. Errors are ignored, as the directories
+        // usually exist already; if not, moving fails below.
+        let mut parent = String::new();
+        for c in rel
+            .split('/')
+            .rev()
+            .skip(1)
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+        {
+            parent = join(&parent, c);
+            if self.trash_dirs.insert(parent.clone()) {
+                let _ = hd
+                    .files()
+                    .mkdir(Identifier::Path(join(trash, &parent)), None)
+                    .await;
+            }
+        }
+        let to = Identifier::Path(join(trash, rel));
+        let mut p = Params::new();
+        p.add_on_exist(OnExist::Autoname);
+        if dir {
+            hd.files().mvdir(id, to, Some(&p)).await?;
+        } else {
+            hd.files().mv(id, to, Some(&p)).await?;
+        }
+        Ok(())
+    }
+}
+
+async fn upload_file(
+    hd: &mut HiDrive,
+    root: &str,
+    rel: &str,
+    path: &Path,
+    st: &FileState,
+) -> Result<()> {
+    let (parent, name) = rel.rsplit_once('/').unwrap_or(("", rel));
+    // Setting the mtime makes the remote `mhash` match the local one.
+    let mut p = Params::new();
+    p.add_int("mtime", st.mtime as isize);
+    let body = match st.link {
+        Some(ref target) => target.clone().into(),
+        None => {
+            let f = tokio::fs::File::open(path)
+                .await
+                .with_context(|| format!("opening {:?}", path))?;
+            match hd.throttle() {
+                Some(t) => reqwest::Body::wrap_stream(t.stream(f)),
+                None => f.into(),
+            }
+        }
+    };
+    hd.files()
+        .upload(Identifier::Path(join(root, parent)), name, body, Some(&p))
+        .await?;
+    Ok(())
+}
+
+/// Copy the remote file `rel` to a new name next to it, before it is overwritten.
+async fn conflict_copy(hd: &mut HiDrive, root: &str, rel: &str) -> Result<()> {
+    let path = join(root, rel);
+    let mut p = Params::new();
+    p.add_on_exist(OnExist::Autoname);
+    let copy = hd
+        .files()
+        .copy(
+            Identifier::Path(path.clone()),
+            Identifier::Path(path),
+            Some(&p),
+        )
+        .await
+        .with_context(|| format!("creating conflict copy of {}", rel))?;
+    info!(target: "hd_api::sync", "kept remote version of {} as {}", rel, copy.path);
+    Ok(())
+}
+
+/// Carries out the operations of a `SyncPlan`.
+struct Executor<'a> {
+    root: &'a str,
+    local_dir: &'a Path,
+    local: &'a Snapshot,
+    remote: &'a Snapshot,
+    remover: RemoteRemover<'a>,
+    local_trash: Option<&'a Path>,
+    /// Remote directories known to exist.
+    remote_dirs: HashSet<String>,
+    observer: Option<&'a dyn SyncObserver>,
+    schedule: Option<&'a BandwidthSchedule>,
+    budget: Option<u64>,
+    /// The schedule's throttle and the one it replaced, once installed.
+    throttle: Option<(Arc<Throttle>, Option<Arc<Throttle>>)>,
+    transfers: usize,
+    metadata_ops: usize,
+    conflict_copies: bool,
+    /// Forks of the `HiDrive` for concurrent operations.
+    hubs: Vec<HiDrive>,
+    /// Bytes of transfers in progress.
+    in_flight: u64,
+    summary: SyncSummary,
+    start: Instant,
+}
+
+/// Consecutive operations in the same lane may run concurrently.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Lane {
+    Transfer,
+    Delete,
+    /// Directories are created level by level, so that parents exist first.
+    CreateDir(usize),
+    Serial,
+}
+
+impl Lane {
+    fn of(op: &Operation) -> Lane {
+        match op {
+            Operation::Upload { .. } | Operation::Download { .. } => Lane::Transfer,
+            Operation::Delete { .. } => Lane::Delete,
+            Operation::CreateDir { path, .. } => Lane::CreateDir(path.matches('/').count()),
+            Operation::Rename { .. } | Operation::SetMtime { .. } => Lane::Serial,
+        }
+    }
+}
+
+/// What operations need to know about the synchronized trees. Unlike the `Executor`, it can
+/// be shared by concurrent operations.
+#[derive(Clone, Copy)]
+struct Target<'a> {
+    root: &'a str,
+    local_dir: &'a Path,
+    local: &'a Snapshot,
+    remote: &'a Snapshot,
+    trash: Option<&'a str>,
+    local_trash: Option<&'a Path>,
+    conflict_copies: bool,
+}
+
+impl Target<'_> {
+    /// Carry out an operation of any lane but `Lane::Serial`. Uploads expect the remote parent
+    /// directory to exist.
+    async fn run(self, hd: &mut HiDrive, op: &Operation) -> Result<()> {
+        let root = self.root;
+        match op {
+            Operation::Upload { path, .. } => {
+                let st = &self.local.files[path];
+                let newer = self
+                    .remote
+                    .files
+                    .get(path)
+                    .is_some_and(|r| r.mtime > st.mtime);
+                if self.conflict_copies && newer {
+                    conflict_copy(hd, root, &self.remote.remote_path(path)).await?;
+                }
+                let rel = self.remote.remote_path(path);
+                upload_file(hd, root, &rel, &local_path(self.local_dir, path), st).await
+            }
+            Operation::Download { path, .. } => {
+                check_local_path(path)?;
+                let dst = local_path(self.local_dir, path);
+                create_local_parents(&dst).await?;
+                let mtime = self.remote.files[path].mtime;
+                let existing = match self.local.files.get(path) {
+                    // Replace link placeholders instead of writing to the link target.
+                    Some(l) if l.link.is_some() => {
+                        tokio::fs::remove_file(&dst)
+                            .await
+                            .with_context(|| format!("removing {:?}", dst))?;
+                        false
+                    }
+                    l => l.is_some(),
+                };
+                let rel = self.remote.remote_path(path);
+                download_file(hd, root, &rel, &dst, existing, mtime).await
+            }
+            Operation::CreateDir {
+                path,
+                side: Side::Remote,
+            } => {
+                hd.files()
+                    .mkdir(
+                        Identifier::Path(join(root, &self.remote.remote_path(path))),
+                        None,
+                    )
+                    .await?;
+                Ok(())
+            }
+            Operation::CreateDir {
+                path,
+                side: Side::Local,
+            } => {
+                check_local_path(path)?;
+                let dir = local_path(self.local_dir, path);
+                tokio::fs::create_dir_all(&dir)
+                    .await
+                    .with_context(|| format!("creating {:?}", dir))
+            }
+            Operation::Delete {
+                path,
+                side: Side::Remote,
+                dir,
+            } => {
+                let mut remover = RemoteRemover {
+                    root,
+                    trash: self.trash,
+                    trash_dirs: HashSet::new(),
+                };
+                remover
+                    .remove(hd, &self.remote.remote_path(path), *dir)
+                    .await
+            }
+            Operation::Delete {
+                path,
+                side: Side::Local,
+                dir,
+            } => {
+                let p = local_path(self.local_dir, path);
+                remove_local(&p, self.local_trash, path, *dir).await
+            }
+            Operation::Rename { .. } | Operation::SetMtime { .. } => {
+                unreachable!("serial operation {:?}", op)
+            }
+        }
+    }
+}
+
+impl<'a> Executor<'a> {
+    fn new(
+        root: &'a str,
+        local_dir: &'a Path,
+        local: &'a Snapshot,
+        remote: &'a Snapshot,
+        opts: &'a MirrorOptions,
+    ) -> Executor<'a> {
+        Executor {
+            root,
+            local_dir,
+            local,
+            remote,
+            remover: RemoteRemover {
+                root,
+                trash: opts.trash.as_deref(),
+                trash_dirs: HashSet::new(),
+            },
+            local_trash: opts.local_trash.as_deref(),
+            remote_dirs: remote.dirs.iter().cloned().collect(),
+            observer: opts.progress.as_deref(),
+            schedule: opts.schedule.as_ref(),
+            budget: opts.budget,
+            throttle: None,
+            transfers: opts.transfers.max(1),
+            metadata_ops: opts.metadata_ops.max(1),
+            conflict_copies: opts.conflict_copies,
+            hubs: vec![],
+            in_flight: 0,
+            summary: SyncSummary::default(),
+            start: Instant::now(),
+        }
+    }
+
+    /// The journal header for carrying out `operations`.
+    fn header<'b>(
+        &'b self,
+        kind: &'b str,
+        operations: &'b [Operation],
+        keep_base: &'b [String],
+    ) -> HeaderRef<'b> {
+        HeaderRef {
+            kind,
+            local_dir: self.local_dir,
+            root: self.root,
+            local: self.local,
+            remote: self.remote,
+            operations,
+            keep_base,
+        }
+    }
+
+    fn emit(&self, ev: SyncEvent) {
+        if let Some(o) = self.observer {
+            o.event(&ev);
+        }
+    }
+
+    fn scanned(&mut self) {
+        self.summary.files_scanned = self.local.files.len() + self.remote.files.len();
+        self.emit(SyncEvent::Scanned {
+            local_files: self.local.files.len(),
+            remote_files: self.remote.files.len(),
+        });
+    }
+
+    fn skipped(&mut self, path: &str) {
+        self.summary.skipped += 1;
+        self.emit(SyncEvent::Skipped {
+            path: path.to_string(),
+        });
+    }
+
+    /// Record paths left out of the plan, with the reason, as failed.
+    fn left_out(&mut self, failed: &mut Vec<(String, String)>, paths: Vec<(String, String)>) {
+        self.summary.failed += paths.len();
+        failed.extend(paths);
+    }
+
+    fn conflict(&self, path: &str) {
+        self.emit(SyncEvent::Conflict {
+            path: path.to_string(),
+        });
+    }
+
+    fn target(&self) -> Target<'a> {
+        Target {
+            root: self.root,
+            local_dir: self.local_dir,
+            local: self.local,
+            remote: self.remote,
+            trash: self.remover.trash,
+            local_trash: self.local_trash,
+            conflict_copies: self.conflict_copies,
+        }
+    }
+
+    /// Carry out `ops` (unless `dry_run` is set) and return their outcomes. Operations already
+    /// done according to `journal` are skipped; the others are marked there once done.
+    /// Consecutive operations of the same `Lane` run concurrently if configured.
+    async fn execute_all(
+        &mut self,
+        hd: &mut HiDrive,
+        ops: &[Operation],
+        dry_run: bool,
+        journal: &mut Option<Journal>,
+    ) -> Result<Vec<Result<()>>> {
+        if self.observer.is_some() {
+            self.emit(SyncEvent::Planned(SyncPlan {
+                operations: ops.to_vec(),
+            }));
+        }
+        let mut results: Vec<Option<Result<()>>> = (0..ops.len())
+            .map(|i| journal.as_ref().and_then(|j| j.done(i)))
+            .collect();
+        let mut i = 0;
+        while i < ops.len() {
+            let lane = Lane::of(&ops[i]);
+            let end = (i..ops.len())
+                .find(|k| Lane::of(&ops[*k]) != lane)
+                .unwrap_or(ops.len());
+            let pending: Vec<usize> = (i..end).filter(|k| results[*k].is_none()).collect();
+            let limit = match lane {
+                Lane::Transfer => self.transfers,
+                Lane::Delete | Lane::CreateDir(_) => self.metadata_ops,
+                Lane::Serial => 1,
+            };
+            if limit > 1 && pending.len() > 1 && !dry_run {
+                self.execute_concurrently(hd, ops, pending, limit, journal, &mut results)
+                    .await?;
+            } else {
+                for k in pending {
+                    let r = self.execute(hd, &ops[k], dry_run).await;
+                    if let Some(j) = journal.as_mut() {
+                        j.mark(k, &r).await?;
+                    }
+                    results[k] = Some(r);
+                }
+            }
+            i = end;
+        }
+        Ok(results
+            .into_iter()
+            .map(|r| r.expect("all operations carried out"))
+            .collect())
+    }
+
+    /// Carry out the operations `pending` of one lane, up to `limit` at once, each on a fork of
+    /// `hd`.
+    async fn execute_concurrently(
+        &mut self,
+        hd: &mut HiDrive,
+        ops: &[Operation],
+        pending: Vec<usize>,
+        limit: usize,
+        journal: &mut Option<Journal>,
+        results: &mut [Option<Result<()>>],
+    ) -> Result<()> {
+        self.apply_schedule(hd, &ops[pending[0]]);
+        let mut hubs = std::mem::take(&mut self.hubs);
+        hubs.truncate(limit);
+        while hubs.len() < limit {
+            hubs.push(hd.fork());
+        }
+        for h in hubs.iter_mut() {
+            h.set_throttle(hd.throttle());
+        }
+        // Concurrent uploads into a new directory would all try to create it. Errors show up
+        // when uploading.
+        for k in pending.iter() {
+            if let Operation::Upload { path, .. } = &ops[*k] {
+                let _ =
+                    create_remote_parents(hd, self.root, self.remote, path, &mut self.remote_dirs)
+                        .await;
+            }
+        }
+
+        let target = self.target();
+        let mut queue = pending.into_iter();
+        let mut running = FuturesUnordered::new();
+        loop {
+            while !hubs.is_empty() {
+                let k = match queue.next() {
+                    Some(k) => k,
+                    None => break,
+                };
+                let op = &ops[k];
+                if let Some(r) = self.begin(op) {
+                    self.end(op, &r);
+                    if let Some(j) = journal.as_mut() {
+                        j.mark(k, &r).await?;
+                    }
+                    results[k] = Some(r);
+                    continue;
+                }
+                let mut hub = hubs.pop().expect("a free hub");
+                running.push(async move {
+                    let r = target.run(&mut hub, op).await;
+                    (k, hub, r)
+                });
+            }
+            let (k, hub, r) = match running.next().await {
+                Some(done) => done,
+                None => break,
+            };
+            hubs.push(hub);
+            if let (
+                Ok(()),
+                Operation::CreateDir {
+                    path,
+                    side: Side::Remote,
+                },
+            ) = (&r, &ops[k])
+            {
+                self.remote_dirs.insert(path.clone());
+            }
+            self.end(&ops[k], &r);
+            if let Some(j) = journal.as_mut() {
+                j.mark(k, &r).await?;
+            }
+            results[k] = Some(r);
+        }
+        self.hubs = hubs;
+        Ok(())
+    }
+
+    /// Carry out `op` (unless `dry_run` is set), and track its progress.
+    async fn execute(&mut self, hd: &mut HiDrive, op: &Operation, dry_run: bool) -> Result<()> {
+        let r = match self.begin(op) {
+            Some(r) => r,
+            None if dry_run => Ok(()),
+            None => {
+                self.apply_schedule(hd, op);
+                self.run(hd, op).await
+            }
+        };
+        self.end(op, &r);
+        r
+    }
+
+    /// Announce `op`; returns an error if it must not be carried out.
+    fn begin(&mut self, op: &Operation) -> Option<Result<()>> {
+        self.emit(SyncEvent::Started(op.clone()));
+        let exceeded = match self.budget {
+            Some(budget) if op.bytes() > 0 => {
+                self.summary.bytes + self.in_flight + op.bytes() > budget
+            }
+            _ => false,
+        };
+        self.in_flight += op.bytes();
+        match self.budget {
+            Some(budget) if exceeded => Some(Err(anyhow::Error::new(BudgetExhausted { budget }))),
+            _ => None,
+        }
+    }
+
+    /// Account for the outcome `r` of `op`.
+    fn end(&mut self, op: &Operation, r: &Result<()>) {
+        match (r, op) {
+            (Err(_), _) => self.summary.failed += 1,
+            (Ok(()), Operation::Upload { bytes, .. } | Operation::Download { bytes, .. }) => {
+                self.summary.transferred += 1;
+                self.summary.bytes += bytes;
+            }
+            (Ok(()), Operation::Delete { .. }) => self.summary.deleted += 1,
+            (Ok(()), _) => (),
+        }
+        self.in_flight -= op.bytes();
+        self.emit(SyncEvent::Finished {
+            op: op.clone(),
+            error: r.as_ref().err().map(|e| format!("{:#}", e)),
+        });
+    }
+
+    /// Set the scheduled rate before transferring files.
+    fn apply_schedule(&mut self, hd: &mut HiDrive, op: &Operation) {
+        let schedule = match self.schedule {
+            Some(s) if op.bytes() > 0 => s,
+            _ => return,
+        };
+        let (t, _) = self.throttle.get_or_insert_with(|| {
+            let t = Arc::new(Throttle::new(None));
+            let prev = hd.throttle();
+            hd.set_throttle(Some(t.clone()));
+            (t, prev)
+        });
+        t.set_rate(schedule.current_rate());
+    }
+
+    fn finish(&mut self, hd: &mut HiDrive) -> SyncSummary {
+        if let Some((_, prev)) = self.throttle.take() {
+            hd.set_throttle(prev);
+        }
+        self.summary.elapsed = self.start.elapsed();
+        self.emit(SyncEvent::Done(self.summary.clone()));
+        self.summary.clone()
+    }
+
+    async fn run(&mut self, hd: &mut HiDrive, op: &Operation) -> Result<()> {
+        let root = self.root;
+        match op {
+            Operation::Upload { path, .. } => {
+                create_remote_parents(hd, root, self.remote, path, &mut self.remote_dirs).await?;
+                self.target().run(hd, op).await
+            }
+            Operation::CreateDir {
+                path,
+                side: Side::Remote,
+            } => {
+                self.target().run(hd, op).await?;
+                self.remote_dirs.insert(path.clone());
+                Ok(())
+            }
+            Operation::Delete {
+                path,
+                side: Side::Remote,
+                dir,
+            } => {
+                let rel = self.remote.remote_path(path);
+                self.remover.remove(hd, &rel, *dir).await
+            }
+            Operation::Download { .. } | Operation::CreateDir { .. } | Operation::Delete { .. } => {
+                self.target().run(hd, op).await
+            }
+            Operation::Rename {
+                from,
+                to,
+                side: Side::Remote,
+            } => {
+                create_remote_parents(hd, root, self.remote, to, &mut self.remote_dirs).await?;
+                let (src, dst) = (
+                    Identifier::Path(join(root, &self.remote.remote_path(from))),
+                    Identifier::Path(join(root, &self.remote.remote_path(to))),
+                );
+                if !self.remote_dirs.contains(from) {
+                    hd.files().mv(src, dst, None).await?;
+                    return Ok(());
+                }
+                hd.files().mvdir(src, dst, None).await?;
+                let prefix = format!("{}/", from);
+                let moved: Vec<String> = self
+                    .remote_dirs
+                    .iter()
+                    .filter(|d| *d == from || d.starts_with(&prefix))
+                    .cloned()
+                    .collect();
+                for d in moved {
+                    self.remote_dirs.remove(&d);
+                    self.remote_dirs
+                        .insert(format!("{}{}", to, &d[from.len()..]));
+                }
+                Ok(())
+            }
+            Operation::Rename {
+                from,
+                to,
+                side: Side::Local,
+            } => {
+                check_local_path(to)?;
+                let dst = local_path(self.local_dir, to);
+                create_local_parents(&dst).await?;
+                tokio::fs::rename(local_path(self.local_dir, from), &dst)
+                    .await
+                    .with_context(|| format!("renaming {:?}", from))
+            }
+            Operation::SetMtime {
+                path,
+                mtime,
+                side: Side::Local,
+            } => set_mtime(&local_path(self.local_dir, path), *mtime),
+            Operation::SetMtime {
+                path,
+                mtime,
+                side: Side::Remote,
+            } => {
+                hd.files()
+                    .set_mtime(
+                        Identifier::Path(join(root, &self.remote.remote_path(path))),
+                        *mtime,
+                    )
+                    .await
+            }
+        }
+    }
+}
+
+/// Make the remote directory `remote_id` a copy of the local directory `local_dir`: upload new
+/// and changed files, create missing directories and, if `opts.delete` is set, remove remote
+/// files and directories that don't exist locally. Errors concerning single files are recorded
+/// in the report; the other files are still synchronized.
+pub async fn mirror_up(
+    hd: &mut HiDrive,
+    local_dir: impl AsRef<Path>,
+    remote_id: Identifier,
+    opts: &MirrorOptions,
+) -> Result<MirrorReport> {
+    let local_dir = local_dir.as_ref();
+    let (report, root, _) = mirror_up_(hd, local_dir, remote_id, opts).await?;
+    info!(target: "hd_api::sync", "mirror_up: {:?} -> {}: {}", local_dir, root, report);
+    Ok(report)
+}
+
+/// `mirror_up()`, also returning the remote path and the local snapshot. The snapshot carries the
+/// `remote_names` of the remote tree, so that it describes the remote side once mirrored.
+async fn mirror_up_(
+    hd: &mut HiDrive,
+    local_dir: &Path,
+    remote_id: Identifier,
+    opts: &MirrorOptions,
+) -> Result<(MirrorReport, String, Snapshot)> {
+    if let Some(j) = Journal::resume(opts, "mirror_up", local_dir).await? {
+        return resume(hd, local_dir, j, opts).await;
+    }
+    let ignore = ignore_rules(local_dir, opts).await?;
+    let local = Snapshot::scan_with_links(local_dir, &ignore, opts.symlinks).await?;
+    let tree = remote_tree(hd, remote_id, &ignore).await?;
+    let mut remote = Snapshot::from_item(&tree);
+    let collisions = names::match_names(opts.names, &local, &mut remote);
+    let root = tree.path.as_str();
+    let mut ex = Executor::new(root, local_dir, &local, &remote, opts);
+    ex.scanned();
+    let mut report = MirrorReport::default();
+    ex.left_out(&mut report.failed, collided(collisions));
+    let ops = &mut report.plan.operations;
+
+    if opts.delete {
+        for (path, dir) in removed(&remote, &local) {
+            ops.push(Operation::Delete {
+                path,
+                side: Side::Remote,
+                dir,
+            });
+        }
+    }
+    for d in local.dirs.iter().filter(|d| !remote.dirs.contains(*d)) {
+        ops.push(Operation::CreateDir {
+            path: d.clone(),
+            side: Side::Remote,
+        });
+    }
+    for (rel, l) in local.files.iter() {
+        let unchanged = match remote.files.get(rel) {
+            Some(r) if r.mhash == l.mhash => Ok(true),
+            Some(r) if r.chash.is_some() => l
+                .content_hash(&local_path(local_dir, rel))
+                .await
+                .map(|h| Some(&h) == r.chash.as_ref()),
+            _ => Ok(false),
+        };
+        match unchanged {
+            Ok(true) => {
+                report.unchanged += 1;
+                ex.skipped(rel);
+                // Only the mtime differs; setting it avoids hashing the file next time.
+                if remote.files[rel].mhash != l.mhash {
+                    report.plan.operations.push(Operation::SetMtime {
+                        path: rel.clone(),
+                        mtime: l.mtime,
+                        side: Side::Remote,
+                    });
+                }
+            }
+            Ok(false) => report.plan.operations.push(Operation::Upload {
+                path: rel.clone(),
+                bytes: l.size,
+            }),
+            Err(e) => {
+                ex.summary.failed += 1;
+                report.failed.push((rel.clone(), format!("{:#}", e)));
+            }
+        }
+    }
+
+    let left_out = quota::fit_quota(hd, opts, &remote, &mut report.plan.operations).await?;
+    ex.left_out(&mut report.failed, over_quota(left_out));
+    let header = ex.header("mirror_up", &report.plan.operations, &[]);
+    let journal = Journal::start(opts, header).await?;
+    execute(hd, &mut ex, &mut report, opts, journal).await?;
+    let local = Snapshot {
+        remote_names: remote.remote_names,
+        ..local
+    };
+    Ok((report, tree.path, local))
+}
+
+/// Remote paths left out because of name collisions (see `NameMatching`), with the reason.
+fn collided(collisions: Vec<(String, String)>) -> Vec<(String, String)> {
+    collisions
+        .into_iter()
+        .map(|(p, other)| (p, format!("name collides with {}", other)))
+        .collect()
+}
+
+/// Uploads left out because of `MirrorOptions::quota`, with the reason.
+fn over_quota(paths: Vec<String>) -> Vec<(String, String)> {
+    paths
+        .into_iter()
+        .map(|p| (p, "insufficient quota".to_string()))
+        .collect()
+}
+
+/// Files and directories of `from` missing in `to`, with a flag marking directories. Only the
+/// topmost directory of a missing subtree is returned.
+fn removed(from: &Snapshot, to: &Snapshot) -> Vec<(String, bool)> {
+    let mut removed: Vec<(String, bool)> = vec![];
+    let under_removed = |p: &str, removed: &[(String, bool)]| {
+        removed
+            .iter()
+            .any(|(d, _)| p.starts_with(&format!("{}/", d)))
+    };
+    for d in from.dirs.iter() {
+        if !to.dirs.contains(d) && !under_removed(d, &removed) {
+            removed.push((d.clone(), true));
+        }
+    }
+    for f in from.files.keys() {
+        if !to.files.contains_key(f) && !under_removed(f, &removed) {
+            removed.push((f.clone(), false));
+        }
+    }
+    removed
+}
+
+/// Carry out the planned operations of `report`, unless `opts.dry_run` is set.
+/// Operations already done according to `journal` are only recorded.
+async fn execute(
+    hd: &mut HiDrive,
+    ex: &mut Executor<'_>,
+    report: &mut MirrorReport,
+    opts: &MirrorOptions,
+    mut journal: Option<Journal>,
+) -> Result<()> {
+    let ops = std::mem::take(&mut report.plan.operations);
+    let results = ex.execute_all(hd, &ops, opts.dry_run, &mut journal).await;
+    report.summary = ex.finish(hd);
+    for (op, r) in ops.iter().zip(results?) {
+        report.record(op, r);
+    }
+    report.plan.operations = ops;
+    match journal {
+        Some(j) => j.finish().await,
+        None => Ok(()),
+    }
+}
+
+/// Carry out the remaining operations of an interrupted mirror run recorded in a journal.
+async fn resume(
+    hd: &mut HiDrive,
+    local_dir: &Path,
+    (mut h, j): (journal::Header, Journal),
+    opts: &MirrorOptions,
+) -> Result<(MirrorReport, String, Snapshot)> {
+    let mut report = MirrorReport {
+        plan: SyncPlan {
+            operations: std::mem::take(&mut h.operations),
+        },
+        ..Default::default()
+    };
+    let mut ex = Executor::new(&h.root, local_dir, &h.local, &h.remote, opts);
+    ex.scanned();
+    execute(hd, &mut ex, &mut report, opts, Some(j)).await?;
+    let local = Snapshot {
+        remote_names: h.remote.remote_names,
+        ..h.local
+    };
+    Ok((report, h.root, local))
+}
+
+/// Move `path` to `trash/rel`, appending a number to the name if that exists already.
+async fn move_to_trash(path: &Path, trash: &Path, rel: &str) -> Result<()> {
+    let dst = local_path(trash, rel);
+    if let Some(parent) = dst.parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .with_context(|| format!("creating {:?}", parent))?;
+    }
+    let mut target = dst.clone();
+    let mut i = 0;
+    while tokio::fs::try_exists(&target).await? {
+        i += 1;
+        let mut name = dst.file_name().unwrap_or_default().to_os_string();
+        name.push(format!(".{}", i));
+        target = dst.with_file_name(name);
+    }
+    tokio::fs::rename(path, &target)
+        .await
+        .with_context(|| format!("moving {:?} to {:?}", path, target))
+}
+
+async fn remove_local(path: &Path, trash: Option<&Path>, rel: &str, dir: bool) -> Result<()> {
+    let r = match trash {
+        Some(t) => return move_to_trash(path, t, rel).await,
+        None if dir => tokio::fs::remove_dir_all(path).await,
+        None => tokio::fs::remove_file(path).await,
+    };
+    r.with_context(|| format!("removing {:?}", path))
+}
+
+fn set_mtime(path: &Path, mtime: i64) -> Result<()> {
+    filetime::set_file_mtime(path, filetime::FileTime::from_unix_time(mtime, 0))
+        .with_context(|| format!("setting mtime of {:?}", path))
+}
+
+/// Download `root/rel` to `path`, updating an `existing` file with `download_delta()`.
+async fn download_file(
+    hd: &mut HiDrive,
+    root: &str,
+    rel: &str,
+    path: &Path,
+    existing: bool,
+    mtime: i64,
+) -> Result<()> {
+    let id = Identifier::Path(join(root, rel));
+    if existing {
+        hd.files().download_delta(id, path).await?;
+    } else {
+        hd.files().download_to_path(id, path, None).await?;
+    }
+    set_mtime(path, mtime)
+}
+
+/// Make the local directory `local_dir` a copy of the remote directory `remote_id`: download new
+/// and changed files (changed ones using `download_delta()`), create missing directories and, if
+/// `opts.delete` is set, remove local files and directories that don't exist remotely. The
+/// modification times of downloaded files are set to the remote ones. Errors concerning single
+/// files are recorded in the report; the other files are still synchronized.
+pub async fn mirror_down(
+    hd: &mut HiDrive,
+    remote_id: Identifier,
+    local_dir: impl AsRef<Path>,
+    opts: &MirrorOptions,
+) -> Result<MirrorReport> {
+    let local_dir = local_dir.as_ref();
+    if let Some(j) = Journal::resume(opts, "mirror_down", local_dir).await? {
+        let (report, root, _) = resume(hd, local_dir, j, opts).await?;
+        info!(target: "hd_api::sync", "mirror_down: {} -> {:?}: {}", root, local_dir, report);
+        return Ok(report);
+    }
+    let ignore = ignore_rules(local_dir, opts).await?;
+    let tree = remote_tree(hd, remote_id, &ignore).await?;
+    let report = mirror_down_(hd, &tree, local_dir, &ignore, opts).await?;
+    info!(target: "hd_api::sync", "mirror_down: {} -> {:?}: {}", tree.path, local_dir, report);
+    Ok(report)
+}
+
+/// `mirror_down()` from the already retrieved remote `tree`.
+async fn mirror_down_(
+    hd: &mut HiDrive,
+    tree: &Item,
+    local_dir: &Path,
+    ignore: &IgnoreRules,
+    opts: &MirrorOptions,
+) -> Result<MirrorReport> {
+    let mut remote = Snapshot::from_item(tree);
+    let root = tree.path.as_str();
+    let local = Snapshot::scan_with_links(local_dir, ignore, opts.symlinks).await?;
+    let collisions = names::match_names(opts.names, &local, &mut remote);
+    let mut ex = Executor::new(root, local_dir, &local, &remote, opts);
+    ex.scanned();
+    let mut report = MirrorReport::default();
+    ex.left_out(&mut report.failed, collided(collisions));
+    let ops = &mut report.plan.operations;
+
+    if opts.delete {
+        for (path, dir) in removed(&local, &remote) {
+            ops.push(Operation::Delete {
+                path,
+                side: Side::Local,
+                dir,
+            });
+        }
+    }
+    for d in remote.dirs.iter().filter(|d| !local.dirs.contains(*d)) {
+        ops.push(Operation::CreateDir {
+            path: d.clone(),
+            side: Side::Local,
+        });
+    }
+    for (rel, r) in remote.files.iter() {
+        let l = local.files.get(rel);
+        let unchanged = match l {
+            Some(l) if l.mhash == r.mhash => Ok(true),
+            Some(l) if r.chash.is_some() => l
+                .content_hash(&local_path(local_dir, rel))
+                .await
+                .map(|h| Some(&h) == r.chash.as_ref()),
+            _ => Ok(false),
+        };
+        let ops = &mut report.plan.operations;
+        match unchanged {
+            Ok(true) => {
+                report.unchanged += 1;
+                ex.skipped(rel);
+                // Only the mtime differs; adopting it avoids hashing the file next time.
+                if l.is_some_and(|l| l.mhash != r.mhash) {
+                    ops.push(Operation::SetMtime {
+                        path: rel.clone(),
+                        mtime: r.mtime,
+                        side: Side::Local,
+                    });
+                }
+            }
+            Ok(false) => ops.push(Operation::Download {
+                path: rel.clone(),
+                bytes: r.size,
+            }),
+            Err(e) => {
+                ex.summary.failed += 1;
+                report.failed.push((rel.clone(), format!("{:#}", e)));
+            }
+        }
+    }
+
+    let header = ex.header("mirror_down", &report.plan.operations, &[]);
+    let journal = Journal::start(opts, header).await?;
+    execute(hd, &mut ex, &mut report, opts, journal).await?;
+    Ok(report)
+}
+
+/// What `bisync()` did. Paths are relative to the synchronized directories.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BisyncReport {
+    /// All operations, including failed ones.
+    pub plan: SyncPlan,
+    pub uploaded: Vec<String>,
+    pub downloaded: Vec<String>,
+    /// Deletions and renames carried over from the other side.
+    pub propagated: Vec<Operation>,
+    /// Files changed on both sides, which were left alone.
+    pub conflicts: Vec<String>,
+    pub unchanged: usize,
+    /// Paths that couldn't be synchronized, with the error.
+    pub failed: Vec<(String, String)>,
+    pub summary: SyncSummary,
+}
+
+impl Display for BisyncReport {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} uploaded, {} downloaded, {} deletions/renames, {} conflicts, {} unchanged, {} failed",
+            self.uploaded.len(),
+            self.downloaded.len(),
+            self.propagated.len(),
+            self.conflicts.len(),
+            self.unchanged,
+            self.failed.len()
+        )
+    }
+}
+
+/// Create the missing remote parent directories of `rel`.
+async fn create_remote_parents(
+    hd: &mut HiDrive,
+    root: &str,
+    remote: &Snapshot,
+    rel: &str,
+    dirs: &mut HashSet<String>,
+) -> Result<()> {
+    let mut parent = String::new();
+    for c in rel
+        .split('/')
+        .rev()
+        .skip(1)
+        .collect::<Vec<_>>()
+        .into_iter()
+        .rev()
+    {
+        parent = join(&parent, c);
+        if !dirs.contains(&parent) {
+            hd.files()
+                .mkdir(
+                    Identifier::Path(join(root, &remote.remote_path(&parent))),
+                    None,
+                )
+                .await?;
+            dirs.insert(parent.clone());
+        }
+    }
+    Ok(())
+}
+
+async fn create_local_parents(path: &Path) -> Result<()> {
+    match path.parent() {
+        Some(p) => tokio::fs::create_dir_all(p)
+            .await
+            .with_context(|| format!("creating {:?}", p)),
+        None => Ok(()),
+    }
+}
+
+async fn load_state(path: &Path) -> Result<Option<Snapshot>> {
+    match tokio::fs::read(path).await {
+        Ok(b) => Ok(Some(serde_json::from_slice(&b)?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("reading sync state {:?}", path)),
+    }
+}
+
+async fn save_state(path: &Path, s: &Snapshot) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    tokio::fs::write(&tmp, serde_json::to_vec(s)?)
+        .await
+        .with_context(|| format!("writing sync state {:?}", tmp))?;
+    tokio::fs::rename(&tmp, path).await?;
+    Ok(())
+}
+
+/// Synchronize the local directory `local_dir` and the remote directory `remote_id` in both
+/// directions. `state` is a local file storing the state after the last synchronization (see
+/// `planner::plan()`); without it, differing files are conflicts and nothing is deleted.
+///
+/// Changes on one side are carried over to the other, including deletions (if `opts.delete`
+/// is set) and renames. Files changed on both sides are not touched, and remain conflicts until
+/// one side is changed to match the other. The state is updated unless `opts.dry_run` is set.
+pub async fn bisync(
+    hd: &mut HiDrive,
+    local_dir: impl AsRef<Path>,
+    remote_id: Identifier,
+    state: impl AsRef<Path>,
+    opts: &MirrorOptions,
+) -> Result<BisyncReport> {
+    let local_dir = local_dir.as_ref();
+    let ignore = ignore_rules(local_dir, opts).await?;
+    let state = state.as_ref();
+    let base = load_state(state).await?;
+    let mut report = BisyncReport::default();
+    let mut skipped = vec![];
+    let mut left_out = vec![];
+    let (h, mut journal) = match Journal::resume(opts, "bisync", local_dir).await? {
+        Some((h, j)) => (h, Some(j)),
+        None => {
+            let local = Snapshot::scan_with_links(local_dir, &ignore, opts.symlinks).await?;
+            let tree = remote_tree(hd, remote_id, &ignore).await?;
+            let mut remote = Snapshot::from_item(&tree);
+            left_out = collided(names::match_names(opts.names, &local, &mut remote));
+            let plan = planner::plan_snapshots(local_dir, &local, &remote, base.as_ref()).await?;
+            let mut operations = vec![];
+            // Paths whose state after the last synchronization still applies.
+            let mut keep_base: Vec<String> = vec![];
+            for a in plan.actions.into_iter() {
+                let op = match a {
+                    Action::Skip(p) => {
+                        report.unchanged += 1;
+                        skipped.push(p);
+                        continue;
+                    }
+                    Action::Conflict(p) => {
+                        skipped.push(p.clone());
+                        keep_base.push(p.clone());
+                        report.conflicts.push(p);
+                        continue;
+                    }
+                    Action::Delete { path, side } if !opts.deletes_on(side) => {
+                        keep_base.push(path);
+                        continue;
+                    }
+                    // Keep the old name, but transfer the new one.
+                    Action::Rename { from, to, side } if !opts.deletes_on(side) => {
+                        keep_base.push(from);
+                        match side {
+                            Side::Remote => Operation::Upload {
+                                bytes: local.files[&to].size,
+                                path: to,
+                            },
+                            Side::Local => Operation::Download {
+                                bytes: remote.files[&to].size,
+                                path: to,
+                            },
+                        }
+                    }
+                    Action::Upload(path) => Operation::Upload {
+                        bytes: local.files[&path].size,
+                        path,
+                    },
+                    Action::Download(path) => Operation::Download {
+                        bytes: remote.files[&path].size,
+                        path,
+                    },
+                    Action::Delete { path, side } => Operation::Delete {
+                        path,
+                        side,
+                        dir: false,
+                    },
+                    Action::Rename { from, to, side } => Operation::Rename { from, to, side },
+                };
+                operations.push(op);
+            }
+            let over = quota::fit_quota(hd, opts, &remote, &mut operations).await?;
+            keep_base.extend(over.iter().cloned());
+            left_out.extend(over_quota(over));
+            let h = journal::Header {
+                kind: "bisync".into(),
+                local_dir: local_dir.to_path_buf(),
+                root: tree.path,
+                local,
+                remote,
+                operations,
+                keep_base,
+            };
+            (h, None)
+        }
+    };
+    let mut ex = Executor::new(&h.root, local_dir, &h.local, &h.remote, opts);
+    ex.scanned();
+    ex.left_out(&mut report.failed, left_out);
+    for p in skipped.iter() {
+        ex.skipped(p);
+    }
+    for p in report.conflicts.iter() {
+        ex.conflict(p);
+    }
+    if journal.is_none() {
+        let header = ex.header("bisync", &h.operations, &h.keep_base);
+        journal = Journal::start(opts, header).await?;
+    }
+
+    let mut keep_base = h.keep_base.clone();
+    let results = ex
+        .execute_all(hd, &h.operations, opts.dry_run, &mut journal)
+        .await;
+    report.summary = ex.finish(hd);
+    for (op, r) in h.operations.iter().zip(results?) {
+        match (r, op) {
+            (Ok(()), Operation::Upload { path, .. }) => report.uploaded.push(path.clone()),
+            (Ok(()), Operation::Download { path, .. }) => report.downloaded.push(path.clone()),
+            (Ok(()), op) => report.propagated.push(op.clone()),
+            (Err(e), op) => {
+                if let Operation::Rename { to, .. } = op {
+                    keep_base.push(to.clone());
+                }
+                keep_base.push(op.path().to_string());
+                report
+                    .failed
+                    .push((op.path().to_string(), format!("{:#}", e)));
+            }
+        }
+    }
+    info!(target: "hd_api::sync", "bisync: {:?} <-> {}: {}", local_dir, h.root, report);
+    report.plan.operations = h.operations;
+    if opts.dry_run {
+        return Ok(report);
+    }
+
+    // The new state is the local tree after synchronization, except for paths that weren't
+    // synchronized. Content hashes are taken from the old state where the file is unchanged.
+    let base = base.unwrap_or_default();
+    let mut new = Snapshot::scan_with_links(local_dir, &ignore, opts.symlinks).await?;
+    for (rel, st) in new.files.iter_mut() {
+        if let Some(b) = base.files.get(rel).filter(|b| b.mhash == st.mhash) {
+            st.chash = b.chash.clone();
+        }
+    }
+    for p in keep_base {
+        match base.files.get(&p) {
+            Some(b) => new.files.insert(p, b.clone()),
+            None => new.files.remove(&p),
+        };
+    }
+    new.fill_chash(local_dir).await?;
+    save_state(state, &new).await?;
+    if let Some(j) = journal {
+        j.finish().await?;
+    }
+    Ok(report)
+}
+
+/// A snapshot of `files`, given as path, size and mtime, and `dirs`, for tests.
+#[cfg(test)]
+pub(crate) fn test_snapshot(files: &[(&str, u64, i64)], dirs: &[&str]) -> Snapshot {
+    let mut s = Snapshot::default();
+    for (p, size, mtime) in files {
+        s.files.insert(
+            p.to_string(),
+            FileState {
+                size: *size,
+                mtime: *mtime,
+                mhash: crate::hashing::mhash(p, *mtime, Some(*size)),
+                ..Default::default()
+            },
+        );
+    }
+    s.dirs = dirs.iter().map(|d| d.to_string()).collect();
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hashing;
+    use crate::http::mock::{hidrive, MockTransport};
+
+    use hyper::Method;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_mirror_up() {
+        let root = std::env::temp_dir().join("hd_api_test_mirror_up");
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::create_dir_all(root.join("empty")).unwrap();
+        std::fs::write(root.join("a.txt"), "new").unwrap();
+        std::fs::write(root.join("same.txt"), "same").unwrap();
+        std::fs::write(root.join("sub/b.txt"), "bb").unwrap();
+        let local = Snapshot::scan(&root).await.unwrap();
+
+        let listing = format!(
+            r#"{{"path": "/m", "members": [
+                {{"path": "/m/same.txt", "name": "same.txt", "type": "file", "size": 4, "mhash": "{}"}},
+                {{"path": "/m/a.txt", "name": "a.txt", "type": "file", "size": 3, "mtime": 1, "chash": "{}"}},
+                {{"path": "/m/old.txt", "name": "old.txt", "type": "file", "size": 3, "mtime": 1}},
+                {{"path": "/m/gone", "name": "gone", "type": "dir"}}
+            ]}}"#,
+            local.files["same.txt"].mhash,
+            hashing::chash(&b"old"[..]).await.unwrap().top_hash(),
+        );
+        let gone = r#"{"path": "/m/gone", "name": "gone", "type": "dir", "members": [
+            {"path": "/m/gone/x", "name": "x", "type": "file", "size": 1}
+        ]}"#;
+        let t = MockTransport::new();
+        let mut hd = hidrive(t.clone());
+        let events = Arc::new(std::sync::Mutex::new(vec![]));
+        let ev = events.clone();
+        let opts = MirrorOptions {
+            trash: Some("/trash".into()),
+            progress: Some(Arc::new(move |e: &SyncEvent| {
+                ev.lock().unwrap().push(e.clone())
+            })),
+            ..Default::default()
+        };
+        let op = |path: &str, side: Side, dir: bool| Operation::Delete {
+            path: path.into(),
+            side,
+            dir,
+        };
+        let expected = MirrorReport {
+            plan: SyncPlan {
+                operations: vec![
+                    op("gone", Side::Remote, true),
+                    op("old.txt", Side::Remote, false),
+                    Operation::CreateDir {
+                        path: "empty".into(),
+                        side: Side::Remote,
+                    },
+                    Operation::CreateDir {
+                        path: "sub".into(),
+                        side: Side::Remote,
+                    },
+                    Operation::Upload {
+                        path: "a.txt".into(),
+                        bytes: 3,
+                    },
+                    Operation::Upload {
+                        path: "sub/b.txt".into(),
+                        bytes: 2,
+                    },
+                ],
+            },
+            transferred: vec!["a.txt".into(), "sub/b.txt".into()],
+            deleted: vec!["gone".into(), "old.txt".into()],
+            created_dirs: vec!["empty".into(), "sub".into()],
+            unchanged: 1,
+            bytes: 5,
+            failed: vec![],
+            summary: SyncSummary::default(),
+        };
+
+        t.push(200, listing.clone());
+        t.push(200, gone);
+        let mut report = mirror_up(&mut hd, &root, Identifier::Id("b1.4".into()), &opts)
+            .await
+            .unwrap();
+        let summary = std::mem::take(&mut report.summary);
+        assert_eq!(expected, report);
+        assert_eq!(
+            SyncSummary {
+                files_scanned: 7,
+                transferred: 2,
+                skipped: 1,
+                deleted: 2,
+                failed: 0,
+                bytes: 5,
+                elapsed: summary.elapsed,
+            },
+            summary
+        );
+        let events = std::mem::take(&mut *events.lock().unwrap());
+        assert_eq!(16, events.len());
+        assert_eq!(
+            SyncEvent::Scanned {
+                local_files: 3,
+                remote_files: 4
+            },
+            events[0]
+        );
+        assert_eq!(
+            SyncEvent::Skipped {
+                path: "same.txt".into()
+            },
+            events[1]
+        );
+        assert_eq!(SyncEvent::Planned(expected.plan.clone()), events[2]);
+        assert_eq!(
+            SyncEvent::Started(expected.plan.operations[0].clone()),
+            events[3]
+        );
+        assert_eq!(
+            SyncEvent::Finished {
+                op: expected.plan.operations[0].clone(),
+                error: None
+            },
+            events[4]
+        );
+        assert_eq!(SyncEvent::Done(summary), events[15]);
+        let rqs = t.requests();
+        assert_eq!(8, rqs.len());
+        assert_eq!(Some("/m/gone".into()), rqs[1].param("path"));
+        assert_eq!("/2.1/dir/move", rqs[2].url.path());
+        assert_eq!(Some("/trash/gone".into()), rqs[2].param("dst"));
+        assert_eq!(Some("autoname".into()), rqs[2].param("on_exist"));
+        assert_eq!("/2.1/file/move", rqs[3].url.path());
+        assert_eq!(Some("/trash/old.txt".into()), rqs[3].param("dst"));
+        assert_eq!(Method::POST, rqs[4].method);
+        assert_eq!(Some("/m/empty".into()), rqs[4].param("path"));
+        assert_eq!(Some("/m/sub".into()), rqs[5].param("path"));
+        assert_eq!(Method::PUT, rqs[6].method);
+        assert_eq!(Some("/m".into()), rqs[6].param("dir"));
+        assert_eq!(Some("a.txt".into()), rqs[6].param("name"));
+        assert_eq!(
+            Some(local.files["a.txt"].mtime.to_string()),
+            rqs[6].param("mtime")
+        );
+        assert_eq!(Some("/m/sub".into()), rqs[7].param("dir"));
+
+        // A dry run only lists.
+        t.push(200, listing);
+        t.push(200, gone);
+        let opts = MirrorOptions {
+            dry_run: true,
+            ..opts
+        };
+        let mut report = mirror_up(&mut hd, &root, Identifier::Id("b1.4".into()), &opts)
+            .await
+            .unwrap();
+        assert_eq!(2, report.summary.transferred);
+        report.summary = SyncSummary::default();
+        assert_eq!(expected, report);
+        assert_eq!(10, t.requests().len());
+        assert!(report
+            .to_string()
+            .starts_with("2 transferred (5 bytes), 2 deleted"));
+        let plan = report.plan.to_string();
+        assert!(plan.contains("delete    gone (remote)\nmkdir"));
+        assert!(plan.contains("upload    a.txt (3 bytes)\n"));
+        assert!(plan.ends_with("6 operations, 5 bytes to transfer\n"));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_mirror_down() {
+        let base = std::env::temp_dir().join("hd_api_test_mirror_down");
+        let _ = std::fs::remove_dir_all(&base);
+        let root = base.join("mirror");
+        let trash = base.join("trash");
+        std::fs::create_dir_all(root.join("gone")).unwrap();
+        std::fs::create_dir_all(&trash).unwrap();
+        std::fs::write(root.join("gone/x"), "x").unwrap();
+        std::fs::write(root.join("extra.txt"), "extra").unwrap();
+        std::fs::write(trash.join("extra.txt"), "trashed before").unwrap();
+        std::fs::write(root.join("same.txt"), "same").unwrap();
+        std::fs::write(root.join("touched.txt"), "touched").unwrap();
+        let local = Snapshot::scan(&root).await.unwrap();
+
+        let listing = format!(
+            r#"{{"path": "/m", "members": [
+                {{"path": "/m/a.txt", "name": "a.txt", "type": "file", "size": 3, "mtime": 1000}},
+                {{"path": "/m/same.txt", "name": "same.txt", "type": "file", "size": 4, "mhash": "{}"}},
+                {{"path": "/m/touched.txt", "name": "touched.txt", "type": "file", "size": 7, "mtime": 2000, "chash": "{}"}},
+                {{"path": "/m/sub", "name": "sub", "type": "dir"}}
+            ]}}"#,
+            local.files["same.txt"].mhash,
+            hashing::chash(&b"touched"[..]).await.unwrap().top_hash(),
+        );
+        let sub = r#"{"path": "/m/sub", "name": "sub", "type": "dir", "members": [
+            {"path": "/m/sub/b.txt", "name": "b.txt", "type": "file", "size": 2, "mtime": 3000}
+        ]}"#;
+        let t = MockTransport::new();
+        let mut hd = hidrive(t.clone());
+        let opts = MirrorOptions {
+            local_trash: Some(trash.clone()),
+            ..Default::default()
+        };
+
+        t.push(200, listing);
+        t.push(200, sub);
+        t.push(200, "new");
+        t.push(200, "bb");
+        let mut report = mirror_down(&mut hd, Identifier::Id("b1.4".into()), &root, &opts)
+            .await
+            .unwrap();
+        assert_eq!(2, report.summary.skipped);
+        report.summary = SyncSummary::default();
+        assert_eq!(
+            MirrorReport {
+                plan: SyncPlan {
+                    operations: vec![
+                        Operation::Delete {
+                            path: "gone".into(),
+                            side: Side::Local,
+                            dir: true,
+                        },
+                        Operation::Delete {
+                            path: "extra.txt".into(),
+                            side: Side::Local,
+                            dir: false,
+                        },
+                        Operation::CreateDir {
+                            path: "sub".into(),
+                            side: Side::Local,
+                        },
+                        Operation::Download {
+                            path: "a.txt".into(),
+                            bytes: 3,
+                        },
+                        Operation::Download {
+                            path: "sub/b.txt".into(),
+                            bytes: 2,
+                        },
+                        Operation::SetMtime {
+                            path: "touched.txt".into(),
+                            mtime: 2000,
+                            side: Side::Local,
+                        },
+                    ],
+                },
+                transferred: vec!["a.txt".into(), "sub/b.txt".into()],
+                deleted: vec!["gone".into(), "extra.txt".into()],
+                created_dirs: vec!["sub".into()],
+                unchanged: 2,
+                bytes: 5,
+                failed: vec![],
+                summary: SyncSummary::default(),
+            },
+            report
+        );
+        let rqs = t.requests();
+        assert_eq!(4, rqs.len());
+        assert_eq!(Some("/m/a.txt".into()), rqs[2].param("path"));
+        assert_eq!("new", std::fs::read_to_string(root.join("a.txt")).unwrap());
+        assert_eq!(
+            "bb",
+            std::fs::read_to_string(root.join("sub/b.txt")).unwrap()
+        );
+        assert_eq!("x", std::fs::read_to_string(trash.join("gone/x")).unwrap());
+        assert_eq!(
+            "extra",
+            std::fs::read_to_string(trash.join("extra.txt.1")).unwrap()
+        );
+        assert!(!root.join("extra.txt").exists());
+
+        let local = Snapshot::scan(&root).await.unwrap();
+        assert_eq!(1000, local.files["a.txt"].mtime);
+        assert_eq!(2000, local.files["touched.txt"].mtime);
+        assert_eq!(3000, local.files["sub/b.txt"].mtime);
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_budget() {
+        let root = std::env::temp_dir().join("hd_api_test_sync_budget");
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        let listing = r#"{"path": "/m", "members": [
+            {"path": "/m/a.txt", "name": "a.txt", "type": "file", "size": 3},
+            {"path": "/m/b.txt", "name": "b.txt", "type": "file", "size": 4}
+        ]}"#;
+        let t = MockTransport::new();
+        let mut hd = hidrive(t.clone());
+        let opts = MirrorOptions {
+            schedule: Some(BandwidthSchedule::new(Some(1 << 20))),
+            budget: Some(5),
+            ..Default::default()
+        };
+
+        t.push(200, listing);
+        t.push(200, "aaa");
+        let report = mirror_down(&mut hd, Identifier::Path("/m".into()), &root, &opts)
+            .await
+            .unwrap();
+        assert_eq!(vec!["a.txt".to_string()], report.transferred);
+        assert_eq!(1, report.failed.len());
+        assert_eq!("b.txt", report.failed[0].0);
+        assert!(report.failed[0].1.contains("budget of 5 bytes"));
+        assert_eq!(2, t.requests().len());
+        // The schedule's throttle is only installed during the run.
+        assert!(hd.throttle().is_none());
+        assert!(!root.join("b.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn test_set_remote_mtime() {
+        let root = std::env::temp_dir().join("hd_api_test_sync_mtime");
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("x"), "same").unwrap();
+        set_mtime(&root.join("x"), 1000).unwrap();
+        let listing = format!(
+            r#"{{"path": "/m", "members": [
+                {{"path": "/m/x", "name": "x", "type": "file", "size": 4, "mtime": 5, "chash": "{}"}}
+            ]}}"#,
+            hashing::chash(&b"same"[..]).await.unwrap().top_hash(),
+        );
+        let t = MockTransport::new();
+        let mut hd = hidrive(t.clone());
+
+        t.push(200, listing);
+        let report = mirror_up(
+            &mut hd,
+            &root,
+            Identifier::Path("/m".into()),
+            &MirrorOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            vec![Operation::SetMtime {
+                path: "x".into(),
+                mtime: 1000,
+                side: Side::Remote,
+            }],
+            report.plan.operations
+        );
+        assert_eq!(1, report.unchanged);
+        let rq = t.last();
+        assert_eq!(Method::PATCH, rq.method);
+        assert_eq!(Some("/m/x".into()), rq.param("path"));
+        assert_eq!(Some("1000".into()), rq.param("mtime"));
+
+        // Remote files without `mhash` get one computed the same way as local ones.
+        let local = Snapshot::scan(&root).await.unwrap();
+        assert_eq!(
+            hashing::mhash_bytes("x", 1000, Some(4)),
+            local.files["x"].mhash
+        );
+    }
+
+    #[tokio::test]
+    async fn test_conflict_copies() {
+        let root = std::env::temp_dir().join("hd_api_test_sync_conflict_copies");
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        for f in ["a.txt", "b.txt"] {
+            std::fs::write(root.join(f), "local").unwrap();
+            set_mtime(&root.join(f), 1000).unwrap();
+        }
+        // a.txt is newer remotely, b.txt older.
+        let listing = r#"{"path": "/m", "members": [
+            {"path": "/m/a.txt", "name": "a.txt", "type": "file", "size": 6, "mtime": 2000},
+            {"path": "/m/b.txt", "name": "b.txt", "type": "file", "size": 6, "mtime": 10}
+        ]}"#;
+        let t = MockTransport::new();
+        let mut hd = hidrive(t.clone());
+        let opts = MirrorOptions {
+            conflict_copies: true,
+            ..Default::default()
+        };
+
+        t.push(200, listing);
+        t.push(200, r#"{"path": "/m/a (1).txt"}"#);
+        let report = mirror_up(&mut hd, &root, Identifier::Path("/m".into()), &opts)
+            .await
+            .unwrap();
+        assert_eq!(2, report.transferred.len());
+        let rqs = t.requests();
+        assert_eq!(4, rqs.len());
+        assert_eq!("/2.1/file/copy", rqs[1].url.path());
+        assert_eq!(Some("/m/a.txt".into()), rqs[1].param("src"));
+        assert_eq!(Some("/m/a.txt".into()), rqs[1].param("dst"));
+        assert_eq!(Some("autoname".into()), rqs[1].param("on_exist"));
+        assert_eq!(Method::PUT, rqs[2].method);
+        assert_eq!(Method::PUT, rqs[3].method);
+    }
+
+    #[tokio::test]
+    async fn test_parallel() {
+        let root = std::env::temp_dir().join("hd_api_test_sync_parallel");
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        for f in ["old1", "old2"] {
+            std::fs::write(root.join(f), "old").unwrap();
+        }
+        let listing = r#"{"path": "/m", "members": [
+            {"path": "/m/a", "name": "a", "type": "file", "size": 1},
+            {"path": "/m/b", "name": "b", "type": "file", "size": 1},
+            {"path": "/m/c", "name": "c", "type": "file", "size": 1}
+        ]}"#;
+        let t = MockTransport::new();
+        let mut hd = hidrive(t.clone());
+        let opts = MirrorOptions {
+            transfers: 2,
+            metadata_ops: 2,
+            ..Default::default()
+        };
+
+        t.push(200, listing);
+        for _ in 0..3 {
+            t.push(200, "x");
+        }
+        let report = mirror_down(&mut hd, Identifier::Path("/m".into()), &root, &opts)
+            .await
+            .unwrap();
+        assert!(report.failed.is_empty());
+        assert_eq!(3, report.transferred.len());
+        assert_eq!(2, report.deleted.len());
+        assert_eq!(3, report.summary.transferred);
+        assert_eq!(4, t.requests().len());
+        for f in ["a", "b", "c"] {
+            assert_eq!("x", std::fs::read_to_string(root.join(f)).unwrap());
+        }
+        assert!(!root.join("old1").exists());
+    }
+
+    /// Records the `SyncObserver` callbacks.
+    #[derive(Default)]
+    struct Callbacks(std::sync::Mutex<Vec<String>>);
+
+    impl SyncObserver for Callbacks {
+        fn on_plan(&self, plan: &SyncPlan) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("plan {}", plan.operations.len()));
+        }
+        fn on_file_start(&self, op: &Operation) {
+            self.0.lock().unwrap().push(format!("start {}", op.path()));
+        }
+        fn on_file_finish(&self, op: &Operation, _error: Option<&str>) {
+            self.0.lock().unwrap().push(format!("finish {}", op.path()));
+        }
+        fn on_conflict(&self, path: &str) {
+            self.0.lock().unwrap().push(format!("conflict {}", path));
+        }
+        fn on_complete(&self, summary: &SyncSummary) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("complete {}", summary.transferred));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bisync() {
+        let base = std::env::temp_dir().join("hd_api_test_bisync");
+        let _ = std::fs::remove_dir_all(&base);
+        let root = base.join("dir");
+        let state = base.join("state.json");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("up.txt"), "up").unwrap();
+        std::fs::write(root.join("conflict.txt"), "local version").unwrap();
+
+        // The state after the last run: conflict.txt was "orig" on both sides, del.txt existed.
+        let orig = hashing::chash(&b"orig"[..])
+            .await
+            .unwrap()
+            .top_hash()
+            .clone();
+        let del = hashing::chash(&b"del"[..])
+            .await
+            .unwrap()
+            .top_hash()
+            .clone();
+        let mut last = Snapshot::default();
+        for (p, h) in [("conflict.txt", &orig), ("del.txt", &del)] {
+            last.files.insert(
+                p.into(),
+                planner::FileState {
+                    size: 4,
+                    mtime: 0,
+                    nhash: hashing::nhash(p),
+                    mhash: hashing::mhash(p, 0, Some(4)),
+                    chash: Some(h.clone()),
+                    link: None,
+                },
+            );
+        }
+        save_state(&state, &last).await.unwrap();
+
+        let listing = format!(
+            r#"{{"path": "/m", "members": [
+                {{"path": "/m/conflict.txt", "name": "conflict.txt", "type": "file", "size": 14, "mtime": 1, "chash": "{}"}},
+                {{"path": "/m/del.txt", "name": "del.txt", "type": "file", "size": 3, "mtime": 1, "chash": "{}"}},
+                {{"path": "/m/down.txt", "name": "down.txt", "type": "file", "size": 4, "mtime": 1000}}
+            ]}}"#,
+            hashing::chash(&b"remote version"[..])
+                .await
+                .unwrap()
+                .top_hash(),
+            del,
+        );
+        let t = MockTransport::new();
+        let mut hd = hidrive(t.clone());
+        t.push(200, listing);
+        t.push(200, "{}");
+        t.push(200, "down");
+        let del = Operation::Delete {
+            path: "del.txt".into(),
+            side: Side::Remote,
+            dir: false,
+        };
+        let observer = Arc::new(Callbacks::default());
+        let opts = MirrorOptions {
+            progress: Some(observer.clone()),
+            ..Default::default()
+        };
+        let mut report = bisync(&mut hd, &root, Identifier::Id("b1.4".into()), &state, &opts)
+            .await
+            .unwrap();
+        assert_eq!(
+            vec![
+                "conflict conflict.txt",
+                "plan 3",
+                "start up.txt",
+                "finish up.txt",
+                "start down.txt",
+                "finish down.txt",
+                "start del.txt",
+                "finish del.txt",
+                "complete 2",
+            ],
+            *observer.0.lock().unwrap()
+        );
+        assert_eq!(1, report.summary.skipped);
+        assert_eq!(1, report.summary.deleted);
+        report.summary = SyncSummary::default();
+        assert_eq!(
+            BisyncReport {
+                plan: SyncPlan {
+                    operations: vec![
+                        Operation::Upload {
+                            path: "up.txt".into(),
+                            bytes: 2,
+                        },
+                        Operation::Download {
+                            path: "down.txt".into(),
+                            bytes: 4,
+                        },
+                        del.clone(),
+                    ],
+                },
+                uploaded: vec!["up.txt".into()],
+                downloaded: vec!["down.txt".into()],
+                propagated: vec![del],
+                conflicts: vec!["conflict.txt".into()],
+                unchanged: 0,
+                failed: vec![],
+                summary: SyncSummary::default(),
+            },
+            report
+        );
+        let rqs = t.requests();
+        assert_eq!(4, rqs.len());
+        assert_eq!(Method::PUT, rqs[1].method);
+        assert_eq!(Some("up.txt".into()), rqs[1].param("name"));
+        assert_eq!(Some("/m/down.txt".into()), rqs[2].param("path"));
+        assert_eq!(Method::DELETE, rqs[3].method);
+        assert_eq!(Some("/m/del.txt".into()), rqs[3].param("path"));
+        assert_eq!(
+            "local version",
+            std::fs::read_to_string(root.join("conflict.txt")).unwrap()
+        );
+
+        // The conflict is still recorded with its old state.
+        let new = load_state(&state).await.unwrap().unwrap();
+        let files: Vec<&String> = new.files.keys().collect();
+        assert_eq!(vec!["conflict.txt", "down.txt", "up.txt"], files);
+        assert_eq!(last.files["conflict.txt"], new.files["conflict.txt"]);
+        assert_eq!(1000, new.files["down.txt"].mtime);
+        assert!(new.files["up.txt"].chash.is_some());
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_bisync_delete_side() {
+        let base = std::env::temp_dir().join("hd_api_test_bisync_delete_side");
+        let _ = std::fs::remove_dir_all(&base);
+        let root = base.join("dir");
+        let state = base.join("state.json");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("gone.txt"), "gone").unwrap();
+
+        // gone.txt was deleted remotely, del.txt locally.
+        let mut last = Snapshot::scan(&root).await.unwrap();
+        last.fill_chash(&root).await.unwrap();
+        let del = hashing::chash(&b"del"[..])
+            .await
+            .unwrap()
+            .top_hash()
+            .clone();
+        last.files.insert(
+            "del.txt".into(),
+            planner::FileState {
+                size: 3,
+                mtime: 0,
+                nhash: hashing::nhash("del.txt"),
+                mhash: hashing::mhash("del.txt", 0, Some(3)),
+                chash: Some(del.clone()),
+                link: None,
+            },
+        );
+        save_state(&state, &last).await.unwrap();
+
+        let t = MockTransport::new();
+        let mut hd = hidrive(t.clone());
+        t.push(
+            200,
+            format!(
+                r#"{{"path": "/m", "members": [
+                    {{"path": "/m/del.txt", "name": "del.txt", "type": "file", "size": 3, "mtime": 1, "chash": "{}"}}
+                ]}}"#,
+                del
+            ),
+        );
+        let opts = MirrorOptions {
+            delete_side: Some(Side::Local),
+            ..Default::default()
+        };
+        let report = bisync(&mut hd, &root, Identifier::Id("b1.4".into()), &state, &opts)
+            .await
+            .unwrap();
+        assert_eq!(
+            vec![Operation::Delete {
+                path: "gone.txt".into(),
+                side: Side::Local,
+                dir: false,
+            }],
+            report.plan.operations
+        );
+        assert_eq!(1, t.requests().len());
+        assert!(!root.join("gone.txt").exists());
+
+        // The remote file stays, and is still known as deleted locally.
+        let new = load_state(&state).await.unwrap().unwrap();
+        let files: Vec<&String> = new.files.keys().collect();
+        assert_eq!(vec!["del.txt"], files);
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
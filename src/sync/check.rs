@@ -0,0 +1,124 @@
+//! Comparing a local and a remote directory without transferring content, see `check()`.
+
+use super::{collided, ignore_rules, names, remote_tree, removed, MirrorOptions};
+use crate::hidrive::HiDrive;
+use crate::planner::Snapshot;
+use crate::platform::local_path;
+use crate::types::Identifier;
+
+use std::fmt::{self, Display, Formatter};
+use std::path::Path;
+
+use anyhow::Result;
+use log::info;
+
+/// How `check()` compares files present on both sides.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CheckMode {
+    /// Files of equal size and mtime match. Files of equal size but different mtime are
+    /// compared by content hash, hashing the local file.
+    #[default]
+    Metadata,
+    /// Compare the content hashes of all files of equal size, hashing every local file.
+    Content,
+}
+
+/// The differences found by `check()`. Paths are relative to the compared directories; of a
+/// missing subtree, only the topmost directory is listed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CheckReport {
+    /// Files present on both sides with the same content.
+    pub matching: usize,
+    /// Files and directories only present locally.
+    pub missing_remote: Vec<String>,
+    /// Files and directories only present remotely.
+    pub missing_local: Vec<String>,
+    /// Files whose content differs.
+    pub differing: Vec<String>,
+    /// Files that couldn't be compared, with the error.
+    pub failed: Vec<(String, String)>,
+}
+
+impl CheckReport {
+    /// Both sides have the same files and directories, with the same content.
+    pub fn is_identical(&self) -> bool {
+        self.missing_remote.is_empty()
+            && self.missing_local.is_empty()
+            && self.differing.is_empty()
+            && self.failed.is_empty()
+    }
+}
+
+impl Display for CheckReport {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} matching, {} differing, {} missing remotely, {} missing locally, {} failed",
+            self.matching,
+            self.differing.len(),
+            self.missing_remote.len(),
+            self.missing_local.len(),
+            self.failed.len()
+        )
+    }
+}
+
+/// Compare the local directory `local_dir` with the remote directory `remote_id`, without
+/// transferring any content: trees, sizes and mtimes are compared, and content hashes as
+/// required by `mode` (see `CheckMode`). Of `opts`, `ignore`, `symlinks` and `names` apply.
+pub async fn check(
+    hd: &mut HiDrive,
+    local_dir: impl AsRef<Path>,
+    remote_id: Identifier,
+    mode: CheckMode,
+    opts: &MirrorOptions,
+) -> Result<CheckReport> {
+    let local_dir = local_dir.as_ref();
+    let ignore = ignore_rules(local_dir, opts).await?;
+    let local = Snapshot::scan_with_links(local_dir, &ignore, opts.symlinks).await?;
+    let tree = remote_tree(hd, remote_id, &ignore).await?;
+    let mut remote = Snapshot::from_item(&tree);
+    let collisions = names::match_names(opts.names, &local, &mut remote);
+
+    let mut report = CheckReport {
+        missing_remote: removed(&local, &remote)
+            .into_iter()
+            .map(|(p, _)| p)
+            .collect(),
+        missing_local: removed(&remote, &local)
+            .into_iter()
+            .map(|(p, _)| p)
+            .collect(),
+        failed: collided(collisions),
+        ..Default::default()
+    };
+    for (rel, l) in local.files.iter() {
+        let r = match remote.files.get(rel) {
+            Some(r) => r,
+            None => continue,
+        };
+        if l.size != r.size {
+            report.differing.push(rel.clone());
+            continue;
+        }
+        if mode == CheckMode::Metadata && l.mtime == r.mtime {
+            report.matching += 1;
+            continue;
+        }
+        let remote_hash = match r.chash {
+            Some(ref h) => h,
+            None => {
+                let e = "remote file has no content hash".to_string();
+                report.failed.push((rel.clone(), e));
+                continue;
+            }
+        };
+        match l.content_hash(&local_path(local_dir, rel)).await {
+            Ok(h) if h == *remote_hash => report.matching += 1,
+            Ok(_) => report.differing.push(rel.clone()),
+            Err(e) => report.failed.push((rel.clone(), format!("{:#}", e))),
+        }
+    }
+    info!(target: "hd_api::sync", "check: {:?} <-> {}: {}", local_dir, tree.path, report);
+    Ok(report)
+}
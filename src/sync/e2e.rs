@@ -0,0 +1,229 @@
+//! End-to-end tests of the sync engine against `MockHiDrive`, an in-memory HiDrive server.
+//!
+//! Tests change the server's contents between runs to simulate remote changes, and make single
+//! requests fail to simulate network errors.
+
+use super::*;
+use crate::test_util::MockHiDrive;
+
+/// Write `files` (path, contents) below `root`, creating parent directories.
+fn write_files(root: &Path, files: &[(&str, &str)]) {
+    for (p, c) in files {
+        let path = root.join(p);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, c).unwrap();
+    }
+}
+
+fn read(root: &Path, rel: &str) -> Option<String> {
+    std::fs::read_to_string(root.join(rel)).ok()
+}
+
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(name);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn remote() -> Identifier {
+    Identifier::Path("/m".into())
+}
+
+#[tokio::test]
+async fn test_e2e_mirror_up() {
+    let root = test_dir("hd_api_test_e2e_mirror_up");
+    write_files(
+        &root,
+        &[
+            ("a.txt", "a"),
+            ("sub/b.txt", "bb"),
+            ("sub/deep/c.txt", "ccc"),
+        ],
+    );
+    let (fake, mut hd) = MockHiDrive::start().await;
+    fake.put("stale.txt", "stale", 1).await;
+    let opts = MirrorOptions::default();
+
+    let report = mirror_up(&mut hd, &root, remote(), &opts).await.unwrap();
+    assert_eq!(3, report.transferred.len());
+    assert!(report.failed.is_empty());
+    assert_eq!(vec!["stale.txt".to_string()], report.deleted);
+    assert_eq!(
+        vec!["a.txt", "sub/b.txt", "sub/deep/c.txt"],
+        fake.files().await
+    );
+    assert_eq!(Some("ccc".into()), fake.read("sub/deep/c.txt").await);
+
+    // Nothing changed: the remote mtimes and hashes match.
+    let report = mirror_up(&mut hd, &root, remote(), &opts).await.unwrap();
+    assert!(report.plan.operations.is_empty());
+    assert_eq!(3, report.unchanged);
+
+    write_files(&root, &[("a.txt", "changed")]);
+    std::fs::remove_dir_all(root.join("sub/deep")).unwrap();
+    let report = mirror_up(&mut hd, &root, remote(), &opts).await.unwrap();
+    assert_eq!(vec!["a.txt".to_string()], report.transferred);
+    assert_eq!(vec!["sub/deep".to_string()], report.deleted);
+    assert_eq!(vec!["a.txt", "sub/b.txt"], fake.files().await);
+    assert_eq!(Some("changed".into()), fake.read("a.txt").await);
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[tokio::test]
+async fn test_e2e_mirror_down() {
+    let root = test_dir("hd_api_test_e2e_mirror_down");
+    write_files(&root, &[("local.txt", "only here")]);
+    let (fake, mut hd) = MockHiDrive::start().await;
+    fake.put("a.txt", "version 1", 1000).await;
+    fake.put("sub/b.txt", "b", 1000).await;
+    let opts = MirrorOptions::default();
+
+    let report = mirror_down(&mut hd, remote(), &root, &opts).await.unwrap();
+    assert_eq!(2, report.transferred.len());
+    assert_eq!(vec!["local.txt".to_string()], report.deleted);
+    assert_eq!(Some("version 1".into()), read(&root, "a.txt"));
+    assert_eq!(Some("b".into()), read(&root, "sub/b.txt"));
+
+    // A remote change is downloaded as a delta into the existing file.
+    fake.put("a.txt", "version 22", 2000).await;
+    fake.remove("sub/b.txt").await;
+    let report = mirror_down(&mut hd, remote(), &root, &opts).await.unwrap();
+    assert_eq!(vec!["a.txt".to_string()], report.transferred);
+    assert_eq!(vec!["sub/b.txt".to_string()], report.deleted);
+    assert_eq!(Some("version 22".into()), read(&root, "a.txt"));
+    let mtime = std::fs::metadata(root.join("a.txt"))
+        .unwrap()
+        .modified()
+        .unwrap();
+    assert_eq!(
+        2000,
+        mtime
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    );
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[tokio::test]
+async fn test_e2e_bisync() {
+    let base = test_dir("hd_api_test_e2e_bisync");
+    let root = base.join("dir");
+    let state = base.join("state.json");
+    write_files(&root, &[("both.txt", "orig"), ("gone.txt", "gone")]);
+    let (fake, mut hd) = MockHiDrive::start().await;
+    let opts = MirrorOptions::default();
+
+    let report = bisync(&mut hd, &root, remote(), &state, &opts)
+        .await
+        .unwrap();
+    assert_eq!(2, report.uploaded.len());
+    assert_eq!(vec!["both.txt", "gone.txt"], fake.files().await);
+
+    // Changes on both sides, and a conflict.
+    write_files(&root, &[("new.txt", "local"), ("both.txt", "local edit")]);
+    fake.put("both.txt", "remote edit", 5000).await;
+    fake.put("remote.txt", "remote", 5000).await;
+    fake.remove("gone.txt").await;
+    let report = bisync(&mut hd, &root, remote(), &state, &opts)
+        .await
+        .unwrap();
+    assert_eq!(vec!["new.txt".to_string()], report.uploaded);
+    assert_eq!(vec!["remote.txt".to_string()], report.downloaded);
+    assert_eq!(vec!["both.txt".to_string()], report.conflicts);
+    assert!(report.failed.is_empty());
+    assert_eq!(None, read(&root, "gone.txt"));
+    assert_eq!(Some("remote".into()), read(&root, "remote.txt"));
+    assert_eq!(Some("local".into()), fake.read("new.txt").await);
+    assert_eq!(Some("local edit".into()), read(&root, "both.txt"));
+    assert_eq!(Some("remote edit".into()), fake.read("both.txt").await);
+
+    // Resolving the conflict locally makes it an upload.
+    fake.put("both.txt", "local edit", 6000).await;
+    let report = bisync(&mut hd, &root, remote(), &state, &opts)
+        .await
+        .unwrap();
+    assert!(report.conflicts.is_empty());
+    assert!(report.plan.operations.is_empty());
+    std::fs::remove_dir_all(&base).unwrap();
+}
+
+#[tokio::test]
+async fn test_e2e_failures() {
+    let base = test_dir("hd_api_test_e2e_failures");
+    let root = base.join("dir");
+    write_files(&root, &[("a.txt", "a"), ("b.txt", "b"), ("c.txt", "c")]);
+    let (fake, mut hd) = MockHiDrive::start().await;
+    fake.fail_once("PUT", "b.txt").await;
+    let opts = MirrorOptions {
+        journal: Some(base.join("journal")),
+        transfers: 2,
+        ..Default::default()
+    };
+
+    // The failed upload is reported; the others go through.
+    let report = mirror_up(&mut hd, &root, remote(), &opts).await.unwrap();
+    assert_eq!(1, report.failed.len());
+    assert_eq!("b.txt", report.failed[0].0);
+    assert_eq!(1, report.summary.failed);
+    assert_eq!(vec!["a.txt", "c.txt"], fake.files().await);
+    assert!(!base.join("journal").exists());
+
+    // The next run only uploads the missing file.
+    let report = mirror_up(&mut hd, &root, remote(), &opts).await.unwrap();
+    assert_eq!(vec!["b.txt".to_string()], report.transferred);
+    assert_eq!(vec!["a.txt", "b.txt", "c.txt"], fake.files().await);
+
+    // A failing listing fails the whole run.
+    fake.fail_once("GET", "").await;
+    assert!(mirror_up(&mut hd, &root, remote(), &opts).await.is_err());
+    std::fs::remove_dir_all(&base).unwrap();
+}
+
+#[tokio::test]
+async fn test_e2e_check() {
+    let root = test_dir("hd_api_test_e2e_check");
+    write_files(
+        &root,
+        &[
+            ("same.txt", "same"),
+            ("touched.txt", "touched"),
+            ("edited.txt", "abc"),
+            ("size.txt", "local"),
+            ("local.txt", "l"),
+        ],
+    );
+    for f in ["same.txt", "edited.txt", "size.txt"] {
+        set_mtime(&root.join(f), 1000).unwrap();
+    }
+    set_mtime(&root.join("touched.txt"), 2000).unwrap();
+    let (fake, mut hd) = MockHiDrive::start().await;
+    fake.put("same.txt", "same", 1000).await;
+    fake.put("touched.txt", "touched", 1000).await;
+    // Same size and mtime: only a content check finds the difference.
+    fake.put("edited.txt", "abd", 1000).await;
+    fake.put("size.txt", "remote", 1000).await;
+    fake.put("sub/remote.txt", "r", 1000).await;
+    let opts = MirrorOptions::default();
+
+    let report = check(&mut hd, &root, remote(), CheckMode::Metadata, &opts)
+        .await
+        .unwrap();
+    assert_eq!(3, report.matching);
+    assert_eq!(vec!["size.txt".to_string()], report.differing);
+    assert_eq!(vec!["local.txt".to_string()], report.missing_remote);
+    assert_eq!(vec!["sub".to_string()], report.missing_local);
+    assert!(report.failed.is_empty());
+    assert!(!report.is_identical());
+
+    let report = check(&mut hd, &root, remote(), CheckMode::Content, &opts)
+        .await
+        .unwrap();
+    assert_eq!(2, report.matching);
+    assert_eq!(vec!["edited.txt", "size.txt"], report.differing);
+    // Nothing was transferred.
+    assert_eq!(Some("abc".into()), read(&root, "edited.txt"));
+    assert_eq!(Some("abd".into()), fake.read("edited.txt").await);
+    std::fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,242 @@
+//! Crash-safe journal of sync operations, see `MirrorOptions::journal`.
+//!
+//! The journal is a file of JSON lines. The first line holds everything needed to carry out the
+//! planned operations (the plan and both snapshots); every following line marks one operation as
+//! done. Lines are flushed to disk before the next operation starts, so an interrupted run
+//! repeats at most the operation in progress.
+
+use super::{MirrorOptions, Operation};
+use crate::planner::Snapshot;
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use log::info;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+
+/// The first line of a journal, as written.
+#[derive(Serialize)]
+pub(super) struct HeaderRef<'a> {
+    pub kind: &'a str,
+    pub local_dir: &'a Path,
+    pub root: &'a str,
+    pub local: &'a Snapshot,
+    pub remote: &'a Snapshot,
+    pub operations: &'a [Operation],
+    /// Only used by `bisync()`.
+    pub keep_base: &'a [String],
+}
+
+/// The first line of a journal, as read.
+#[derive(Deserialize)]
+pub(super) struct Header {
+    pub kind: String,
+    pub local_dir: PathBuf,
+    pub root: String,
+    pub local: Snapshot,
+    pub remote: Snapshot,
+    pub operations: Vec<Operation>,
+    #[serde(default)]
+    pub keep_base: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Done {
+    done: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+pub(super) struct Journal {
+    path: PathBuf,
+    file: tokio::fs::File,
+    done: HashMap<usize, Option<String>>,
+}
+
+impl Journal {
+    /// Start a journal at `opts.journal`, if set and not a dry run.
+    pub async fn start(opts: &MirrorOptions, header: HeaderRef<'_>) -> Result<Option<Journal>> {
+        let path = match opts.journal {
+            Some(ref p) if !opts.dry_run => p,
+            _ => return Ok(None),
+        };
+        let tmp = path.with_extension("tmp");
+        let mut line = serde_json::to_vec(&header)?;
+        line.push(b'\n');
+        let mut f = tokio::fs::File::create(&tmp)
+            .await
+            .with_context(|| format!("creating sync journal {:?}", tmp))?;
+        f.write_all(&line).await?;
+        f.sync_all().await?;
+        drop(f);
+        tokio::fs::rename(&tmp, path).await?;
+        Journal::open(path, HashMap::new()).await.map(Some)
+    }
+
+    /// Load the journal at `opts.journal`, if it exists, to resume an interrupted run of `kind`
+    /// in `local_dir`. Dry runs don't resume.
+    pub async fn resume(
+        opts: &MirrorOptions,
+        kind: &str,
+        local_dir: &Path,
+    ) -> Result<Option<(Header, Journal)>> {
+        let path = match opts.journal {
+            Some(ref p) if !opts.dry_run => p,
+            _ => return Ok(None),
+        };
+        let data = match tokio::fs::read(path).await {
+            Ok(d) => d,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("reading sync journal {:?}", path)),
+        };
+        let mut lines = data.split_inclusive(|b| *b == b'\n');
+        let first = lines.next().unwrap_or_default();
+        let header: Header = serde_json::from_slice(first)
+            .with_context(|| format!("parsing sync journal {:?}", path))?;
+        if header.kind != kind || header.local_dir != local_dir {
+            anyhow::bail!(
+                "sync journal {:?} belongs to {} of {:?}",
+                path,
+                header.kind,
+                header.local_dir
+            );
+        }
+        let mut done = HashMap::new();
+        let mut valid = first.len();
+        for l in lines {
+            // An incomplete last line was being written when the run was interrupted.
+            match serde_json::from_slice::<Done>(l) {
+                Ok(d) if l.ends_with(b"\n") => done.insert(d.done, d.error),
+                _ => break,
+            };
+            valid += l.len();
+        }
+        let j = Journal::open(path, done).await?;
+        j.file.set_len(valid as u64).await?;
+        info!(
+            target: "hd_api::sync",
+            "resuming {} of {:?}: {} of {} operations done",
+            kind,
+            local_dir,
+            j.done.len(),
+            header.operations.len()
+        );
+        Ok(Some((header, j)))
+    }
+
+    async fn open(path: &Path, done: HashMap<usize, Option<String>>) -> Result<Journal> {
+        let file = tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(path)
+            .await
+            .with_context(|| format!("opening sync journal {:?}", path))?;
+        Ok(Journal {
+            path: path.to_path_buf(),
+            file,
+            done,
+        })
+    }
+
+    /// The outcome of operation `i`, if it was carried out before.
+    pub fn done(&self, i: usize) -> Option<Result<()>> {
+        self.done.get(&i).map(|e| match e {
+            None => Ok(()),
+            Some(e) => Err(anyhow::Error::msg(e.clone())),
+        })
+    }
+
+    /// Record that operation `i` was carried out.
+    pub async fn mark(&mut self, i: usize, r: &Result<()>) -> Result<()> {
+        let d = Done {
+            done: i,
+            error: r.as_ref().err().map(|e| format!("{:#}", e)),
+        };
+        let mut line = serde_json::to_vec(&d)?;
+        line.push(b'\n');
+        self.file.write_all(&line).await?;
+        self.file.sync_data().await?;
+        self.done.insert(i, d.error);
+        Ok(())
+    }
+
+    /// Remove the journal once the run is complete.
+    pub async fn finish(self) -> Result<()> {
+        drop(self.file);
+        tokio::fs::remove_file(&self.path)
+            .await
+            .with_context(|| format!("removing sync journal {:?}", self.path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::mock::{hidrive, MockTransport};
+    use crate::sync::{mirror_down, test_snapshot};
+    use crate::types::Identifier;
+
+    use std::io::Write;
+
+    #[tokio::test]
+    async fn test_resume() {
+        let root = std::env::temp_dir().join("hd_api_test_sync_journal");
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        let path = std::env::temp_dir().join("hd_api_test_sync_journal.json");
+        let opts = MirrorOptions {
+            journal: Some(path.clone()),
+            ..Default::default()
+        };
+        let remote = test_snapshot(&[("a", 1, 1000), ("b", 2, 1000)], &[]);
+        let ops = vec![
+            Operation::Download {
+                path: "a".into(),
+                bytes: 1,
+            },
+            Operation::Download {
+                path: "b".into(),
+                bytes: 2,
+            },
+        ];
+        let local = Snapshot::default();
+        let header = |kind: &'static str| HeaderRef {
+            kind,
+            local_dir: &root,
+            root: "/m",
+            local: &local,
+            remote: &remote,
+            operations: &ops,
+            keep_base: &[],
+        };
+
+        let j = Journal::start(&opts, header("mirror_up")).await.unwrap();
+        drop(j);
+        assert!(Journal::resume(&opts, "mirror_down", &root).await.is_err());
+
+        let mut j = Journal::start(&opts, header("mirror_down"))
+            .await
+            .unwrap()
+            .unwrap();
+        j.mark(0, &Ok(())).await.unwrap();
+        drop(j);
+        // Interrupted while marking the next operation.
+        let mut f = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        f.write_all(br#"{"done": 1"#).unwrap();
+
+        let t = MockTransport::new();
+        let mut hd = hidrive(t.clone());
+        t.push(200, "bb");
+        let report = mirror_down(&mut hd, Identifier::Path("/m".into()), &root, &opts)
+            .await
+            .unwrap();
+        assert_eq!(vec!["a".to_string(), "b".to_string()], report.transferred);
+        assert_eq!(1, t.requests().len());
+        assert_eq!("bb", std::fs::read_to_string(root.join("b")).unwrap());
+        assert!(!path.exists());
+    }
+}
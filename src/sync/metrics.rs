@@ -0,0 +1,159 @@
+//! Metrics of sync runs, see `MetricsObserver`.
+
+use super::{Operation, SyncObserver, SyncPlan, SyncSummary};
+use crate::metrics::Metrics;
+
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::SystemTime;
+
+/// Reports the progress of sync runs to `Metrics`; set it as `MirrorOptions::progress`. Can be
+/// shared by consecutive runs, e.g. of a service synchronizing periodically.
+///
+/// Counters: `hd_sync_runs_total`, `hd_sync_files_transferred_total`,
+/// `hd_sync_bytes_transferred_total`, `hd_sync_files_deleted_total`, `hd_sync_errors_total`,
+/// `hd_sync_conflicts_total`. Gauges: `hd_sync_queue_depth`, `hd_sync_last_duration_seconds`,
+/// `hd_sync_last_success_timestamp_seconds`.
+pub struct MetricsObserver<M> {
+    metrics: M,
+    queued: AtomicI64,
+}
+
+impl<M: Metrics> MetricsObserver<M> {
+    pub fn new(metrics: M) -> MetricsObserver<M> {
+        MetricsObserver {
+            metrics,
+            queued: AtomicI64::new(0),
+        }
+    }
+
+    pub fn metrics(&self) -> &M {
+        &self.metrics
+    }
+
+    fn queue(&self, delta: i64) {
+        let n = self.queued.fetch_add(delta, Ordering::Relaxed) + delta;
+        self.metrics.gauge(
+            "hd_sync_queue_depth",
+            "Operations of the current sync run not yet finished.",
+            n as f64,
+        );
+    }
+}
+
+impl<M: Metrics> SyncObserver for MetricsObserver<M> {
+    fn on_plan(&self, plan: &SyncPlan) {
+        self.queued.store(0, Ordering::Relaxed);
+        self.queue(plan.operations.len() as i64);
+    }
+
+    fn on_file_finish(&self, op: &Operation, error: Option<&str>) {
+        self.queue(-1);
+        if error.is_some() {
+            return;
+        }
+        match op {
+            Operation::Upload { bytes, .. } | Operation::Download { bytes, .. } => {
+                self.metrics.counter(
+                    "hd_sync_files_transferred_total",
+                    "Files uploaded or downloaded.",
+                    1,
+                );
+                self.metrics.counter(
+                    "hd_sync_bytes_transferred_total",
+                    "Bytes of the files uploaded or downloaded.",
+                    *bytes,
+                );
+            }
+            Operation::Delete { .. } => self.metrics.counter(
+                "hd_sync_files_deleted_total",
+                "Files and directories deleted or moved to the trash.",
+                1,
+            ),
+            _ => (),
+        }
+    }
+
+    fn on_conflict(&self, _path: &str) {
+        self.metrics.counter(
+            "hd_sync_conflicts_total",
+            "Files changed on both sides and left alone.",
+            1,
+        );
+    }
+
+    fn on_error(&self, _op: &Operation, _error: &str) {
+        self.metrics
+            .counter("hd_sync_errors_total", "Failed operations.", 1);
+    }
+
+    fn on_complete(&self, summary: &SyncSummary) {
+        // Operations done before resuming from a journal aren't finished again.
+        self.queued.store(0, Ordering::Relaxed);
+        self.queue(0);
+        self.metrics
+            .counter("hd_sync_runs_total", "Completed sync runs.", 1);
+        self.metrics.gauge(
+            "hd_sync_last_duration_seconds",
+            "Duration of the last sync run.",
+            summary.elapsed.as_secs_f64(),
+        );
+        if summary.failed == 0 {
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default();
+            self.metrics.gauge(
+                "hd_sync_last_success_timestamp_seconds",
+                "Time of the last sync run without failures, in seconds since the epoch.",
+                now.as_secs() as f64,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::PrometheusMetrics;
+    use crate::planner::Side;
+    use crate::sync::SyncEvent;
+
+    use std::sync::Arc;
+
+    #[test]
+    fn test_metrics_observer() {
+        let m = Arc::new(PrometheusMetrics::new());
+        let o = MetricsObserver::new(m.clone());
+        let up = Operation::Upload {
+            path: "a".into(),
+            bytes: 10,
+        };
+        let del = Operation::Delete {
+            path: "b".into(),
+            side: Side::Remote,
+            dir: false,
+        };
+        o.event(&SyncEvent::Planned(SyncPlan {
+            operations: vec![up.clone(), del.clone()],
+        }));
+        assert_eq!(Some(2.0), m.get("hd_sync_queue_depth"));
+        o.event(&SyncEvent::Finished {
+            op: up,
+            error: None,
+        });
+        o.event(&SyncEvent::Finished {
+            op: del,
+            error: Some("gone".into()),
+        });
+        o.event(&SyncEvent::Done(SyncSummary {
+            failed: 1,
+            ..Default::default()
+        }));
+        assert_eq!(Some(0.0), m.get("hd_sync_queue_depth"));
+        assert_eq!(Some(1.0), m.get("hd_sync_files_transferred_total"));
+        assert_eq!(Some(10.0), m.get("hd_sync_bytes_transferred_total"));
+        assert_eq!(None, m.get("hd_sync_files_deleted_total"));
+        assert_eq!(Some(1.0), m.get("hd_sync_errors_total"));
+        assert_eq!(Some(1.0), m.get("hd_sync_runs_total"));
+        assert_eq!(None, m.get("hd_sync_last_success_timestamp_seconds"));
+    }
+}
@@ -0,0 +1,172 @@
+//! Matching of local names with remote ones spelled differently, see `NameMatching`.
+
+use crate::planner::Snapshot;
+
+use std::collections::HashMap;
+
+use unicode_normalization::UnicodeNormalization;
+
+/// Which differences between local and remote names are ignored when comparing the trees, see
+/// `MirrorOptions::names`. Matched remote files keep their names; new remote files get the
+/// local spelling.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NameMatching {
+    /// Compare names in Unicode normalization form C, e.g. for macOS, where names are often
+    /// decomposed (NFD) locally, but composed (NFC) remotely.
+    pub unicode: bool,
+    /// The local file system ignores case (Windows, macOS). Remote names differing only in case
+    /// can't be synchronized; all but the first are left out and reported as failed.
+    pub case_insensitive: bool,
+}
+
+impl NameMatching {
+    fn key(&self, path: &str) -> String {
+        let s: String = if self.unicode {
+            path.nfc().collect()
+        } else {
+            path.to_string()
+        };
+        if self.case_insensitive {
+            s.to_lowercase()
+        } else {
+            s
+        }
+    }
+}
+
+/// Rename the entries of `remote` that match local ones according to `m` to the local spelling,
+/// recording their remote spelling in `remote.remote_names`. Remote entries colliding with
+/// another remote entry are left out, including the contents of directories; they are returned
+/// with the entry they collide with.
+pub(super) fn match_names(
+    m: NameMatching,
+    local: &Snapshot,
+    remote: &mut Snapshot,
+) -> Vec<(String, String)> {
+    if m == NameMatching::default() {
+        return vec![];
+    }
+    let mut names = Names {
+        m,
+        local: local
+            .dirs
+            .iter()
+            .chain(local.files.keys())
+            .map(|p| (m.key(p), p.as_str()))
+            .collect(),
+        seen: HashMap::new(),
+        dirs: HashMap::new(),
+        collisions: vec![],
+    };
+    // Directories come first, parents before their contents.
+    let dirs = std::mem::take(&mut remote.dirs);
+    for d in dirs {
+        if let Some(name) = names.matched(&d) {
+            if remote.remote_path(&name) != d {
+                remote.remote_names.insert(name.clone(), d.clone());
+            }
+            names.dirs.insert(d, name.clone());
+            remote.dirs.insert(name);
+        }
+    }
+    let files = std::mem::take(&mut remote.files);
+    for (f, st) in files {
+        if let Some(name) = names.matched(&f) {
+            if remote.remote_path(&name) != f {
+                remote.remote_names.insert(name.clone(), f);
+            }
+            remote.files.insert(name, st);
+        }
+    }
+    names.collisions
+}
+
+struct Names<'a> {
+    m: NameMatching,
+    /// Local paths by key.
+    local: HashMap<String, &'a str>,
+    /// Remote paths by key.
+    seen: HashMap<String, String>,
+    /// The names under which remote directories are kept.
+    dirs: HashMap<String, String>,
+    collisions: Vec<(String, String)>,
+}
+
+impl Names<'_> {
+    /// The name under which the remote entry `path` is kept, or `None` if it is left out.
+    fn matched(&mut self, path: &str) -> Option<String> {
+        let (parent, name) = match path.rsplit_once('/') {
+            Some((parent, name)) => (Some(parent), name),
+            None => (None, path),
+        };
+        // Parents are either kept or left out because of a collision.
+        let parent = match parent {
+            Some(p) => Some(self.dirs.get(p)?),
+            None => None,
+        };
+        let key = self.m.key(path);
+        if let Some(other) = self.seen.get(&key) {
+            self.collisions.push((path.to_string(), other.clone()));
+            return None;
+        }
+        self.seen.insert(key.clone(), path.to_string());
+        Some(match (self.local.get(&key), parent) {
+            (Some(l), _) => l.to_string(),
+            (None, Some(p)) => format!("{}/{}", p, name),
+            (None, None) => path.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::test_snapshot;
+
+    fn snapshot(files: &[&str], dirs: &[&str]) -> Snapshot {
+        let files: Vec<_> = files.iter().map(|f| (*f, 0, 0)).collect();
+        test_snapshot(&files, dirs)
+    }
+
+    #[test]
+    fn test_match_names() {
+        let m = NameMatching {
+            unicode: true,
+            case_insensitive: true,
+        };
+        // "Cafe\u{301}" is decomposed, "Caf\u{e9}" composed.
+        let local = snapshot(&["Cafe\u{301}.txt", "dir/a.txt"], &["dir"]);
+        let mut remote = snapshot(
+            &[
+                "caf\u{e9}.txt",
+                "Dir/a.txt",
+                "Dir/new.txt",
+                "DIR/b.txt",
+                "x.txt",
+                "X.txt",
+            ],
+            &["DIR", "Dir"],
+        );
+        let collisions = match_names(m, &local, &mut remote);
+        assert_eq!(
+            vec![
+                ("Dir".to_string(), "DIR".to_string()),
+                ("x.txt".to_string(), "X.txt".to_string()),
+            ],
+            collisions
+        );
+        assert_eq!(
+            vec!["Cafe\u{301}.txt", "X.txt", "dir/b.txt"],
+            remote.files.keys().collect::<Vec<_>>()
+        );
+        assert_eq!(vec!["dir"], remote.dirs.iter().collect::<Vec<_>>());
+        assert_eq!("caf\u{e9}.txt", remote.remote_path("Cafe\u{301}.txt"));
+        assert_eq!("DIR/b.txt", remote.remote_path("dir/b.txt"));
+        assert_eq!("DIR/new.txt", remote.remote_path("dir/new.txt"));
+        assert_eq!("X.txt", remote.remote_path("X.txt"));
+
+        let mut remote = snapshot(&["x.txt", "X.txt"], &[]);
+        assert!(match_names(NameMatching::default(), &local, &mut remote).is_empty());
+        assert_eq!(2, remote.files.len());
+    }
+}
@@ -0,0 +1,350 @@
+//! Continuous download of remote changes, see `watch_down()`.
+
+use super::{
+    execute, ignore_rules, mirror_down_, remote_tree_, removed, same_hashes, Executor,
+    MirrorOptions, MirrorReport, Operation, SyncEvent, SyncPlan,
+};
+use crate::hidrive::HiDrive;
+use crate::ignore::IgnoreRules;
+use crate::planner::{FileState, Side, Snapshot};
+use crate::types::{Identifier, Item, Params};
+
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::Result;
+use log::{info, warn};
+use tokio_util::sync::CancellationToken;
+
+/// Options of `watch_down()`.
+#[derive(Debug, Clone)]
+pub struct PollOptions {
+    /// Options of the initial `mirror_down()` and of the downloads of changes.
+    pub mirror: MirrorOptions,
+    /// Time between checks of the remote directory. Default: 60 s.
+    pub interval: Duration,
+    /// Start with a full `mirror_down()`. Disable if both trees are known to be in sync, e.g.
+    /// after `bisync()`. Default: true.
+    pub initial_sync: bool,
+}
+
+impl Default for PollOptions {
+    fn default() -> PollOptions {
+        PollOptions {
+            mirror: MirrorOptions::default(),
+            interval: Duration::from_secs(60),
+            initial_sync: true,
+        }
+    }
+}
+
+/// Detects changes of a remote tree by comparing the `chash` and `mohash` of its directories with
+/// those seen by the previous poll.
+pub struct RemotePoller {
+    id: Identifier,
+    ignore: IgnoreRules,
+    tree: Option<Item>,
+}
+
+impl RemotePoller {
+    /// Poll the directory `id`, leaving out members excluded by `ignore`.
+    pub fn new(id: Identifier, ignore: IgnoreRules) -> RemotePoller {
+        RemotePoller {
+            id,
+            ignore,
+            tree: None,
+        }
+    }
+
+    /// The tree retrieved by the latest poll, in the format of `remote_tree()`.
+    pub fn tree(&self) -> Option<&Item> {
+        self.tree.as_ref()
+    }
+
+    /// Returns the current tree if it changed since the previous call; the first call always
+    /// returns it. If nothing changed, only the hashes of the root are requested; otherwise only
+    /// changed directories are listed again. After an error, the next call lists the whole
+    /// tree.
+    pub async fn poll(&mut self, hd: &mut HiDrive) -> Result<Option<&Item>> {
+        if let Some(ref prev) = self.tree {
+            let mut p = Params::new();
+            p.add_str("fields", "chash,mohash");
+            let root = hd.files().get_dir(self.id.clone(), Some(&p)).await?;
+            if same_hashes(&root, prev) {
+                return Ok(None);
+            }
+        }
+        let prev = self.tree.take();
+        let tree = remote_tree_(hd, self.id.clone(), &self.ignore, prev).await?;
+        self.tree = Some(tree);
+        Ok(self.tree.as_ref())
+    }
+}
+
+fn same_file(a: Option<&FileState>, b: Option<&FileState>) -> bool {
+    a.map(|f| (f.size, f.mtime)) == b.map(|f| (f.size, f.mtime))
+}
+
+/// The operations applying the changes from `prev` to `remote` to the local tree `local`, which
+/// was `known` after the previous round. Paths changed locally since then are left alone and
+/// returned separately as conflicts.
+fn plan_changes(
+    prev: &Snapshot,
+    remote: &Snapshot,
+    known: &Snapshot,
+    local: &Snapshot,
+    delete: bool,
+) -> (Vec<Operation>, Vec<String>) {
+    let mut ops = vec![];
+    let mut conflicts = vec![];
+    let changed_locally = |p: &str| !same_file(known.files.get(p), local.files.get(p));
+    for (path, dir) in removed(prev, remote).into_iter().filter(|_| delete) {
+        let exists = if dir {
+            local.dirs.contains(&path)
+        } else {
+            local.files.contains_key(&path)
+        };
+        let prefix = format!("{}/", path);
+        let changed = if dir {
+            known
+                .files
+                .keys()
+                .chain(local.files.keys())
+                .filter(|f| f.starts_with(&prefix))
+                .any(|f| changed_locally(f.as_str()))
+        } else {
+            changed_locally(&path)
+        };
+        if changed {
+            conflicts.push(path);
+        } else if exists {
+            ops.push(Operation::Delete {
+                path,
+                side: Side::Local,
+                dir,
+            });
+        }
+    }
+    for d in remote.dirs.iter() {
+        if !prev.dirs.contains(d) && !local.dirs.contains(d) {
+            ops.push(Operation::CreateDir {
+                path: d.clone(),
+                side: Side::Local,
+            });
+        }
+    }
+    for (rel, r) in remote.files.iter() {
+        if prev.files.get(rel).is_some_and(|p| p.mhash == r.mhash) {
+            continue;
+        }
+        if same_file(local.files.get(rel), Some(r)) {
+            // E.g. uploaded from here.
+            continue;
+        }
+        if changed_locally(rel) {
+            conflicts.push(rel.clone());
+        } else {
+            ops.push(Operation::Download {
+                path: rel.clone(),
+                bytes: r.size,
+            });
+        }
+    }
+    (ops, conflicts)
+}
+
+/// Mirror `remote_id` to `local_dir` like `mirror_down()` (unless `opts.initial_sync` is unset),
+/// then poll `remote_id` every `opts.interval` and download changes until `cancel` is
+/// triggered. Files changed both locally and remotely since the previous poll are skipped, so
+/// that `watch_down()` can run next to `watch_up()` in a two-way synchronization daemon.
+pub async fn watch_down(
+    hd: &mut HiDrive,
+    remote_id: Identifier,
+    local_dir: impl AsRef<Path>,
+    opts: &PollOptions,
+    cancel: CancellationToken,
+) -> Result<()> {
+    let local_dir = local_dir.as_ref();
+    let mopts = &opts.mirror;
+    let ignore = ignore_rules(local_dir, mopts).await?;
+    let mut poller = RemotePoller::new(remote_id, ignore.clone());
+    let tree = poller.poll(hd).await?.expect("first poll returns the tree");
+    let root = tree.path.clone();
+    if opts.initial_sync {
+        let report = mirror_down_(hd, tree, local_dir, &ignore, mopts).await?;
+        info!(target: "hd_api::sync", "watch_down: {} -> {:?}: {}", root, local_dir, report);
+    }
+    let mut prev = Snapshot::from_item(tree);
+    let mut known = Snapshot::scan_with_links(local_dir, &ignore, mopts.symlinks).await?;
+
+    loop {
+        tokio::select! {
+            _ = cancel.cancelled() => return Ok(()),
+            _ = tokio::time::sleep(opts.interval) => (),
+        }
+        let remote = match poller.poll(hd).await {
+            Ok(Some(tree)) => Snapshot::from_item(tree),
+            Ok(None) => continue,
+            Err(e) => {
+                warn!(target: "hd_api::sync", "watch_down: polling {} failed: {:#}", root, e);
+                continue;
+            }
+        };
+        let local = match Snapshot::scan_with_links(local_dir, &ignore, mopts.symlinks).await {
+            Ok(s) => s,
+            Err(e) => {
+                warn!(target: "hd_api::sync", "watch_down: scan failed: {:#}", e);
+                continue;
+            }
+        };
+        let (operations, conflicts) = plan_changes(&prev, &remote, &known, &local, mopts.delete);
+        for c in conflicts.iter() {
+            warn!(target: "hd_api::sync", "watch_down: {} changed on both sides, skipping", c);
+            if let Some(o) = mopts.progress.as_deref() {
+                o.event(&SyncEvent::Conflict { path: c.clone() });
+            }
+        }
+        if !operations.is_empty() {
+            let mut ex = Executor::new(&root, local_dir, &local, &remote, mopts);
+            let mut report = MirrorReport {
+                plan: SyncPlan { operations },
+                ..Default::default()
+            };
+            execute(hd, &mut ex, &mut report, mopts, None).await?;
+            info!(target: "hd_api::sync", "watch_down: {} -> {:?}: {}", root, local_dir, report);
+        }
+        prev = remote;
+        known = match Snapshot::scan_with_links(local_dir, &ignore, mopts.symlinks).await {
+            Ok(s) => s,
+            Err(_) => local,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::mock::{hidrive, MockTransport};
+    use crate::sync::test_snapshot as snapshot;
+
+    #[tokio::test]
+    async fn test_poller() {
+        let h = |n: u8| format!("{:040x}", n);
+        let root = |chash: u8, sub: u8| {
+            format!(
+                r#"{{"path": "/m", "chash": "{}", "mohash": "{}", "members": [
+                    {{"path": "/m/sub", "name": "sub", "type": "dir", "chash": "{}", "mohash": "{}"}}
+                ]}}"#,
+                h(chash),
+                h(1),
+                h(sub),
+                h(1)
+            )
+        };
+        let sub = r#"{"path": "/m/sub", "members": [
+            {"path": "/m/sub/a", "name": "a", "type": "file", "size": 1}
+        ]}"#;
+        let t = MockTransport::new();
+        let mut hd = hidrive(t.clone());
+        let mut poller = RemotePoller::new(Identifier::Path("/m".into()), IgnoreRules::new());
+
+        t.push(200, root(1, 2));
+        t.push(200, sub);
+        let tree = poller.poll(&mut hd).await.unwrap().unwrap();
+        assert_eq!(1, tree.members[0].members.len());
+        assert_eq!(2, t.requests().len());
+
+        // Unchanged: only the root hashes are requested.
+        t.push(
+            200,
+            format!(r#"{{"chash": "{}", "mohash": "{}"}}"#, h(1), h(1)),
+        );
+        assert!(poller.poll(&mut hd).await.unwrap().is_none());
+        assert_eq!(3, t.requests().len());
+        assert_eq!(Some("chash,mohash".into()), t.last().param("fields"));
+
+        // Changed outside of `sub`: its listing is reused.
+        t.push(
+            200,
+            format!(r#"{{"chash": "{}", "mohash": "{}"}}"#, h(3), h(1)),
+        );
+        t.push(200, root(3, 2));
+        let tree = poller.poll(&mut hd).await.unwrap().unwrap();
+        assert_eq!("a", tree.members[0].members[0].name.as_deref().unwrap());
+        assert_eq!(5, t.requests().len());
+    }
+
+    #[test]
+    fn test_plan_changes() {
+        let prev = snapshot(
+            &[
+                ("gone", 1, 10),
+                ("edited", 2, 10),
+                ("d/f", 3, 10),
+                ("both", 4, 10),
+            ],
+            &["d"],
+        );
+        let remote = snapshot(
+            &[
+                ("new", 5, 20),
+                ("edited", 6, 20),
+                ("both", 7, 20),
+                ("up", 8, 30),
+            ],
+            &["e"],
+        );
+        let known = snapshot(
+            &[
+                ("gone", 1, 10),
+                ("edited", 2, 10),
+                ("d/f", 3, 10),
+                ("both", 4, 10),
+            ],
+            &["d"],
+        );
+        // `both` was changed locally, `up` was uploaded from here.
+        let local = snapshot(
+            &[
+                ("gone", 1, 10),
+                ("edited", 2, 10),
+                ("d/f", 3, 10),
+                ("both", 9, 15),
+                ("up", 8, 30),
+            ],
+            &["d"],
+        );
+        let (ops, conflicts) = plan_changes(&prev, &remote, &known, &local, true);
+        assert_eq!(vec!["both".to_string()], conflicts);
+        assert_eq!(
+            vec![
+                Operation::Delete {
+                    path: "d".into(),
+                    side: Side::Local,
+                    dir: true,
+                },
+                Operation::Delete {
+                    path: "gone".into(),
+                    side: Side::Local,
+                    dir: false,
+                },
+                Operation::CreateDir {
+                    path: "e".into(),
+                    side: Side::Local,
+                },
+                Operation::Download {
+                    path: "edited".into(),
+                    bytes: 6,
+                },
+                Operation::Download {
+                    path: "new".into(),
+                    bytes: 5,
+                },
+            ],
+            ops
+        );
+
+        let (ops, _) = plan_changes(&prev, &remote, &known, &local, false);
+        assert_eq!(3, ops.len());
+    }
+}
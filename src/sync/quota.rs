@@ -0,0 +1,150 @@
+//! Fitting planned uploads into the free space of the account, see `QuotaPolicy`.
+
+use super::{MirrorOptions, Operation};
+use crate::hidrive::HiDrive;
+use crate::planner::Snapshot;
+use crate::types::QuotaExceeded;
+
+use anyhow::Result;
+use log::info;
+
+/// What to do before a sync run whose uploads may not fit into the account's free space, see
+/// `MirrorOptions::quota`. The space an upload needs is its size minus that of the remote file
+/// it replaces; space freed by deletions isn't counted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum QuotaPolicy {
+    /// Don't check the quota.
+    #[default]
+    Ignore,
+    /// Fail with `QuotaExceeded` before carrying out anything.
+    Fail,
+    /// Leave out the largest uploads until the others fit.
+    SkipLargest,
+    /// Upload in plan order as long as at least this many bytes remain free; leave out the
+    /// uploads after that.
+    Reserve(u64),
+}
+
+/// Apply `opts.quota` to the uploads in `ops`, removing those left out and returning their
+/// paths.
+pub(super) async fn fit_quota(
+    hd: &mut HiDrive,
+    opts: &MirrorOptions,
+    remote: &Snapshot,
+    ops: &mut Vec<Operation>,
+) -> Result<Vec<String>> {
+    if opts.quota == QuotaPolicy::Ignore || !ops.iter().any(is_upload) {
+        return Ok(vec![]);
+    }
+    let free = hd.user().quota().await?.free();
+    let left_out = fit(opts.quota, free, remote, ops)?;
+    if !left_out.is_empty() {
+        info!(
+            target: "hd_api::sync",
+            "{} bytes free: leaving out {} uploads",
+            free,
+            left_out.len()
+        );
+    }
+    Ok(left_out)
+}
+
+fn is_upload(op: &Operation) -> bool {
+    matches!(op, Operation::Upload { .. })
+}
+
+/// Bytes the upload `op` adds to the account.
+fn growth(remote: &Snapshot, op: &Operation) -> u64 {
+    match op {
+        Operation::Upload { path, bytes } => {
+            bytes.saturating_sub(remote.files.get(path).map(|f| f.size).unwrap_or(0))
+        }
+        _ => 0,
+    }
+}
+
+fn fit(
+    policy: QuotaPolicy,
+    free: u64,
+    remote: &Snapshot,
+    ops: &mut Vec<Operation>,
+) -> Result<Vec<String>> {
+    let needed: u64 = ops.iter().map(|op| growth(remote, op)).sum();
+    let mut left_out = vec![];
+    match policy {
+        QuotaPolicy::Ignore => (),
+        QuotaPolicy::Fail if needed > free => {
+            return Err(anyhow::Error::new(QuotaExceeded { needed, free }))
+        }
+        QuotaPolicy::Fail => (),
+        QuotaPolicy::SkipLargest => {
+            let mut uploads: Vec<(u64, &str)> = ops
+                .iter()
+                .filter(|op| is_upload(op))
+                .map(|op| (growth(remote, op), op.path()))
+                .collect();
+            uploads.sort_by(|a, b| b.cmp(a));
+            let mut needed = needed;
+            for (g, path) in uploads {
+                if needed <= free {
+                    break;
+                }
+                needed -= g;
+                left_out.push(path.to_string());
+            }
+        }
+        QuotaPolicy::Reserve(reserve) => {
+            let mut available = free.saturating_sub(reserve);
+            let mut full = false;
+            for op in ops.iter().filter(|op| is_upload(op)) {
+                let g = growth(remote, op);
+                full = full || g > available;
+                if full {
+                    left_out.push(op.path().to_string());
+                } else {
+                    available -= g;
+                }
+            }
+        }
+    }
+    ops.retain(|op| !is_upload(op) || !left_out.iter().any(|p| p == op.path()));
+    Ok(left_out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::test_snapshot;
+
+    fn upload(path: &str, bytes: u64) -> Operation {
+        Operation::Upload {
+            path: path.into(),
+            bytes,
+        }
+    }
+
+    #[test]
+    fn test_fit() {
+        let remote = test_snapshot(&[("b", 5, 0)], &[]);
+        // a needs 4 bytes, b 5 (replacing 5 of 10), c 3: 12 in total.
+        let ops = vec![upload("a", 4), upload("b", 10), upload("c", 3)];
+
+        let mut o = ops.clone();
+        assert!(fit(QuotaPolicy::Fail, 12, &remote, &mut o)
+            .unwrap()
+            .is_empty());
+        let err = fit(QuotaPolicy::Fail, 11, &remote, &mut o).unwrap_err();
+        assert!(err.downcast_ref::<QuotaExceeded>().is_some());
+        assert_eq!(ops, o);
+
+        let mut o = ops.clone();
+        let left_out = fit(QuotaPolicy::SkipLargest, 7, &remote, &mut o).unwrap();
+        assert_eq!(vec!["b".to_string()], left_out);
+        assert_eq!(vec![upload("a", 4), upload("c", 3)], o);
+
+        let mut o = ops.clone();
+        let left_out = fit(QuotaPolicy::Reserve(2), 8, &remote, &mut o).unwrap();
+        assert_eq!(vec!["b".to_string(), "c".to_string()], left_out);
+        assert_eq!(vec![upload("a", 4)], o);
+    }
+}
@@ -0,0 +1,329 @@
+//! Running sync jobs on schedules, see `SyncRunner`.
+
+use super::{bisync, mirror_down, mirror_up, MirrorOptions, SyncSummary};
+use crate::hidrive::HiDrive;
+use crate::types::Identifier;
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::Result;
+use futures_util::future::join_all;
+use log::{info, warn};
+use time::{OffsetDateTime, Time, UtcOffset, Weekday};
+use tokio_util::sync::CancellationToken;
+
+/// What a `SyncJob` does.
+#[derive(Debug, Clone)]
+pub enum JobKind {
+    /// `mirror_up()` from `local` to `remote`.
+    MirrorUp { local: PathBuf, remote: Identifier },
+    /// `mirror_down()` from `remote` to `local`.
+    MirrorDown { remote: Identifier, local: PathBuf },
+    /// `bisync()` of `local` and `remote`, keeping the state in `state`.
+    Bisync {
+        local: PathBuf,
+        remote: Identifier,
+        state: PathBuf,
+    },
+}
+
+/// When a `SyncJob` runs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JobSchedule {
+    /// Each time `interval` after the previous run finished.
+    Every(Duration),
+    /// At the given times of day, like a cron entry `M H * * D`.
+    At {
+        times: Vec<Time>,
+        /// Days of the week to run on; all days if empty.
+        weekdays: Vec<Weekday>,
+        /// Time zone of `times`.
+        offset: UtcOffset,
+    },
+}
+
+impl JobSchedule {
+    /// Run daily at `times` (UTC).
+    pub fn daily(times: &[Time]) -> JobSchedule {
+        JobSchedule::At {
+            times: times.to_vec(),
+            weekdays: vec![],
+            offset: UtcOffset::UTC,
+        }
+    }
+
+    /// The first time after `t` the job is due.
+    pub fn next_after(&self, t: OffsetDateTime) -> OffsetDateTime {
+        let (times, weekdays, offset) = match self {
+            JobSchedule::Every(interval) => return t + *interval,
+            JobSchedule::At {
+                times,
+                weekdays,
+                offset,
+            } => (times, weekdays, *offset),
+        };
+        let local = t.to_offset(offset);
+        let mut times = times.clone();
+        times.sort();
+        for day in 0..=7 {
+            let date = local.date() + time::Duration::days(day);
+            if !weekdays.is_empty() && !weekdays.contains(&date.weekday()) {
+                continue;
+            }
+            if let Some(next) = times
+                .iter()
+                .map(|time| date.with_time(*time).assume_offset(offset))
+                .find(|next| *next > t)
+            {
+                return next;
+            }
+        }
+        // No times configured.
+        t + time::Duration::days(1)
+    }
+}
+
+/// Delays after failed runs: `initial` after the first failure, doubling with each further one
+/// up to `max`. A run fails if it returns an error or a file couldn't be synchronized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Backoff {
+        Backoff {
+            initial: Duration::from_secs(60),
+            max: Duration::from_secs(3600),
+        }
+    }
+}
+
+impl Backoff {
+    /// The delay after `failures` consecutive failed runs.
+    pub fn delay(&self, failures: u32) -> Duration {
+        if failures == 0 {
+            return Duration::ZERO;
+        }
+        let factor = 1u32.checked_shl(failures - 1).unwrap_or(u32::MAX);
+        self.initial.saturating_mul(factor).min(self.max)
+    }
+}
+
+/// A sync run repeated on a schedule.
+#[derive(Debug, Clone)]
+pub struct SyncJob {
+    /// Used in log messages.
+    pub name: String,
+    pub kind: JobKind,
+    pub schedule: JobSchedule,
+    pub opts: MirrorOptions,
+    pub backoff: Backoff,
+    /// Run once right away instead of waiting for the first scheduled time. Default: false.
+    pub run_at_start: bool,
+}
+
+impl SyncJob {
+    pub fn new(name: impl Into<String>, kind: JobKind, schedule: JobSchedule) -> SyncJob {
+        SyncJob {
+            name: name.into(),
+            kind,
+            schedule,
+            opts: MirrorOptions::default(),
+            backoff: Backoff::default(),
+            run_at_start: false,
+        }
+    }
+
+    async fn run_once(&self, hd: &mut HiDrive) -> Result<SyncSummary> {
+        let opts = &self.opts;
+        Ok(match &self.kind {
+            JobKind::MirrorUp { local, remote } => {
+                mirror_up(hd, local, remote.clone(), opts).await?.summary
+            }
+            JobKind::MirrorDown { remote, local } => {
+                mirror_down(hd, remote.clone(), local, opts).await?.summary
+            }
+            JobKind::Bisync {
+                local,
+                remote,
+                state,
+            } => {
+                bisync(hd, local, remote.clone(), state, opts)
+                    .await?
+                    .summary
+            }
+        })
+    }
+
+    /// Run the job on its schedule until `cancel` is triggered. A run in progress is finished
+    /// first; runs missed meanwhile are skipped, so that runs never overlap.
+    async fn run(&self, mut hd: HiDrive, cancel: CancellationToken) {
+        let mut failures = 0;
+        let mut next = if self.run_at_start {
+            OffsetDateTime::now_utc()
+        } else {
+            self.schedule.next_after(OffsetDateTime::now_utc())
+        };
+        loop {
+            let wait = Duration::try_from(next - OffsetDateTime::now_utc()).unwrap_or_default();
+            tokio::select! {
+                _ = cancel.cancelled() => return,
+                _ = tokio::time::sleep(wait) => (),
+            }
+            match self.run_once(&mut hd).await {
+                Ok(s) if s.failed == 0 => {
+                    failures = 0;
+                    info!(target: "hd_api::sync", "job {}: {}", self.name, s);
+                }
+                Ok(s) => {
+                    failures += 1;
+                    warn!(target: "hd_api::sync", "job {}: {}", self.name, s);
+                }
+                Err(e) => {
+                    failures += 1;
+                    warn!(target: "hd_api::sync", "job {} failed: {:#}", self.name, e);
+                }
+            }
+            let now = OffsetDateTime::now_utc();
+            next = self
+                .schedule
+                .next_after(now)
+                .max(now + self.backoff.delay(failures));
+        }
+    }
+}
+
+/// Runs `SyncJob`s on their schedules, each on its own fork of a `HiDrive` (see
+/// `HiDrive::fork()`), so that jobs run independently of each other.
+///
+/// ```ignore
+/// let remote = Identifier::Path("/users/me/photos".into());
+/// let job = SyncJob::new(
+///     "photos",
+///     JobKind::MirrorUp { local: "/photos".into(), remote },
+///     JobSchedule::daily(&[time!(03:00)]),
+/// );
+/// SyncRunner::new().job(job).run(&hd, cancel).await;
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct SyncRunner {
+    jobs: Vec<SyncJob>,
+}
+
+impl SyncRunner {
+    pub fn new() -> SyncRunner {
+        SyncRunner::default()
+    }
+
+    pub fn job(mut self, job: SyncJob) -> SyncRunner {
+        self.jobs.push(job);
+        self
+    }
+
+    /// Run all jobs until `cancel` is triggered and runs in progress are finished.
+    pub async fn run(&self, hd: &HiDrive, cancel: CancellationToken) {
+        join_all(
+            self.jobs
+                .iter()
+                .map(|job| job.run(hd.fork(), cancel.clone())),
+        )
+        .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::mock::{hidrive, MockTransport};
+    use crate::sync::SyncObserver;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use time::{Date, Month};
+
+    #[test]
+    fn test_next_after() {
+        let s = JobSchedule::At {
+            times: vec![
+                Time::from_hms(18, 0, 0).unwrap(),
+                Time::from_hms(3, 30, 0).unwrap(),
+            ],
+            weekdays: vec![Weekday::Monday, Weekday::Friday],
+            offset: UtcOffset::from_hms(2, 0, 0).unwrap(),
+        };
+        // April 22, 2024 is a Monday.
+        let at = |day, h, m, s| {
+            Date::from_calendar_date(2024, Month::April, day)
+                .unwrap()
+                .with_hms(h, m, s)
+                .unwrap()
+                .assume_utc()
+        };
+        // 01:00 UTC is 03:00 local.
+        let t = at(22, 1, 0, 0);
+        assert_eq!(at(22, 1, 30, 0), s.next_after(t));
+        assert_eq!(at(22, 16, 0, 0), s.next_after(at(22, 1, 30, 0)));
+        assert_eq!(at(26, 1, 30, 0), s.next_after(at(22, 16, 0, 0)));
+        let every = JobSchedule::Every(Duration::from_secs(90));
+        assert_eq!(at(22, 1, 1, 30), every.next_after(t));
+    }
+
+    #[test]
+    fn test_backoff() {
+        let b = Backoff {
+            initial: Duration::from_secs(10),
+            max: Duration::from_secs(60),
+        };
+        assert_eq!(Duration::ZERO, b.delay(0));
+        assert_eq!(Duration::from_secs(10), b.delay(1));
+        assert_eq!(Duration::from_secs(40), b.delay(3));
+        assert_eq!(Duration::from_secs(60), b.delay(4));
+        assert_eq!(Duration::from_secs(60), b.delay(100));
+    }
+
+    struct Runs(AtomicUsize);
+
+    impl SyncObserver for Runs {
+        fn on_complete(&self, _summary: &SyncSummary) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_runner() {
+        let root = std::env::temp_dir().join("hd_api_test_runner");
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        let t = MockTransport::new();
+        let hd = hidrive(t.clone());
+
+        let runs = Arc::new(Runs(AtomicUsize::new(0)));
+        let mut job = SyncJob::new(
+            "test",
+            JobKind::MirrorUp {
+                local: root.clone(),
+                remote: Identifier::Path("/m".into()),
+            },
+            JobSchedule::Every(Duration::from_millis(10)),
+        );
+        job.opts.progress = Some(runs.clone());
+        job.run_at_start = true;
+        let runner = SyncRunner::new().job(job);
+        let cancel = CancellationToken::new();
+        let c = cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            c.cancel();
+        });
+        runner.run(&hd, cancel).await;
+        // Each run lists the (empty) remote directory.
+        let n = runs.0.load(Ordering::SeqCst);
+        assert!(n >= 2, "{} runs", n);
+        assert_eq!(n, t.requests().len());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
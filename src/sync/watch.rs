@@ -0,0 +1,423 @@
+//! Continuous upload of local changes, see `watch_up()`.
+
+use super::{
+    execute, ignore_rules, mirror_up_, removed, Executor, MirrorOptions, MirrorReport, Operation,
+    SyncPlan,
+};
+use crate::hidrive::HiDrive;
+use crate::planner::{Side, Snapshot};
+use crate::types::{Identifier, Params};
+
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use notify::event::{EventKind, ModifyKind, RenameMode};
+use notify::{RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+/// Options of `watch_up()`.
+#[derive(Debug, Clone)]
+pub struct WatchOptions {
+    /// Options of the initial `mirror_up()` and of the uploads of changes.
+    pub mirror: MirrorOptions,
+    /// Wait until no changes have been observed for this long before uploading. Default: 2 s.
+    pub debounce: Duration,
+    /// Upload at the latest this long after the first change, even if changes continue.
+    /// Default: 30 s.
+    pub max_delay: Duration,
+    /// Start with a full `mirror_up()`. Disable if both trees are known to be in sync, e.g.
+    /// after `bisync()`. Default: true.
+    pub initial_sync: bool,
+}
+
+impl Default for WatchOptions {
+    fn default() -> WatchOptions {
+        WatchOptions {
+            mirror: MirrorOptions::default(),
+            debounce: Duration::from_secs(2),
+            max_delay: Duration::from_secs(30),
+            initial_sync: true,
+        }
+    }
+}
+
+/// Changes observed during one debounce period. Paths are relative to the watched directory.
+#[derive(Debug, Default)]
+struct Changes {
+    paths: BTreeSet<String>,
+    /// Renames in order; chains like `a -> b -> c` are coalesced into `a -> c`.
+    renames: Vec<(String, String)>,
+    /// Events were lost; the whole tree needs to be compared.
+    rescan: bool,
+}
+
+impl Changes {
+    fn add(&mut self, root: &Path, ev: notify::Result<notify::Event>) {
+        let ev = match ev {
+            Ok(ev) => ev,
+            Err(e) => {
+                warn!(target: "hd_api::sync", "watch_up: watcher error: {}", e);
+                self.rescan = true;
+                return;
+            }
+        };
+        if ev.need_rescan() {
+            self.rescan = true;
+        }
+        match ev.kind {
+            EventKind::Access(_) => return,
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if ev.paths.len() == 2 => {
+                if let (Some(from), Some(to)) =
+                    (relative(root, &ev.paths[0]), relative(root, &ev.paths[1]))
+                {
+                    self.rename(from, to);
+                }
+            }
+            _ => (),
+        }
+        self.paths
+            .extend(ev.paths.iter().filter_map(|p| relative(root, p)));
+    }
+
+    fn rename(&mut self, from: String, to: String) {
+        match self.renames.iter_mut().find(|(_, t)| *t == from) {
+            Some(r) => r.1 = to,
+            None => self.renames.push((from, to)),
+        }
+    }
+
+    /// Returns true if `path` is one of the changed paths or below one of them.
+    fn affects(&self, path: &str) -> bool {
+        self.paths.iter().any(|p| {
+            path == p || (path.starts_with(p.as_str()) && path.as_bytes()[p.len()] == b'/')
+        })
+    }
+}
+
+/// `path` relative to `root`, with `/` as separator.
+fn relative(root: &Path, path: &Path) -> Option<String> {
+    let parts = path
+        .strip_prefix(root)
+        .ok()?
+        .components()
+        .map(|c| c.as_os_str().to_str())
+        .collect::<Option<Vec<_>>>()?;
+    if parts.is_empty() {
+        None
+    } else {
+        Some(parts.join("/"))
+    }
+}
+
+/// Move the entries at and below `from` to `to`.
+fn rename_entries(s: &mut Snapshot, from: &str, to: &str) {
+    let prefix = format!("{}/", from);
+    let moved = |p: &String| *p == from || p.starts_with(&prefix);
+    let remap = |p: &str| format!("{}{}", to, &p[from.len()..]);
+    let files: Vec<String> = s.files.keys().filter(|p| moved(p)).cloned().collect();
+    for f in files {
+        let st = s.files.remove(&f).unwrap();
+        s.files.insert(remap(&f), st);
+    }
+    let dirs: Vec<String> = s.dirs.iter().filter(|p| moved(p)).cloned().collect();
+    for d in dirs {
+        s.dirs.remove(&d);
+        s.dirs.insert(remap(&d));
+    }
+}
+
+/// The operations applying the `changes` found in the local tree `new` to the remote tree,
+/// which corresponds to `prev`. Renames are carried out remotely instead of uploading the files
+/// again, unless `delete` is unset.
+fn plan_changes(
+    prev: &Snapshot,
+    new: &Snapshot,
+    changes: &Changes,
+    delete: bool,
+) -> Vec<Operation> {
+    let mut ops = vec![];
+    let mut base = prev.clone();
+    for (from, to) in changes.renames.iter().filter(|_| delete) {
+        let valid = if base.dirs.contains(from) {
+            new.dirs.contains(to) && !new.dirs.contains(from)
+        } else {
+            base.files.contains_key(from)
+                && new.files.contains_key(to)
+                && !new.files.contains_key(from)
+        };
+        let free = !base.dirs.contains(to) && !base.files.contains_key(to);
+        if from != to && valid && free {
+            rename_entries(&mut base, from, to);
+            ops.push(Operation::Rename {
+                from: from.clone(),
+                to: to.clone(),
+                side: Side::Remote,
+            });
+        }
+    }
+    if delete {
+        for (path, dir) in removed(&base, new) {
+            if changes.affects(&path) {
+                ops.push(Operation::Delete {
+                    path,
+                    side: Side::Remote,
+                    dir,
+                });
+            }
+        }
+    }
+    for d in new.dirs.iter() {
+        if !base.dirs.contains(d) && changes.affects(d) {
+            ops.push(Operation::CreateDir {
+                path: d.clone(),
+                side: Side::Remote,
+            });
+        }
+    }
+    for (rel, f) in new.files.iter() {
+        // Renamed files keep size and mtime, but not their mhash.
+        let unchanged = base
+            .files
+            .get(rel)
+            .is_some_and(|b| b.size == f.size && b.mtime == f.mtime);
+        if !unchanged && changes.affects(rel) {
+            ops.push(Operation::Upload {
+                path: rel.clone(),
+                bytes: f.size,
+            });
+        }
+    }
+    ops
+}
+
+/// Mirror `local_dir` to `remote_id` like `mirror_up()` (unless `opts.initial_sync` is unset),
+/// then keep watching `local_dir` and upload changes until `cancel` is triggered. Changes are
+/// collected until none have occurred for `opts.debounce`; renames are carried out remotely
+/// instead of uploading again. If some operations fail or file system events are lost, the next
+/// round of changes triggers a full `mirror_up()`. Ignore rules are read once, at the start.
+pub async fn watch_up(
+    hd: &mut HiDrive,
+    local_dir: impl AsRef<Path>,
+    remote_id: Identifier,
+    opts: &WatchOptions,
+    cancel: CancellationToken,
+) -> Result<()> {
+    let local_dir = local_dir.as_ref();
+    let local_dir = tokio::fs::canonicalize(local_dir)
+        .await
+        .with_context(|| format!("resolving {:?}", local_dir))?;
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |ev: notify::Result<notify::Event>| {
+        // Fails only once watch_up() has returned.
+        let _ = tx.send(ev);
+    })?;
+    watcher.watch(&local_dir, RecursiveMode::Recursive)?;
+
+    let mopts = &opts.mirror;
+    let ignore = ignore_rules(&local_dir, mopts).await?;
+    let (root, mut prev, mut resync) = if opts.initial_sync {
+        let (report, root, local) = mirror_up_(hd, &local_dir, remote_id, mopts).await?;
+        info!(target: "hd_api::sync", "watch_up: {:?} -> {}: {}", local_dir, root, report);
+        (root, local, !report.failed.is_empty())
+    } else {
+        let mut p = Params::new();
+        p.add_str("fields", "path");
+        let root = hd.files().get_dir(remote_id, Some(&p)).await?.path;
+        (
+            root,
+            Snapshot::scan_with_links(&local_dir, &ignore, mopts.symlinks).await?,
+            false,
+        )
+    };
+
+    loop {
+        let mut changes = Changes::default();
+        tokio::select! {
+            _ = cancel.cancelled() => return Ok(()),
+            ev = rx.recv() => changes.add(&local_dir, ev.context("file watcher stopped")?),
+        }
+        let deadline = Instant::now() + opts.max_delay;
+        loop {
+            let quiet = opts
+                .debounce
+                .min(deadline.saturating_duration_since(Instant::now()));
+            tokio::select! {
+                _ = cancel.cancelled() => return Ok(()),
+                ev = rx.recv() => changes.add(&local_dir, ev.context("file watcher stopped")?),
+                _ = tokio::time::sleep(quiet) => break,
+            }
+        }
+
+        if resync || changes.rescan {
+            let id = Identifier::Path(root.clone());
+            match mirror_up_(hd, &local_dir, id, mopts).await {
+                Ok((report, _, local)) => {
+                    info!(
+                        target: "hd_api::sync",
+                        "watch_up: {:?} -> {}: {}",
+                        local_dir,
+                        root,
+                        report
+                    );
+                    resync = !report.failed.is_empty();
+                    prev = local;
+                }
+                Err(e) => warn!(target: "hd_api::sync", "watch_up: resync failed: {:#}", e),
+            }
+            continue;
+        }
+        let mut new = match Snapshot::scan_with_links(&local_dir, &ignore, mopts.symlinks).await {
+            Ok(s) => s,
+            Err(e) => {
+                warn!(target: "hd_api::sync", "watch_up: scan failed: {:#}", e);
+                resync = true;
+                continue;
+            }
+        };
+        let operations = plan_changes(&prev, &new, &changes, mopts.delete);
+        if !operations.is_empty() {
+            // The remote tree matches `prev`, spelled as in `prev.remote_names`.
+            let mut ex = Executor::new(&root, &local_dir, &new, &prev, mopts);
+            let mut report = MirrorReport {
+                plan: SyncPlan { operations },
+                ..Default::default()
+            };
+            execute(hd, &mut ex, &mut report, mopts, None).await?;
+            info!(target: "hd_api::sync", "watch_up: {:?} -> {}: {}", local_dir, root, report);
+            resync = !report.failed.is_empty();
+        }
+        new.remote_names = std::mem::take(&mut prev.remote_names);
+        prev = new;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::test_snapshot;
+
+    use notify::event::CreateKind;
+    use notify::Event;
+
+    fn snapshot(files: &[(&str, u64)], dirs: &[&str]) -> Snapshot {
+        let files: Vec<_> = files.iter().map(|(p, size)| (*p, *size, 1000)).collect();
+        test_snapshot(&files, dirs)
+    }
+
+    #[test]
+    fn test_changes() {
+        let root = Path::new("/w");
+        let rename = |from: &str, to: &str| {
+            Ok(
+                Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::Both)))
+                    .add_path(root.join(from))
+                    .add_path(root.join(to)),
+            )
+        };
+        let mut c = Changes::default();
+        c.add(root, rename("a", "b"));
+        c.add(root, rename("b", "d/c"));
+        c.add(
+            root,
+            Ok(Event::new(EventKind::Create(CreateKind::File)).add_path(root.join("x/y"))),
+        );
+        c.add(
+            root,
+            Ok(
+                Event::new(EventKind::Access(notify::event::AccessKind::Any))
+                    .add_path(root.join("z")),
+            ),
+        );
+        c.add(
+            root,
+            Ok(Event::new(EventKind::Create(CreateKind::Any)).add_path("/elsewhere/q".into())),
+        );
+        assert_eq!(vec![("a".to_string(), "d/c".to_string())], c.renames);
+        assert_eq!(
+            vec!["a", "b", "d/c", "x/y"],
+            c.paths.iter().map(String::as_str).collect::<Vec<_>>()
+        );
+        assert!(c.affects("x/y"));
+        assert!(c.affects("d/c/e"));
+        assert!(!c.affects("x"));
+        assert!(!c.affects("d/cc"));
+        assert!(!c.rescan);
+    }
+
+    #[test]
+    fn test_plan_changes() {
+        let prev = snapshot(&[("a", 1), ("dir/f", 2), ("old", 3), ("same", 4)], &["dir"]);
+        let mut new = snapshot(
+            &[
+                ("b", 1),
+                ("moved/f", 2),
+                ("same", 4),
+                ("sub/new", 5),
+                ("old2", 3),
+            ],
+            &["moved", "sub"],
+        );
+        new.files.get_mut("old2").unwrap().mtime = 2000;
+        let mut c = Changes::default();
+        for p in [
+            "a", "b", "dir", "moved", "old", "old2", "sub", "sub/new", "same",
+        ] {
+            c.paths.insert(p.to_string());
+        }
+        c.rename("a".into(), "b".into());
+        c.rename("dir".into(), "moved".into());
+        c.rename("old".into(), "old2".into());
+
+        let rename = |from: &str, to: &str| Operation::Rename {
+            from: from.into(),
+            to: to.into(),
+            side: Side::Remote,
+        };
+        let ops = plan_changes(&prev, &new, &c, true);
+        assert_eq!(
+            vec![
+                rename("a", "b"),
+                rename("dir", "moved"),
+                rename("old", "old2"),
+                Operation::CreateDir {
+                    path: "sub".into(),
+                    side: Side::Remote,
+                },
+                // Renamed, then modified.
+                Operation::Upload {
+                    path: "old2".into(),
+                    bytes: 3,
+                },
+                Operation::Upload {
+                    path: "sub/new".into(),
+                    bytes: 5,
+                },
+            ],
+            ops
+        );
+
+        // Without deletions, renamed files are uploaded again.
+        let ops = plan_changes(&prev, &new, &c, false);
+        assert_eq!(6, ops.len());
+        assert!(ops
+            .iter()
+            .all(|o| matches!(o, Operation::Upload { .. } | Operation::CreateDir { .. })));
+
+        // Deleted files are only removed if they were affected by an event.
+        let mut c = Changes::default();
+        c.paths.insert("dir".to_string());
+        let ops = plan_changes(&prev, &snapshot(&[], &[]), &c, true);
+        assert_eq!(
+            vec![Operation::Delete {
+                path: "dir".into(),
+                side: Side::Remote,
+                dir: true,
+            }],
+            ops
+        );
+    }
+}
@@ -0,0 +1,607 @@
+//! An in-memory HiDrive server for integration tests without real credentials, see
+//! `MockHiDrive`. Enabled by the `test-util` feature.
+
+use crate::hashing;
+use crate::hidrive::{Endpoints, HiDrive};
+use crate::oauth2;
+use crate::types::{FileHash, HashedBlock, Item, Share, User};
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use hyper::service::{make_service_fn, service_fn};
+use reqwest::Method;
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+/// The home directory of the mock user. Paths given to `MockHiDrive`'s methods are relative to
+/// it.
+pub const HOME: &str = "/m";
+/// The access token issued by the mock's OAuth2 token endpoint, and required by its API.
+pub const ACCESS_TOKEN: &str = "mock-access-token";
+
+const TOKEN_RESPONSE: &str = r#"{
+  "refresh_token": "mock-refresh-token",
+  "expires_in": 3600,
+  "userid": "12345.12345.12345",
+  "access_token": "mock-access-token",
+  "alias": "mock",
+  "token_type": "Bearer",
+  "scope": "rw,user"
+}"#;
+
+#[derive(Default)]
+struct Tree {
+    /// Contents and mtime of files by absolute path.
+    files: BTreeMap<String, (Vec<u8>, i64)>,
+    dirs: HashSet<String>,
+    /// Requests to fail once with 500, by method and target path.
+    failures: Vec<(Method, String)>,
+    sharelinks: BTreeMap<String, Share>,
+    next_id: usize,
+}
+
+impl Tree {
+    fn exists(&self, path: &str) -> bool {
+        self.files.contains_key(path) || self.dirs.contains(path)
+    }
+
+    /// Create the directory `path` below `HOME` and its parents.
+    fn mkdirs(&mut self, path: &str) {
+        let mut p = path;
+        while p.len() > HOME.len() {
+            self.dirs.insert(p.to_string());
+            p = parent(p);
+        }
+    }
+
+    /// `path`, or with a number appended to the name if that exists already.
+    fn autoname(&self, path: &str) -> String {
+        let (stem, ext) = match path.rsplit_once('.') {
+            Some((s, e)) if !s.ends_with('/') && !e.contains('/') => (s, format!(".{}", e)),
+            _ => (path, String::new()),
+        };
+        let mut candidate = path.to_string();
+        let mut i = 0;
+        while self.exists(&candidate) {
+            i += 1;
+            candidate = format!("{} ({}){}", stem, i, ext);
+        }
+        candidate
+    }
+
+    /// Move or copy everything at or below `from` to `to`.
+    fn transfer(&mut self, from: &str, to: &str, keep: bool) {
+        let below = |p: &str| p == from || p.starts_with(&format!("{}/", from));
+        let rename = |p: &str| format!("{}{}", to, &p[from.len()..]);
+        let files: Vec<String> = self.files.keys().filter(|p| below(p)).cloned().collect();
+        for f in files {
+            let v = if keep {
+                self.files[&f].clone()
+            } else {
+                self.files.remove(&f).unwrap()
+            };
+            self.files.insert(rename(&f), v);
+        }
+        let dirs: Vec<String> = self.dirs.iter().filter(|p| below(p)).cloned().collect();
+        for d in dirs {
+            if !keep {
+                self.dirs.remove(&d);
+            }
+            self.dirs.insert(rename(&d));
+        }
+    }
+}
+
+/// The absolute path of `rel` below `HOME`.
+fn abs(rel: &str) -> String {
+    if rel.is_empty() {
+        HOME.into()
+    } else {
+        join(HOME, rel)
+    }
+}
+
+fn join(base: &str, rel: &str) -> String {
+    format!("{}/{}", base.trim_end_matches('/'), rel)
+}
+
+fn parent(path: &str) -> &str {
+    path.rsplit_once('/').map_or("", |(p, _)| p)
+}
+
+fn name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+async fn file_item(path: &str, data: &[u8], mtime: i64) -> Item {
+    Item {
+        path: path.to_string(),
+        name: Some(name(path).to_string()),
+        typ: Some("file".into()),
+        size: Some(data.len()),
+        mtime: time::OffsetDateTime::from_unix_timestamp(mtime).ok(),
+        chash: Some(hashing::chash(data).await.unwrap().top_hash().clone()),
+        ..Default::default()
+    }
+}
+
+fn dir_item(path: &str) -> Item {
+    Item {
+        path: path.to_string(),
+        name: Some(name(path).to_string()),
+        typ: Some("dir".into()),
+        ..Default::default()
+    }
+}
+
+fn json<T: Serialize>(v: &T) -> (u16, Vec<u8>) {
+    (200, serde_json::to_vec(v).unwrap())
+}
+
+fn status(code: u16) -> (u16, Vec<u8>) {
+    (code, br#"{"code": "error", "msg": "fake error"}"#.to_vec())
+}
+
+/// Parse the query parameter `k`, if present. A malformed value results in an error 400.
+fn parse_param<T: std::str::FromStr>(
+    q: &HashMap<String, String>,
+    k: &str,
+) -> Result<Option<T>, (u16, Vec<u8>)> {
+    q.get(k)
+        .map(|v| v.parse())
+        .transpose()
+        .map_err(|_| status(400))
+}
+
+/// Parse a `Range` header value `bytes=a-b` or `bytes=a-` into `a` and the optional `b`.
+fn parse_range(v: &str) -> Option<(usize, Option<usize>)> {
+    let (a, b) = v.strip_prefix("bytes=")?.split_once('-')?;
+    let b = match b {
+        "" => None,
+        b => Some(b.parse().ok()?),
+    };
+    Some((a.parse().ok()?, b))
+}
+
+/// An in-memory HiDrive server on a local port, serving the OAuth2 token endpoint, `/user/me`,
+/// `/dir`, `/file` (including copy, move and hashes), `/meta` and `/sharelink`. API requests
+/// must carry `ACCESS_TOKEN`.
+///
+/// ```ignore
+/// let (mock, mut hd) = MockHiDrive::start().await;
+/// mock.put("a.txt", "hello", 0).await;
+/// let it = hd.files().get_dir(Identifier::Path(test_util::HOME.into()), None).await?;
+/// ```
+pub struct MockHiDrive {
+    tree: Mutex<Tree>,
+}
+
+impl MockHiDrive {
+    /// Start a server, returning it and a `HiDrive` talking to it.
+    pub async fn start() -> (Arc<MockHiDrive>, HiDrive) {
+        let mut tree = Tree::default();
+        tree.dirs.insert(HOME.into());
+        let fake = Arc::new(MockHiDrive {
+            tree: Mutex::new(tree),
+        });
+        let f = fake.clone();
+        let mk = make_service_fn(move |_: &hyper::server::conn::AddrStream| {
+            let f = f.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |rq: hyper::Request<hyper::Body>| {
+                    let f = f.clone();
+                    async move { Ok::<_, Infallible>(f.serve(rq).await) }
+                }))
+            }
+        });
+        let srv = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(mk);
+        let addr: SocketAddr = srv.local_addr();
+        tokio::spawn(srv);
+
+        let cred: oauth2::Credentials = serde_json::from_str(TOKEN_RESPONSE).unwrap();
+        let authz = oauth2::Authorizer::new(cred, oauth2::ClientSecret::default());
+        let hd = HiDrive::builder(authz)
+            .endpoints(Endpoints::for_host(format!("http://{}", addr)))
+            .build()
+            .unwrap();
+        (fake, hd)
+    }
+
+    /// Create or replace the file `rel`, creating its parents.
+    pub async fn put(&self, rel: &str, content: impl AsRef<[u8]>, mtime: i64) {
+        let mut t = self.tree.lock().await;
+        let path = abs(rel);
+        t.mkdirs(parent(&path));
+        t.files.insert(path, (content.as_ref().to_vec(), mtime));
+    }
+
+    /// Create the directory `rel` and its parents.
+    pub async fn mkdir(&self, rel: &str) {
+        self.tree.lock().await.mkdirs(&abs(rel));
+    }
+
+    /// Remove the file `rel`.
+    pub async fn remove(&self, rel: &str) {
+        self.tree.lock().await.files.remove(&abs(rel));
+    }
+
+    /// The contents of the file `rel`.
+    pub async fn read(&self, rel: &str) -> Option<String> {
+        let t = self.tree.lock().await;
+        t.files
+            .get(&abs(rel))
+            .map(|(d, _)| String::from_utf8_lossy(d).into_owned())
+    }
+
+    /// The files below `HOME`, relative to it.
+    pub async fn files(&self) -> Vec<String> {
+        let t = self.tree.lock().await;
+        let prefix = format!("{}/", HOME);
+        t.files
+            .keys()
+            .filter_map(|p| p.strip_prefix(&prefix).map(String::from))
+            .collect()
+    }
+
+    /// The sharelinks created through the API.
+    pub async fn sharelinks(&self) -> Vec<Share> {
+        self.tree
+            .lock()
+            .await
+            .sharelinks
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    /// Answer the next `method` (e.g. "PUT") request concerning `rel` with an error 500.
+    pub async fn fail_once(&self, method: &str, rel: &str) {
+        let method = Method::from_bytes(method.as_bytes()).expect("HTTP method");
+        let mut t = self.tree.lock().await;
+        t.failures.push((method, abs(rel)));
+    }
+
+    async fn serve(&self, rq: hyper::Request<hyper::Body>) -> hyper::Response<hyper::Body> {
+        let url = reqwest::Url::parse(&format!("http://fake{}", rq.uri())).unwrap();
+        let q: HashMap<String, String> = url.query_pairs().into_owned().collect();
+        let method = rq.method().clone();
+        let range = rq
+            .headers()
+            .get("range")
+            .map(|v| v.to_str().ok().and_then(parse_range));
+        let authorized = rq
+            .headers()
+            .get("authorization")
+            .is_some_and(|v| v.as_bytes() == format!("Bearer {}", ACCESS_TOKEN).as_bytes());
+        let body = hyper::body::to_bytes(rq.into_body()).await.unwrap();
+        let (code, body) = if url.path().ends_with("/oauth2/token") {
+            let grant = q.get("grant_type").map(String::as_str);
+            if method == Method::POST
+                && matches!(grant, Some("refresh_token" | "authorization_code"))
+            {
+                (200, TOKEN_RESPONSE.as_bytes().to_vec())
+            } else {
+                status(400)
+            }
+        } else if !authorized {
+            status(401)
+        } else if range == Some(None) {
+            status(400)
+        } else {
+            let endpoint = url.path().trim_start_matches("/2.1");
+            self.handle(&method, endpoint, &q, range.flatten(), &body)
+                .await
+        };
+        hyper::Response::builder()
+            .status(code)
+            .header("content-type", "application/json")
+            .body(body.into())
+            .unwrap()
+    }
+
+    async fn handle(
+        &self,
+        method: &Method,
+        endpoint: &str,
+        q: &HashMap<String, String>,
+        range: Option<(usize, Option<usize>)>,
+        body: &[u8],
+    ) -> (u16, Vec<u8>) {
+        let param = |k: &str| q.get(k).cloned().unwrap_or_default();
+        let target = match (q.get("dir"), q.get("name")) {
+            (Some(d), Some(n)) => join(d, n),
+            _ => q.get("path").or(q.get("src")).cloned().unwrap_or_default(),
+        };
+        let mut t = self.tree.lock().await;
+        if let Some(i) = t
+            .failures
+            .iter()
+            .position(|(m, p)| m == method && *p == target)
+        {
+            t.failures.remove(i);
+            return status(500);
+        }
+        let mtime = match parse_param(q, "mtime") {
+            Ok(m) => m,
+            Err(e) => return e,
+        };
+        match (method.as_str(), endpoint) {
+            ("GET", "/dir") => {
+                if !t.dirs.contains(&target) {
+                    return status(404);
+                }
+                let mut it = dir_item(&target);
+                for d in t.dirs.iter().filter(|d| parent(d) == target) {
+                    it.members.push(dir_item(d));
+                }
+                for (f, (data, mtime)) in t.files.iter().filter(|(f, _)| parent(f) == target) {
+                    it.members.push(file_item(f, data, *mtime).await);
+                }
+                json(&it)
+            }
+            ("POST", "/dir") => {
+                if t.exists(&target) {
+                    return status(409);
+                }
+                if !t.dirs.contains(parent(&target)) {
+                    return status(404);
+                }
+                t.dirs.insert(target.clone());
+                json(&dir_item(&target))
+            }
+            ("DELETE", "/dir") => {
+                if !t.dirs.contains(&target) {
+                    return status(404);
+                }
+                let below = format!("{}/", target);
+                t.files.retain(|f, _| !f.starts_with(&below));
+                t.dirs.retain(|d| *d != target && !d.starts_with(&below));
+                (204, vec![])
+            }
+            ("PUT" | "POST", "/file") => {
+                if !t.dirs.contains(parent(&target)) {
+                    return status(404);
+                }
+                if *method == Method::POST && t.exists(&target) {
+                    return status(409);
+                }
+                let mtime = mtime.unwrap_or(0);
+                t.files.insert(target.clone(), (body.to_vec(), mtime));
+                json(&file_item(&target, body, mtime).await)
+            }
+            ("GET", "/file") => match (t.files.get(&target), range) {
+                (None, _) => status(404),
+                (Some((data, _)), None) => (200, data.clone()),
+                (Some((data, _)), Some((a, b))) => {
+                    let end = b.map_or(data.len(), |b| usize::min(b + 1, data.len()));
+                    if a >= end {
+                        return status(416);
+                    }
+                    (206, data[a..end].to_vec())
+                }
+            },
+            ("PATCH", "/file") => {
+                let offset: usize = match parse_param(q, "offset") {
+                    Ok(Some(o)) => o,
+                    _ => return status(400),
+                };
+                let (data, old) = match t.files.get_mut(&target) {
+                    Some(f) => f,
+                    None => return status(404),
+                };
+                if data.len() < offset + body.len() {
+                    data.resize(offset + body.len(), 0);
+                }
+                data[offset..offset + body.len()].copy_from_slice(body);
+                *old = mtime.unwrap_or(*old);
+                let (data, mtime) = (data.clone(), *old);
+                json(&file_item(&target, &data, mtime).await)
+            }
+            ("DELETE", "/file") => match t.files.remove(&target) {
+                Some(_) => (204, vec![]),
+                None => status(404),
+            },
+            ("POST", "/file/copy" | "/file/move" | "/dir/move") => {
+                let dst = param("dst");
+                if !t.exists(&target) {
+                    return status(404);
+                }
+                let dst = match param("on_exist").as_str() {
+                    "autoname" => t.autoname(&dst),
+                    "overwrite" => dst,
+                    _ if t.exists(&dst) => return status(409),
+                    _ => dst,
+                };
+                t.transfer(&target, &dst, endpoint == "/file/copy");
+                match t.files.get(&dst) {
+                    Some((data, mtime)) => json(&file_item(&dst, data, *mtime).await),
+                    None => json(&dir_item(&dst)),
+                }
+            }
+            ("GET", "/meta") => match t.files.get(&target) {
+                Some((data, mtime)) => json(&file_item(&target, data, *mtime).await),
+                None if t.dirs.contains(&target) => json(&dir_item(&target)),
+                None => status(404),
+            },
+            ("GET", "/file/hash") => {
+                let data = match t.files.get(&target) {
+                    Some((data, _)) => data.clone(),
+                    None => return status(404),
+                };
+                let hashes = hashing::chash(&data[..]).await.unwrap();
+                let level: usize = match parse_param(q, "level") {
+                    Ok(Some(l)) => l,
+                    _ => return status(400),
+                };
+                let hs = hashes.level(level).unwrap_or_default();
+                let mut fh = FileHash {
+                    level,
+                    chash: hashes.top_hash().clone(),
+                    list: vec![],
+                };
+                for r in param("ranges").split(',').filter(|r| *r != "-") {
+                    let (a, b) = match r.split_once('-').map(|(a, b)| (a.parse(), b.parse())) {
+                        Some((Ok(a), Ok(b))) => (a, b),
+                        _ => return status(400),
+                    };
+                    fh.list.push(
+                        (a..=b)
+                            .filter_map(|i| {
+                                hs.get(i).map(|h| HashedBlock {
+                                    hash: h.clone(),
+                                    level,
+                                    block: i,
+                                })
+                            })
+                            .collect(),
+                    );
+                }
+                json(&fh)
+            }
+            ("GET", "/user/me") => json(&User {
+                account: "mock".into(),
+                alias: "mock".into(),
+                home: format!("root{}", HOME),
+                folder: dir_item(HOME),
+                ..Default::default()
+            }),
+            ("GET", "/sharelink") => match q.get("id") {
+                Some(id) => match t.sharelinks.get(id) {
+                    Some(s) => json(s),
+                    None => status(404),
+                },
+                None => json(&t.sharelinks.values().collect::<Vec<_>>()),
+            },
+            ("POST", "/sharelink") => {
+                let size = match t.files.get(&target) {
+                    Some((data, _)) => Some(data.len()),
+                    None if t.dirs.contains(&target) => None,
+                    None => return status(404),
+                };
+                let (ttl, maxcount) = match (parse_param(q, "ttl"), parse_param(q, "maxcount")) {
+                    (Ok(ttl), Ok(maxcount)) => (ttl, maxcount),
+                    _ => return status(400),
+                };
+                t.next_id += 1;
+                let id = format!("s{}", t.next_id);
+                let share = Share {
+                    name: Some(name(&target).to_string()),
+                    path: Some(target.clone()),
+                    id: Some(id.clone()),
+                    size,
+                    status: Some("valid".into()),
+                    file_type: Some(if size.is_some() { "file" } else { "dir" }.into()),
+                    created: Some(time::OffsetDateTime::now_utc()),
+                    ttl,
+                    maxcount,
+                    has_password: Some(q.contains_key("password")),
+                    uri: Some(format!("https://mock.invalid/share/{}", id)),
+                    count: Some(0),
+                    ..Default::default()
+                };
+                let r = json(&share);
+                t.sharelinks.insert(id, share);
+                r
+            }
+            ("DELETE", "/sharelink") => match t.sharelinks.remove(&param("id")) {
+                Some(_) => (204, vec![]),
+                None => status(404),
+            },
+            ("GET", "/user/me/quota") => {
+                let used: usize = t.files.values().map(|(d, _)| d.len()).sum();
+                json(&serde_json::json!({"limit": 1 << 20, "used": used}))
+            }
+            _ => status(404),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hidrive::NO_PARAMS;
+    use crate::types::{error_status, Identifier, Params};
+
+    #[tokio::test]
+    async fn test_mock_hidrive() {
+        let (mock, mut hd) = MockHiDrive::start().await;
+        mock.put("a.txt", "hello", 1000).await;
+        mock.mkdir("empty/sub").await;
+
+        let me = hd.user().me(None).await.unwrap();
+        assert_eq!("root/m", me.home);
+
+        let it = hd
+            .files()
+            .get_dir(Identifier::Path(HOME.into()), NO_PARAMS)
+            .await
+            .unwrap();
+        let mut names: Vec<_> = it.members.iter().map(|m| m.name.clone().unwrap()).collect();
+        names.sort();
+        assert_eq!(vec!["a.txt", "empty"], names);
+
+        let u = format!("{}/sharelink", hd.base_url());
+        let mut p = Params::new();
+        p.add_str("path", "/m/a.txt");
+        let share: Share = hd
+            .client()
+            .request(Method::POST, &u, &p, NO_PARAMS)
+            .await
+            .unwrap()
+            .go()
+            .await
+            .unwrap();
+        assert_eq!(Some(5), share.size);
+        assert_eq!(1, mock.sharelinks().await.len());
+        let mut p = Params::new();
+        p.add_str("id", share.id.as_deref().unwrap());
+        let got: Share = hd
+            .client()
+            .request(Method::GET, &u, &p, NO_PARAMS)
+            .await
+            .unwrap()
+            .go()
+            .await
+            .unwrap();
+        assert_eq!(share.uri, got.uri);
+
+        // Requests without the mock's access token are rejected.
+        let rp = reqwest::get(format!("{}/user/me", hd.base_url()))
+            .await
+            .unwrap();
+        assert_eq!(401, rp.status().as_u16());
+
+        mock.fail_once("GET", "a.txt").await;
+        let e = hd
+            .files()
+            .metadata(Identifier::Path("/m/a.txt".into()), "path", None)
+            .await
+            .unwrap_err();
+        assert_eq!(Some(500), error_status(&e));
+
+        // Ranges are clamped to the file size, malformed requests are rejected.
+        let u = format!("{}/file?path=/m/a.txt", hd.base_url());
+        let get = |range: &'static str| {
+            reqwest::Client::new()
+                .get(&u)
+                .bearer_auth(ACCESS_TOKEN)
+                .header("range", range)
+                .send()
+        };
+        let rp = get("bytes=3-99").await.unwrap();
+        assert_eq!(206, rp.status().as_u16());
+        assert_eq!("lo", rp.text().await.unwrap());
+        assert_eq!(416, get("bytes=5-").await.unwrap().status().as_u16());
+        assert_eq!(400, get("bytes=x-1").await.unwrap().status().as_u16());
+        let rp = reqwest::Client::new()
+            .patch(format!("{}&offset=x", u))
+            .bearer_auth(ACCESS_TOKEN)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(400, rp.status().as_u16());
+    }
+}
@@ -0,0 +1,298 @@
+//! Bandwidth limiting for uploads and downloads.
+//!
+//! A `Throttle` is a token bucket shared by all transfers of a client (see
+//! `HiDrive::set_throttle()`). Downloads and uploads of in-memory bodies are throttled by the
+//! client; streamed uploads must be wrapped using `Throttle::stream()`. A `BandwidthSchedule`
+//! selects the rate depending on the time of day.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures_util::Stream;
+use time::{OffsetDateTime, Time, UtcOffset};
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+/// Size of chunks read by `ThrottledStream`.
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug)]
+struct Bucket {
+    rate: Option<u64>,
+    /// Available bytes; negative if transfers are ahead of the rate.
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Limits the rate of transfers to a number of bytes per second. Up to one second worth of
+/// bytes can be transferred at once after idle periods. A rate of zero pauses transfers until
+/// the rate is changed.
+#[derive(Debug)]
+pub struct Throttle {
+    bucket: Mutex<Bucket>,
+    changed: Notify,
+}
+
+impl Throttle {
+    /// Create a throttle allowing `rate` bytes per second; `None` means unlimited.
+    pub fn new(rate: Option<u64>) -> Throttle {
+        Throttle {
+            bucket: Mutex::new(Bucket {
+                rate,
+                tokens: rate.unwrap_or(0) as f64,
+                updated: Instant::now(),
+            }),
+            changed: Notify::new(),
+        }
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        self.bucket.lock().unwrap().rate
+    }
+
+    /// Change the rate, taking effect for subsequently transferred bytes.
+    pub fn set_rate(&self, rate: Option<u64>) {
+        let mut b = self.bucket.lock().unwrap();
+        if b.rate != rate {
+            let cap = rate.unwrap_or(0) as f64;
+            // Start with a full bucket when switching from unlimited.
+            b.tokens = if b.rate.is_none() {
+                cap
+            } else {
+                b.tokens.min(cap)
+            };
+            b.rate = rate;
+            b.updated = Instant::now();
+            self.changed.notify_waiters();
+        }
+    }
+
+    /// Wait until transfers are not paused (by a rate of zero).
+    pub async fn resumed(&self) {
+        loop {
+            // Created before checking, so that a change in between isn't missed.
+            let changed = self.changed.notified();
+            if self.rate() != Some(0) {
+                return;
+            }
+            changed.await;
+        }
+    }
+
+    /// Account for `bytes` being transferred and return how long to wait before transferring
+    /// more. Doesn't wait while paused, see `resumed()`.
+    pub fn reserve(&self, bytes: u64) -> Duration {
+        let mut b = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        let rate = match b.rate {
+            None | Some(0) => {
+                b.updated = now;
+                return Duration::ZERO;
+            }
+            Some(r) => r as f64,
+        };
+        let elapsed = now.duration_since(b.updated).as_secs_f64();
+        b.tokens = (b.tokens + elapsed * rate).min(rate) - bytes as f64;
+        b.updated = now;
+        if b.tokens < 0. {
+            Duration::from_secs_f64(-b.tokens / rate)
+        } else {
+            Duration::ZERO
+        }
+    }
+
+    /// Account for `bytes` being transferred, waiting as long as necessary to keep the rate, and
+    /// while paused.
+    pub async fn consume(&self, bytes: u64) {
+        self.resumed().await;
+        let wait = self.reserve(bytes);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Wrap `r` into a stream of chunks throttled by `self`, e.g. for streamed uploads:
+    /// `reqwest::Body::wrap_stream(throttle.stream(file))`.
+    pub fn stream<R: AsyncRead + Unpin>(self: &Arc<Self>, r: R) -> ThrottledStream<R> {
+        ThrottledStream {
+            inner: r,
+            throttle: self.clone(),
+            buf: vec![0; STREAM_CHUNK_SIZE],
+            wait: None,
+        }
+    }
+}
+
+/// A stream of chunks read from an `AsyncRead`, see `Throttle::stream()`.
+pub struct ThrottledStream<R> {
+    inner: R,
+    throttle: Arc<Throttle>,
+    buf: Vec<u8>,
+    wait: Option<Pin<Box<dyn Future<Output = ()> + Send>>>,
+}
+
+impl<R: AsyncRead + Unpin> Stream for ThrottledStream<R> {
+    type Item = std::io::Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(ref mut w) = this.wait {
+                if w.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                this.wait = None;
+            }
+            if this.throttle.rate() != Some(0) {
+                break;
+            }
+            let t = this.throttle.clone();
+            this.wait = Some(Box::pin(async move { t.resumed().await }));
+        }
+        let mut rb = ReadBuf::new(&mut this.buf);
+        match Pin::new(&mut this.inner).poll_read(cx, &mut rb) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Err(e)) => Poll::Ready(Some(Err(e))),
+            Poll::Ready(Ok(())) if rb.filled().is_empty() => Poll::Ready(None),
+            Poll::Ready(Ok(())) => {
+                let chunk = Bytes::copy_from_slice(rb.filled());
+                let wait = this.throttle.reserve(chunk.len() as u64);
+                if !wait.is_zero() {
+                    this.wait = Some(Box::pin(tokio::time::sleep(wait)));
+                }
+                Poll::Ready(Some(Ok(chunk)))
+            }
+        }
+    }
+}
+
+/// A period of the day with its own transfer rate. If `start` is after `end`, the window
+/// extends past midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeWindow {
+    pub start: Time,
+    pub end: Time,
+    /// Bytes per second, `None` for unlimited.
+    pub rate: Option<u64>,
+}
+
+impl TimeWindow {
+    pub fn contains(&self, t: Time) -> bool {
+        if self.start <= self.end {
+            self.start <= t && t < self.end
+        } else {
+            t >= self.start || t < self.end
+        }
+    }
+}
+
+/// Transfer rates by time of day, e.g. unlimited at night and 1 MB/s during the day:
+///
+/// ```ignore
+/// let s = BandwidthSchedule::new(Some(1_000_000))
+///     .with_offset(UtcOffset::from_hms(2, 0, 0)?)
+///     .window(time!(22:00), time!(07:00), None);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BandwidthSchedule {
+    /// Time zone the windows are specified in (default: UTC).
+    pub offset: UtcOffset,
+    pub windows: Vec<TimeWindow>,
+    /// Rate outside of all windows.
+    pub default_rate: Option<u64>,
+}
+
+impl BandwidthSchedule {
+    pub fn new(default_rate: Option<u64>) -> BandwidthSchedule {
+        BandwidthSchedule {
+            offset: UtcOffset::UTC,
+            windows: vec![],
+            default_rate,
+        }
+    }
+
+    pub fn with_offset(mut self, offset: UtcOffset) -> BandwidthSchedule {
+        self.offset = offset;
+        self
+    }
+
+    /// Add a window. Earlier windows take precedence where windows overlap.
+    pub fn window(mut self, start: Time, end: Time, rate: Option<u64>) -> BandwidthSchedule {
+        self.windows.push(TimeWindow { start, end, rate });
+        self
+    }
+
+    /// The rate in effect at `t`.
+    pub fn rate_at(&self, t: OffsetDateTime) -> Option<u64> {
+        let t = t.to_offset(self.offset).time();
+        self.windows
+            .iter()
+            .find(|w| w.contains(t))
+            .map(|w| w.rate)
+            .unwrap_or(self.default_rate)
+    }
+
+    /// The rate in effect now.
+    pub fn current_rate(&self) -> Option<u64> {
+        self.rate_at(OffsetDateTime::now_utc())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures_util::StreamExt;
+
+    #[test]
+    fn test_schedule() {
+        let h = |h| Time::from_hms(h, 0, 0).unwrap();
+        let s = BandwidthSchedule::new(Some(1000))
+            .with_offset(UtcOffset::from_hms(2, 0, 0).unwrap())
+            .window(h(22), h(7), None)
+            .window(h(12), h(13), Some(5000));
+        let at = |hour| OffsetDateTime::UNIX_EPOCH.replace_time(h(hour));
+        // 21:00 UTC is 23:00 local.
+        assert_eq!(s.rate_at(at(21)), None);
+        assert_eq!(s.rate_at(at(4)), None);
+        assert_eq!(s.rate_at(at(5)), Some(1000));
+        assert_eq!(s.rate_at(at(10)), Some(5000));
+        assert_eq!(s.rate_at(at(11)), Some(1000));
+    }
+
+    #[tokio::test]
+    async fn test_throttle() {
+        let t = Arc::new(Throttle::new(Some(1000)));
+        // A full bucket allows a burst of one second.
+        assert_eq!(t.reserve(1000), Duration::ZERO);
+        let w = t.reserve(500);
+        assert!(w > Duration::from_millis(450) && w <= Duration::from_millis(500));
+        t.set_rate(None);
+        assert_eq!(t.reserve(1 << 30), Duration::ZERO);
+
+        t.set_rate(Some(1 << 20));
+        let chunks: Vec<_> = t.stream(&[7u8; 100_000][..]).collect().await;
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].as_ref().unwrap().len(), STREAM_CHUNK_SIZE);
+    }
+
+    #[tokio::test]
+    async fn test_pause() {
+        let t = Arc::new(Throttle::new(Some(0)));
+        let consumer = t.clone();
+        let consumed = tokio::spawn(async move { consumer.consume(100).await });
+        let mut stream = t.stream(&[7u8; 100][..]);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!consumed.is_finished());
+        assert!(futures_util::poll!(stream.next()).is_pending());
+
+        t.set_rate(Some(1000));
+        consumed.await.unwrap();
+        assert_eq!(100, stream.next().await.unwrap().unwrap().len());
+    }
+}
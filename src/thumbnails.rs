@@ -0,0 +1,215 @@
+//! A local cache of thumbnails (see `HiDriveFiles::thumbnail()`), for applications showing the
+//! same images again and again, such as galleries.
+//!
+//! Thumbnails are stored in a local directory, one subdirectory per item ID, and named after
+//! their size and the item's mtime: a modified item gets a new thumbnail, replacing the old one.
+
+use crate::hidrive::HiDrive;
+use crate::types::{Identifier, Item, Params};
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use filetime::FileTime;
+use log::info;
+use tokio::fs;
+
+/// The bounding box of a thumbnail, in pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ThumbnailSize {
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Serves thumbnails from a local directory, downloading them as needed.
+///
+/// ```ignore
+/// let cache = ThumbnailCache::new(&hd, "/home/me/.cache/hd/thumbnails");
+/// let path = cache.get(&item, ThumbnailSize { width: 256, height: 256 }).await?;
+/// ```
+pub struct ThumbnailCache {
+    hd: HiDrive,
+    dir: PathBuf,
+}
+
+/// A file name for the item ID `id`.
+fn escape(id: &str) -> String {
+    id.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' | '_' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+impl ThumbnailCache {
+    pub fn new(hd: &HiDrive, dir: impl Into<PathBuf>) -> ThumbnailCache {
+        ThumbnailCache {
+            hd: hd.fork(),
+            dir: dir.into(),
+        }
+    }
+
+    fn item_dir(&self, id: &str) -> PathBuf {
+        self.dir.join(escape(id))
+    }
+
+    /// The local path of the thumbnail of `item` in `size`, downloaded unless cached already.
+    /// `item` must have the `id` and `mtime` fields. Thumbnails of earlier versions of the item in
+    /// the same size are removed.
+    pub async fn get(&self, item: &Item, size: ThumbnailSize) -> Result<PathBuf> {
+        let id = item.id.as_deref().context("thumbnail: item without id")?;
+        let mtime = item
+            .mtime
+            .context("thumbnail: item without mtime")?
+            .unix_timestamp();
+        let dir = self.item_dir(id);
+        let prefix = format!("{}x{}-", size.width, size.height);
+        let name = format!("{}{}", prefix, mtime);
+        let path = dir.join(&name);
+        if fs::try_exists(&path).await? {
+            // Marks the thumbnail as recently used, see `prune()`.
+            let _ = filetime::set_file_mtime(&path, FileTime::now());
+            return Ok(path);
+        }
+        fs::create_dir_all(&dir)
+            .await
+            .with_context(|| format!("thumbnail: creating {:?}", dir))?;
+        remove_stale(&dir, &prefix, &name).await?;
+
+        let mut p = Params::new();
+        p.add_uint("width", size.width as usize)
+            .add_uint("height", size.height as usize);
+        let tmp = dir.join(format!("{}.tmp", name));
+        let download = async {
+            let f = fs::File::create(&tmp).await?;
+            self.hd
+                .fork()
+                .files()
+                .thumbnail(Identifier::Id(id.into()), f, Some(&p))
+                .await
+        };
+        if let Err(e) = download.await {
+            let _ = fs::remove_file(&tmp).await;
+            return Err(e.context(format!("thumbnail: downloading {}", id)));
+        }
+        fs::rename(&tmp, &path).await?;
+        info!(target: "hd_api::thumbnails", "cached thumbnail of {} at {:?}", id, path);
+        Ok(path)
+    }
+
+    /// Remove all cached thumbnails of the item `id`, e.g. after deleting it.
+    pub async fn remove(&self, id: &str) -> Result<()> {
+        match fs::remove_dir_all(self.item_dir(id)).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Remove the least recently used thumbnails until they take up at most `max_bytes`.
+    /// Returns the number of bytes removed.
+    pub async fn prune(&self, max_bytes: u64) -> Result<u64> {
+        let mut files = vec![];
+        let mut total = 0;
+        let mut dirs = match fs::read_dir(&self.dir).await {
+            Ok(d) => d,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        while let Some(d) = dirs.next_entry().await? {
+            if !d.file_type().await?.is_dir() {
+                continue;
+            }
+            let mut entries = fs::read_dir(d.path()).await?;
+            while let Some(e) = entries.next_entry().await? {
+                let meta = e.metadata().await?;
+                total += meta.len();
+                files.push((
+                    FileTime::from_last_modification_time(&meta),
+                    meta.len(),
+                    e.path(),
+                ));
+            }
+        }
+        files.sort();
+        let mut removed = 0;
+        for (_, len, path) in files {
+            if total - removed <= max_bytes {
+                break;
+            }
+            fs::remove_file(&path).await?;
+            removed += len;
+        }
+        info!(target: "hd_api::thumbnails", "pruned {} bytes of thumbnails", removed);
+        Ok(removed)
+    }
+}
+
+/// Remove the files in `dir` starting with `prefix`, except `keep`.
+async fn remove_stale(dir: &Path, prefix: &str, keep: &str) -> Result<()> {
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(e) = entries.next_entry().await? {
+        let name = e.file_name();
+        let name = name.to_string_lossy();
+        if name.starts_with(prefix) && name != keep && !name.ends_with(".tmp") {
+            fs::remove_file(e.path()).await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::mock::{hidrive, MockTransport};
+
+    use time::OffsetDateTime;
+
+    #[tokio::test]
+    async fn test_thumbnail_cache() {
+        let dir = std::env::temp_dir().join("hd_api_test_thumbnails");
+        let _ = std::fs::remove_dir_all(&dir);
+        let t = MockTransport::new();
+        let hd = hidrive(t.clone());
+        let cache = ThumbnailCache::new(&hd, &dir);
+        let size = ThumbnailSize {
+            width: 64,
+            height: 48,
+        };
+        let mut item = Item {
+            id: Some("b1234.5".into()),
+            mtime: Some(OffsetDateTime::from_unix_timestamp(1000).unwrap()),
+            ..Default::default()
+        };
+
+        t.push(200, "thumb v1");
+        let p = cache.get(&item, size).await.unwrap();
+        assert_eq!("thumb v1", std::fs::read_to_string(&p).unwrap());
+        assert_eq!(Some("b1234.5"), t.last().param("pid").as_deref());
+        assert_eq!(Some("64"), t.last().param("width").as_deref());
+        assert_eq!(p, cache.get(&item, size).await.unwrap());
+        assert_eq!(1, t.requests().len());
+
+        // A modified item gets a new thumbnail.
+        item.mtime = Some(OffsetDateTime::from_unix_timestamp(2000).unwrap());
+        t.push(200, "thumb v2");
+        let p2 = cache.get(&item, size).await.unwrap();
+        assert_eq!("thumb v2", std::fs::read_to_string(&p2).unwrap());
+        assert!(!p.exists());
+
+        t.push(404, r#"{"code": 404, "msg": "not found"}"#);
+        let small = ThumbnailSize {
+            width: 16,
+            height: 16,
+        };
+        assert!(cache.get(&item, small).await.is_err());
+        assert_eq!(1, std::fs::read_dir(p2.parent().unwrap()).unwrap().count());
+
+        assert_eq!(0, cache.prune(100).await.unwrap());
+        assert_eq!(8, cache.prune(0).await.unwrap());
+        assert!(!p2.exists());
+        cache.remove("b1234.5").await.unwrap();
+        cache.remove("b1234.5").await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
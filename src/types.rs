@@ -2,6 +2,8 @@ use crate::hashing::Hash;
 
 use std::collections::LinkedList;
 use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+use std::time::Duration;
 
 use serde::ser::SerializeSeq;
 use serde::{Deserialize, Serialize};
@@ -98,6 +100,16 @@ impl Params {
         });
         self
     }
+
+    /// Set the `on_exist` parameter of copy, move, rename and upload calls.
+    pub fn add_on_exist(&mut self, v: OnExist) -> &mut Self {
+        self.add_str("on_exist", v.to_string())
+    }
+
+    /// Iterate over names and (formatted) values, in the order they were added.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, String)> {
+        self.p.iter().map(|p| (p.name.as_str(), p.val.to_string()))
+    }
 }
 
 impl Display for Params {
@@ -114,12 +126,83 @@ impl Display for Params {
         Ok(())
     }
 }
+
+/// Parses a query string like `a=1&b=x%20y` (optionally starting with `?`), as found in URLs.
+/// Values are percent-decoded and kept as strings; a parameter without `=` has an empty value.
+/// Fails with a `ParseError` for empty names.
+impl FromStr for Params {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Params> {
+        let q = s.strip_prefix('?').unwrap_or(s);
+        let mut p = Params::new();
+        for pair in q.split('&').filter(|pair| !pair.is_empty()) {
+            let (k, v) = pair.split_once('=').unwrap_or((pair, ""));
+            if k.is_empty() {
+                return Err(anyhow::Error::new(ParseError::new(
+                    "query string",
+                    s,
+                    "empty parameter name",
+                )));
+            }
+            p.add_str(percent_decode(k), percent_decode(v));
+        }
+        Ok(p)
+    }
+}
+
+/// Decode `%XX` escapes and `+` (as space) in a query string component. Invalid escapes are kept
+/// as they are, and invalid UTF-8 is replaced.
+fn percent_decode(s: &str) -> String {
+    let hex = |c: u8| (c as char).to_digit(16).map(|d| d as u8);
+    let b = s.as_bytes();
+    let mut out = Vec::with_capacity(b.len());
+    let mut i = 0;
+    while i < b.len() {
+        match (
+            b[i],
+            b.get(i + 1).and_then(|&c| hex(c)),
+            b.get(i + 2).and_then(|&c| hex(c)),
+        ) {
+            (b'%', Some(hi), Some(lo)) => {
+                out.push((hi << 4) | lo);
+                i += 3;
+                continue;
+            }
+            (b'+', _, _) => out.push(b' '),
+            (c, _, _) => out.push(c),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
 #[derive(Debug, Default, Serialize, Deserialize)]
 #[serde(default)]
 pub struct ApiError {
     pub msg: String,
+    /// The HTTP status, sent as number or string.
+    #[serde(deserialize_with = "code_from_number_or_string")]
     pub code: usize,
     pub auth: Option<String>,
+    /// Rate limit headers of the response, if any.
+    #[serde(skip)]
+    pub rate_limit: Option<RateLimit>,
+}
+
+fn code_from_number_or_string<'de, D: serde::Deserializer<'de>>(d: D) -> Result<usize, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Code {
+        Number(usize),
+        String(String),
+    }
+    match Code::deserialize(d)? {
+        Code::Number(n) => Ok(n),
+        Code::String(s) => s
+            .trim()
+            .parse()
+            .map_err(|_| serde::de::Error::custom(format!("invalid error code {:?}", s))),
+    }
 }
 
 impl std::error::Error for ApiError {}
@@ -139,8 +222,280 @@ impl Display for ApiError {
     }
 }
 
+/// Returned for error responses whose body is not an `ApiError`, e.g. HTML pages or empty bodies
+/// produced by proxies and gateways.
+#[derive(Debug, Default)]
+pub struct HttpStatusError {
+    pub status: u16,
+    pub content_type: Option<String>,
+    /// The beginning of the response body.
+    pub snippet: String,
+    /// Rate limit headers of the response, if any.
+    pub rate_limit: Option<RateLimit>,
+}
+
+impl std::error::Error for HttpStatusError {}
+
+impl Display for HttpStatusError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_fmt(format_args!(
+            "HTTP status {} ({}): {}",
+            self.status,
+            self.content_type.as_deref().unwrap_or("no content type"),
+            self.snippet
+        ))
+    }
+}
+
+/// The HTTP status of a failed API call, taken from its `ApiError` or `HttpStatusError`.
+pub fn error_status(e: &anyhow::Error) -> Option<u16> {
+    if let Some(e) = e.downcast_ref::<ApiError>() {
+        return u16::try_from(e.code).ok();
+    }
+    e.downcast_ref::<HttpStatusError>().map(|e| e.status)
+}
+
+/// The rate limit headers of a failed API call's response, e.g. to find out how long to wait
+/// after a 429 status.
+pub fn error_rate_limit(e: &anyhow::Error) -> Option<&RateLimit> {
+    if let Some(e) = e.downcast_ref::<ApiError>() {
+        return e.rate_limit.as_ref();
+    }
+    e.downcast_ref::<HttpStatusError>()
+        .and_then(|e| e.rate_limit.as_ref())
+}
+
+/// Timestamps in `X-RateLimit-Reset` above this are Unix times rather than seconds.
+const RESET_TIMESTAMP_MIN: u64 = 1_000_000_000;
+
+/// Rate limit state sent by the server in the `Retry-After` header and the `X-RateLimit-Limit`,
+/// `X-RateLimit-Remaining` and `X-RateLimit-Reset` headers (or `RateLimit-*` without prefix).
+/// See `HiDrive::rate_limit()` and `error_rate_limit()`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RateLimit {
+    /// Requests allowed in the current window.
+    pub limit: Option<u64>,
+    /// Requests left in the current window.
+    pub remaining: Option<u64>,
+    /// Time until the window resets.
+    pub reset: Option<Duration>,
+    /// How long to wait before sending more requests.
+    pub retry_after: Option<Duration>,
+}
+
+impl RateLimit {
+    /// Parse the rate limit headers in `h`. Returns `None` if there are none.
+    pub fn from_headers(h: &reqwest::header::HeaderMap) -> Option<RateLimit> {
+        let get = |names: &[&str]| {
+            names
+                .iter()
+                .find_map(|n| h.get(*n))
+                .and_then(|v| v.to_str().ok())
+                .map(str::trim)
+        };
+        let num = |names: &[&str]| get(names).and_then(|v| v.parse::<u64>().ok());
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let rl = RateLimit {
+            limit: num(&["x-ratelimit-limit", "ratelimit-limit"]),
+            remaining: num(&["x-ratelimit-remaining", "ratelimit-remaining"]),
+            reset: num(&["x-ratelimit-reset", "ratelimit-reset"]).map(|r| {
+                if r >= RESET_TIMESTAMP_MIN {
+                    Duration::from_secs(r.saturating_sub(now.max(0) as u64))
+                } else {
+                    Duration::from_secs(r)
+                }
+            }),
+            retry_after: get(&["retry-after"]).and_then(|v| match v.parse::<u64>() {
+                Ok(s) => Some(Duration::from_secs(s)),
+                Err(_) => parse_http_date(v).map(|t| Duration::from_secs((t - now).max(0) as u64)),
+            }),
+        };
+        (rl != RateLimit::default()).then_some(rl)
+    }
+
+    /// How long to wait before the next request: `retry_after`, or `reset` if no requests are
+    /// left in the current window.
+    pub fn wait(&self) -> Option<Duration> {
+        match self.retry_after {
+            Some(d) => Some(d),
+            None if self.remaining == Some(0) => self.reset,
+            None => None,
+        }
+    }
+}
+
+/// Parse an HTTP date in the preferred format (`Sun, 06 Nov 1994 08:49:37 GMT`) to a Unix
+/// timestamp.
+fn parse_http_date(s: &str) -> Option<i64> {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let f: Vec<&str> = s.split_whitespace().collect();
+    let (day, month, year, hms) = match f[..] {
+        [_, day, month, year, hms, "GMT"] => (day, month, year, hms),
+        _ => return None,
+    };
+    let month = MONTHS.iter().position(|m| *m == month)? as u8 + 1;
+    let date = time::Date::from_calendar_date(
+        year.parse().ok()?,
+        time::Month::try_from(month).ok()?,
+        day.parse().ok()?,
+    )
+    .ok()?;
+    let hms: Vec<u8> = hms
+        .split(':')
+        .map(|x| x.parse().ok())
+        .collect::<Option<_>>()?;
+    let t = match hms[..] {
+        [h, m, s] => time::Time::from_hms(h, m, s).ok()?,
+        _ => return None,
+    };
+    Some(date.with_time(t).assume_utc().unix_timestamp())
+}
+
+/// Returned if a response body exceeds the configured maximum size (see
+/// `HiDrive::set_max_body_size()`).
+#[derive(Debug, Default)]
+pub struct TooLarge {
+    pub limit: usize,
+}
+
+impl std::error::Error for TooLarge {}
+
+impl Display for TooLarge {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_fmt(format_args!(
+            "Response body exceeds maximum size of {} bytes",
+            self.limit
+        ))
+    }
+}
+
+/// Returned if sending a request, including token refresh and retries, takes longer than the
+/// configured deadline (see `HiDrive::set_request_deadline()`).
+#[derive(Debug, Default)]
+pub struct DeadlineExceeded {
+    pub budget: std::time::Duration,
+}
+
+impl std::error::Error for DeadlineExceeded {}
+
+impl Display for DeadlineExceeded {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_fmt(format_args!(
+            "Request deadline of {:?} exceeded",
+            self.budget
+        ))
+    }
+}
+
+/// Returned for transfers of a sync run that would exceed its byte budget (see
+/// `sync::MirrorOptions::budget`).
+#[derive(Debug, Default)]
+pub struct BudgetExhausted {
+    pub budget: u64,
+}
+
+impl std::error::Error for BudgetExhausted {}
+
+impl Display for BudgetExhausted {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_fmt(format_args!(
+            "Transfer budget of {} bytes exhausted",
+            self.budget
+        ))
+    }
+}
+
+/// Returned by a sync run whose uploads don't fit into the free space of the account (see
+/// `sync::QuotaPolicy::Fail`).
+#[derive(Debug, Default)]
+pub struct QuotaExceeded {
+    pub needed: u64,
+    pub free: u64,
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+impl Display for QuotaExceeded {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_fmt(format_args!(
+            "Uploads need {} bytes, but only {} bytes are free",
+            self.needed, self.free
+        ))
+    }
+}
+
+/// Returned if an operation was aborted through its `CancellationToken`.
+#[derive(Debug, Default)]
+pub struct Cancelled;
+
+impl std::error::Error for Cancelled {}
+
+impl Display for Cancelled {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str("Operation cancelled")
+    }
+}
+
+/// Returned by parsers (e.g. `Hash::parse()`, `Identifier::from_str()`) for malformed input.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ParseError {
+    /// What was being parsed, e.g. "SHA-1 hash".
+    pub what: &'static str,
+    /// The beginning of the input.
+    pub input: String,
+    pub reason: &'static str,
+}
+
+/// Inputs are cut to this many bytes in `ParseError`.
+const PARSE_ERROR_INPUT_LEN: usize = 64;
+
+impl ParseError {
+    pub fn new(what: &'static str, input: &str, reason: &'static str) -> ParseError {
+        let mut end = input.len().min(PARSE_ERROR_INPUT_LEN);
+        while !input.is_char_boundary(end) {
+            end -= 1;
+        }
+        ParseError {
+            what,
+            input: input[..end].to_string(),
+            reason,
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_fmt(format_args!(
+            "Invalid {} {:?}: {}",
+            self.what, self.input, self.reason
+        ))
+    }
+}
+
+/// What to do if the destination of a copy, move, rename or upload exists, see
+/// `Params::add_on_exist()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnExist {
+    /// Choose a new name for the destination by appending a number.
+    Autoname,
+    Overwrite,
+}
+
+impl Display for OnExist {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(match self {
+            OnExist::Autoname => "autoname",
+            OnExist::Overwrite => "overwrite",
+        })
+    }
+}
+
 /// An identifier of a file or directory.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Identifier {
     /// A file or directory ID.
     Id(String),
@@ -160,6 +515,79 @@ impl Identifier {
                 .add_str(path_parameter.as_ref(), path),
         };
     }
+
+    /// The identifier of the entry `name` in this directory.
+    pub fn join<S: AsRef<str>>(&self, name: S) -> Identifier {
+        let join = |p: &str| format!("{}/{}", p.trim_end_matches('/'), name.as_ref());
+        match self {
+            Identifier::Id(ref id) => Identifier::Relative {
+                id: id.clone(),
+                path: name.as_ref().to_string(),
+            },
+            Identifier::Path(ref p) => Identifier::Path(join(p)),
+            Identifier::Relative { ref id, ref path } => Identifier::Relative {
+                id: id.clone(),
+                path: join(path),
+            },
+        }
+    }
+}
+
+impl From<&Home> for Identifier {
+    fn from(home: &Home) -> Identifier {
+        Identifier::Id(home.id.clone())
+    }
+}
+
+impl From<Home> for Identifier {
+    fn from(home: Home) -> Identifier {
+        Identifier::Id(home.id)
+    }
+}
+
+/// Formats as parsed by `Identifier::from_str()`.
+impl Display for Identifier {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Identifier::Id(ref id) => write!(f, "id:{}", id),
+            Identifier::Path(ref p) => f.write_str(p),
+            Identifier::Relative { ref id, ref path } => write!(f, "id:{}/{}", id, path),
+        }
+    }
+}
+
+/// Parses an absolute path (`/users/me/a.txt`), an ID (`id:b1234.56`) or a path relative to an
+/// ID (`id:b1234.56/sub/a.txt`). Fails with a `ParseError` for other input, empty IDs and
+/// control characters.
+impl FromStr for Identifier {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Identifier> {
+        let err = |reason| anyhow::Error::new(ParseError::new("identifier", s, reason));
+        if s.chars().any(char::is_control) {
+            return Err(err("contains control characters"));
+        }
+        if s.starts_with('/') {
+            return Ok(Identifier::Path(s.to_string()));
+        }
+        let rest = s
+            .strip_prefix("id:")
+            .ok_or_else(|| err("expected an absolute path or id:<id>"))?;
+        let (id, path) = rest.split_once('/').unwrap_or((rest, ""));
+        if id.is_empty() {
+            return Err(err("empty id"));
+        }
+        if path.starts_with('/') {
+            return Err(err("relative path starts with /"));
+        }
+        Ok(if path.is_empty() {
+            Identifier::Id(id.to_string())
+        } else {
+            Identifier::Relative {
+                id: id.to_string(),
+                path: path.to_string(),
+            }
+        })
+    }
 }
 
 #[derive(Debug, Default, Serialize, Deserialize)]
@@ -232,7 +660,7 @@ pub struct Item {
     pub rshare: Option<Share>,
 }
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 #[serde(default)]
 pub struct Share {
     pub name: Option<String>,
@@ -287,6 +715,130 @@ pub struct User {
     pub folder: Item,
 }
 
+/// The user's home directory, see `HiDriveUser::home()`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Home {
+    /// Path as used in API calls, e.g. `/users/me`.
+    pub path: String,
+    pub id: String,
+}
+
+impl Home {
+    /// Build from the `home` and `home_id` fields of `user`.
+    pub fn from_user(user: &User) -> Home {
+        let path = match user.home.strip_prefix("root") {
+            Some(rest) if rest.is_empty() || rest.starts_with('/') => rest,
+            _ => &user.home,
+        };
+        Home {
+            path: if path.starts_with('/') {
+                path.to_string()
+            } else {
+                format!("/{}", path)
+            },
+            id: user.home_id.clone(),
+        }
+    }
+
+    /// The identifier of `path` relative to the home directory, or of the home directory itself
+    /// if `path` is empty.
+    pub fn join(&self, path: impl AsRef<str>) -> Identifier {
+        let path = path.as_ref().trim_matches('/');
+        if path.is_empty() {
+            Identifier::from(self)
+        } else {
+            Identifier::from(self).join(path)
+        }
+    }
+}
+
+/// Profile changes for `HiDriveUser::update()`. Fields left at `None` are not changed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UserPatch {
+    /// The account to change, if not the own one (requires admin rights).
+    pub account: Option<String>,
+    /// Description, usually the user's full name.
+    pub descr: Option<String>,
+    pub email: Option<String>,
+    /// Language code, e.g. `de` or `en`.
+    pub language: Option<String>,
+}
+
+impl UserPatch {
+    /// Add the changed fields to `p`.
+    pub fn to_params(&self, p: &mut Params) {
+        for (k, v) in [
+            ("account", &self.account),
+            ("descr", &self.descr),
+            ("email", &self.email),
+            ("language", &self.language),
+        ] {
+            if let Some(v) = v {
+                p.add_str(k, v);
+            }
+        }
+    }
+}
+
+/// Storage quota of an account, in bytes.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Quota {
+    pub limit: u64,
+    pub used: u64,
+}
+
+impl Quota {
+    /// Bytes that can still be stored.
+    pub fn free(&self) -> u64 {
+        self.limit.saturating_sub(self.used)
+    }
+}
+
+/// The application making requests, see `HiDriveApp::me()`.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct App {
+    pub id: String,
+    pub name: String,
+    pub homepage: String,
+}
+
+/// The access token used for a request, as known to the server; see `HiDriveApp::token()`.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TokenInfo {
+    pub app_id: String,
+    pub alias: String,
+    pub userid: String,
+    /// Granted scope, e.g. `rw,user`.
+    pub scope: String,
+    /// Seconds until the token expires.
+    pub expires_in: u64,
+}
+
+impl TokenInfo {
+    /// Whether the token grants at least `scope`: `rw` includes `ro`, and `owner` includes
+    /// `admin`, which includes `user`. If not, the user has to authorize the app again with the
+    /// wider scope.
+    pub fn grants(&self, scope: &crate::oauth2::Scope) -> bool {
+        use crate::oauth2::{Access, Role};
+
+        let parts: Vec<&str> = self.scope.split(',').map(str::trim).collect();
+        let has = |s: &str| parts.contains(&s);
+        let access = match scope.access {
+            Access::Ro => has("ro") || has("rw"),
+            Access::Rw => has("rw"),
+        };
+        let role = match scope.role {
+            Role::User => has("user") || has("admin") || has("owner"),
+            Role::Admin => has("admin") || has("owner"),
+            Role::Owner => has("owner"),
+        };
+        access && role
+    }
+}
+
 #[derive(Debug, Default, Serialize, Deserialize)]
 #[serde(default)]
 pub struct Url {
@@ -318,3 +870,214 @@ pub struct WebsocketNotification {
     name: String,
     args: WebsocketArgs,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde::de::DeserializeOwned;
+    use serde_json::Value;
+
+    /// Returns the path of the first value of `fixture` that differs in or is missing from `out`.
+    fn lost(fixture: &Value, out: &Value, path: &str) -> Option<String> {
+        match (fixture, out) {
+            (Value::Object(f), Value::Object(o)) => f.iter().find_map(|(k, v)| match o.get(k) {
+                Some(ov) => lost(v, ov, &format!("{}.{}", path, k)),
+                None => Some(format!("{}.{}", path, k)),
+            }),
+            (Value::Array(f), Value::Array(o)) if f.len() == o.len() => f
+                .iter()
+                .zip(o.iter())
+                .enumerate()
+                .find_map(|(i, (fv, ov))| lost(fv, ov, &format!("{}[{}]", path, i))),
+            _ if fixture == out => None,
+            _ => Some(path.to_string()),
+        }
+    }
+
+    /// Deserialize the fixture `name` as `T` and check that serializing it again retains all
+    /// values, and that another round trip is stable.
+    fn round_trip<T: Serialize + DeserializeOwned>(name: &str) {
+        let s = std::fs::read_to_string(format!("testdata/fixtures/{}", name)).unwrap();
+        let fixture: Value = serde_json::from_str(&s).unwrap();
+        let t: T = serde_json::from_str(&s).unwrap();
+        let out = serde_json::to_value(&t).unwrap();
+        assert_eq!(None, lost(&fixture, &out, name), "{}", out);
+        let again = serde_json::to_value(serde_json::from_value::<T>(out.clone()).unwrap());
+        assert_eq!(out, again.unwrap(), "{}", name);
+    }
+
+    #[test]
+    fn test_fixtures() {
+        round_trip::<Item>("item_tree.json");
+        round_trip::<Share>("share.json");
+        round_trip::<User>("user.json");
+        round_trip::<Quota>("quota.json");
+        round_trip::<App>("app.json");
+        round_trip::<TokenInfo>("token_info.json");
+        round_trip::<ApiError>("error.json");
+        round_trip::<ApiError>("error_auth.json");
+        round_trip::<FileHash>("file_hash.json");
+        round_trip::<Permissions>("permissions.json");
+        round_trip::<SearchResult>("search.json");
+        round_trip::<Url>("url.json");
+        round_trip::<WebsocketNotification>("websocket_notification.json");
+    }
+
+    #[test]
+    fn test_fixture_values() {
+        let s = std::fs::read_to_string("testdata/fixtures/item_tree.json").unwrap();
+        let it: Item = serde_json::from_str(&s).unwrap();
+        assert_eq!(Some("dir"), it.typ.as_deref());
+        assert_eq!(1672531200, it.mtime.unwrap().unix_timestamp());
+        let file = &it.members[0].members[0];
+        assert_eq!(Some("Jänner Ünïcode.jpg"), file.name.as_deref());
+        assert_eq!(
+            "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa",
+            file.chash.as_ref().unwrap().to_string()
+        );
+        let share = it.members[1].rshare.as_ref().unwrap();
+        assert_eq!(Some(4), share.count);
+
+        let s = std::fs::read_to_string("testdata/fixtures/error_auth.json").unwrap();
+        let e: ApiError = serde_json::from_str(&s).unwrap();
+        assert_eq!("ApiError 401: Unauthorized invalid_token", e.to_string());
+    }
+
+    #[test]
+    fn test_identifier_from_str() {
+        let parse = |s: &str| s.parse::<Identifier>().unwrap().to_string();
+        for s in ["/users/me/a b.txt", "id:b1.2", "id:b1.2/sub/ä.txt"] {
+            assert_eq!(s, parse(s));
+        }
+        assert!(matches!("id:b1.2/".parse::<Identifier>(), Ok(Identifier::Id(id)) if id == "b1.2"));
+        for bad in ["", "a.txt", "id:", "id:/a", "id:b1//a", "/a\nb", "ID:b1"] {
+            let e = bad.parse::<Identifier>().unwrap_err();
+            assert!(e.downcast_ref::<ParseError>().is_some(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_params_from_str() {
+        let p: Params = "?a=1&b=x%20y+z&c&&d=%zz%C3%A4".parse().unwrap();
+        let v: Vec<(&str, String)> = p.iter().collect();
+        assert_eq!(
+            vec![
+                ("a", "1".to_string()),
+                ("b", "x y z".to_string()),
+                ("c", "".to_string()),
+                ("d", "%zzä".to_string())
+            ],
+            v
+        );
+        assert!("a=1&=2".parse::<Params>().is_err());
+        assert_eq!(0, "".parse::<Params>().unwrap().iter().count());
+        let e = ParseError::new("x", &"ä".repeat(100), "r");
+        assert!(e.input.len() <= 64);
+    }
+
+    #[test]
+    fn test_identifier_round_trip() {
+        for id in [
+            Identifier::Id("b1.2".into()),
+            Identifier::Path("/users/me/a b".into()),
+            Identifier::Relative {
+                id: "b1.2".into(),
+                path: "sub/c".into(),
+            },
+        ] {
+            let s = serde_json::to_string(&id).unwrap();
+            let back: Identifier = serde_json::from_str(&s).unwrap();
+            assert_eq!(s, serde_json::to_string(&back).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_home_from_user() {
+        let home = |h: &str| {
+            let user = User {
+                home: h.into(),
+                ..Default::default()
+            };
+            Home::from_user(&user).path
+        };
+        assert_eq!("/users/me", home("root/users/me"));
+        assert_eq!("/", home("root"));
+        assert_eq!("/rootfolder/x", home("rootfolder/x"));
+        assert_eq!("/users/me", home("/users/me"));
+    }
+
+    #[test]
+    fn test_token_grants() {
+        use crate::oauth2::{Access, Role, Scope};
+
+        let tok = TokenInfo {
+            scope: "rw,admin".into(),
+            ..Default::default()
+        };
+        let scope = |role, access| Scope { role, access };
+        assert!(tok.grants(&scope(Role::User, Access::Ro)));
+        assert!(tok.grants(&scope(Role::Admin, Access::Rw)));
+        assert!(!tok.grants(&scope(Role::Owner, Access::Ro)));
+        let tok = TokenInfo {
+            scope: "ro,user".into(),
+            ..Default::default()
+        };
+        assert!(tok.grants(&scope(Role::User, Access::Ro)));
+        assert!(!tok.grants(&scope(Role::User, Access::Rw)));
+        assert!(!TokenInfo::default().grants(&scope(Role::User, Access::Ro)));
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        use reqwest::header::HeaderMap;
+
+        let headers = |kvs: &[(&'static str, &str)]| {
+            let mut h = HeaderMap::new();
+            for (k, v) in kvs {
+                h.insert(*k, v.parse().unwrap());
+            }
+            h
+        };
+        assert_eq!(None, RateLimit::from_headers(&headers(&[("etag", "x")])));
+        let rl = RateLimit::from_headers(&headers(&[
+            ("x-ratelimit-limit", "100"),
+            ("x-ratelimit-remaining", "0"),
+            ("x-ratelimit-reset", "30"),
+        ]))
+        .unwrap();
+        assert_eq!(Some(100), rl.limit);
+        assert_eq!(Some(Duration::from_secs(30)), rl.wait());
+        let rl = RateLimit::from_headers(&headers(&[
+            ("ratelimit-remaining", "5"),
+            ("retry-after", " 7 "),
+        ]))
+        .unwrap();
+        assert_eq!(Some(5), rl.remaining);
+        assert_eq!(Some(Duration::from_secs(7)), rl.wait());
+
+        // Dates in the past mean no wait; malformed values are ignored.
+        let rl = RateLimit::from_headers(&headers(&[
+            ("retry-after", "Sun, 06 Nov 1994 08:49:37 GMT"),
+            ("x-ratelimit-reset", "1000000000"),
+        ]))
+        .unwrap();
+        assert_eq!(Some(Duration::ZERO), rl.retry_after);
+        assert_eq!(Some(Duration::ZERO), rl.reset);
+        assert_eq!(
+            Some(784111777),
+            parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT")
+        );
+        for d in [
+            "Sun, 06 Nov 1994 08:49 GMT",
+            "Sun, 06 Foo 1994 08:49:37 GMT",
+            "soon",
+        ] {
+            assert_eq!(None, parse_http_date(d), "{}", d);
+        }
+        assert_eq!(
+            None,
+            RateLimit::from_headers(&headers(&[("retry-after", "soon")]))
+        );
+    }
+}